@@ -0,0 +1,185 @@
+use std::path::Path;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+use criterion::measurement::WallTime;
+use criterion::{
+    BenchmarkGroup, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main,
+};
+use kvs::{FlushPolicy, KvStore, KvsEngine, SizeLimits, SledEngine};
+use tempfile::TempDir;
+
+const KEYS: usize = 1000;
+const READS_PER_THREAD: usize = 1000;
+
+// Total time for every thread to read `READS_PER_THREAD` keys, scaling with cores.
+fn concurrent_reads(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..KEYS {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+
+    let mut group = c.benchmark_group("concurrent_reads");
+    for threads in [1, 2, 4, 8] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    let barrier = Arc::new(Barrier::new(threads));
+                    let handles: Vec<_> = (0..threads)
+                        .map(|t| {
+                            let store = store.clone();
+                            let barrier = barrier.clone();
+                            thread::spawn(move || {
+                                barrier.wait();
+                                for i in 0..READS_PER_THREAD {
+                                    let key = format!("key{}", (i + t) % KEYS);
+                                    assert!(store.get(key).unwrap().is_some());
+                                }
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+// Appends of small records, where any per-write syscall beyond the write
+// itself shows up.
+fn sequential_sets(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let mut i = 0;
+    c.bench_function("sequential_sets", |b| {
+        b.iter(|| {
+            store
+                .set(format!("key{}", i % KEYS), format!("value{}", i))
+                .unwrap();
+            i += 1;
+        })
+    });
+}
+
+// Sled sets under each flush policy, showing what an fsync per write costs.
+fn sled_flush_policies(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sled_flush_policies");
+    for (name, policy) in [
+        ("always", FlushPolicy::Always),
+        ("every_100_ops", FlushPolicy::EveryOps(100)),
+        (
+            "interval_100ms",
+            FlushPolicy::Interval(Duration::from_millis(100)),
+        ),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let store =
+            SledEngine::open_with_flush_policy(temp_dir.path(), SizeLimits::default(), policy)
+                .unwrap();
+        let mut i = 0;
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                store
+                    .set(format!("key{}", i % KEYS), format!("value{}", i))
+                    .unwrap();
+                i += 1;
+            })
+        });
+    }
+    group.finish();
+}
+
+// Key counts, value sizes in bytes and percentages of reads the mixed
+// workloads run with, every combination against each engine.
+const MIXED_KEYS: [usize; 2] = [100, 10_000];
+const MIXED_VALUE_SIZES: [usize; 3] = [16, 1024, 16 * 1024];
+const MIXED_READ_PERCENTS: [usize; 3] = [50, 90, 100];
+const MIXED_OPS: usize = 100;
+
+// Batches of `MIXED_OPS` reads and sets of preloaded keys on each engine,
+// reported as operations per second.
+fn mixed_workloads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mixed_workloads");
+    group.throughput(Throughput::Elements(MIXED_OPS as u64));
+    for keys in MIXED_KEYS {
+        for value_size in MIXED_VALUE_SIZES {
+            for read_percent in MIXED_READ_PERCENTS {
+                let workload = MixedWorkload {
+                    keys,
+                    value: "v".repeat(value_size),
+                    read_percent,
+                };
+                let parameter = format!("{keys}_keys/{value_size}_bytes/{read_percent}_reads");
+                workload.bench(&mut group, "kvs", &parameter, |path| KvStore::open(path));
+                workload.bench(&mut group, "sled", &parameter, |path| {
+                    SledEngine::open(path)
+                });
+            }
+        }
+    }
+    group.finish();
+}
+
+struct MixedWorkload {
+    keys: usize,
+    value: String,
+    read_percent: usize,
+}
+
+impl MixedWorkload {
+    fn bench<E: KvsEngine>(
+        &self,
+        group: &mut BenchmarkGroup<WallTime>,
+        engine: &str,
+        parameter: &str,
+        open: fn(&Path) -> kvs::Result<E>,
+    ) {
+        // Criterion calls the routine once per sample, and not at all for
+        // filtered out benchmarks, so the store is opened and filled lazily.
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = None;
+        // A multiplicative step spreads consecutive operations over the keys
+        // without pulling in a random number generator.
+        let mut i: usize = 0;
+        group.bench_function(BenchmarkId::new(engine, parameter), |b| {
+            let store = store.get_or_insert_with(|| {
+                let store = open(temp_dir.path()).unwrap();
+                for key in 0..self.keys {
+                    store
+                        .set(format!("key{}", key), self.value.clone())
+                        .unwrap();
+                }
+                store
+            });
+            b.iter(|| {
+                for _ in 0..MIXED_OPS {
+                    let key = format!("key{}", i.wrapping_mul(7919) % self.keys);
+                    if i % 100 < self.read_percent {
+                        assert!(store.get(key).unwrap().is_some());
+                    } else {
+                        store.set(key, self.value.clone()).unwrap();
+                    }
+                    i += 1;
+                }
+            })
+        });
+    }
+}
+
+criterion_group!(
+    benches,
+    concurrent_reads,
+    sequential_sets,
+    sled_flush_policies,
+    mixed_workloads
+);
+criterion_main!(benches);
@@ -0,0 +1,47 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use crossbeam_utils::sync::WaitGroup;
+use kvs::thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
+
+const JOBS: usize = 10_000;
+
+// Spawn `JOBS` jobs doing next to nothing and wait for all of them, so the
+// time is spent handing jobs to the workers.
+fn dispatch<P: ThreadPool>(pool: &P) {
+    let wg = WaitGroup::new();
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..JOBS {
+        let wg = wg.clone();
+        let counter = counter.clone();
+        pool.spawn(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            drop(wg);
+        });
+    }
+    wg.wait();
+    assert_eq!(counter.load(Ordering::Relaxed), JOBS);
+}
+
+// The mpsc receiver behind a mutex against the lock-free shared queue.
+fn tiny_jobs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tiny_jobs");
+    group.throughput(Throughput::Elements(JOBS as u64));
+    for threads in [1, 2, 4, 8] {
+        let pool = NaiveThreadPool::new(threads).unwrap();
+        group.bench_with_input(BenchmarkId::new("mpsc_mutex", threads), &pool, |b, pool| {
+            b.iter(|| dispatch(pool))
+        });
+        let pool = SharedQueueThreadPool::new(threads).unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("shared_queue", threads),
+            &pool,
+            |b, pool| b.iter(|| dispatch(pool)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, tiny_jobs);
+criterion_main!(benches);
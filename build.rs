@@ -0,0 +1,10 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Compile the proto with protox, so building needs no `protoc` install.
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/kvs.proto");
+        let fds = protox::compile(["proto/kvs.proto"], ["proto"])?;
+        tonic_prost_build::configure().compile_fds(fds)?;
+    }
+    Ok(())
+}
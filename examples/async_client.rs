@@ -0,0 +1,45 @@
+//! Store and read back a key with an async client of the JSON protocol.
+//!
+//! Talks to the `kvs-server` at the address given as the first argument, or
+//! else to an async server of its own over a temporary directory:
+//!
+//! ```text
+//! cargo run --example async_client --features async -- 127.0.0.1:4000
+//! ```
+
+use kvs::async_kvs::{self, AsyncClient, AsyncKvStore};
+use kvs::protocol::{Request, Response};
+use kvs::{KvStore, Result};
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr = match std::env::args().nth(1) {
+        Some(addr) => addr,
+        None => {
+            let store = AsyncKvStore::new(KvStore::open(temp_dir.path())?);
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            tokio::spawn(async_kvs::serve(listener, store));
+            addr.to_string()
+        }
+    };
+
+    let mut client = AsyncClient::connect(&addr).await?;
+    let set = Request::Set {
+        key: "greeting".to_owned(),
+        value: "hello".to_owned(),
+        request_id: None,
+    };
+    assert!(matches!(client.request(&set).await?, Response::Ok));
+    let get = Request::Get {
+        key: "greeting".to_owned(),
+    };
+    match client.request(&get).await? {
+        Response::Value(Some(value)) => println!("greeting = {value}"),
+        response => println!("unexpected response {response:?}"),
+    }
+    Ok(())
+}
@@ -0,0 +1,279 @@
+//! Async access to a [`KvsEngine`] with tokio, for services that mustn't block
+//! their runtime on engine calls.
+//!
+//! [`AsyncKvStore`] runs each engine call on tokio's blocking pool. [`serve`]
+//! answers the JSON protocol of `kvs-server` over tokio sockets, and
+//! [`AsyncClient`] speaks it to either server.
+
+use std::io;
+
+use serde::Serialize;
+use serde::de::{self, DeserializeOwned};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::engine::{KvStore, KvsEngine};
+use crate::error::{KvsError, Result};
+use crate::protocol::{ErrorCode, PROTOCOL_VERSION, Request, Response};
+
+/// A [`KvsEngine`] whose calls return futures.
+///
+/// Each call runs on tokio's blocking pool with its own clone of the engine,
+/// so the futures are `'static` and may be spawned.
+#[derive(Clone)]
+pub struct AsyncKvStore<E: KvsEngine = KvStore> {
+    engine: E,
+}
+
+impl<E: KvsEngine> AsyncKvStore<E> {
+    /// Wrap `engine`.
+    pub fn new(engine: E) -> Self {
+        Self { engine }
+    }
+
+    /// Set a key-value pair, see [`KvsEngine::set`].
+    pub fn set(
+        &self,
+        key: String,
+        value: String,
+    ) -> impl Future<Output = Result<()>> + Send + use<E> {
+        self.run(move |engine| engine.set(key, value))
+    }
+
+    /// Get the value of a key, see [`KvsEngine::get`].
+    pub fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send + use<E> {
+        self.run(move |engine| engine.get(key))
+    }
+
+    /// Remove a key, see [`KvsEngine::remove`].
+    pub fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send + use<E> {
+        self.run(move |engine| engine.remove(key))
+    }
+
+    /// Run `f` against a clone of the engine off the async runtime.
+    pub(crate) fn run<T, F>(&self, f: F) -> impl Future<Output = Result<T>> + Send + use<E, T, F>
+    where
+        T: Send + 'static,
+        F: FnOnce(E) -> Result<T> + Send + 'static,
+    {
+        let engine = self.engine.clone();
+        async move {
+            tokio::task::spawn_blocking(move || f(engine))
+                .await
+                .map_err(io::Error::other)?
+        }
+    }
+}
+
+/// Serve `store` on `listener` until accepting fails, one task per connection.
+///
+/// Only the handshake, `Get`, `Set` and `Remove` are served; other requests
+/// are answered with a `BadRequest` error.
+pub async fn serve<E: KvsEngine>(listener: TcpListener, store: AsyncKvStore<E>) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let store = store.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_stream(stream, store).await {
+                log::error!("Error handling stream: {:?}", e);
+            }
+        });
+    }
+}
+
+/// Answer the requests of one connection in order until the client closes it.
+async fn handle_stream<E: KvsEngine>(stream: TcpStream, store: AsyncKvStore<E>) -> Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut requests = JsonReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    loop {
+        let request = match requests.next::<Request>().await {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(KvsError::SerdeError(e)) if !e.is_io() => {
+                let response = Response::Err {
+                    code: ErrorCode::BadRequest,
+                    message: format!("malformed request: {e}"),
+                };
+                return write_message(&mut writer, &response).await;
+            }
+            Err(e) => return Err(e),
+        };
+        let response = match request {
+            Request::Hello { protocol_version } if protocol_version != PROTOCOL_VERSION => {
+                let response = Response::Err {
+                    code: ErrorCode::BadRequest,
+                    message: format!(
+                        "the client speaks protocol version {protocol_version}, the server {PROTOCOL_VERSION}"
+                    ),
+                };
+                return write_message(&mut writer, &response).await;
+            }
+            Request::Hello { .. } => Response::Hello {
+                protocol_version: PROTOCOL_VERSION,
+            },
+            // Request ids are not remembered here, a retry sets the key again.
+            Request::Set { key, value, .. } => match store.set(key, value).await {
+                Ok(()) => Response::Ok,
+                Err(e) => Response::error(&e),
+            },
+            Request::Get { key } => match store.get(key).await {
+                Ok(value) => Response::Value(value),
+                Err(e) => Response::error(&e),
+            },
+            Request::Remove { key } => match store.remove(key).await {
+                Ok(()) => Response::Ok,
+                Err(e) => Response::error(&e),
+            },
+            request => Response::Err {
+                code: ErrorCode::BadRequest,
+                message: format!("unsupported request {request:?}"),
+            },
+        };
+        write_message(&mut writer, &response).await?;
+    }
+}
+
+/// A client of the JSON protocol over a tokio socket, answered in order like
+/// [`crate::client::Client`].
+pub struct AsyncClient {
+    responses: JsonReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+}
+
+impl AsyncClient {
+    /// Connect to the server at `addr` and exchange protocol versions.
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<AsyncClient> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        let mut client = AsyncClient {
+            responses: JsonReader::new(reader),
+            writer: BufWriter::new(writer),
+        };
+        let hello = Request::Hello {
+            protocol_version: PROTOCOL_VERSION,
+        };
+        match client.request(&hello).await? {
+            Response::Hello { protocol_version } if protocol_version == PROTOCOL_VERSION => {
+                Ok(client)
+            }
+            Response::Hello { protocol_version } => Err(KvsError::IncompatibleProtocol(format!(
+                "the server speaks protocol version {protocol_version}, the client {PROTOCOL_VERSION}"
+            ))),
+            Response::Err { code, message } => Err(KvsError::ResponseError { code, message }),
+            response => Err(KvsError::IncompatibleProtocol(format!(
+                "unexpected response {response:?} to the handshake"
+            ))),
+        }
+    }
+
+    /// Send `request` and wait for its response.
+    pub async fn request(&mut self, request: &Request) -> Result<Response> {
+        write_message(&mut self.writer, request).await?;
+        match self.responses.next().await? {
+            Some(response) => Ok(response),
+            None => {
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no response from server").into())
+            }
+        }
+    }
+}
+
+/// Splits a stream of concatenated JSON values, as the protocol frames them.
+///
+/// The messages are objects or strings, so a value is whole once its brackets
+/// or quotes close. Each byte is scanned once for that, keeping the nesting
+/// between reads, and a value is parsed only once it is whole.
+struct JsonReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    /// Where the next value starts in `buf`, the values before it are parsed.
+    start: usize,
+    /// How far the next value has been scanned.
+    scanned: usize,
+    /// Objects and arrays open where the scan stopped.
+    depth: usize,
+    /// Whether the scan stopped in a string, and right after a backslash in it.
+    in_string: bool,
+    escaped: bool,
+}
+
+impl<R: AsyncRead + Unpin> JsonReader<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            start: 0,
+            scanned: 0,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+        }
+    }
+
+    /// The next value, or `None` once the peer closed the stream between values.
+    async fn next<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        loop {
+            if let Some(end) = self.scan()? {
+                let value = serde_json::from_slice(&self.buf[self.start..end]);
+                self.start = end;
+                return Ok(Some(value?));
+            }
+            // Drop the parsed values before reading more.
+            self.buf.drain(..self.start);
+            self.scanned -= self.start;
+            self.start = 0;
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+    }
+
+    /// Scan the bytes not scanned yet for the end of the next value, skipping
+    /// the whitespace before it.
+    fn scan(&mut self) -> Result<Option<usize>> {
+        while let Some(&byte) = self.buf.get(self.scanned) {
+            self.scanned += 1;
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+            } else {
+                match byte {
+                    b'"' => self.in_string = true,
+                    b'{' | b'[' => self.depth += 1,
+                    b'}' | b']' if self.depth > 0 => self.depth -= 1,
+                    _ if self.depth > 0 => {}
+                    _ if byte.is_ascii_whitespace() => {
+                        self.start = self.scanned;
+                        continue;
+                    }
+                    _ => {
+                        let message = format!("expected an object or a string, found {byte:#04x}");
+                        return Err(<serde_json::Error as de::Error>::custom(message).into());
+                    }
+                }
+            }
+            if self.depth == 0 && !self.in_string {
+                return Ok(Some(self.scanned));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Write `message` and flush it.
+async fn write_message(
+    writer: &mut (impl AsyncWrite + Unpin),
+    message: &impl Serialize,
+) -> Result<()> {
+    writer.write_all(&serde_json::to_vec(message)?).await?;
+    writer.flush().await?;
+    Ok(())
+}
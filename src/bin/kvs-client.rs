@@ -1,14 +1,22 @@
-use std::{
-    io::{BufReader, BufWriter, Write},
-    net::TcpStream,
-};
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
 
-use clap::{Parser, Subcommand};
-use kvs::protocol::{Request, Response};
-use serde_json::Deserializer;
+use base64::prelude::{BASE64_STANDARD, Engine};
+use clap::{Parser, Subcommand, ValueEnum};
+use kvs::client::{Client, ClientConfig};
+use kvs::error::{KvsError, Result};
+use kvs::protocol::{ErrorCode, Request, Response};
+use kvs::{KvStore, KvsEngine, data_dir};
+use serde_json::json;
+
+/// Keys copied between progress reports of `clone`.
+const CLONE_PROGRESS_EVERY: usize = 1000;
 
 #[derive(Parser, Debug)]
-#[command(author, version)]
+#[command(author, version = kvs::protocol::VERSION)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
@@ -18,19 +26,120 @@ struct Cli {
 struct CommandOpts {
     #[arg(short, long, default_value = "127.0.0.1:4000")]
     addr: String,
+    /// Milliseconds to wait for the server to accept the connection
+    #[arg(long, value_name = "MS")]
+    connect_timeout: Option<u64>,
+    /// Milliseconds to wait for each response
+    #[arg(long, value_name = "MS")]
+    request_timeout: Option<u64>,
+    /// Times to retry a refused connection, backing off exponentially
+    #[arg(long, default_value_t = 0)]
+    retries: u32,
+    /// Milliseconds the server has to answer, failing with exit status 7 after
+    #[arg(long, value_name = "MS")]
+    deadline: Option<u64>,
+    /// Talk TLS to the server, checking its certificate against --ca
+    #[arg(long, requires = "ca")]
+    tls: bool,
+    /// PEM file of the certificates trusted to sign the server's
+    #[arg(long, value_name = "PATH", requires = "tls")]
+    ca: Option<PathBuf>,
+    /// The shared secret of a server started with --auth-token
+    #[arg(long, value_name = "TOKEN")]
+    auth_token: Option<String>,
+    /// Have the server compress large values it sends
+    #[arg(long)]
+    compress: bool,
+    /// How to print responses: `plain` text, or `json` with one object per
+    /// line, errors included as `{"error": ...}` on stdout
+    #[arg(long, value_enum, default_value_t = Output::Plain, alias = "format")]
+    output: Output,
+}
+
+/// How responses and errors are printed.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Output {
+    Plain,
+    Json,
+}
+
+impl CommandOpts {
+    fn client_config(&self) -> ClientConfig {
+        ClientConfig {
+            connect_timeout: self.connect_timeout.map(Duration::from_millis),
+            request_timeout: self.request_timeout.map(Duration::from_millis),
+            retries: self.retries,
+            tls_ca: self.ca.clone(),
+            // The certificate is checked against the host part of the address.
+            server_name: self.ca.as_ref().map(|_| {
+                let host = self
+                    .addr
+                    .rsplit_once(':')
+                    .map_or(&*self.addr, |(host, _)| host);
+                host.trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_owned()
+            }),
+            auth_token: self.auth_token.clone(),
+            compress_values: self.compress,
+        }
+    }
+}
+
+/// `request`, limited to `deadline` milliseconds if given.
+fn with_deadline(request: Request, deadline: Option<u64>) -> Request {
+    match deadline {
+        Some(deadline_ms) => Request::WithDeadline {
+            deadline_ms,
+            request: Box::new(request),
+        },
+        None => request,
+    }
 }
 
 #[derive(Subcommand, Debug)]
 
 enum Commands {
+    /// Print the value of a key, or "Key not found". Exits 0 either way
     Get {
         key: String,
+        /// Print this instead of "Key not found" when the key is not set
+        #[arg(long, value_name = "VALUE")]
+        default: Option<String>,
         #[command(flatten)]
         opts: CommandOpts,
     },
     Set {
         key: String,
         value: String,
+        /// Seconds until the key expires
+        #[arg(long, value_name = "SECS")]
+        ttl: Option<u64>,
+        /// Id to retry the set under, which the server applies at most once
+        #[arg(long, value_name = "ID", conflicts_with = "ttl")]
+        request_id: Option<u64>,
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Set a key and print the value it held before
+    #[command(name = "getset")]
+    GetSet {
+        key: String,
+        value: String,
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Set a key to the bytes read from stdin, which needn't be UTF-8
+    #[command(name = "set-bytes")]
+    SetBytes {
+        key: String,
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Write the value of a key to stdout as it is, without a newline
+    #[command(name = "get-bytes")]
+    GetBytes {
+        key: String,
         #[command(flatten)]
         opts: CommandOpts,
     },
@@ -40,63 +149,468 @@ enum Commands {
         #[command(flatten)]
         opts: CommandOpts,
     },
+    /// Remove every key starting with a prefix and print how many were set
+    #[command(name = "rm-prefix")]
+    RemovePrefix {
+        prefix: String,
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Check whether a key exists without fetching its value
+    Exists {
+        key: String,
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Print the number of keys set
+    #[command(alias = "dbsize")]
+    Len {
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Add to the float stored at a key
+    #[command(name = "incrbyfloat")]
+    IncrByFloat {
+        key: String,
+        #[arg(allow_negative_numbers = true)]
+        delta: f64,
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Add to the integer stored at a key, a missing key counts as 0
+    Incr {
+        key: String,
+        #[arg(allow_negative_numbers = true, default_value_t = 1)]
+        delta: i64,
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Write every key of the server to stdout, one JSON object per line
+    Export {
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Set every key of a dump written by `export`, read from stdin
+    Import {
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Make the server force every write so far to disk, as before a backup
+    Flush {
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Print the server's effective configuration
+    Config {
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Print the server's request and connection counters
+    Stats {
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Make the server stop accepting new connections, still serving open ones
+    Drain {
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Make the server accept new connections again after `drain`
+    Resume {
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Turn the server's read-only mode on or off
+    ReadOnly {
+        /// Whether the server rejects writes
+        #[arg(value_parser = ["on", "off"])]
+        mode: String,
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Read commands line by line and send them over one connection, until end of input
+    Repl {
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
+    /// Copy every key of a running server into a local kvs data directory
+    Clone {
+        /// The data directory to copy into, created if missing
+        #[arg(long)]
+        into: PathBuf,
+        #[command(flatten)]
+        opts: CommandOpts,
+    },
 }
 
-/// 发送请求并接收响应
-fn send_request_and_get_response(
-    request: Request,
-    buf_writer: &mut BufWriter<TcpStream>,
-    buf_reader: &mut BufReader<TcpStream>,
-) -> kvs::error::Result<Response> {
-    serde_json::to_writer(&mut *buf_writer, &request)?;
-    buf_writer.flush()?;
-    let deserializer = Deserializer::from_reader(buf_reader);
-    let response = deserializer.into_iter::<Response>().next().unwrap()?;
-    Ok(response)
+/// A line typed in the REPL, parsed like the command line without the program name.
+#[derive(Parser, Debug)]
+#[command(multicall = true)]
+struct ReplLine {
+    #[command(subcommand)]
+    command: Commands,
 }
 
-fn main() -> kvs::error::Result<()> {
-    let cli = Cli::parse();
-
-    // 从命令中提取地址
-    let addr = match &cli.command {
-        Commands::Get { opts, .. } => opts.addr.clone(),
-        Commands::Set { opts, .. } => opts.addr.clone(),
-        Commands::Remove { opts, .. } => opts.addr.clone(),
-    };
-
-    let stream = TcpStream::connect(&addr)?;
-    let mut buf_reader = BufReader::new(stream.try_clone()?);
-    let mut buf_writer = BufWriter::new(stream);
-
-    // 构建请求
-    let request = match cli.command {
+/// 构建请求, `repl`, `clone` 和 `import` 除外
+fn request(command: Commands) -> Option<Request> {
+    Some(match command {
         Commands::Get { key, .. } => Request::Get { key },
-        Commands::Set { key, value, .. } => Request::Set {
-            key: key.clone(),
-            value: value.clone(),
+        Commands::Set {
+            key,
+            value,
+            ttl: None,
+            request_id,
+            ..
+        } => Request::Set {
+            key,
+            value,
+            request_id,
         },
+        Commands::Set {
+            key,
+            value,
+            ttl: Some(ttl_secs),
+            ..
+        } => Request::SetEx {
+            key,
+            value,
+            ttl_secs,
+        },
+        Commands::GetSet { key, value, .. } => Request::GetSet { key, value },
+        Commands::GetBytes { key, .. } => Request::GetBytes { key },
         Commands::Remove { key, .. } => Request::Remove { key },
-    };
+        Commands::RemovePrefix { prefix, .. } => Request::RemovePrefix { prefix },
+        Commands::Exists { key, .. } => Request::Exists { key },
+        Commands::Len { .. } => Request::Len,
+        Commands::IncrByFloat { key, delta, .. } => Request::IncrByFloat { key, delta },
+        Commands::Incr { key, delta, .. } => Request::Incr { key, delta },
+        Commands::Export { .. } => Request::Export,
+        Commands::Flush { .. } => Request::Flush,
+        Commands::Config { .. } => Request::Config,
+        Commands::Stats { .. } => Request::Stats,
+        Commands::Drain { .. } => Request::Drain,
+        Commands::Resume { .. } => Request::Resume,
+        Commands::ReadOnly { mode, .. } => Request::SetReadOnly {
+            enabled: mode == "on",
+        },
+        Commands::Repl { .. }
+        | Commands::Clone { .. }
+        | Commands::Import { .. }
+        | Commands::SetBytes { .. } => return None,
+    })
+}
 
-    // 发送请求并获取响应
-    let response = send_request_and_get_response(request, &mut buf_writer, &mut buf_reader)?;
+/// The value `command` prints for a missing key instead of "Key not found".
+fn default_value(command: &Commands) -> Option<String> {
+    match command {
+        Commands::Get { default, .. } => default.clone(),
+        _ => None,
+    }
+}
 
-    // 处理响应
+/// 处理响应, 键不存在时打印 `default` (若有)
+fn print_response(response: Response, default: Option<&str>, output: Output) -> Result<()> {
+    if output == Output::Json {
+        return print_json(response, default);
+    }
     match response {
-        Response::Value(value) => {
-            if let Some(value) = value {
-                println!("{value}");
-            } else {
-                println!("Key not found");
-            }
+        Response::Value(value) => match value.as_deref().or(default) {
+            Some(value) => println!("{value}"),
+            None => println!("Key not found"),
+        },
+        Response::Bytes(value) => match value {
+            Some(value) => io::stdout().write_all(&value)?,
+            None => println!("Key not found"),
+        },
+        response @ Response::CompressedValue(_) => {
+            return print_response(response.decompress()?, default, output);
         }
         Response::Ok => {
             // Set 和 Remove 操作成功，无需输出
         }
-        Response::Err(e) => {
-            return Err(kvs::error::KvsError::ResponseError(e));
+        Response::Err { code, message } => {
+            return Err(KvsError::ResponseError { code, message });
+        }
+        Response::Hello { protocol_version } => {
+            println!("protocol {protocol_version}");
+        }
+        Response::Bool(exists) => {
+            println!("{exists}");
+        }
+        Response::Removed(removed) => {
+            println!("{removed}");
+        }
+        Response::Float(value) => {
+            println!("{value}");
+        }
+        Response::Int(value) => {
+            println!("{value}");
+        }
+        Response::Count(count) => {
+            println!("{count}");
+        }
+        Response::Pairs(pairs) => {
+            for (key, value) in pairs {
+                println!("{key} {value}");
+            }
+        }
+        Response::Export(data) => {
+            io::stdout().write_all(data.as_bytes())?;
+        }
+        Response::Event { key, value } => match value {
+            Some(value) => println!("{key} {value}"),
+            None => println!("{key} removed"),
+        },
+        Response::Config(config) => {
+            println!("{}", serde_json::to_string_pretty(&config)?);
+        }
+        Response::Stats(stats) => {
+            println!("{}", serde_json::to_string_pretty(&stats)?);
         }
     }
     Ok(())
 }
+
+/// Print `response` as one JSON object, `{"value": ...}` for the responses
+/// holding one. A missing key has a `null` value, unless there's a `default`.
+///
+/// Errors are returned like [`print_response`] does, for [`report`] to print.
+fn print_json(response: Response, default: Option<&str>) -> Result<()> {
+    let json = match response {
+        Response::Value(value) => json!({ "value": value.as_deref().or(default) }),
+        Response::Bytes(value) => {
+            json!({ "bytes": value.map(|value| BASE64_STANDARD.encode(value)) })
+        }
+        response @ Response::CompressedValue(_) => {
+            return print_json(response.decompress()?, default);
+        }
+        Response::Ok => json!({ "ok": true }),
+        Response::Err { code, message } => {
+            return Err(KvsError::ResponseError { code, message });
+        }
+        Response::Hello { protocol_version } => json!({ "protocol_version": protocol_version }),
+        Response::Bool(exists) => json!({ "value": exists }),
+        Response::Removed(removed) => json!({ "removed": removed }),
+        Response::Float(value) => json!({ "value": value }),
+        Response::Int(value) => json!({ "value": value }),
+        Response::Count(count) => json!({ "value": count }),
+        Response::Pairs(pairs) => {
+            let pairs: serde_json::Map<_, _> = pairs
+                .into_iter()
+                .map(|(key, value)| (key, value.into()))
+                .collect();
+            json!({ "value": pairs })
+        }
+        // Already one JSON object per line.
+        Response::Export(data) => {
+            io::stdout().write_all(data.as_bytes())?;
+            return Ok(());
+        }
+        Response::Event { key, value } => json!({ "key": key, "value": value }),
+        Response::Config(config) => json!({ "value": config }),
+        Response::Stats(stats) => json!({ "value": stats }),
+    };
+    println!("{json}");
+    Ok(())
+}
+
+/// Run the commands read from stdin over one connection. Errors of a single
+/// command are printed and the session goes on; end of input (Ctrl-D) ends it.
+/// Every command gets the session's `deadline` and is printed as its `output` says.
+fn repl(client: &mut Client, deadline: Option<u64>, output: Output) -> Result<()> {
+    let stdin = io::stdin();
+    let prompt = stdin.is_terminal();
+    let mut lines = stdin.lock().lines();
+    loop {
+        if prompt {
+            print!("kvs> ");
+            io::stdout().flush()?;
+        }
+        let Some(line) = lines.next() else {
+            return Ok(());
+        };
+        let words = shell_words(&line?);
+        if words.is_empty() {
+            continue;
+        }
+        let command = match ReplLine::try_parse_from(words) {
+            Ok(line) => line.command,
+            Err(e) => {
+                e.print()?;
+                continue;
+            }
+        };
+        let default = default_value(&command);
+        let Some(request) = request(command) else {
+            eprintln!("not available in the REPL");
+            continue;
+        };
+        let response = client.request(&with_deadline(request, deadline))?;
+        if let Err(e) = print_response(response, default.as_deref(), output) {
+            report(&e, output);
+        }
+    }
+}
+
+/// Copy the whole dataset of the server into a kvs store in `into`, expiry
+/// included, reporting progress on stderr and the total on stdout.
+fn clone(opts: &CommandOpts, into: &Path) -> Result<()> {
+    let mut client = Client::connect_with_config(opts.addr.as_str(), &opts.client_config())?;
+    let dump = match client.request(&with_deadline(Request::Export, opts.deadline))? {
+        Response::Export(dump) => dump,
+        Response::Err { code, message } => return Err(KvsError::ResponseError { code, message }),
+        response => {
+            return Err(io::Error::other(format!("unexpected response {response:?}")).into());
+        }
+    };
+    client.shutdown()?;
+
+    fs::create_dir_all(into)?;
+    data_dir::check_engine(into, "kvs")?;
+    let store = KvStore::open(into)?;
+    let total = dump.lines().count();
+    // Import the dump a slice of lines at a time, reporting progress in between.
+    let mut rest = dump.as_str();
+    let mut copied = 0;
+    while !rest.is_empty() {
+        let end = rest
+            .match_indices('\n')
+            .nth(CLONE_PROGRESS_EVERY - 1)
+            .map_or(rest.len(), |(i, _)| i + 1);
+        let (batch, tail) = rest.split_at(end);
+        store.import(batch.as_bytes())?;
+        copied += batch.lines().count();
+        rest = tail;
+        if copied % CLONE_PROGRESS_EVERY == 0 {
+            eprintln!("copied {copied} of {total} keys");
+        }
+    }
+    println!("Copied {total} keys");
+    Ok(())
+}
+
+/// Split a REPL line on whitespace; double quotes keep a word with spaces together.
+fn shell_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = None::<String>;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_default();
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_default().push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let output = cli.command.opts().output;
+    if let Err(e) = run(cli) {
+        report(&e, output);
+        process::exit(exit_status(&e));
+    }
+}
+
+/// 打印错误到 stderr, 服务端报告的错误只打印其描述, 如 "Key not found".
+/// JSON 输出时打印到 stdout, 服务端报告的错误带上其种类
+fn report(e: &KvsError, output: Output) {
+    match (e, output) {
+        (KvsError::ResponseError { code, message }, Output::Json) => {
+            println!("{}", json!({ "error": message, "code": code }));
+        }
+        (e, Output::Json) => println!("{}", json!({ "error": e.to_string() })),
+        (KvsError::ResponseError { message, .. }, Output::Plain) => eprintln!("{message}"),
+        (e, Output::Plain) => eprintln!("Error: {e}"),
+    }
+}
+
+/// 退出码: 服务端报告的错误按种类区分, 键不存在和其余错误 (如连接失败) 为 1
+fn exit_status(e: &KvsError) -> i32 {
+    match e {
+        KvsError::ResponseError { code, .. } => match code {
+            ErrorCode::NotFound => 1,
+            ErrorCode::BadRequest => 3,
+            ErrorCode::TooLarge => 4,
+            ErrorCode::Busy => 5,
+            ErrorCode::Internal => 6,
+            ErrorCode::DeadlineExceeded => 7,
+            ErrorCode::ReadOnly => 8,
+            ErrorCode::NotAuthenticated => 9,
+            ErrorCode::Conflict => 10,
+        },
+        _ => 1,
+    }
+}
+
+impl Commands {
+    /// 命令的连接选项
+    fn opts(&self) -> &CommandOpts {
+        match self {
+            Commands::Get { opts, .. } => opts,
+            Commands::Set { opts, .. } => opts,
+            Commands::GetSet { opts, .. } => opts,
+            Commands::SetBytes { opts, .. } => opts,
+            Commands::GetBytes { opts, .. } => opts,
+            Commands::Remove { opts, .. } => opts,
+            Commands::RemovePrefix { opts, .. } => opts,
+            Commands::Exists { opts, .. } => opts,
+            Commands::Len { opts } => opts,
+            Commands::IncrByFloat { opts, .. } => opts,
+            Commands::Incr { opts, .. } => opts,
+            Commands::Export { opts } => opts,
+            Commands::Import { opts } => opts,
+            Commands::Flush { opts } => opts,
+            Commands::Config { opts } => opts,
+            Commands::Stats { opts } => opts,
+            Commands::Drain { opts } => opts,
+            Commands::Resume { opts } => opts,
+            Commands::ReadOnly { opts, .. } => opts,
+            Commands::Repl { opts } => opts,
+            Commands::Clone { opts, .. } => opts,
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let opts = match &cli.command {
+        Commands::Clone { into, opts } => return clone(opts, into),
+        command => command.opts(),
+    };
+
+    let mut client = Client::connect_with_config(opts.addr.as_str(), &opts.client_config())?;
+    let deadline = opts.deadline;
+    let output = opts.output;
+
+    let default = default_value(&cli.command);
+    let request = match cli.command {
+        Commands::Import { .. } => {
+            // 从 stdin 读取整个导出文件
+            let mut data = String::new();
+            io::stdin().read_to_string(&mut data)?;
+            Request::Import { data }
+        }
+        Commands::SetBytes { key, .. } => {
+            let mut value = Vec::new();
+            io::stdin().read_to_end(&mut value)?;
+            Request::SetBytes { key, value }
+        }
+        command => match request(command) {
+            Some(request) => request,
+            None => {
+                repl(&mut client, deadline, output)?;
+                // 关闭连接, 让服务端结束这次会话
+                return client.shutdown();
+            }
+        },
+    };
+
+    // 发送请求并获取响应
+    let response = client.request(&with_deadline(request, deadline))?;
+    print_response(response, default.as_deref(), output)
+}
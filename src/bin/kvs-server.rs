@@ -1,95 +1,326 @@
 use std::{
-    fs,
-    io::{BufReader, BufWriter, Write},
-    net::{TcpListener, TcpStream},
-    path::Path,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, BufReader, BufWriter, Read, Write},
+    iter,
+    net::{Shutdown, TcpListener, TcpStream},
+    os::fd::{AsRawFd, RawFd},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
     sync::{
-        Arc,
-        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc,
     },
-    time::Duration,
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Error, Result};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use kvs::{
-    KvStore, SledEngine,
-    engine::KvsEngine,
-    protocol::{Request, Response},
-    thread_pool::{NaiveThreadPool, ThreadPool},
+    BatchOp, Change, Codec, CompactionStrategy, Compression, FlushPolicy, KvStore, KvStoreConfig,
+    KvsError, LogFormat, MemoryEngine, SizeLimits, SledEngine, VerifyLevel, data_dir,
+    engine::{KvsEngine, LockContention},
+    protocol::{
+        ErrorCode, Latency, PROTOCOL_VERSION, Request, Response, ResponseFlush, ServerConfig,
+        ServerStats, constant_time_eq,
+    },
+    thread_pool::{self, NaiveThreadPool, ThreadPool},
+    tls::{self, TlsStream},
 };
+use log::{debug, error, info, warn};
+use mio::{Events, Interest, Poll, Token, Waker, unix::SourceFd};
 use serde_json::Deserializer;
 #[derive(Parser)]
-#[command(author, version)]
+#[command(author, version = kvs::protocol::VERSION)]
 struct Args {
+    /// The address to listen on, `host:port`. Repeatable, to listen on IPv4
+    /// and IPv6 or on several interfaces
     #[arg(short, long, default_value = "127.0.0.1:4000")]
-    addr: String,
+    addr: Vec<String>,
+    /// Also listen on this address, `host:port` or `unix:<path>`. Repeatable
+    #[arg(long, value_name = "ADDR")]
+    listen: Vec<String>,
+    /// The storage engine: `kvs`, `sled`, or `memory` to keep nothing on disk
     #[arg(short, long, default_value = "kvs")]
     engine: String,
+    /// Compaction strategy of the kvs engine
+    #[arg(long, value_enum, default_value_t = CompactionMode::Auto)]
+    compaction: CompactionMode,
+    /// Stale bytes that trigger an `auto` compaction, once they are half the logs
+    #[arg(long, default_value_t = 1 << 20)]
+    compaction_stale_bytes: u64,
+    /// Log bytes that trigger a `size` compaction
+    #[arg(long, default_value_t = 64 << 20)]
+    compaction_size: u64,
+    /// Stale byte fraction that triggers a `ratio` compaction
+    #[arg(long, default_value_t = 0.5)]
+    compaction_ratio: f64,
+    /// Seconds between `interval` compactions
+    #[arg(long, default_value_t = 60)]
+    compaction_interval: u64,
+    /// Bytes after which the kvs engine starts a new log file
+    #[arg(long, default_value_t = 1 << 20)]
+    max_log_size: u64,
+    /// Fail writes when the next log file can't be created, instead of
+    /// growing the current one past --max-log-size
+    #[arg(long)]
+    strict_rotation: bool,
+    /// Log failed compactions and retry them later instead of failing the
+    /// write that triggered them
+    #[arg(long)]
+    tolerate_compaction_failures: bool,
+    /// Seconds between sweeps writing a tombstone for every expired key of the
+    /// kvs engine, 0 to leave them until they're read or compacted away
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    expiry_sweep_interval: u64,
+    /// Compact the kvs engine on shutdown once this many bytes of its logs are stale
+    #[arg(long, value_name = "BYTES")]
+    compact_on_close: Option<u64>,
+    /// Compress values of the kvs engine longer than this many bytes
+    #[arg(long, value_name = "BYTES")]
+    compress_above: Option<usize>,
+    /// Zstandard level of compressed values, 1 (fastest) to 22 (smallest)
+    #[arg(long, default_value_t = 3, requires = "compress_above")]
+    compression_level: i32,
+    /// Longest key accepted by `set`, in bytes
+    #[arg(long, default_value_t = 256)]
+    max_key_size: usize,
+    /// Longest value accepted by `set`, in bytes
+    #[arg(long, default_value_t = 4 << 20)]
+    max_value_size: usize,
+    /// Record format of the logs of a new kvs data directory. One holding
+    /// records keeps the format of its newest log
+    #[arg(long, value_enum, default_value_t = FormatMode::Binary)]
+    log_format: FormatMode,
+    /// Log files of the kvs engine whose checksums are verified on open
+    #[arg(long, value_enum, default_value_t = VerifyMode::All)]
+    verify_on_open: VerifyMode,
+    /// When the sled engine flushes writes to disk: after `always` one, after
+    /// `every-ops` --sled-flush-ops of them, or every `interval`
+    #[arg(long, value_enum, default_value_t = SledFlushMode::Always)]
+    sled_flush: SledFlushMode,
+    /// Writes between flushes of the sled engine under `every-ops`
+    #[arg(long, value_name = "N", default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..))]
+    sled_flush_ops: u64,
+    /// Milliseconds between flushes of the sled engine under `interval`
+    #[arg(long, value_name = "MS", default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    sled_flush_interval: u64,
+    /// Scans allowed to run at once, extra ones are rejected as busy
+    #[arg(long, default_value_t = 4)]
+    max_scans: usize,
+    /// Worker threads serving connections, by default one per CPU the cgroup quota allows.
+    /// Each subscribed connection keeps one to itself
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+    /// Accepted connections allowed to wait for a free worker thread
+    #[arg(long, default_value_t = thread_pool::DEFAULT_QUEUE_BOUND)]
+    queue_bound: usize,
+    /// Connections served or waiting at once, extra ones are rejected as busy
+    #[arg(long, value_name = "N")]
+    max_connections: Option<usize>,
+    /// Close connections idle for this many seconds, freeing their worker thread
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout: Option<u64>,
+    /// Record how long requests take, reported by `stats` as percentiles per operation
+    #[arg(long)]
+    latency_stats: bool,
+    /// When to send responses: `every` one as it is written, or `batched` while
+    /// pipelined requests are already read, before waiting for more
+    #[arg(long, value_enum, default_value_t = FlushMode::Every)]
+    response_flush: FlushMode,
+    /// Request ids of recent sets remembered to answer retries without setting
+    /// the key again, 0 to ignore the ids
+    #[arg(long, value_name = "N", default_value_t = 1024)]
+    request_id_cache: usize,
+    /// Start in read-only mode, rejecting mutations until `kvs-client read-only off`
+    #[arg(long)]
+    read_only: bool,
+    /// Serve TCP connections over TLS, presenting the certificate chain in this PEM file
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM file of the private key of --tls-cert
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Serve only connections presenting this shared secret
+    #[arg(long, value_name = "TOKEN")]
+    auth_token: Option<String>,
+    /// Also serve the engine over gRPC on this address. Calls present
+    /// --auth-token as `authorization: Bearer <TOKEN>` metadata
+    #[cfg(feature = "grpc")]
+    #[arg(long)]
+    grpc_addr: Option<std::net::SocketAddr>,
 }
 
-/// 检查数据目录中之前使用的引擎
-fn detect_previous_engine(path: &Path) -> Result<Option<String>> {
-    if !path.exists() {
-        return Ok(None);
-    }
+#[derive(Clone, Copy, ValueEnum)]
+enum VerifyMode {
+    None,
+    Current,
+    All,
+}
 
-    let mut has_kvs = false;
-    let mut has_sled = false;
+#[derive(Clone, Copy, ValueEnum)]
+enum FormatMode {
+    Text,
+    Binary,
+}
 
-    // 遍历目录中的所有条目（文件和目录）
-    for entry in fs::read_dir(path)? {
-        let entry = entry?;
-        let file_name = entry.file_name();
-        let name_str = file_name.to_str().unwrap_or("");
+#[derive(Clone, Copy, ValueEnum)]
+enum SledFlushMode {
+    Always,
+    EveryOps,
+    Interval,
+}
 
-        // 检查 kvs 引擎的 .log 文件
-        if name_str.ends_with(".log") {
-            has_kvs = true;
-        }
+#[derive(Clone, Copy, ValueEnum)]
+enum FlushMode {
+    Every,
+    Batched,
+}
 
-        // 检查 sled 引擎的特定文件/目录
-        // sled 会在目录中创建 "db" 目录或 "_sled" 开头的文件
-        if name_str == "db" || name_str.starts_with("_sled") {
-            has_sled = true;
+#[derive(Clone, Copy, ValueEnum)]
+enum CompactionMode {
+    Off,
+    Size,
+    Ratio,
+    Interval,
+    Auto,
+}
+
+impl Args {
+    /// Resolve the command line into the configuration the server runs with.
+    fn resolve(self) -> Result<ServerConfig> {
+        let kvs = self.kvs_config();
+        // The first address is the main one, the others are served like `--listen`.
+        let mut addrs = self.addr.into_iter();
+        let addr = addrs.next().expect("--addr has a default");
+        Ok(ServerConfig {
+            addr,
+            listen: addrs.chain(self.listen).collect(),
+            engine: self.engine,
+            data_dir: std::env::current_dir()?,
+            threads: self.threads.unwrap_or_else(thread_pool::default_threads),
+            queue_bound: self.queue_bound,
+            max_scans: self.max_scans,
+            max_connections: self.max_connections,
+            idle_timeout: self.idle_timeout.map(Duration::from_secs),
+            latency_stats: self.latency_stats,
+            response_flush: match self.response_flush {
+                FlushMode::Every => ResponseFlush::Every,
+                FlushMode::Batched => ResponseFlush::Batched,
+            },
+            request_id_cache: self.request_id_cache,
+            read_only: self.read_only,
+            kvs,
+            sled_flush: match self.sled_flush {
+                SledFlushMode::Always => FlushPolicy::Always,
+                SledFlushMode::EveryOps => FlushPolicy::EveryOps(self.sled_flush_ops),
+                SledFlushMode::Interval => {
+                    FlushPolicy::Interval(Duration::from_millis(self.sled_flush_interval))
+                }
+            },
+            auth_token: self.auth_token,
+        })
+    }
+
+    /// The TLS configuration to serve TCP connections with, if any.
+    fn tls(&self) -> Result<Option<Arc<rustls::ServerConfig>>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some(tls::server_config(cert, key)?)),
+            _ => Ok(None),
         }
     }
 
-    match (has_kvs, has_sled) {
-        (true, false) => Ok(Some("kvs".to_string())),
-        (false, true) => Ok(Some("sled".to_string())),
-        (false, false) => Ok(None), // 新目录，没有之前的引擎
-        (true, true) => Err(Error::msg("Both kvs and sled data detected")), // 不应该发生
+    fn kvs_config(&self) -> KvStoreConfig {
+        let compaction = match self.compaction {
+            CompactionMode::Off => CompactionStrategy::Off,
+            CompactionMode::Size => CompactionStrategy::Size(self.compaction_size),
+            CompactionMode::Ratio => CompactionStrategy::Ratio(self.compaction_ratio),
+            CompactionMode::Interval => {
+                CompactionStrategy::Interval(Duration::from_secs(self.compaction_interval))
+            }
+            CompactionMode::Auto => CompactionStrategy::Auto(self.compaction_stale_bytes),
+        };
+        KvStoreConfig::default()
+            .compaction(compaction)
+            .max_log_size(self.max_log_size)
+            .strict_rotation(self.strict_rotation)
+            .tolerate_compaction_failures(self.tolerate_compaction_failures)
+            .compact_on_close(self.compact_on_close)
+            .expiry_sweep_interval(
+                (self.expiry_sweep_interval > 0)
+                    .then(|| Duration::from_secs(self.expiry_sweep_interval)),
+            )
+            .compression(self.compress_above.map(|threshold| Compression {
+                codec: Codec::Zstd(self.compression_level),
+                threshold,
+            }))
+            .log_format(match self.log_format {
+                FormatMode::Text => LogFormat::Text,
+                FormatMode::Binary => LogFormat::Binary,
+            })
+            .verify_on_open(match self.verify_on_open {
+                VerifyMode::None => VerifyLevel::None,
+                VerifyMode::Current => VerifyLevel::CurrentFileOnly,
+                VerifyMode::All => VerifyLevel::All,
+            })
+            .limits(SizeLimits {
+                max_key_size: self.max_key_size,
+                max_value_size: self.max_value_size,
+            })
     }
 }
 
 fn main() -> Result<()> {
-    eprintln!("CARGO_PKG_VERSION: {}", env!("CARGO_PKG_VERSION"));
+    // 默认 info 级别，可用 RUST_LOG 调整
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    info!("CARGO_PKG_VERSION: {}", env!("CARGO_PKG_VERSION"));
     let args = Args::parse();
-    eprintln!(
+    #[cfg(feature = "grpc")]
+    let grpc_addr = args.grpc_addr;
+    let tls = args.tls()?;
+    let config = args.resolve()?;
+    info!(
         "Starting server on {}, and using engine {}",
-        args.addr, args.engine
+        config.addr, config.engine
     );
-
-    let data_dir = Path::new("./");
+    if tls.is_some() {
+        info!("Serving TCP connections over TLS");
+    }
 
     // 检查之前使用的引擎
-    if let Some(previous_engine) = detect_previous_engine(data_dir)? {
-        if previous_engine != args.engine {
-            return Err(Error::msg(format!(
-                "Wrong engine! Previous: {}, current: {}",
-                previous_engine, args.engine
-            )));
-        }
+    // The memory engine leaves the data directory alone.
+    if config.engine != "memory" {
+        data_dir::check_engine(&config.data_dir, &config.engine)?;
     }
 
-    match args.engine.as_str() {
+    match config.engine.as_str() {
         "kvs" => {
-            let mut server = KvsServer::new(args.addr, KvStore::open("./")?)?;
+            let engine = KvStore::open_with_config(&config.data_dir, config.kvs.clone())?;
+            let mut server = KvsServer::new(config, engine, tls)?;
+            #[cfg(feature = "grpc")]
+            server.spawn_grpc(grpc_addr)?;
             server.run()?;
         }
         "sled" => {
-            let mut server = KvsServer::new(args.addr, SledEngine::open("./")?)?;
+            let engine = SledEngine::open_with_flush_policy(
+                &config.data_dir,
+                config.kvs.limits,
+                config.sled_flush,
+            )?;
+            let mut server = KvsServer::new(config, engine, tls)?;
+            #[cfg(feature = "grpc")]
+            server.spawn_grpc(grpc_addr)?;
+            server.run()?;
+        }
+        "memory" => {
+            let engine = MemoryEngine::with_limits(config.kvs.limits);
+            let mut server = KvsServer::new(config, engine, tls)?;
+            #[cfg(feature = "grpc")]
+            server.spawn_grpc(grpc_addr)?;
             server.run()?;
         }
         _ => return Err(Error::msg("Unknown engine")),
@@ -98,131 +329,1521 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+/// A socket the server accepts connections on.
+enum Listener {
+    Tcp(TcpListener),
+    /// Removes its socket file when dropped.
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Listen on `addr`, a Unix socket if it starts with `unix:`, else TCP.
+    fn bind(addr: &str) -> io::Result<Listener> {
+        let listener = match addr.strip_prefix("unix:") {
+            Some(path) => Listener::Unix(UnixListener::bind(path)?, path.into()),
+            None => Listener::Tcp(TcpListener::bind(addr)?),
+        };
+        // 非阻塞模式: 由 poll 通知可接受的连接, 直到 WouldBlock
+        match &listener {
+            Listener::Tcp(listener) => listener.set_nonblocking(true)?,
+            Listener::Unix(listener, _) => listener.set_nonblocking(true)?,
+        }
+        Ok(listener)
+    }
+
+    /// Watch for connections to accept, as `token` in `poll`.
+    fn register(&self, poll: &Poll, token: Token) -> io::Result<()> {
+        poll.registry()
+            .register(&mut SourceFd(&self.as_raw_fd()), token, Interest::READABLE)
+    }
+
+    /// Stop watching for connections in `poll`.
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        poll.registry().deregister(&mut SourceFd(&self.as_raw_fd()))
+    }
+
+    /// Accept a connection, over TLS with `tls` if it came in over TCP.
+    fn accept(&self, tls: Option<&Arc<rustls::ServerConfig>>) -> io::Result<Connection> {
+        Ok(match self {
+            Listener::Tcp(listener) => {
+                let stream = listener.accept()?.0;
+                match tls {
+                    Some(tls) => Connection::Tls(
+                        TlsStream::accept(tls.clone(), stream).map_err(io::Error::other)?,
+                    ),
+                    None => Connection::Tcp(stream),
+                }
+            }
+            Listener::Unix(listener, _) => Connection::Unix(listener.accept()?.0),
+        })
+    }
+}
+
+impl fmt::Display for Listener {
+    /// The address bound, with the port chosen for a port of 0.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{addr}"),
+                Err(_) => write!(f, "an unknown address"),
+            },
+            Listener::Unix(_, path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// A client connection, over whichever kind of socket it came in on.
+enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(TlsStream),
+}
+
+impl Connection {
+    fn try_clone(&self) -> io::Result<Connection> {
+        Ok(match self {
+            Connection::Tcp(stream) => Connection::Tcp(stream.try_clone()?),
+            Connection::Unix(stream) => Connection::Unix(stream.try_clone()?),
+            Connection::Tls(stream) => Connection::Tls(stream.clone()),
+        })
+    }
+
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+            Connection::Unix(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+            Connection::Tls(stream) => stream.set_timeout(timeout),
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.shutdown(how),
+            Connection::Unix(stream) => stream.shutdown(how),
+            Connection::Tls(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            Connection::Unix(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            Connection::Unix(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            Connection::Unix(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
 /// KVS 服务器
 pub struct KvsServer<E: KvsEngine> {
-    listener: TcpListener,
+    listeners: Vec<Listener>,
     thread_pool: NaiveThreadPool,
     engine: E,
-    shutdown: Arc<AtomicBool>,
+    config: Arc<ServerConfig>,
+    active_scans: Arc<AtomicUsize>,
+    counters: Arc<Counters>,
+    shutdown: Arc<LoopFlag>,
+    /// Connections handed to the thread pool and not finished yet.
+    open_connections: Arc<AtomicUsize>,
+    /// Set by a `Drain` request, cleared by `Resume`.
+    draining: Arc<LoopFlag>,
+    /// Whether the listeners are watched in `poll`, lagging `draining` until
+    /// the accept loop applies it.
+    accepting: bool,
+    /// Set by `--read-only` or a `SetReadOnly` request, rejecting mutations while on.
+    read_only: Arc<AtomicBool>,
+    /// Connections streaming key changes, see `Request::Subscribe`.
+    subscribers: Arc<Subscribers>,
+    /// Sets recently applied under a request id, shared as a retry may come
+    /// on another connection.
+    seen_requests: Arc<SeenRequests>,
+    /// Set to serve TCP connections over TLS.
+    tls: Option<Arc<rustls::ServerConfig>>,
+    /// Waits for connections on the listeners, and for the flags to change.
+    poll: Poll,
+}
+
+/// The token of the waker in the poll, the listeners' tokens are their indices.
+const WAKER: Token = Token(usize::MAX);
+
+/// A flag the accept loop acts on, waking it up whenever it is stored.
+struct LoopFlag {
+    flag: AtomicBool,
+    waker: Arc<Waker>,
+}
+
+impl LoopFlag {
+    fn new(value: bool, waker: Arc<Waker>) -> Self {
+        Self {
+            flag: AtomicBool::new(value),
+            waker,
+        }
+    }
+
+    fn load(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+
+    fn store(&self, value: bool) {
+        self.flag.store(value, Ordering::Relaxed);
+        if let Err(e) = self.waker.wake() {
+            error!("Can't wake the accept loop: {e}");
+        }
+    }
 }
 
 impl<E: KvsEngine> KvsServer<E> {
     /// 创建新的 KVS 服务器
-    pub fn new(addr: String, engine: E) -> Result<Self> {
-        let cpus = num_cpus::get();
-        let thread_pool = NaiveThreadPool::new(cpus as u32)?;
-        let listener = TcpListener::bind(addr)?;
-        // 设置非阻塞模式以便能够检查关闭标志
-        listener.set_nonblocking(true)?;
-
+    pub fn new(
+        config: ServerConfig,
+        engine: E,
+        tls: Option<Arc<rustls::ServerConfig>>,
+    ) -> Result<Self> {
+        let thread_pool = NaiveThreadPool::with_queue_bound(config.threads, config.queue_bound)?;
+        let counters = Counters::new(config.latency_stats);
+        let read_only = config.read_only;
+        let seen_requests = SeenRequests::new(config.request_id_cache);
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let listeners = Self::bind(&config)?;
+        Self::register(&poll, &listeners)?;
+        let subscribers = Arc::new(Subscribers::default());
         Ok(Self {
-            listener,
+            listeners,
             thread_pool,
             engine,
-            shutdown: Arc::new(AtomicBool::new(false)),
+            config: Arc::new(config),
+            active_scans: Arc::new(AtomicUsize::new(0)),
+            counters: Arc::new(counters),
+            shutdown: Arc::new(LoopFlag::new(false, waker.clone())),
+            open_connections: Arc::new(AtomicUsize::new(0)),
+            draining: Arc::new(LoopFlag::new(false, waker)),
+            accepting: true,
+            read_only: Arc::new(AtomicBool::new(read_only)),
+            subscribers,
+            seen_requests: Arc::new(seen_requests),
+            tls,
+            poll,
         })
     }
 
+    /// Serve the engine over gRPC from a thread of its own, if an address is
+    /// given, behind the same auth token and read-only mode.
+    #[cfg(feature = "grpc")]
+    fn spawn_grpc(&self, addr: Option<std::net::SocketAddr>) -> Result<()> {
+        let Some(addr) = addr else {
+            return Ok(());
+        };
+        let runtime = tokio::runtime::Runtime::new()?;
+        let service = kvs::grpc::KvsService::new(self.engine.clone())
+            .auth_token(self.config.auth_token.clone())
+            .read_only(self.read_only.clone());
+        info!("Serving gRPC on {}", addr);
+        std::thread::spawn(move || {
+            if let Err(e) = runtime.block_on(kvs::grpc::serve_service(service, addr)) {
+                error!("gRPC server error: {:?}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Listen on every address of `config`.
+    fn bind(config: &ServerConfig) -> io::Result<Vec<Listener>> {
+        let mut listeners = Vec::new();
+        for addr in iter::once(&config.addr).chain(&config.listen) {
+            let listener = Listener::bind(addr)?;
+            info!("Listening on {}", listener);
+            listeners.push(listener);
+        }
+        Ok(listeners)
+    }
+
+    /// Watch every listener in `poll`, each under its index as token.
+    fn register(poll: &Poll, listeners: &[Listener]) -> io::Result<()> {
+        for (i, listener) in listeners.iter().enumerate() {
+            listener.register(poll, Token(i))?;
+        }
+        Ok(())
+    }
+
+    /// Stop watching the listeners while draining, so new connections wait
+    /// unaccepted, and watch them again once resumed. They stay bound all
+    /// along, keeping the ports chosen for a port of 0.
+    fn apply_draining(&mut self) {
+        let draining = self.draining.load();
+        if draining && self.accepting {
+            info!("Draining, no longer accepting connections");
+            for listener in &self.listeners {
+                if let Err(e) = listener.deregister(&self.poll) {
+                    warn!("Can't stop watching a listener: {e}");
+                }
+            }
+            self.accepting = false;
+        } else if !draining && !self.accepting {
+            match Self::register(&self.poll, &self.listeners) {
+                Ok(()) => {
+                    info!("Resumed accepting connections");
+                    self.accepting = true;
+                }
+                Err(e) => {
+                    error!("Can't watch the listeners again, still draining: {e}");
+                    self.draining.store(true);
+                }
+            }
+        }
+    }
+
     /// 运行服务器
     pub fn run(&mut self) -> Result<()> {
-        eprintln!("Server started, waiting for connections...");
+        info!("Server started, waiting for connections...");
 
+        let mut events = Events::with_capacity(64);
         loop {
             // 检查是否收到关闭信号
-            if self.shutdown.load(Ordering::Relaxed) {
-                eprintln!("Shutdown signal received, stopping server...");
+            if self.shutdown.load() {
+                info!("Shutdown signal received, stopping server...");
                 break;
             }
+            self.apply_draining();
 
-            // 尝试接受新连接（非阻塞）
-            match self.listener.accept() {
-                Ok((stream, _)) => {
-                    let engine = self.engine.clone();
-                    let shutdown = self.shutdown.clone();
-                    self.thread_pool.spawn(move || {
-                        // 在处理流时也检查关闭标志
-                        if !shutdown.load(Ordering::Relaxed) {
-                            if let Err(e) = handle_stream(stream, engine) {
-                                eprintln!("Error handling stream: {:?}", e);
-                            }
-                        }
-                    });
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // 没有新连接，继续循环检查关闭标志
-                    std::thread::sleep(Duration::from_millis(10));
+            // 等待新连接, 或者关闭/排空标志的变化
+            match self.poll.poll(&mut events, None) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::from(e)),
+            }
+            for event in &events {
+                if event.token() == WAKER {
                     continue;
                 }
-                Err(e) => {
-                    // 其他错误
-                    if !self.shutdown.load(Ordering::Relaxed) {
-                        return Err(Error::from(e));
+                let Some(listener) = self.listeners.get(event.token().0) else {
+                    continue;
+                };
+                // 边沿触发: 接受所有等待中的连接, 直到 WouldBlock
+                loop {
+                    match listener.accept(self.tls.as_ref()) {
+                        Ok(stream) => self.serve(stream),
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            // 其他错误
+                            if !self.shutdown.load() {
+                                return Err(Error::from(e));
+                            }
+                            break;
+                        }
                     }
-                    break;
                 }
             }
         }
 
-        eprintln!(
+        info!(
             "Server stopped accepting new connections, waiting for active connections to finish..."
         );
         // 线程池会在 Drop 时等待所有任务完成
         Ok(())
     }
 
+    /// Handle `stream` on the thread pool, or reject it if the server has
+    /// `max_connections` open already.
+    fn serve(&self, stream: Connection) {
+        let max = self.config.max_connections.unwrap_or(usize::MAX);
+        let Some(permit) = ConnectionPermit::try_acquire(&self.open_connections, max) else {
+            warn!("Rejecting a connection, {max} are open already");
+            if let Err(e) = reject(stream) {
+                debug!("Error rejecting connection: {:?}", e);
+            }
+            return;
+        };
+        let engine = self.engine.clone();
+        let config = self.config.clone();
+        let active_scans = self.active_scans.clone();
+        let counters = self.counters.clone();
+        let shutdown = self.shutdown.clone();
+        let draining = self.draining.clone();
+        let read_only = self.read_only.clone();
+        let subscribers = self.subscribers.clone();
+        let seen_requests = self.seen_requests.clone();
+        self.thread_pool.spawn(move || {
+            let _permit = permit;
+            let _connection = counters.connect();
+            // 在处理流时也检查关闭标志
+            if !shutdown.load()
+                && let Err(e) = handle_stream(
+                    stream,
+                    engine,
+                    &config,
+                    &active_scans,
+                    &counters,
+                    &draining,
+                    &read_only,
+                    &subscribers,
+                    &seen_requests,
+                    &shutdown,
+                )
+            {
+                if is_disconnect(&e) {
+                    debug!("Client went away: {e}");
+                } else {
+                    error!("Error handling stream: {:?}", e);
+                }
+            }
+        });
+    }
+
     /// 关闭服务器
     pub fn shutdown(&self) {
-        eprintln!("Shutting down server...");
-        self.shutdown.store(true, Ordering::Relaxed);
+        info!("Shutting down server...");
+        self.shutdown.store(true);
+    }
+}
+
+/// Operations counted in the server stats, named as in `ServerStats::ops`.
+const OPS: [&str; 26] = [
+    "set",
+    "setex",
+    "get",
+    "setbytes",
+    "getbytes",
+    "exists",
+    "len",
+    "remove",
+    "take",
+    "getset",
+    "scan",
+    "remove_prefix",
+    "incrbyfloat",
+    "incr",
+    "modify",
+    "begin",
+    "commit",
+    "export",
+    "import",
+    "flush",
+    "config",
+    "stats",
+    "drain",
+    "resume",
+    "set_read_only",
+    "subscribe",
+];
+
+/// Request and connection counters shared by all connections.
+struct Counters {
+    requests: AtomicU64,
+    ops: HashMap<&'static str, AtomicU64>,
+    /// Latencies per operation, empty unless the server records them.
+    latencies: HashMap<&'static str, Histogram>,
+    connections: AtomicU64,
+    active_connections: AtomicU64,
+}
+
+impl Counters {
+    fn new(latency_stats: bool) -> Self {
+        let latencies = if latency_stats {
+            OPS.into_iter().map(|op| (op, Histogram::new())).collect()
+        } else {
+            HashMap::new()
+        };
+        Self {
+            requests: AtomicU64::new(0),
+            ops: OPS.into_iter().map(|op| (op, AtomicU64::new(0))).collect(),
+            latencies,
+            connections: AtomicU64::new(0),
+            active_connections: AtomicU64::new(0),
+        }
+    }
+
+    /// Count `request`.
+    fn record(&self, request: &Request) {
+        if let Some(op) = op_name(request) {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+            self.ops[op].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Record that a request for `op` took `elapsed` to answer.
+    fn record_latency(&self, op: &str, elapsed: Duration) {
+        if let Some(histogram) = self.latencies.get(op) {
+            histogram.record(elapsed);
+        }
+    }
+
+    /// Count a new connection as active until the guard is dropped.
+    fn connect(&self) -> ConnectionGuard<'_> {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(&self.active_connections)
+    }
+
+    /// The counters so far, and the latencies since the previous snapshot.
+    fn snapshot(&self, compactions: u64, lock_contention: LockContention) -> ServerStats {
+        ServerStats {
+            requests: self.requests.load(Ordering::Relaxed),
+            ops: self
+                .ops
+                .iter()
+                .map(|(op, n)| (op.to_string(), n.load(Ordering::Relaxed)))
+                .collect(),
+            connections: self.connections.load(Ordering::Relaxed),
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            compactions,
+            lock_contention,
+            latencies: self
+                .latencies
+                .iter()
+                .filter_map(|(op, histogram)| Some((op.to_string(), histogram.take()?)))
+                .collect(),
+        }
+    }
+}
+
+/// The name `request` is counted under in the server stats, `None` for
+/// requests that aren't operations.
+fn op_name(request: &Request) -> Option<&'static str> {
+    let op = match request {
+        Request::Set { .. } => "set",
+        Request::SetEx { .. } => "setex",
+        Request::Get { .. } => "get",
+        Request::SetBytes { .. } => "setbytes",
+        Request::GetBytes { .. } => "getbytes",
+        Request::Exists { .. } => "exists",
+        Request::Len => "len",
+        Request::Remove { .. } => "remove",
+        Request::Take { .. } => "take",
+        Request::GetSet { .. } => "getset",
+        Request::Scan { .. } => "scan",
+        Request::RemovePrefix { .. } => "remove_prefix",
+        Request::IncrByFloat { .. } => "incrbyfloat",
+        Request::Incr { .. } => "incr",
+        Request::Modify { .. } => "modify",
+        Request::Begin { .. } => "begin",
+        Request::Commit => "commit",
+        Request::Export => "export",
+        Request::Import { .. } => "import",
+        Request::Flush => "flush",
+        Request::Config => "config",
+        Request::Stats => "stats",
+        Request::Drain => "drain",
+        Request::Resume => "resume",
+        Request::SetReadOnly { .. } => "set_read_only",
+        Request::Subscribe { .. } => "subscribe",
+        Request::WithDeadline { request, .. } => return op_name(request),
+        // Part of connecting, not an operation.
+        Request::Hello { .. }
+        | Request::Auth { .. }
+        | Request::CompressValues { .. }
+        | Request::ReportRemoved { .. } => {
+            return None;
+        }
+    };
+    Some(op)
+}
+
+/// Request durations in microseconds, counted in buckets at most 1/8 of their
+/// lower bound wide, like an HDR histogram with one significant digit.
+struct Histogram(Box<[AtomicU64]>);
+
+impl Histogram {
+    /// 8 exact buckets for 0 to 7, then 8 for each power of two up to 2^63.
+    const BUCKETS: usize = 8 * 62;
+
+    fn new() -> Self {
+        Self((0..Self::BUCKETS).map(|_| AtomicU64::new(0)).collect())
+    }
+
+    fn record(&self, elapsed: Duration) {
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        self.0[Self::bucket(micros)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The percentiles of the durations recorded since the last call, `None`
+    /// if there were none. Leaves the histogram empty.
+    fn take(&self) -> Option<Latency> {
+        let counts: Vec<u64> = self
+            .0
+            .iter()
+            .map(|n| n.swap(0, Ordering::Relaxed))
+            .collect();
+        let count: u64 = counts.iter().sum();
+        if count == 0 {
+            return None;
+        }
+        let percentile = |p: u64| {
+            let rank = (count * p).div_ceil(100);
+            let mut seen = 0;
+            for (bucket, n) in counts.iter().enumerate() {
+                seen += n;
+                if seen >= rank {
+                    return Self::bucket_max(bucket);
+                }
+            }
+            unreachable!("the counts add up to {count}")
+        };
+        Some(Latency {
+            count,
+            p50_micros: percentile(50),
+            p95_micros: percentile(95),
+            p99_micros: percentile(99),
+        })
+    }
+
+    fn bucket(micros: u64) -> usize {
+        if micros < 8 {
+            return micros as usize;
+        }
+        let exp = 63 - micros.leading_zeros() as usize;
+        let sub = (micros >> (exp - 3)) as usize & 7;
+        (exp - 2) * 8 + sub
+    }
+
+    /// The largest duration counted in `bucket`.
+    fn bucket_max(bucket: usize) -> u64 {
+        if bucket < 8 {
+            return bucket as u64;
+        }
+        let shift = bucket / 8 - 1;
+        let sub = (bucket % 8) as u64;
+        ((8 + sub) << shift) + ((1 << shift) - 1)
+    }
+}
+
+/// Counts an open connection until dropped.
+struct ConnectionGuard<'a>(&'a AtomicU64);
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a connection towards `max_connections` until dropped.
+struct ConnectionPermit(Arc<AtomicUsize>);
+
+impl ConnectionPermit {
+    /// Take a permit unless `max` connections are already open.
+    fn try_acquire(open: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        open.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+            (n < max).then_some(n + 1)
+        })
+        .ok()
+        .map(|_| ConnectionPermit(open.clone()))
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// How long a rejected connection may take to read its error.
+const REJECT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Answer a connection over `max_connections` with a `Busy` error and close it.
+///
+/// This runs on the accept loop, so a client slow to read the error is cut
+/// off after [`REJECT_TIMEOUT`] rather than holding up everyone else.
+fn reject(mut stream: Connection) -> io::Result<()> {
+    stream.set_timeout(Some(REJECT_TIMEOUT))?;
+    let response = Response::Err {
+        code: ErrorCode::Busy,
+        message: "server busy: too many connections".to_string(),
+    };
+    serde_json::to_writer(&mut stream, &response)?;
+    stream.flush()?;
+    stream.shutdown(Shutdown::Write)?;
+    // Wait for the client to hang up, as closing with its requests unread
+    // would reset the connection, possibly before it read the error.
+    io::copy(&mut stream, &mut io::sink())?;
+    Ok(())
+}
+
+/// Counts a running scan until dropped.
+struct ScanPermit<'a>(&'a AtomicUsize);
+
+impl<'a> ScanPermit<'a> {
+    /// Take a permit unless `max` scans are already running.
+    fn try_acquire(active: &'a AtomicUsize, max: usize) -> Option<Self> {
+        active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| ScanPermit(active))
+    }
+}
+
+impl Drop for ScanPermit<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
-fn handle_stream(stream: TcpStream, engine: impl KvsEngine) -> Result<()> {
-    let mut buf_reader = BufReader::new(stream.try_clone()?);
-    let mut buf_writer = BufWriter::new(stream.try_clone()?);
-    let stream = Deserializer::from_reader(&mut buf_reader).into_iter::<Request>();
+/// How often a subscribed connection checks whether the server is shutting down.
+const SUBSCRIBER_POLL: Duration = Duration::from_millis(100);
+
+/// Connections subscribed to key changes, each with the prefix it watches.
+///
+/// The engine's changes are only followed while a connection is subscribed,
+/// so writes don't copy their values into a channel nobody reads.
+#[derive(Default)]
+struct Subscribers(Mutex<SubscriberList>);
+
+#[derive(Default)]
+struct SubscriberList {
+    /// The id, prefix and events of each subscription.
+    subscriptions: Vec<(u64, String, mpsc::Sender<Response>)>,
+    next_id: u64,
+    /// Whether a thread follows the engine's changes.
+    following: bool,
+}
+
+/// The events of a subscription, which ends once this is dropped.
+struct Subscription<'a> {
+    id: u64,
+    events: mpsc::Receiver<Response>,
+    subscribers: &'a Subscribers,
+}
+
+impl Drop for Subscription<'_> {
+    fn drop(&mut self) {
+        let mut list = self.subscribers.0.lock().unwrap();
+        list.subscriptions.retain(|(id, ..)| *id != self.id);
+    }
+}
+
+impl Subscribers {
+    /// Watch the keys starting with `prefix` for as long as the subscription
+    /// is kept, following the changes of `engine` if nothing does yet.
+    fn subscribe<'a>(
+        self: &'a Arc<Self>,
+        prefix: String,
+        engine: &impl KvsEngine,
+    ) -> Subscription<'a> {
+        let (sender, events) = mpsc::channel();
+        let mut list = self.0.lock().unwrap();
+        let id = list.next_id;
+        list.next_id += 1;
+        list.subscriptions.push((id, prefix, sender));
+        if !list.following {
+            list.following = true;
+            self.follow(engine.watch());
+        }
+        Subscription {
+            id,
+            events,
+            subscribers: self,
+        }
+    }
+
+    /// Publish each of the engine's `changes` from a thread of its own, which
+    /// stops and drops `changes` at the first change after the last
+    /// subscription ended, or once the engine is gone.
+    fn follow(self: &Arc<Self>, changes: mpsc::Receiver<Change>) {
+        let subscribers = self.clone();
+        thread::spawn(move || {
+            for change in changes {
+                // A value set as bytes that aren't UTF-8 is sent lossily.
+                let value = change
+                    .value
+                    .map(|value| String::from_utf8_lossy(&value).into_owned());
+                if !subscribers.publish(&change.key, value) {
+                    break;
+                }
+            }
+            debug!("Stopped following changes, no connection is subscribed");
+        });
+    }
+
+    /// Tell every subscriber watching `key` that it now holds `value`, and
+    /// forget the ones that went away. Returns whether anyone is still
+    /// subscribed, and stops following the engine if not.
+    fn publish(&self, key: &str, value: Option<String>) -> bool {
+        let mut list = self.0.lock().unwrap();
+        list.subscriptions.retain(|(_, prefix, events)| {
+            !key.starts_with(prefix.as_str())
+                || events
+                    .send(Response::Event {
+                        key: key.to_owned(),
+                        value: value.clone(),
+                    })
+                    .is_ok()
+        });
+        list.following = !list.subscriptions.is_empty();
+        list.following
+    }
+}
+
+/// The request ids of recent sets, so a retried set is answered as the first
+/// try was without setting the key again. The least recently seen id is
+/// forgotten first once `capacity` are remembered.
+struct SeenRequests {
+    capacity: usize,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Default)]
+struct Seen {
+    ids: HashMap<u64, SeenSet>,
+    /// Each id by when it was last seen, oldest first.
+    by_age: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+struct SeenSet {
+    /// When the id was last seen, its key in `by_age`.
+    seen_at: u64,
+    /// A hash of the key and value the id came with.
+    fingerprint: u64,
+    /// Whether the set was applied, rather than still running.
+    applied: bool,
+}
+
+impl SeenRequests {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: Mutex::default(),
+        }
+    }
+
+    /// Remember `id` for a set of `key` to `value` about to run, or return
+    /// the response for a set already seen with it, which must not run.
+    fn claim(&self, id: u64, key: &str, value: &str) -> Option<Response> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        (key, value).hash(&mut hasher);
+        let fingerprint = hasher.finish();
+
+        let mut seen = self.seen.lock().unwrap();
+        seen.clock += 1;
+        let now = seen.clock;
+        if let Some(set) = seen.ids.get_mut(&id) {
+            let last_seen = std::mem::replace(&mut set.seen_at, now);
+            let response = if set.fingerprint != fingerprint {
+                Response::Err {
+                    code: ErrorCode::BadRequest,
+                    message: format!("request id {id} was used for another set"),
+                }
+            } else if set.applied {
+                Response::Ok
+            } else {
+                Response::Err {
+                    code: ErrorCode::Busy,
+                    message: format!("the set with request id {id} is still running"),
+                }
+            };
+            seen.by_age.remove(&last_seen);
+            seen.by_age.insert(now, id);
+            return Some(response);
+        }
+        if seen.ids.len() >= self.capacity
+            && let Some((_, oldest)) = seen.by_age.pop_first()
+        {
+            seen.ids.remove(&oldest);
+        }
+        seen.ids.insert(
+            id,
+            SeenSet {
+                seen_at: now,
+                fingerprint,
+                applied: false,
+            },
+        );
+        seen.by_age.insert(now, id);
+        None
+    }
+
+    /// Record how the set claiming `id` went. A failed one set nothing, so
+    /// its id is forgotten for a retry to run it again.
+    fn finish(&self, id: u64, applied: bool) {
+        let mut seen = self.seen.lock().unwrap();
+        if applied {
+            if let Some(set) = seen.ids.get_mut(&id) {
+                set.applied = true;
+            }
+        } else if let Some(set) = seen.ids.remove(&id) {
+            seen.by_age.remove(&set.seen_at);
+        }
+    }
+}
+
+/// Write the `events` of a subscribed connection to `writer` as they come,
+/// until the client hangs up or the server shuts down.
+///
+/// A client that hung up is only noticed on the next event written to it,
+/// so until then its worker stays busy.
+fn stream_events(
+    events: &mpsc::Receiver<Response>,
+    writer: &mut impl Write,
+    shutdown: &LoopFlag,
+) -> Result<()> {
+    while !shutdown.load() {
+        let event = match events.recv_timeout(SUBSCRIBER_POLL) {
+            Ok(event) => event,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        if let Err(e) = serde_json::to_writer(&mut *writer, &event)
+            .map_err(io::Error::from)
+            .and_then(|_| writer.flush())
+        {
+            debug!("Closing a subscription: {e}");
+            break;
+        }
+        debug!("Sent event: {:?}", event);
+    }
+    Ok(())
+}
+
+/// Whether `e` means the client went away, like by closing the connection
+/// before reading its responses, which ends the connection as normally as
+/// hanging up between requests does.
+fn is_disconnect(e: &Error) -> bool {
+    let kind = e
+        .downcast_ref::<io::Error>()
+        .map(io::Error::kind)
+        .or_else(|| {
+            e.downcast_ref::<serde_json::Error>()
+                .and_then(serde_json::Error::io_error_kind)
+        });
+    matches!(
+        kind,
+        Some(
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+        )
+    )
+}
+
+/// Reads the requests of a connection, first sending the responses written
+/// so far whenever it has to wait for more, so batched responses never wait
+/// on a client that waits on them.
+struct FlushingReader<'a> {
+    reader: BufReader<Connection>,
+    responses: &'a RefCell<BufWriter<Connection>>,
+}
+
+impl Read for FlushingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.reader.buffer().is_empty() {
+            self.responses.borrow_mut().flush()?;
+        }
+        self.reader.read(buf)
+    }
+}
+
+/// A transaction begun by a connection: the values its watched keys held
+/// then, and the writes it queued since.
+struct Transaction {
+    watched: Vec<(String, Option<String>)>,
+    batch: Vec<BatchOp>,
+}
+
+/// Serve the requests of one connection until the client closes it.
+///
+/// A connection is one ordered stream of JSON requests, answered one by one in
+/// the order they arrive, so a client may pipeline several before reading
+/// their responses. A malformed request is answered with a single
+/// `BadRequest` error, after which the connection is closed, as nothing after
+/// it can be framed reliably. A connection idle for longer than the
+/// configured `idle_timeout` is closed, so it doesn't hold its worker forever.
+/// A subscribed one only streams events from then on, see [`stream_events`].
+/// Writes between a `Begin` and its `Commit` are queued in a [`Transaction`].
+/// Responses are sent as the configured `response_flush` says.
+#[allow(clippy::too_many_arguments)]
+fn handle_stream(
+    stream: Connection,
+    engine: impl KvsEngine,
+    config: &ServerConfig,
+    active_scans: &AtomicUsize,
+    counters: &Counters,
+    draining: &LoopFlag,
+    read_only: &AtomicBool,
+    subscribers: &Arc<Subscribers>,
+    seen_requests: &SeenRequests,
+    shutdown: &LoopFlag,
+) -> Result<()> {
+    stream.set_timeout(config.idle_timeout)?;
+    let responses = RefCell::new(BufWriter::new(stream.try_clone()?));
+    let reader = FlushingReader {
+        reader: BufReader::new(stream.try_clone()?),
+        responses: &responses,
+    };
+    let stream = Deserializer::from_reader(reader).into_iter::<Request>();
+    let mut authenticated = config.auth_token.is_none();
+    let mut compress_values = false;
+    let mut report_removed = false;
+    let mut transaction: Option<Transaction> = None;
     for request in stream {
-        let request = request?;
-        eprintln!("Received request: {:?}", request);
+        // Released before reading the next request, for the reader to flush.
+        let mut responses = responses.borrow_mut();
+        let mut buf_writer = &mut *responses;
+        let request = match request {
+            Ok(request) => request,
+            // The client hung up, possibly in the middle of a request.
+            Err(e) if e.is_eof() => break,
+            // A TLS client hung up without ending the session.
+            Err(e) if e.io_error_kind() == Some(io::ErrorKind::UnexpectedEof) => break,
+            Err(e)
+                if matches!(
+                    e.io_error_kind(),
+                    Some(io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+                ) =>
+            {
+                info!("Closing a connection idle for {:?}", config.idle_timeout);
+                break;
+            }
+            Err(e) if e.is_io() => return Err(e.into()),
+            Err(e) => {
+                let response = Response::Err {
+                    code: ErrorCode::BadRequest,
+                    message: format!("malformed request: {e}"),
+                };
+                serde_json::to_writer(&mut buf_writer, &response)?;
+                buf_writer.flush()?;
+                warn!("Closing connection after a malformed request: {e}");
+                break;
+            }
+        };
+        let received = Instant::now();
+        debug!("Received request: {:?}", request);
+        counters.record(&request);
+        let op = op_name(&request);
+        let (request, deadline) = match request {
+            Request::WithDeadline {
+                deadline_ms,
+                request,
+            } => (
+                *request,
+                Some(received + Duration::from_millis(deadline_ms)),
+            ),
+            request => (request, None),
+        };
+        let expired = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        if expired() {
+            let response = Response::error(&KvsError::DeadlineExceeded);
+            serde_json::to_writer(&mut buf_writer, &response)?;
+            debug!("Sent response: {:?}", response);
+            buf_writer.flush()?;
+            continue;
+        }
+        // Scans, exports, imports and compactions check it as they go.
+        let deadlined;
+        let engine = match deadline {
+            Some(deadline) => {
+                deadlined = engine.with_deadline(deadline);
+                &deadlined
+            }
+            None => &engine,
+        };
+        if !authenticated && !matches!(request, Request::Hello { .. } | Request::Auth { .. }) {
+            let response = Response::error(&KvsError::NotAuthenticated);
+            serde_json::to_writer(&mut buf_writer, &response)?;
+            debug!("Sent response: {:?}", response);
+            buf_writer.flush()?;
+            continue;
+        }
+        if read_only.load(Ordering::Relaxed) && request.is_mutation() {
+            let response = Response::error(&KvsError::ReadOnly);
+            serde_json::to_writer(&mut buf_writer, &response)?;
+            debug!("Sent response: {:?}", response);
+            buf_writer.flush()?;
+            continue;
+        }
+        if let Some(transaction) = &mut transaction
+            && request.is_mutation()
+            && !matches!(request, Request::Commit)
+        {
+            let response = match request {
+                // The commit applies it, so its request id has nothing to dedupe.
+                Request::Set { key, value, .. } => {
+                    transaction.batch.push(BatchOp::Set(key, value));
+                    Response::Ok
+                }
+                Request::Remove { key } => {
+                    transaction.batch.push(BatchOp::Remove(key));
+                    Response::Ok
+                }
+                _ => Response::Err {
+                    code: ErrorCode::BadRequest,
+                    message: "only sets and removes can be queued in a transaction".to_string(),
+                },
+            };
+            serde_json::to_writer(&mut buf_writer, &response)?;
+            debug!("Sent response: {:?}", response);
+            buf_writer.flush()?;
+            continue;
+        }
         match request {
-            Request::Set { key, value } => match engine.set(key, value) {
+            Request::Set {
+                key,
+                value,
+                request_id,
+            } => {
+                let seen = request_id.and_then(|id| seen_requests.claim(id, &key, &value));
+                let response = match seen {
+                    Some(response) => response,
+                    None => {
+                        let response = match engine.set(key, value) {
+                            Ok(_) => Response::Ok,
+                            Err(e) => {
+                                warn!("Error setting key: {:?}", e);
+                                Response::error(&e)
+                            }
+                        };
+                        if let Some(id) = request_id {
+                            seen_requests.finish(id, matches!(response, Response::Ok));
+                        }
+                        response
+                    }
+                };
+                serde_json::to_writer(&mut buf_writer, &response)?;
+                debug!("Sent response: {:?}", response);
+            }
+            Request::SetEx {
+                key,
+                value,
+                ttl_secs,
+            } => match engine.set_with_ttl(key, value, Duration::from_secs(ttl_secs)) {
                 Ok(_) => {
                     let response = Response::Ok;
                     serde_json::to_writer(&mut buf_writer, &response)?;
-                    eprintln!("Sent response: {:?}", response);
+                    debug!("Sent response: {:?}", response);
                 }
                 Err(e) => {
-                    let response = Response::Err(e.to_string());
+                    let response = Response::error(&e);
                     serde_json::to_writer(&mut buf_writer, &response)?;
-                    eprintln!("Error setting key: {:?}", e);
+                    warn!("Error setting key: {:?}", e);
                 }
             },
             Request::Get { key } => match engine.get(key) {
+                Ok(value) if compress_values => {
+                    let response = Response::compressed_value(value);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
+                }
                 Ok(value) => {
                     let response = Response::Value(value);
                     serde_json::to_writer(&mut buf_writer, &response)?;
-                    eprintln!("Sent response: {:?}", response);
+                    debug!("Sent response: {:?}", response);
+                }
+                Err(e) => {
+                    let response = Response::error(&e);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    warn!("Error getting key: {:?}", e);
+                }
+            },
+            Request::SetBytes { key, value } => match engine.set_bytes(key, value) {
+                Ok(_) => {
+                    let response = Response::Ok;
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
+                }
+                Err(e) => {
+                    let response = Response::error(&e);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    warn!("Error setting key: {:?}", e);
+                }
+            },
+            Request::GetBytes { key } => match engine.get_bytes(key) {
+                Ok(value) => {
+                    let response = Response::Bytes(value);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
+                }
+                Err(e) => {
+                    let response = Response::error(&e);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    warn!("Error getting key: {:?}", e);
+                }
+            },
+            Request::Exists { key } => match engine.contains_key(key) {
+                Ok(exists) => {
+                    let response = Response::Bool(exists);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
+                }
+                Err(e) => {
+                    let response = Response::error(&e);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    warn!("Error checking key: {:?}", e);
+                }
+            },
+            Request::Len => match engine.len() {
+                Ok(len) => {
+                    let response = Response::Count(len as u64);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
                 }
                 Err(e) => {
-                    let response = Response::Err(e.to_string());
+                    let response = Response::error(&e);
                     serde_json::to_writer(&mut buf_writer, &response)?;
-                    eprintln!("Error getting key: {:?}", e);
+                    warn!("Error counting keys: {:?}", e);
                 }
             },
             Request::Remove { key } => match engine.remove(key) {
                 Ok(_) => {
+                    let response = if report_removed {
+                        Response::Removed(true)
+                    } else {
+                        Response::Ok
+                    };
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
+                }
+                Err(KvsError::NonExistentKey(_)) if report_removed => {
+                    let response = Response::Removed(false);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
+                }
+                Err(e) => {
+                    let response = Response::error(&e);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    warn!("Error removing key: {:?}", e);
+                }
+            },
+            Request::Take { key } => match engine.take(key) {
+                Ok(value) if compress_values => {
+                    let response = Response::compressed_value(value);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
+                }
+                Ok(value) => {
+                    let response = Response::Value(value);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
+                }
+                Err(e) => {
+                    let response = Response::error(&e);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    warn!("Error taking key: {:?}", e);
+                }
+            },
+            Request::GetSet { key, value } => match engine.get_set(key, value) {
+                Ok(value) if compress_values => {
+                    let response = Response::compressed_value(value);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
+                }
+                Ok(value) => {
+                    let response = Response::Value(value);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
+                }
+                Err(e) => {
+                    let response = Response::error(&e);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    warn!("Error setting key: {:?}", e);
+                }
+            },
+            Request::Scan { prefix } => {
+                let response = match ScanPermit::try_acquire(active_scans, config.max_scans) {
+                    Some(_permit) => match engine.scan(prefix) {
+                        // Too late to be of use, and possibly large to send.
+                        Ok(_) if expired() => Response::error(&KvsError::DeadlineExceeded),
+                        Ok(pairs) => Response::Pairs(pairs),
+                        Err(e) => Response::error(&e),
+                    },
+                    None => Response::Err {
+                        code: ErrorCode::Busy,
+                        message: "server busy: too many concurrent scans".to_string(),
+                    },
+                };
+                serde_json::to_writer(&mut buf_writer, &response)?;
+                debug!("Sent response: {:?}", response);
+            }
+            Request::RemovePrefix { prefix } => match engine.remove_prefix(prefix) {
+                Ok(removed) => {
+                    let response = Response::Count(removed);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
+                }
+                Err(e) => {
+                    let response = Response::error(&e);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    warn!("Error removing prefix: {:?}", e);
+                }
+            },
+            Request::IncrByFloat { key, delta } => match engine.increment_float(key, delta) {
+                Ok(value) => {
+                    let response = Response::Float(value);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
+                }
+                Err(e) => {
+                    let response = Response::error(&e);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    warn!("Error incrementing key: {:?}", e);
+                }
+            },
+            Request::Incr { key, delta } => match engine.increment(key, delta) {
+                Ok(value) => {
+                    let response = Response::Int(value);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
+                }
+                Err(e) => {
+                    let response = Response::error(&e);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    warn!("Error incrementing key: {:?}", e);
+                }
+            },
+            Request::Modify { key, op } => match engine.modify(key, op) {
+                Ok(value) => {
+                    let response = Response::Value(value);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
+                }
+                Err(e) => {
+                    let response = Response::error(&e);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    warn!("Error modifying key: {:?}", e);
+                }
+            },
+            Request::Begin { .. } if transaction.is_some() => {
+                let response = Response::Err {
+                    code: ErrorCode::BadRequest,
+                    message: "a transaction is already begun".to_string(),
+                };
+                serde_json::to_writer(&mut buf_writer, &response)?;
+                debug!("Sent response: {:?}", response);
+            }
+            Request::Begin { watch } => {
+                let watched = watch
+                    .into_iter()
+                    .map(|key| Ok((key.clone(), engine.get(key)?)))
+                    .collect::<kvs::Result<_>>();
+                match watched {
+                    Ok(watched) => {
+                        transaction = Some(Transaction {
+                            watched,
+                            batch: Vec::new(),
+                        });
+                        let response = Response::Ok;
+                        serde_json::to_writer(&mut buf_writer, &response)?;
+                        debug!("Sent response: {:?}", response);
+                    }
+                    Err(e) => {
+                        let response = Response::error(&e);
+                        serde_json::to_writer(&mut buf_writer, &response)?;
+                        warn!("Error beginning a transaction: {:?}", e);
+                    }
+                }
+            }
+            Request::Commit => match transaction.take() {
+                Some(Transaction { watched, batch }) => match engine.commit(watched, batch) {
+                    Ok(_) => {
+                        let response = Response::Ok;
+                        serde_json::to_writer(&mut buf_writer, &response)?;
+                        debug!("Sent response: {:?}", response);
+                    }
+                    Err(e) => {
+                        let response = Response::error(&e);
+                        serde_json::to_writer(&mut buf_writer, &response)?;
+                        warn!("Error committing a transaction: {:?}", e);
+                    }
+                },
+                None => {
+                    let response = Response::Err {
+                        code: ErrorCode::BadRequest,
+                        message: "no transaction to commit".to_string(),
+                    };
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
+                }
+            },
+            // An export reads as much as a full scan, so it takes a scan permit.
+            Request::Export => {
+                let response = match ScanPermit::try_acquire(active_scans, config.max_scans) {
+                    Some(_permit) => {
+                        let mut data = Vec::new();
+                        match engine.export(&mut data) {
+                            Ok(_) if expired() => Response::error(&KvsError::DeadlineExceeded),
+                            Ok(_) => match String::from_utf8(data) {
+                                Ok(data) => Response::Export(data),
+                                Err(e) => Response::Err {
+                                    code: ErrorCode::Internal,
+                                    message: e.to_string(),
+                                },
+                            },
+                            Err(e) => Response::error(&e),
+                        }
+                    }
+                    None => Response::Err {
+                        code: ErrorCode::Busy,
+                        message: "server busy: too many concurrent scans".to_string(),
+                    },
+                };
+                serde_json::to_writer(&mut buf_writer, &response)?;
+                debug!("Sent export response");
+            }
+            Request::Import { data } => match engine.import(data.as_bytes()) {
+                Ok(_) => {
+                    let response = Response::Ok;
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    debug!("Sent response: {:?}", response);
+                }
+                Err(e) => {
+                    let response = Response::error(&e);
+                    serde_json::to_writer(&mut buf_writer, &response)?;
+                    warn!("Error importing: {:?}", e);
+                }
+            },
+            Request::Flush => match engine.flush() {
+                Ok(()) => {
                     let response = Response::Ok;
                     serde_json::to_writer(&mut buf_writer, &response)?;
-                    eprintln!("Sent response: {:?}", response);
+                    debug!("Sent response: {:?}", response);
                 }
                 Err(e) => {
-                    let response = Response::Err(e.to_string());
+                    let response = Response::error(&e);
                     serde_json::to_writer(&mut buf_writer, &response)?;
-                    eprintln!("Error removing key: {:?}", e);
+                    warn!("Error flushing: {:?}", e);
                 }
             },
+            Request::Config => {
+                let response = Response::Config(config.clone());
+                serde_json::to_writer(&mut buf_writer, &response)?;
+                debug!("Sent response: {:?}", response);
+            }
+            Request::Stats => {
+                let response = Response::Stats(
+                    counters.snapshot(engine.compactions(), engine.lock_contention()),
+                );
+                serde_json::to_writer(&mut buf_writer, &response)?;
+                debug!("Sent response: {:?}", response);
+            }
+            Request::Hello { protocol_version } if protocol_version != PROTOCOL_VERSION => {
+                let response = Response::Err {
+                    code: ErrorCode::BadRequest,
+                    message: format!(
+                        "the client speaks protocol version {protocol_version}, the server {PROTOCOL_VERSION}"
+                    ),
+                };
+                serde_json::to_writer(&mut buf_writer, &response)?;
+                buf_writer.flush()?;
+                warn!(
+                    "Closing connection of a client speaking protocol version {protocol_version}"
+                );
+                break;
+            }
+            Request::Hello { .. } => {
+                let response = Response::Hello {
+                    protocol_version: PROTOCOL_VERSION,
+                };
+                serde_json::to_writer(&mut buf_writer, &response)?;
+                debug!("Sent response: {:?}", response);
+            }
+            Request::Auth { token } => {
+                let response = match &config.auth_token {
+                    Some(expected) if !constant_time_eq(token.as_bytes(), expected.as_bytes()) => {
+                        warn!("Rejected a wrong auth token");
+                        Response::error(&KvsError::NotAuthenticated)
+                    }
+                    _ => {
+                        authenticated = true;
+                        Response::Ok
+                    }
+                };
+                serde_json::to_writer(&mut buf_writer, &response)?;
+                debug!("Sent response: {:?}", response);
+            }
+            Request::CompressValues { enabled } => {
+                compress_values = enabled;
+                let response = Response::Ok;
+                serde_json::to_writer(&mut buf_writer, &response)?;
+                debug!("Sent response: {:?}", response);
+            }
+            Request::ReportRemoved { enabled } => {
+                report_removed = enabled;
+                let response = Response::Ok;
+                serde_json::to_writer(&mut buf_writer, &response)?;
+                debug!("Sent response: {:?}", response);
+            }
+            Request::Drain | Request::Resume => {
+                draining.store(matches!(request, Request::Drain));
+                let response = Response::Ok;
+                serde_json::to_writer(&mut buf_writer, &response)?;
+                debug!("Sent response: {:?}", response);
+            }
+            Request::SetReadOnly { enabled } => {
+                read_only.store(enabled, Ordering::Relaxed);
+                info!("Read-only mode {}", if enabled { "on" } else { "off" });
+                let response = Response::Ok;
+                serde_json::to_writer(&mut buf_writer, &response)?;
+                debug!("Sent response: {:?}", response);
+            }
+            Request::Subscribe { prefix } => {
+                let subscription = subscribers.subscribe(prefix, engine);
+                let response = Response::Ok;
+                serde_json::to_writer(&mut buf_writer, &response)?;
+                buf_writer.flush()?;
+                debug!("Sent response: {:?}", response);
+                return stream_events(&subscription.events, &mut buf_writer, shutdown);
+            }
+            Request::WithDeadline { .. } => {
+                let response = Response::Err {
+                    code: ErrorCode::BadRequest,
+                    message: "nested deadline".to_string(),
+                };
+                serde_json::to_writer(&mut buf_writer, &response)?;
+                debug!("Sent response: {:?}", response);
+            }
+        }
+        if config.response_flush == ResponseFlush::Every {
+            buf_writer.flush()?;
+        }
+        if config.latency_stats
+            && let Some(op) = op
+        {
+            counters.record_latency(op, received.elapsed());
         }
-        buf_writer.flush().unwrap();
     }
     Ok(())
 }
@@ -0,0 +1,200 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::{Parser, Subcommand};
+use kvs::{KvStore, KvsEngine, KvsError, Result, SledEngine, data_dir};
+
+/// Work on a kvs data directory directly, without a server.
+#[derive(Parser, Debug)]
+#[command(author, version)]
+struct Cli {
+    /// The directory holding the log files
+    #[arg(long, global = true, default_value = ".")]
+    data_dir: PathBuf,
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Print the value of a key
+    Get { key: String },
+    /// Set the value of a key
+    Set { key: String, value: String },
+    /// Remove a key
+    #[command(name = "rm")]
+    Remove { key: String },
+    /// Print every key with the log file, offset and length of its latest
+    /// record, as indexed on open, without reading the values
+    DumpIndex,
+    /// Copy every key into another engine in the same directory, then switch
+    /// the directory over to it. Keys expiring in a sled store lose their expiry
+    Migrate {
+        /// The engine to migrate to
+        #[arg(long, value_parser = ["kvs", "sled"])]
+        to: String,
+        /// Delete the old engine's files once the migration succeeded
+        #[arg(long)]
+        delete_source: bool,
+    },
+    /// Verify every record of the logs without changing them and print how
+    /// many there are, exiting with 2 if a log is corrupt
+    Check,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    match &cli.command {
+        Commands::Migrate { to, delete_source } => {
+            return migrate(&cli.data_dir, to, *delete_source);
+        }
+        Commands::Check => return check(&cli.data_dir),
+        _ => {}
+    }
+    data_dir::check_engine(&cli.data_dir, "kvs")?;
+    let store = KvStore::open(&cli.data_dir)?;
+
+    match cli.command {
+        Commands::Get { key } => match store.get(key)? {
+            Some(value) => println!("{value}"),
+            None => println!("Key not found"),
+        },
+        Commands::Set { key, value } => store.set(key, value)?,
+        Commands::Remove { key } => match store.remove(key) {
+            Err(KvsError::NonExistentKey(_)) => {
+                println!("Key not found");
+                process::exit(1);
+            }
+            result => result?,
+        },
+        Commands::DumpIndex => {
+            // 每行: 键, 文件, 偏移, 长度, 以制表符分隔
+            for (key, idx) in store.index() {
+                println!(
+                    "{key}\t{}\t{}\t{}",
+                    idx.path().display(),
+                    idx.offset(),
+                    idx.len()
+                );
+            }
+        }
+        Commands::Migrate { .. } | Commands::Check => {
+            unreachable!("handled before opening the store")
+        }
+    }
+    Ok(())
+}
+
+/// Verify the logs of the data directory at `path`, which must not be open.
+///
+/// Unlike the other commands it doesn't mark an unmarked directory as kvs,
+/// or cut off torn records, so it changes nothing.
+fn check(path: &Path) -> Result<()> {
+    match data_dir::engine_of(path)? {
+        Some(engine) if engine != "kvs" => {
+            return Err(KvsError::WrongEngine {
+                previous: engine,
+                current: "kvs".to_owned(),
+            });
+        }
+        _ => {}
+    }
+    let check = KvStore::check(path)?;
+    for (log, torn) in &check.torn {
+        eprintln!(
+            "{}: {torn} bytes of a torn record at the end",
+            log.display()
+        );
+    }
+    for (log, e) in &check.corrupt {
+        eprintln!("{}: corrupt: {e}", log.display());
+    }
+    println!(
+        "{} files, {} records, {} stale, {} corrupt",
+        check.files,
+        check.records,
+        check.stale,
+        check.corrupt.len()
+    );
+    if !check.corrupt.is_empty() {
+        process::exit(2);
+    }
+    Ok(())
+}
+
+/// Copy the data directory at `path` into the `to` engine, then mark the
+/// directory as `to`'s and, if `delete_source`, delete the old engine's files.
+///
+/// The old engine stays marked until every key is copied, so a migration cut
+/// short leaves the directory as it was and can be run again.
+fn migrate(path: &Path, to: &str, delete_source: bool) -> Result<()> {
+    let Some(from) = data_dir::engine_of(path)? else {
+        data_dir::write_engine_marker(path, to)?;
+        println!("Nothing to migrate");
+        return Ok(());
+    };
+    if from == to {
+        println!("Already using {to}");
+        return Ok(());
+    }
+    data_dir::write_engine_marker(path, &from)?;
+
+    let copied = match (from.as_str(), to) {
+        ("kvs", "sled") => {
+            let source = KvStore::open(path)?;
+            let expiry = source
+                .index()
+                .into_iter()
+                .filter_map(|(key, idx)| Some((key, idx.expires_at()?)))
+                .collect();
+            copy(&source, &expiry, &SledEngine::open(path)?)?
+        }
+        ("sled", "kvs") => copy(
+            &SledEngine::open(path)?,
+            &HashMap::new(),
+            &KvStore::open(path)?,
+        )?,
+        _ => {
+            return Err(KvsError::WrongEngine {
+                previous: from,
+                current: to.to_owned(),
+            });
+        }
+    };
+
+    data_dir::write_engine_marker(path, to)?;
+    if delete_source {
+        data_dir::remove_engine_files(path, &from)?;
+    }
+    println!("Migrated {copied} keys from {from} to {to}");
+    Ok(())
+}
+
+/// Copy every live key of `source` into `dest`, with the expiry times in
+/// `expiry`, in milliseconds since the Unix epoch. Returns the keys copied.
+fn copy(
+    source: &impl KvsEngine,
+    expiry: &HashMap<String, u64>,
+    dest: &impl KvsEngine,
+) -> Result<u64> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let mut copied = 0;
+    for (key, value) in source.scan(String::new())? {
+        match expiry.get(&key) {
+            Some(&expires_at) => {
+                let ttl = Duration::from_millis(expires_at.saturating_sub(now));
+                dest.set_with_ttl(key, value, ttl)?;
+            }
+            None => dest.set(key, value)?,
+        }
+        copied += 1;
+    }
+    Ok(copied)
+}
@@ -0,0 +1,643 @@
+//! Clients speaking the JSON protocol of `kvs-server`.
+//!
+//! The server answers the requests of a connection one by one and in order,
+//! so a [`Client`] may send several requests before reading their responses.
+//! [`BufferedClient`] does that in the background for requests queued from
+//! any thread. [`KvsClient`] has typed methods for the common requests.
+
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde_json::{Deserializer, StreamDeserializer, de::IoRead};
+
+use crate::error::{KvsError, Result};
+use crate::protocol::{ErrorCode, PROTOCOL_VERSION, Request, Response};
+use crate::tls::{self, TlsStream};
+
+/// How long to wait before the first retry of a refused connection, doubled
+/// for every retry after it.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How a [`Client`] connects and how long it waits on the server.
+///
+/// ```rust
+/// use std::time::Duration;
+/// use kvs::client::ClientConfig;
+///
+/// let config = ClientConfig::default()
+///     .request_timeout(Duration::from_secs(1))
+///     .retries(3);
+/// assert_eq!(config.connect_timeout, None);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientConfig {
+    /// Give up connecting after this long, `None` to wait as long as the OS does.
+    pub connect_timeout: Option<Duration>,
+    /// Give up on a response, or on sending a request, after this long.
+    /// `None` waits forever.
+    pub request_timeout: Option<Duration>,
+    /// How many times to retry a refused connection, with exponential backoff.
+    pub retries: u32,
+    /// Talk TLS, trusting the certificates in this PEM file. `None` talks
+    /// plain TCP.
+    pub tls_ca: Option<PathBuf>,
+    /// The name the server's certificate must be valid for, by default the
+    /// IP address connected to.
+    pub server_name: Option<String>,
+    /// The token to present to a server started with one, in a
+    /// [`Request::Auth`] right after the hello.
+    pub auth_token: Option<String>,
+    /// Whether to ask the server to compress large values, with a
+    /// [`Request::CompressValues`] right after the hello. They are
+    /// decompressed as they are read.
+    pub compress_values: bool,
+}
+
+impl ClientConfig {
+    /// Set how long to wait for the connection to be accepted.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Set how long to wait for each response.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Set how many times to retry a refused connection.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Talk TLS to a server with a certificate signed by one in the PEM file `ca`.
+    pub fn tls(mut self, ca: impl Into<PathBuf>) -> Self {
+        self.tls_ca = Some(ca.into());
+        self
+    }
+
+    /// Set the name to check the server's certificate against.
+    pub fn server_name(mut self, name: impl Into<String>) -> Self {
+        self.server_name = Some(name.into());
+        self
+    }
+
+    /// Set the token to authenticate with.
+    pub fn auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    /// Ask the server to compress large values.
+    pub fn compress_values(mut self) -> Self {
+        self.compress_values = true;
+        self
+    }
+}
+
+/// Responses read off a connection, one per request sent.
+type Responses = StreamDeserializer<'static, IoRead<BufReader<Stream>>, Response>;
+
+/// A connection to the server, over plain TCP or TLS.
+enum Stream {
+    Tcp(TcpStream),
+    Tls(TlsStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> io::Result<Stream> {
+        Ok(match self {
+            Stream::Tcp(stream) => Stream::Tcp(stream.try_clone()?),
+            Stream::Tls(stream) => Stream::Tls(stream.clone()),
+        })
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            Stream::Tls(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// How far the protocol handshake of a [`Client`] got.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Handshake {
+    NotSent,
+    /// The hello is sent, its response not read yet.
+    Sent,
+    Done,
+}
+
+/// A connection to a `kvs-server`.
+///
+/// The first request sent is preceded by a [`Request::Hello`], so a server
+/// speaking another protocol version fails it with
+/// [`KvsError::IncompatibleProtocol`] instead of a serde error, and by a
+/// [`Request::Auth`] if the client has a token.
+pub struct Client {
+    stream: Stream,
+    writer: BufWriter<Stream>,
+    responses: Responses,
+    handshake: Handshake,
+    auth_token: Option<String>,
+    compress_values: bool,
+}
+
+impl Client {
+    /// Connect to the server at `addr`.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Client> {
+        Client::connect_with_config(addr, &ClientConfig::default())
+    }
+
+    /// Connect to the server at `addr` with the timeouts and retries of `config`.
+    ///
+    /// Running out of time fails with [`KvsError::Timeout`]. After a request
+    /// timed out its response may still arrive, so the client should be dropped.
+    pub fn connect_with_config(addr: impl ToSocketAddrs, config: &ClientConfig) -> Result<Client> {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut retries = config.retries;
+        let stream = loop {
+            match connect(&addr, config.connect_timeout) {
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused && retries > 0 => {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    retries -= 1;
+                }
+                stream => break stream.map_err(timeout)?,
+            }
+        };
+        stream.set_read_timeout(config.request_timeout)?;
+        stream.set_write_timeout(config.request_timeout)?;
+        let stream = match &config.tls_ca {
+            Some(ca) => {
+                let name = match &config.server_name {
+                    Some(name) => name.clone(),
+                    None => stream.peer_addr()?.ip().to_string(),
+                };
+                Stream::Tls(TlsStream::connect(tls::client_config(ca)?, &name, stream)?)
+            }
+            None => Stream::Tcp(stream),
+        };
+        let responses =
+            Deserializer::from_reader(BufReader::new(stream.try_clone()?)).into_iter::<Response>();
+        let writer = BufWriter::new(stream.try_clone()?);
+        Ok(Client {
+            stream,
+            writer,
+            responses,
+            handshake: Handshake::NotSent,
+            auth_token: config.auth_token.clone(),
+            compress_values: config.compress_values,
+        })
+    }
+
+    /// Exchange protocol versions with the server now, instead of with the
+    /// first request.
+    pub fn handshake(&mut self) -> Result<()> {
+        self.send_hello()?;
+        self.flush()?;
+        self.recv_hello()
+    }
+
+    fn send_hello(&mut self) -> Result<()> {
+        if self.handshake == Handshake::NotSent {
+            let hello = Request::Hello {
+                protocol_version: PROTOCOL_VERSION,
+            };
+            serde_json::to_writer(&mut self.writer, &hello).map_err(serde_timeout)?;
+            if let Some(token) = &self.auth_token {
+                let auth = Request::Auth {
+                    token: token.clone(),
+                };
+                serde_json::to_writer(&mut self.writer, &auth).map_err(serde_timeout)?;
+            }
+            if self.compress_values {
+                let compress = Request::CompressValues { enabled: true };
+                serde_json::to_writer(&mut self.writer, &compress).map_err(serde_timeout)?;
+            }
+            self.handshake = Handshake::Sent;
+        }
+        Ok(())
+    }
+
+    fn recv_hello(&mut self) -> Result<()> {
+        if self.handshake != Handshake::Sent {
+            return Ok(());
+        }
+        let message = match next_response(&mut self.responses)? {
+            Response::Hello { protocol_version } if protocol_version == PROTOCOL_VERSION => {
+                self.handshake = Handshake::Done;
+                return self.recv_setup();
+            }
+            Response::Hello { protocol_version } => format!(
+                "the server speaks protocol version {protocol_version}, the client {PROTOCOL_VERSION}"
+            ),
+            Response::Err {
+                code: ErrorCode::BadRequest,
+                message,
+            } if message.starts_with("malformed request") => {
+                "the server predates the protocol handshake".to_string()
+            }
+            Response::Err {
+                code: ErrorCode::BadRequest,
+                message,
+            } => message,
+            // Like a busy server turning the connection away.
+            Response::Err { code, message } => {
+                return Err(KvsError::ResponseError { code, message });
+            }
+            response => format!("unexpected response {response:?} to the handshake"),
+        };
+        Err(KvsError::IncompatibleProtocol(message))
+    }
+
+    /// Read the responses to the requests sent along with the hello.
+    fn recv_setup(&mut self) -> Result<()> {
+        if self.auth_token.is_some() {
+            self.recv_ok("the auth token")?;
+        }
+        if self.compress_values {
+            self.recv_ok("the compression request")?;
+        }
+        Ok(())
+    }
+
+    /// Read the `Ok` to one of them.
+    fn recv_ok(&mut self, what: &str) -> Result<()> {
+        match next_response(&mut self.responses)? {
+            Response::Ok => Ok(()),
+            Response::Err { code, message } => Err(KvsError::ResponseError { code, message }),
+            response => Err(KvsError::IncompatibleProtocol(format!(
+                "unexpected response {response:?} to {what}"
+            ))),
+        }
+    }
+
+    /// Send `request` and wait for its response.
+    pub fn request(&mut self, request: &Request) -> Result<Response> {
+        self.send(request)?;
+        self.flush()?;
+        self.recv()
+    }
+
+    /// Queue `request` without waiting for its response, to pipeline several
+    /// requests. It goes out on the next [`Client::flush`] at the latest.
+    pub fn send(&mut self, request: &Request) -> Result<()> {
+        self.send_hello()?;
+        serde_json::to_writer(&mut self.writer, request).map_err(serde_timeout)
+    }
+
+    /// Send every queued request.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush().map_err(timeout)
+    }
+
+    /// Read the response to the oldest request not answered yet.
+    pub fn recv(&mut self) -> Result<Response> {
+        self.recv_hello()?;
+        next_response(&mut self.responses)?.decompress()
+    }
+
+    /// Close the connection, ending the session on the server.
+    pub fn shutdown(self) -> Result<()> {
+        Ok(self.stream.shutdown(Shutdown::Both)?)
+    }
+}
+
+/// Connect to the first address of `addr` that accepts, within `timeout` each.
+fn connect(addr: &impl ToSocketAddrs, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let Some(timeout) = timeout else {
+        return TcpStream::connect(addr);
+    };
+    let mut last_error = io::Error::from(io::ErrorKind::AddrNotAvailable);
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Turn the io error a socket timeout shows up as into [`KvsError::Timeout`].
+fn timeout(e: io::Error) -> KvsError {
+    match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => KvsError::Timeout,
+        _ => e.into(),
+    }
+}
+
+/// Like [`timeout`], for an io error surfacing through `serde_json`.
+fn serde_timeout(e: serde_json::Error) -> KvsError {
+    match e.io_error_kind() {
+        Some(io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) => KvsError::Timeout,
+        _ => e.into(),
+    }
+}
+
+/// The next response, or an `UnexpectedEof` error if the server hung up
+/// without sending it, like when it crashed serving the request.
+fn next_response(responses: &mut Responses) -> Result<Response> {
+    match responses.next() {
+        Some(response) => response.map_err(serde_timeout),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no response from server").into()),
+    }
+}
+
+/// A [`Client`] with typed methods for the common requests, to talk to a
+/// `kvs-server` from Rust without building requests and matching responses.
+///
+/// Errors the server reports come back as [`KvsError::ResponseError`], except
+/// that removing a missing key fails with [`KvsError::NonExistentKey`], as it
+/// does on an engine.
+///
+/// ```rust
+/// # use std::net::TcpListener;
+/// # use std::thread;
+/// # use kvs::protocol::{PROTOCOL_VERSION, Request, Response};
+/// # use kvs::{KvsEngine, MemoryEngine};
+/// use kvs::KvsError;
+/// use kvs::client::KvsClient;
+///
+/// # let listener = TcpListener::bind("127.0.0.1:0")?;
+/// # let addr = listener.local_addr()?;
+/// # // Serves one connection like `kvs-server` would.
+/// # let server = thread::spawn(move || -> kvs::Result<()> {
+/// #     let engine = MemoryEngine::new();
+/// #     let (stream, _) = listener.accept()?;
+/// #     for request in serde_json::Deserializer::from_reader(&stream).into_iter() {
+/// #         let result = match request? {
+/// #             Request::Hello { .. } => Ok(Response::Hello {
+/// #                 protocol_version: PROTOCOL_VERSION,
+/// #             }),
+/// #             Request::Get { key } => engine.get(key).map(Response::Value),
+/// #             Request::Set { key, value, .. } => engine.set(key, value).map(|_| Response::Ok),
+/// #             Request::Remove { key } => engine.remove(key).map(|_| Response::Ok),
+/// #             request => unreachable!("{request:?}"),
+/// #         };
+/// #         let response = result.unwrap_or_else(|e| Response::error(&e));
+/// #         serde_json::to_writer(&stream, &response)?;
+/// #     }
+/// #     Ok(())
+/// # });
+/// let mut client = KvsClient::connect(addr)?;
+/// client.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+/// client.remove("key".to_owned())?;
+/// assert_eq!(client.get("key".to_owned())?, None);
+/// assert!(matches!(
+///     client.remove("key".to_owned()),
+///     Err(KvsError::NonExistentKey(_))
+/// ));
+/// client.shutdown()?;
+/// # server.join().unwrap()?;
+/// # Ok::<(), KvsError>(())
+/// ```
+pub struct KvsClient {
+    client: Client,
+}
+
+impl KvsClient {
+    /// Connect to the server at `addr`.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        KvsClient::connect_with_config(addr, &ClientConfig::default())
+    }
+
+    /// Connect to the server at `addr` as [`Client::connect_with_config`] does.
+    pub fn connect_with_config(
+        addr: impl ToSocketAddrs,
+        config: &ClientConfig,
+    ) -> Result<KvsClient> {
+        Ok(KvsClient {
+            client: Client::connect_with_config(addr, config)?,
+        })
+    }
+
+    /// Get the value of `key`, `None` if it is not set.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.client.request(&Request::Get { key })? {
+            Response::Value(value) => Ok(value),
+            response => Err(unexpected(response, "a get")),
+        }
+    }
+
+    /// Set `key` to `value`.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.send_set(key, value, None)
+    }
+
+    /// Set `key` to `value` under `request_id`, so retrying with the same id
+    /// after a timeout or a dropped connection sets it at most once, see
+    /// [`Request::Set`].
+    pub fn set_with_id(&mut self, key: String, value: String, request_id: u64) -> Result<()> {
+        self.send_set(key, value, Some(request_id))
+    }
+
+    fn send_set(&mut self, key: String, value: String, request_id: Option<u64>) -> Result<()> {
+        let request = Request::Set {
+            key,
+            value,
+            request_id,
+        };
+        match self.client.request(&request)? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response, "a set")),
+        }
+    }
+
+    /// Remove `key`, failing with [`KvsError::NonExistentKey`] if it is not set.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        match self.client.request(&Request::Remove { key: key.clone() })? {
+            Response::Ok => Ok(()),
+            Response::Err {
+                code: ErrorCode::NotFound,
+                ..
+            } => Err(KvsError::NonExistentKey(key)),
+            response => Err(unexpected(response, "a remove")),
+        }
+    }
+
+    /// Begin a transaction watching `watch`, see [`Request::Begin`]. Sets and
+    /// removes are queued from now on until [`KvsClient::commit`].
+    pub fn begin(&mut self, watch: Vec<String>) -> Result<()> {
+        match self.client.request(&Request::Begin { watch })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response, "a begin")),
+        }
+    }
+
+    /// Apply the writes queued since [`KvsClient::begin`], failing with
+    /// [`KvsError::Conflict`] without applying any if a watched key changed.
+    pub fn commit(&mut self) -> Result<()> {
+        match self.client.request(&Request::Commit)? {
+            Response::Ok => Ok(()),
+            Response::Err {
+                code: ErrorCode::Conflict,
+                ..
+            } => Err(KvsError::Conflict),
+            response => Err(unexpected(response, "a commit")),
+        }
+    }
+
+    /// Make the server force every write so far to disk, see [`Request::Flush`].
+    pub fn flush(&mut self) -> Result<()> {
+        match self.client.request(&Request::Flush)? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response, "a flush")),
+        }
+    }
+
+    /// The untyped client underneath, for the requests without a method here.
+    pub fn into_inner(self) -> Client {
+        self.client
+    }
+
+    /// Close the connection, ending the session on the server.
+    pub fn shutdown(self) -> Result<()> {
+        self.client.shutdown()
+    }
+}
+
+/// The error for `response` to `what`, the one the server reported if it is one.
+fn unexpected(response: Response, what: &str) -> KvsError {
+    match response {
+        Response::Err { code, message } => KvsError::ResponseError { code, message },
+        response => {
+            KvsError::IncompatibleProtocol(format!("unexpected response {response:?} to {what}"))
+        }
+    }
+}
+
+/// A request queued on a [`BufferedClient`], paired with where its response goes.
+type Queued = (Request, SyncSender<Result<Response>>);
+
+/// A [`Client`] fed from a bounded in-memory queue.
+///
+/// A background thread sends the queued requests over one connection,
+/// flushing whenever the queue runs empty, while a second one reads the
+/// responses and hands them to the [`PendingResponse`] of each request. Once
+/// the queue is full, [`BufferedClient::send`] blocks until the connection
+/// catches up.
+pub struct BufferedClient {
+    queue: Option<SyncSender<Queued>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl BufferedClient {
+    /// Connect to the server at `addr`, queueing at most `capacity` requests.
+    pub fn connect(addr: impl ToSocketAddrs, capacity: usize) -> Result<BufferedClient> {
+        let mut client = Client::connect(addr)?;
+        client.handshake()?;
+        let Client {
+            stream,
+            mut writer,
+            mut responses,
+            ..
+        } = client;
+        let (queue, requests) = mpsc::sync_channel::<Queued>(capacity);
+        // Replies in the order the requests went out, which is the order the
+        // responses come back in.
+        let (in_flight, replies) = mpsc::channel::<SyncSender<Result<Response>>>();
+
+        let sender = thread::spawn(move || {
+            let mut next = requests.recv().ok();
+            while let Some((request, reply)) = next {
+                if serde_json::to_writer(&mut writer, &request).is_err() {
+                    break;
+                }
+                if in_flight.send(reply).is_err() {
+                    break;
+                }
+                next = match requests.try_recv() {
+                    Ok(queued) => Some(queued),
+                    Err(_) => writer.flush().ok().and_then(|_| requests.recv().ok()),
+                };
+            }
+            // Let the server finish what was sent and close the connection.
+            let _ = writer.flush();
+            let _ = stream.shutdown(Shutdown::Write);
+        });
+        let receiver = thread::spawn(move || {
+            for reply in replies {
+                let response = next_response(&mut responses);
+                let failed = response.is_err();
+                let _ = reply.send(response);
+                if failed {
+                    break;
+                }
+            }
+        });
+
+        Ok(BufferedClient {
+            queue: Some(queue),
+            threads: vec![sender, receiver],
+        })
+    }
+
+    /// Queue `request`, blocking while the queue is full.
+    ///
+    /// If the connection failed, the request is dropped and its response is
+    /// an error.
+    pub fn send(&self, request: Request) -> PendingResponse {
+        let (reply, response) = mpsc::sync_channel(1);
+        if let Some(queue) = &self.queue {
+            let _ = queue.send((request, reply));
+        }
+        PendingResponse(response)
+    }
+}
+
+impl Drop for BufferedClient {
+    /// Wait for every queued request to be answered, then close the connection.
+    fn drop(&mut self) {
+        self.queue.take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The response to a request queued on a [`BufferedClient`].
+pub struct PendingResponse(Receiver<Result<Response>>);
+
+impl PendingResponse {
+    /// Block until the response arrives.
+    pub fn wait(self) -> Result<Response> {
+        match self.0.recv() {
+            Ok(response) => response,
+            Err(_) => Err(io::Error::from(io::ErrorKind::BrokenPipe).into()),
+        }
+    }
+}
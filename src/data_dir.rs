@@ -0,0 +1,125 @@
+//! Data directory
+//!
+//! Which engine a data directory belongs to, recorded in an
+//! [`ENGINE_MARKER`] file so a server never opens one engine's data with
+//! another.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+use crate::error::{KvsError, Result};
+use crate::kv_store;
+
+/// The file naming the engine a data directory belongs to.
+pub const ENGINE_MARKER: &str = ".kvs-engine";
+
+/// The engine the data directory at `path` belongs to, `None` if it holds no data.
+///
+/// The [`ENGINE_MARKER`] is trusted when present. Directories from before the
+/// marker existed are told apart by their file names.
+pub fn engine_of(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path.join(ENGINE_MARKER)) {
+        Ok(engine) => Ok(Some(engine.trim().to_owned())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => detect_engine(path),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Make sure the data directory at `path` belongs to `engine`, marking it as
+/// such if it isn't marked yet.
+pub fn check_engine(path: &Path, engine: &str) -> Result<()> {
+    match engine_of(path)? {
+        Some(previous) if previous != engine => Err(KvsError::WrongEngine {
+            previous,
+            current: engine.to_owned(),
+        }),
+        Some(_) if path.join(ENGINE_MARKER).exists() => Ok(()),
+        _ => write_engine_marker(path, engine),
+    }
+}
+
+/// Record that the data directory at `path` belongs to `engine`.
+///
+/// The marker is written to a temporary file and renamed over the old one,
+/// so a crash leaves either the old engine or the new one, never a torn name.
+pub fn write_engine_marker(path: &Path, engine: &str) -> Result<()> {
+    let tmp = path.join(format!("{ENGINE_MARKER}.tmp"));
+    let mut file = File::create(&tmp)?;
+    io::Write::write_all(&mut file, format!("{engine}\n").as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path.join(ENGINE_MARKER))?;
+    Ok(File::open(path)?.sync_all()?)
+}
+
+/// Delete the files `engine` keeps in the data directory at `path`, leaving
+/// anything else, the [`ENGINE_MARKER`] included.
+pub fn remove_engine_files(path: &Path, engine: &str) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_str().unwrap_or("");
+        let owned = match engine {
+            // Logs and hints, ones an unfinished compaction left behind, and
+            // lock files.
+            "kvs" => {
+                let name = name.strip_suffix(".compacting").unwrap_or(name);
+                name.strip_suffix(".log")
+                    .or_else(|| name.strip_suffix(".hint"))
+                    .and_then(kv_store::file_number)
+                    .is_some()
+                    || matches!(name, "kvs.lock" | "kvs.writer.lock")
+            }
+            "sled" => {
+                matches!(name, "db" | "conf" | "blobs")
+                    || name.starts_with("snap.")
+                    || name.starts_with("_sled")
+            }
+            _ => false,
+        };
+        if !owned {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(File::open(path)?.sync_all()?)
+}
+
+/// Guess the engine of a data directory without an [`ENGINE_MARKER`].
+fn detect_engine(path: &Path) -> Result<Option<String>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let mut has_kvs = false;
+    let mut has_sled = false;
+
+    // 遍历目录中的所有条目（文件和目录）
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let name_str = file_name.to_str().unwrap_or("");
+
+        // 检查 kvs 引擎的 .log 文件
+        if name_str.ends_with(".log") {
+            has_kvs = true;
+        }
+
+        // 检查 sled 引擎的特定文件/目录
+        // sled 会在目录中创建 "db" 目录或 "_sled" 开头的文件
+        if name_str == "db" || name_str.starts_with("_sled") {
+            has_sled = true;
+        }
+    }
+
+    match (has_kvs, has_sled) {
+        (true, false) => Ok(Some("kvs".to_string())),
+        (false, true) => Ok(Some("sled".to_string())),
+        (false, false) => Ok(None), // 新目录，没有之前的引擎
+        (true, true) => Err(KvsError::AmbiguousEngine), // 不应该发生
+    }
+}
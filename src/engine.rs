@@ -2,128 +2,1472 @@
 //!
 //!
 
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+use sled::Transactional;
+use sled::transaction::{self, TransactionError};
 
 use crate::error::{KvsError, Result};
+use crate::kv_store::{
+    Change, ChangeKind, KvStoreConfig, KvStoreReader, LogCheck, SizeLimits, Watchers, now_millis,
+};
+use crate::log_helper::FileIndex;
+use crate::storage::{DiskStorage, MemoryStorage};
 
 /// A trait for key-value store engine.
 pub trait KvsEngine: Clone + Send + 'static {
     /// Set a key-value pair.
     fn set(&self, key: String, value: String) -> Result<()>;
 
+    /// Set a key-value pair that expires after `ttl`.
+    ///
+    /// Expiry follows the wall clock: the key is gone once the system time
+    /// passes the time it was set plus `ttl`, across restarts too, and a clock
+    /// moved by hand moves it along. An expired key reads as missing, and a
+    /// plain `set` of the key clears its expiry.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()>;
+
     /// Get a value by key.
     fn get(&self, key: String) -> Result<Option<String>>;
 
+    /// Set a key to a value of any bytes, not just UTF-8.
+    ///
+    /// The string methods, `get` included, fail with an `InvalidData`
+    /// [`KvsError::IOError`] on a value that isn't UTF-8.
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()>;
+
+    /// Get a value by key as bytes, whether it was set as a string or as bytes.
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>>;
+
     /// Remove a key-value pair.
+    ///
+    /// Unlike `get`, which returns `None` for a missing key, removing a key
+    /// that is not set fails with [`KvsError::NonExistentKey`] holding the key.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Atomically remove `key` and return the value it held, `None` if it
+    /// was not set.
+    ///
+    /// Of several callers taking the same key at once, exactly one gets it.
+    fn take(&self, key: String) -> Result<Option<String>>;
+
+    /// Atomically set `key` to `value` and return the value it held, `None`
+    /// if it was not set. Like `set`, it clears any expiry of the key.
+    ///
+    /// Of several callers setting the same key at once, each gets the value
+    /// the one before it set.
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>>;
+
+    /// Check whether `key` is set, without reading its value.
+    fn contains_key(&self, key: String) -> Result<bool>;
+
+    /// The number of keys set, not counting expired ones.
+    ///
+    /// For [`KvStore`] this counts live keys in the index, as a compaction
+    /// would leave them, not the records in the logs.
+    fn len(&self) -> Result<usize>;
+
+    /// Whether no key is set.
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Get all key-value pairs whose key starts with `prefix`, ordered by key.
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>>;
+
+    /// Remove every key starting with `prefix` and return how many were set.
+    ///
+    /// The keys go at once: no reader sees some of them removed and others
+    /// still set.
+    fn remove_prefix(&self, prefix: String) -> Result<u64>;
+
+    /// Atomically add `delta` to the float stored at `key`, treating a missing
+    /// key as `0`, and return the new value.
+    ///
+    /// The value is stored in its shortest decimal form, never in scientific
+    /// notation, and keeps the expiry of the key. Returns
+    /// [`KvsError::NotAFloat`] if the stored value does not parse as a finite
+    /// float, and [`KvsError::NonFiniteFloat`] if `delta` or the result is
+    /// infinite or NaN.
+    fn increment_float(&self, key: String, delta: f64) -> Result<f64>;
+
+    /// Atomically add `delta` to the integer stored at `key`, treating a
+    /// missing key as `0`, and return the new value.
+    ///
+    /// Returns [`KvsError::NotAnInteger`] if the stored value does not parse
+    /// as an `i64`, and [`KvsError::IntegerOverflow`] if the sum does not fit.
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        let value = self.modify(key, ModifyOp::IncrBy(delta))?;
+        // `IncrBy` always applies, storing the sum it checked.
+        Ok(value
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_default())
+    }
+
+    /// Atomically apply `op` to the value at `key`, keeping its expiry.
+    ///
+    /// Returns the value stored afterwards, or `None` if the op did not apply.
+    fn modify(&self, key: String, op: ModifyOp) -> Result<Option<String>>;
+
+    /// Apply every op of `batch` at once, but only if each key of `watched`
+    /// still holds the value it was seen with, `None` for a missing key.
+    ///
+    /// Fails with [`KvsError::Conflict`] if one doesn't, and with
+    /// [`KvsError::NonExistentKey`] for a remove of a missing key, writing
+    /// nothing either way. Values are compared, so a key set back to the
+    /// value it was seen with doesn't conflict.
+    fn commit(&self, watched: Vec<(String, Option<String>)>, batch: Vec<BatchOp>) -> Result<()>;
+
+    /// Write every live key to `writer` as one JSON [`ExportEntry`] per line,
+    /// ordered by key, from a consistent snapshot taken under one lock.
+    fn export(&self, writer: impl Write) -> Result<()>;
+
+    /// Set every key of a dump written by [`KvsEngine::export`], keeping
+    /// their expiry. Keys that expired since are skipped.
+    ///
+    /// A deadline passing part way keeps the keys set so far.
+    fn import(&self, reader: impl Read) -> Result<()> {
+        for line in BufReader::new(reader).lines() {
+            check_deadline(self.deadline())?;
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let entry: ExportEntry = serde_json::from_str(&line)?;
+            let now = now_millis();
+            match entry.expires_at {
+                None => self.set(entry.key, entry.value)?,
+                Some(at) if at > now => {
+                    let ttl = Duration::from_millis(at - now);
+                    self.set_with_ttl(entry.key, entry.value, ttl)?;
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Force every write made so far to disk, so a copy of the data
+    /// directory taken afterwards, as for a backup, holds all of them.
+    ///
+    /// A no-op for engines that already sync every write, or that keep
+    /// nothing on disk.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// How many compactions ran since the engine was opened, `0` for engines
+    /// that do not compact.
+    fn compactions(&self) -> u64 {
+        0
+    }
+
+    /// How long operations waited for the engine's lock since it was opened,
+    /// all zero for engines that do not measure it.
+    fn lock_contention(&self) -> LockContention {
+        LockContention::default()
+    }
+
+    /// Receive every change to the engine from now on, in the order they were
+    /// made, until the receiver is dropped.
+    ///
+    /// A key that expired is reported once the engine drops it, which may be
+    /// well after it lapsed, as the engines drop expired keys lazily.
+    fn watch(&self) -> Receiver<Change>;
+
+    /// A handle to the same engine whose scans, exports, imports and
+    /// compactions fail with [`KvsError::DeadlineExceeded`] once `deadline`
+    /// passes, checking it as they go.
+    ///
+    /// A compaction set off by a write gives up instead, leaving the
+    /// compaction to a later write, as the write itself already landed.
+    fn with_deadline(&self, deadline: Instant) -> Self;
+
+    /// The deadline of a handle from [`KvsEngine::with_deadline`].
+    fn deadline(&self) -> Option<Instant>;
+}
+
+/// One line of a dump written by [`KvsEngine::export`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExportEntry {
+    /// The key.
+    pub key: String,
+    /// Its value.
+    pub value: String,
+    /// When the key expires, in milliseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+impl ExportEntry {
+    /// Write the entry as one line of JSON.
+    pub(crate) fn write(
+        writer: &mut impl Write,
+        key: String,
+        value: String,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        let entry = ExportEntry {
+            key,
+            value,
+            expires_at,
+        };
+        serde_json::to_writer(&mut *writer, &entry)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+/// How long operations waited to take an engine's lock.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct LockContention {
+    /// Times the lock was taken.
+    pub acquisitions: u64,
+    /// Nanoseconds spent waiting for the lock, over all acquisitions.
+    pub total_wait_nanos: u64,
+    /// The longest single wait for the lock, in nanoseconds.
+    pub max_wait_nanos: u64,
+}
+
+/// [`LockContention`] counters updated by every thread taking the lock.
+#[derive(Default)]
+struct ContentionCounters {
+    acquisitions: AtomicU64,
+    total_wait_nanos: AtomicU64,
+    max_wait_nanos: AtomicU64,
+}
+
+impl ContentionCounters {
+    fn record(&self, wait: Duration) {
+        let nanos = wait.as_nanos() as u64;
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        self.total_wait_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_wait_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LockContention {
+        LockContention {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            total_wait_nanos: self.total_wait_nanos.load(Ordering::Relaxed),
+            max_wait_nanos: self.max_wait_nanos.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// One write of a batch applied by [`KvStore::write_batch`] or
+/// [`KvsEngine::commit`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum BatchOp {
+    /// Set a key to a value.
+    Set(String, String),
+    /// Remove a key, which must exist.
+    Remove(String),
+}
+
+/// A read-modify-write operation applied atomically by [`KvsEngine::modify`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ModifyOp {
+    /// Append to the value, a missing key counts as empty.
+    Append(String),
+    /// Prepend to the value, a missing key counts as empty.
+    Prepend(String),
+    /// Add to the integer value, a missing key counts as `0`.
+    IncrBy(i64),
+    /// Replace the value only if it currently equals `expected`.
+    SetIfEqual {
+        /// The value the key must hold.
+        expected: String,
+        /// The value to store.
+        new: String,
+    },
+}
+
+impl ModifyOp {
+    /// The value to store in place of `current`, or `None` to leave it as is.
+    fn apply(self, current: Option<String>) -> Result<Option<String>> {
+        Ok(match self {
+            ModifyOp::Append(suffix) => Some(current.unwrap_or_default() + &suffix),
+            ModifyOp::Prepend(prefix) => Some(prefix + &current.unwrap_or_default()),
+            ModifyOp::IncrBy(delta) => {
+                let current = match current {
+                    Some(value) => value.parse::<i64>().map_err(|_| KvsError::NotAnInteger)?,
+                    None => 0,
+                };
+                let value = current
+                    .checked_add(delta)
+                    .ok_or(KvsError::IntegerOverflow)?;
+                Some(value.to_string())
+            }
+            ModifyOp::SetIfEqual { expected, new } => {
+                (current.as_deref() == Some(expected.as_str())).then_some(new)
+            }
+        })
+    }
 }
 /// A key-value store engine.
+///
+/// Every clone reads through its own file handles without taking a lock
+/// against other readers, while writes go one at a time through a shared writer.
 #[derive(Clone)]
 pub struct KvStore {
-    inner: Arc<Mutex<crate::kv_store::KvStore>>,
+    reader: KvStoreReader,
+    writer: Arc<Mutex<crate::kv_store::KvStore>>,
+    contention: Arc<ContentionCounters>,
+    /// See [`KvsEngine::with_deadline`], handed to the writer with its lock.
+    deadline: Option<Instant>,
 }
 
 impl KvStore {
     /// Create a new kvs store engine at the given path.
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_config(path, KvStoreConfig::default())
+    }
+
+    /// Create a new kvs store engine at the given path with the given config.
+    pub fn open_with_config(path: impl Into<PathBuf>, config: KvStoreConfig) -> Result<Self> {
         let path = path.into();
-        let db = crate::kv_store::KvStore::open(path)?;
-        Ok(Self {
-            inner: Arc::new(Mutex::new(db)),
-        })
+        let db = crate::kv_store::KvStore::open(Arc::new(DiskStorage), path, config)?;
+        Ok(Self::new(db))
+    }
+
+    /// Open the store at the given path for reading only, alongside the one
+    /// [`KvStore`] that may be writing to it, in this process or another.
+    ///
+    /// Nothing is ever written to the logs: a torn record at the end of the
+    /// current log, such as one the writer is still appending, is skipped
+    /// rather than cut off. Opening replays the logs while holding a shared
+    /// advisory lock (`flock`) on `kvs.lock` in the directory. The writer takes
+    /// it exclusively while a compaction deletes the logs it replaced, so a
+    /// replay never finds a log gone half way. Writers themselves hold
+    /// `kvs.writer.lock` exclusively for as long as they are open, so a
+    /// second writer fails with [`KvsError::AlreadyOpen`].
+    pub fn open_readonly(path: impl Into<PathBuf>) -> Result<ReadOnlyKvStore> {
+        let path = path.into();
+        let reader = KvStoreReader::open_readonly(Arc::new(DiskStorage), &path)?;
+        Ok(ReadOnlyKvStore { reader, path })
+    }
+
+    /// Read every log of the store at `path`, verifying each record, and
+    /// report how many records there are, how many are stale, and which logs
+    /// are torn or corrupt.
+    ///
+    /// Nothing is written, not even to cut off a torn record, so it can be
+    /// run on a directory before trusting it. It takes no lock either, so
+    /// run it while no writer has the store open.
+    pub fn check(path: impl Into<PathBuf>) -> Result<LogCheck> {
+        crate::kv_store::KvStore::check(&DiskStorage, &path.into())
+    }
+
+    /// Create a new kvs store engine whose logs are only kept in memory.
+    ///
+    /// It runs the same log and compaction code as a store on disk, but
+    /// nothing is persisted once the last clone is dropped.
+    pub fn open_in_memory() -> Result<Self> {
+        let storage = Arc::new(MemoryStorage::default());
+        let db = crate::kv_store::KvStore::open(storage, "", KvStoreConfig::default())?;
+        Ok(Self::new(db))
+    }
+
+    /// Create a new kvs store engine on `storage`, to test recovery from
+    /// the crashes it injects.
+    #[cfg(feature = "fault-injection")]
+    pub fn open_faulty(storage: &crate::FaultyStorage, config: KvStoreConfig) -> Result<Self> {
+        let db = crate::kv_store::KvStore::open(Arc::new(storage.clone()), "", config)?;
+        Ok(Self::new(db))
+    }
+
+    fn new(db: crate::kv_store::KvStore) -> Self {
+        let reader = db.reader();
+        let sweep_interval = db.expiry_sweep_interval();
+        let writer = Arc::new(Mutex::new(db));
+        if let Some(interval) = sweep_interval {
+            let writer = Arc::downgrade(&writer);
+            // Stops once the last clone of the store is gone. Sweeping under
+            // the writer lock keeps it from running alongside a compaction.
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(interval);
+                    let Some(writer) = writer.upgrade() else {
+                        break;
+                    };
+                    let Ok(mut db) = writer.lock() else {
+                        break;
+                    };
+                    db.deadline = None;
+                    if let Err(e) = db.sweep_expired() {
+                        error!("expiry sweep failed: {e}");
+                    }
+                }
+            });
+        }
+        Self {
+            reader,
+            writer,
+            contention: Arc::default(),
+            deadline: None,
+        }
+    }
+
+    /// Take the writer lock, timing how long it took to get it.
+    ///
+    /// Fails if a write panicked while holding the lock, since the log may
+    /// then be left half written.
+    fn lock(&self) -> Result<MutexGuard<'_, crate::kv_store::KvStore>> {
+        let start = Instant::now();
+        let writer = self.writer.lock();
+        self.contention.record(start.elapsed());
+        let mut writer = writer
+            .map_err(|_| io::Error::other("a write panicked while holding the store's lock"))?;
+        writer.deadline = self.deadline;
+        Ok(writer)
+    }
+
+    /// Take the writer lock to read its counters, which a panicked write
+    /// leaves intact.
+    fn lock_counters(&self) -> MutexGuard<'_, crate::kv_store::KvStore> {
+        let start = Instant::now();
+        let writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        self.contention.record(start.elapsed());
+        writer
+    }
+
+    /// Apply every op of `batch` in order, then sync the log to disk once.
+    ///
+    /// Nothing is written if any op is invalid, like a remove of a missing
+    /// key, and readers see none of the batch until it is synced. A crash
+    /// while syncing may still leave part of it in the log.
+    pub fn write_batch(&self, batch: Vec<BatchOp>) -> Result<()> {
+        self.lock()?.write_batch(batch)
+    }
+
+    /// Compact the logs now, regardless of the configured strategy.
+    pub fn compact(&self) -> Result<()> {
+        self.lock()?.compact()
+    }
+
+    /// Sync the current log to disk, and compact it if
+    /// [`KvStoreConfig::compact_on_close`] says so.
+    ///
+    /// Dropping the last clone of the store does the same, logging errors
+    /// instead of returning them. The store remains usable after a close.
+    pub fn close(&self) -> Result<()> {
+        self.lock()?.close()
+    }
+
+    /// How many log files had their checksums verified when the store was opened.
+    pub fn verified_files(&self) -> u64 {
+        self.lock_counters().verified_files()
+    }
+
+    /// Every key, in order, with the log file and offset its latest record
+    /// lives at. Values are not read, so this is cheap and shows the index
+    /// exactly as it was built from the logs.
+    pub fn index(&self) -> Vec<(String, FileIndex)> {
+        self.reader.index()
+    }
+
+    /// Each log file, oldest first, with the fraction of its bytes taken up by
+    /// stale records, which a compaction of that file would reclaim.
+    pub fn file_liveness(&self) -> Vec<(PathBuf, f64)> {
+        self.lock_counters().file_liveness()
+    }
+
+    /// Bytes of the logs taken up by stale records, which the next compaction reclaims.
+    pub fn stale_bytes(&self) -> u64 {
+        self.lock_counters().stale_bytes()
+    }
+}
+
+/// A store opened with [`KvStore::open_readonly`], which reads the logs
+/// without ever writing to them.
+///
+/// It sees the store as it was when opened or last reloaded. Writes made
+/// since show up after a [`ReadOnlyKvStore::reload`], which a read does by
+/// itself when a compaction deleted a log it needed.
+#[derive(Clone)]
+pub struct ReadOnlyKvStore {
+    reader: KvStoreReader,
+    path: PathBuf,
+}
+
+impl ReadOnlyKvStore {
+    /// Get a value by key.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.retry(|reader| reader.get(key.clone()))
+    }
+
+    /// Get a value by key as bytes, see [`KvsEngine::get_bytes`].
+    pub fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        self.retry(|reader| reader.get_bytes(key.clone()))
+    }
+
+    /// Check whether a key is set.
+    pub fn contains_key(&self, key: String) -> bool {
+        self.reader.contains_key(&key)
+    }
+
+    /// The number of keys set, see [`KvsEngine::len`].
+    pub fn len(&self) -> usize {
+        self.reader.len()
+    }
+
+    /// Whether no key is set.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the pairs whose key starts with `prefix`, ordered by key.
+    pub fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.retry(|reader| reader.scan(prefix.clone(), None))
+    }
+
+    /// Replay the logs again, to see what the writer wrote since.
+    pub fn reload(&self) -> Result<()> {
+        self.reader.reload(&self.path)
+    }
+
+    /// Run `read`, and once more after a reload if a log it needed was gone.
+    fn retry<T>(&self, read: impl Fn(&KvStoreReader) -> Result<T>) -> Result<T> {
+        match read(&self.reader) {
+            Err(KvsError::IOError(e)) if e.kind() == io::ErrorKind::NotFound => {
+                self.reload()?;
+                read(&self.reader)
+            }
+            result => result,
+        }
     }
 }
 
 impl KvsEngine for KvStore {
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.inner.lock().unwrap().set(key, value)
+        self.lock()?.set(key, value)
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.lock()?
+            .set_with_expiry(key, value.into_bytes(), Some(expires_at))
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
-        self.inner.lock().unwrap().get(key)
+        self.reader.get(key)
+    }
+
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.lock()?.set_with_expiry(key, value, None)
+    }
+
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        self.reader.get_bytes(key)
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        self.inner.lock().unwrap().remove(key)
+        self.lock()?.remove(key)
+    }
+
+    fn take(&self, key: String) -> Result<Option<String>> {
+        let mut writer = self.lock()?;
+        let value = writer.get(&key)?;
+        if value.is_some() {
+            writer.remove(key)?;
+        }
+        Ok(value)
+    }
+
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        let mut writer = self.lock()?;
+        let old = writer.get(&key)?;
+        writer.set(key, value)?;
+        Ok(old)
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.reader.contains_key(&key))
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.reader.len())
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.reader.scan(prefix, self.deadline)
+    }
+
+    fn remove_prefix(&self, prefix: String) -> Result<u64> {
+        self.lock()?.remove_prefix(&prefix)
+    }
+
+    fn increment_float(&self, key: String, delta: f64) -> Result<f64> {
+        let mut writer = self.lock()?;
+        let value = add_float(writer.get(&key)?, delta)?;
+        writer.update(key, value.to_string())?;
+        Ok(value)
+    }
+
+    fn export(&self, mut writer: impl Write) -> Result<()> {
+        self.lock()?.export(&mut writer)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.lock()?.flush()
+    }
+
+    fn compactions(&self) -> u64 {
+        self.lock_counters().compactions()
+    }
+
+    fn lock_contention(&self) -> LockContention {
+        self.contention.snapshot()
+    }
+
+    /// Expired keys are dropped by the next write that touches them, by the
+    /// next write after a read came across them, by the expiry sweep or by a
+    /// compaction. Their [`crate::ChangeKind::Expired`] change is sent then.
+    fn watch(&self) -> Receiver<Change> {
+        self.lock_counters().watch()
+    }
+
+    fn with_deadline(&self, deadline: Instant) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..self.clone()
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn modify(&self, key: String, op: ModifyOp) -> Result<Option<String>> {
+        let mut writer = self.lock()?;
+        let value = op.apply(writer.get(&key)?)?;
+        if let Some(value) = &value {
+            writer.update(key, value.clone())?;
+        }
+        Ok(value)
+    }
+
+    fn commit(&self, watched: Vec<(String, Option<String>)>, batch: Vec<BatchOp>) -> Result<()> {
+        let mut writer = self.lock()?;
+        for (key, seen) in watched {
+            if writer.get(&key)? != seen {
+                return Err(KvsError::Conflict);
+            }
+        }
+        writer.write_batch(batch)
     }
 }
+
+/// The sled tree of [`SledEngine`] holding the expiry of keys.
+const EXPIRY_TREE: &str = "expiry";
+
 /// A sled engine.
+///
+/// `sled::Db` is safe to share on its own, but every operation still holds
+/// one lock from its first read to its flush, so a plain `set` can't land
+/// between the read and the write of `modify`, `take` or an increment.
 #[derive(Clone)]
 pub struct SledEngine {
     inner: Arc<Mutex<sled::Db>>,
+    limits: SizeLimits,
+    flush: FlushPolicy,
+    /// Writes since the last flush, for [`FlushPolicy::EveryOps`].
+    unflushed: Arc<AtomicU64>,
+    /// Told of each change while the db is still locked, so in order.
+    watchers: Arc<Mutex<Watchers>>,
+    /// See [`KvsEngine::with_deadline`].
+    deadline: Option<Instant>,
+}
+
+/// When [`SledEngine`] flushes its writes to disk.
+///
+/// Writes not flushed yet survive the process exiting, as sled flushes on
+/// drop, but not a crash of the machine.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum FlushPolicy {
+    /// Flush after every write before returning, one fsync per write.
+    #[default]
+    Always,
+    /// Flush after every this many writes.
+    EveryOps(u64),
+    /// Flush from a background thread this often, never on a write.
+    Interval(Duration),
 }
 
 impl SledEngine {
     /// Create a new sled engine at the given path.
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_limits(path, SizeLimits::default())
+    }
+
+    /// Create a new sled engine at the given path, accepting keys and values up to `limits`.
+    pub fn open_with_limits(path: impl Into<PathBuf>, limits: SizeLimits) -> Result<Self> {
+        Self::open_with_flush_policy(path, limits, FlushPolicy::default())
+    }
+
+    /// Create a new sled engine at the given path, accepting keys and values
+    /// up to `limits` and flushing them as `flush` says.
+    pub fn open_with_flush_policy(
+        path: impl Into<PathBuf>,
+        limits: SizeLimits,
+        flush: FlushPolicy,
+    ) -> Result<Self> {
         let path = path.into();
-        let db = sled::open(path).map_err(|e| {
-            KvsError::IOError(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("sled error: {}", e),
-            ))
-        })?;
+        let db = sled::open(path)
+            .map_err(|e| KvsError::IOError(std::io::Error::other(format!("sled error: {}", e))))?;
+        let inner = Arc::new(Mutex::new(db));
+        if let FlushPolicy::Interval(interval) = flush {
+            let inner = Arc::downgrade(&inner);
+            // Stops once the last clone of the engine is gone.
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(interval);
+                    let Some(inner) = inner.upgrade() else {
+                        break;
+                    };
+                    let db = inner.lock().unwrap().clone();
+                    drop(inner);
+                    if let Err(e) = db.flush() {
+                        error!("sled flush failed: {e}");
+                    }
+                }
+            });
+        }
         Ok(Self {
-            inner: Arc::new(Mutex::new(db)),
+            inner,
+            limits,
+            flush,
+            unflushed: Arc::default(),
+            watchers: Arc::default(),
+            deadline: None,
         })
     }
 }
 
+impl SledEngine {
+    /// The tree holding when keys set with a TTL expire, as big-endian
+    /// milliseconds since the Unix epoch.
+    fn expiry(db: &sled::Db) -> Result<sled::Tree> {
+        db.open_tree(EXPIRY_TREE)
+            .map_err(|e| KvsError::IOError(e.into()))
+    }
+
+    /// Whether `key` expired, dropping it if so.
+    fn drop_if_expired(&self, db: &sled::Db, key: &str) -> Result<bool> {
+        let expiry = Self::expiry(db)?;
+        let expired = expiry
+            .get(key.as_bytes())
+            .map_err(|e| KvsError::IOError(e.into()))?
+            .and_then(|at| Some(u64::from_be_bytes(at.as_ref().try_into().ok()?)))
+            .is_some_and(|at| at <= now_millis());
+        if expired {
+            db.remove(key.as_bytes())
+                .map_err(|e| KvsError::IOError(e.into()))?;
+            expiry
+                .remove(key.as_bytes())
+                .map_err(|e| KvsError::IOError(e.into()))?;
+            self.notify(key, ChangeKind::Expired, None);
+        }
+        Ok(expired)
+    }
+
+    /// The value at `key`, `None` if it is missing or expired.
+    fn live_value(&self, db: &sled::Db, key: &str) -> Result<Option<String>> {
+        self.live_bytes(db, key)?.map(utf8).transpose()
+    }
+
+    /// The value at `key` as bytes, `None` if it is missing or expired.
+    fn live_bytes(&self, db: &sled::Db, key: &str) -> Result<Option<Vec<u8>>> {
+        if self.drop_if_expired(db, key)? {
+            return Ok(None);
+        }
+        Ok(db
+            .get(key.as_bytes())
+            .map_err(|e| KvsError::IOError(e.into()))?
+            .map(|value| value.to_vec()))
+    }
+
+    /// Tell the watchers `key` changed, set to `value` for a [`ChangeKind::Set`].
+    fn notify(&self, key: &str, kind: ChangeKind, value: Option<&[u8]>) {
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.notify(key.to_owned(), kind, value);
+    }
+
+    /// Flush `db` after a write if the flush policy says so. Called with
+    /// the lock held, so writes are counted one at a time.
+    fn flush_if_due(&self, db: &sled::Db) -> Result<()> {
+        let due = match self.flush {
+            FlushPolicy::Always => true,
+            FlushPolicy::EveryOps(ops) => {
+                let due = self.unflushed.fetch_add(1, Ordering::Relaxed) + 1 >= ops;
+                if due {
+                    self.unflushed.store(0, Ordering::Relaxed);
+                }
+                due
+            }
+            FlushPolicy::Interval(_) => false,
+        };
+        if due {
+            db.flush().map_err(|e| KvsError::IOError(e.into()))?;
+        }
+        Ok(())
+    }
+
+    /// Store `value` at `key`, expiring at `expires_at` or never if `None`.
+    fn insert(&self, key: &str, value: &[u8], expires_at: Option<u64>) -> Result<()> {
+        self.limits.check_bytes(key, value)?;
+        let db = self.inner.lock().unwrap();
+        let expiry = Self::expiry(&db)?;
+        db.insert(key.as_bytes(), value)
+            .map_err(|e| KvsError::IOError(e.into()))?;
+        match expires_at {
+            Some(at) => expiry.insert(key.as_bytes(), &at.to_be_bytes()),
+            None => expiry.remove(key.as_bytes()),
+        }
+        .map_err(|e| KvsError::IOError(e.into()))?;
+        self.notify(key, ChangeKind::Set, Some(value));
+        self.flush_if_due(&db)?;
+        Ok(())
+    }
+}
+
 impl KvsEngine for SledEngine {
     /// Set a key-value pair.
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.inner
-            .lock()
-            .unwrap()
-            .insert(key.as_bytes(), value.as_bytes())
-            .map_err(|e| KvsError::IOError(e.into()))?;
-        self.inner
-            .lock()
-            .unwrap()
-            .flush()
-            .map_err(|e| KvsError::IOError(e.into()))?;
-        Ok(())
+        self.insert(&key, value.as_bytes(), None)
+    }
+
+    /// Set a key-value pair that expires after `ttl`.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.insert(&key, value.as_bytes(), Some(expires_at))
     }
 
     /// Get a value by key.
     fn get(&self, key: String) -> Result<Option<String>> {
-        match self
-            .inner
-            .lock()
-            .unwrap()
-            .get(key.as_bytes())
-            .map_err(|e| KvsError::IOError(e.into()))?
-        {
-            Some(value) => {
-                let value = String::from_utf8(value.to_vec()).map_err(|e| {
-                    KvsError::IOError(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        format!("Invalid UTF-8: {}", e),
-                    ))
-                })?;
-                Ok(Some(value))
-            }
-            None => Ok(None),
-        }
+        self.live_value(&self.inner.lock().unwrap(), &key)
+    }
+
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.insert(&key, &value, None)
+    }
+
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        self.live_bytes(&self.inner.lock().unwrap(), &key)
     }
 
     /// Remove a key-value pair.
     fn remove(&self, key: String) -> Result<()> {
-        let result = self
-            .inner
-            .lock()
-            .unwrap()
+        let db = self.inner.lock().unwrap();
+        if self.drop_if_expired(&db, &key)? {
+            return Err(KvsError::NonExistentKey(key));
+        }
+        let result = db
             .remove(key.as_bytes())
             .map_err(|e| KvsError::IOError(e.into()))?;
         if result.is_none() {
             return Err(KvsError::NonExistentKey(key));
         }
-        self.inner
-            .lock()
-            .unwrap()
-            .flush()
+        Self::expiry(&db)?
+            .remove(key.as_bytes())
+            .map_err(|e| KvsError::IOError(e.into()))?;
+        self.notify(&key, ChangeKind::Remove, None);
+        self.flush_if_due(&db)?;
+        Ok(())
+    }
+
+    /// Remove `key` while holding the lock, returning its old value.
+    fn take(&self, key: String) -> Result<Option<String>> {
+        let db = self.inner.lock().unwrap();
+        // Read as a string first, so a value that isn't one stays.
+        let Some(value) = self.live_value(&db, &key)? else {
+            return Ok(None);
+        };
+        db.remove(key.as_bytes())
+            .map_err(|e| KvsError::IOError(e.into()))?;
+        Self::expiry(&db)?
+            .remove(key.as_bytes())
             .map_err(|e| KvsError::IOError(e.into()))?;
+        self.notify(&key, ChangeKind::Remove, None);
+        self.flush_if_due(&db)?;
+        Ok(Some(value))
+    }
+
+    /// Swap in `value` while holding the lock, returning the old one.
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        self.limits.check(&key, &value)?;
+        let db = self.inner.lock().unwrap();
+        // An expired value is dropped first, so it is never returned, and the
+        // old value is read as a string before it is replaced.
+        let old = self.live_value(&db, &key)?;
+        db.insert(key.as_bytes(), value.as_bytes())
+            .map_err(|e| KvsError::IOError(e.into()))?;
+        Self::expiry(&db)?
+            .remove(key.as_bytes())
+            .map_err(|e| KvsError::IOError(e.into()))?;
+        self.notify(&key, ChangeKind::Set, Some(value.as_bytes()));
+        self.flush_if_due(&db)?;
+        Ok(old)
+    }
+
+    /// Check whether `key` is set.
+    fn contains_key(&self, key: String) -> Result<bool> {
+        let db = self.inner.lock().unwrap();
+        if self.drop_if_expired(&db, &key)? {
+            return Ok(false);
+        }
+        db.contains_key(key.as_bytes())
+            .map_err(|e| KvsError::IOError(e.into()))
+    }
+
+    /// The number of keys set. Expired keys that haven't been dropped yet
+    /// are left out without dropping them.
+    fn len(&self) -> Result<usize> {
+        let db = self.inner.lock().unwrap();
+        let now = now_millis();
+        let mut expired = 0;
+        for at in Self::expiry(&db)?.iter().values() {
+            let at = at.map_err(|e| KvsError::IOError(e.into()))?;
+            if at
+                .as_ref()
+                .try_into()
+                .is_ok_and(|at| u64::from_be_bytes(at) <= now)
+            {
+                expired += 1;
+            }
+        }
+        Ok(db.len().saturating_sub(expired))
+    }
+
+    /// Get all key-value pairs whose key starts with `prefix`.
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let db = self.inner.lock().unwrap();
+        let mut keys = Vec::new();
+        for key in db.scan_prefix(prefix.as_bytes()).keys() {
+            keys.push(utf8(
+                key.map_err(|e| KvsError::IOError(e.into()))?.to_vec(),
+            )?);
+        }
+        let mut pairs = Vec::new();
+        for key in keys {
+            check_deadline(self.deadline)?;
+            if let Some(value) = self.live_value(&db, &key)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Remove the keys in one batch per tree while holding the lock, which
+    /// every read takes too.
+    fn remove_prefix(&self, prefix: String) -> Result<u64> {
+        let db = self.inner.lock().unwrap();
+        let expiry = Self::expiry(&db)?;
+        let now = now_millis();
+        let mut batch = sled::Batch::default();
+        let mut changes = Vec::new();
+        for key in db.scan_prefix(prefix.as_bytes()).keys() {
+            let key = key.map_err(|e| KvsError::IOError(e.into()))?;
+            let expires_at = expiry
+                .get(&key)
+                .map_err(|e| KvsError::IOError(e.into()))?
+                .and_then(|at| Some(u64::from_be_bytes(at.as_ref().try_into().ok()?)));
+            let kind = match expires_at {
+                Some(at) if at <= now => ChangeKind::Expired,
+                _ => ChangeKind::Remove,
+            };
+            changes.push((String::from_utf8_lossy(&key).into_owned(), kind));
+            batch.remove(key);
+        }
+        let mut expiry_batch = sled::Batch::default();
+        for key in expiry.scan_prefix(prefix.as_bytes()).keys() {
+            expiry_batch.remove(key.map_err(|e| KvsError::IOError(e.into()))?);
+        }
+        db.apply_batch(batch)
+            .map_err(|e| KvsError::IOError(e.into()))?;
+        expiry
+            .apply_batch(expiry_batch)
+            .map_err(|e| KvsError::IOError(e.into()))?;
+        let mut removed = 0;
+        for (key, kind) in changes {
+            if kind == ChangeKind::Remove {
+                removed += 1;
+            }
+            self.notify(&key, kind, None);
+        }
+        self.flush_if_due(&db)?;
+        Ok(removed)
+    }
+
+    /// Write every live key under the lock, so no write lands part way.
+    fn export(&self, mut writer: impl Write) -> Result<()> {
+        let db = self.inner.lock().unwrap();
+        let expiry = Self::expiry(&db)?;
+        let now = now_millis();
+        for pair in db.iter() {
+            check_deadline(self.deadline)?;
+            let (key, value) = pair.map_err(|e| KvsError::IOError(e.into()))?;
+            let expires_at = expiry
+                .get(&key)
+                .map_err(|e| KvsError::IOError(e.into()))?
+                .and_then(|at| Some(u64::from_be_bytes(at.as_ref().try_into().ok()?)));
+            if expires_at.is_some_and(|at| at <= now) {
+                continue;
+            }
+            ExportEntry::write(
+                &mut writer,
+                utf8(key.to_vec())?,
+                utf8(value.to_vec())?,
+                expires_at,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Add `delta` to the float at `key` while holding the lock.
+    fn increment_float(&self, key: String, delta: f64) -> Result<f64> {
+        let db = self.inner.lock().unwrap();
+        let value = add_float(self.live_value(&db, &key)?, delta)?;
+        self.limits.check(&key, &value.to_string())?;
+        db.insert(key.as_bytes(), value.to_string().as_bytes())
+            .map_err(|e| KvsError::IOError(e.into()))?;
+        self.notify(&key, ChangeKind::Set, Some(value.to_string().as_bytes()));
+        self.flush_if_due(&db)?;
+        Ok(value)
+    }
+
+    /// Apply `op` to the value at `key` while holding the lock.
+    fn modify(&self, key: String, op: ModifyOp) -> Result<Option<String>> {
+        let db = self.inner.lock().unwrap();
+        let value = op.apply(self.live_value(&db, &key)?)?;
+        if let Some(value) = &value {
+            self.limits.check(&key, value)?;
+            db.insert(key.as_bytes(), value.as_bytes())
+                .map_err(|e| KvsError::IOError(e.into()))?;
+            self.notify(&key, ChangeKind::Set, Some(value.as_bytes()));
+            self.flush_if_due(&db)?;
+        }
+        Ok(value)
+    }
+
+    /// Check the watched keys and apply the batch in one sled transaction
+    /// over both trees, while holding the lock.
+    fn commit(&self, watched: Vec<(String, Option<String>)>, batch: Vec<BatchOp>) -> Result<()> {
+        for op in &batch {
+            if let BatchOp::Set(key, value) = op {
+                self.limits.check(key, value)?;
+            }
+        }
+        let db = self.inner.lock().unwrap();
+        // Expired keys are dropped first, so the transaction sees them missing.
+        for (key, _) in &watched {
+            self.drop_if_expired(&db, key)?;
+        }
+        for op in &batch {
+            if let BatchOp::Remove(key) = op {
+                self.drop_if_expired(&db, key)?;
+            }
+        }
+        let expiry = Self::expiry(&db)?;
+        let result = (&**db, &expiry).transaction(|(db, expiry)| {
+            for (key, seen) in &watched {
+                if db.get(key.as_bytes())?.as_deref() != seen.as_ref().map(String::as_bytes) {
+                    return transaction::abort(KvsError::Conflict);
+                }
+            }
+            for op in &batch {
+                match op {
+                    BatchOp::Set(key, value) => {
+                        db.insert(key.as_bytes(), value.as_bytes())?;
+                        expiry.remove(key.as_bytes())?;
+                    }
+                    BatchOp::Remove(key) => {
+                        if db.remove(key.as_bytes())?.is_none() {
+                            return transaction::abort(KvsError::NonExistentKey(key.clone()));
+                        }
+                        expiry.remove(key.as_bytes())?;
+                    }
+                }
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => {
+                for op in &batch {
+                    match op {
+                        BatchOp::Set(key, value) => {
+                            self.notify(key, ChangeKind::Set, Some(value.as_bytes()))
+                        }
+                        BatchOp::Remove(key) => self.notify(key, ChangeKind::Remove, None),
+                    }
+                }
+                self.flush_if_due(&db)
+            }
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(KvsError::IOError(e.into())),
+        }
+    }
+
+    /// A no-op under [`FlushPolicy::Always`], which already flushed every write.
+    fn flush(&self) -> Result<()> {
+        if self.flush == FlushPolicy::Always {
+            return Ok(());
+        }
+        let db = self.inner.lock().unwrap();
+        db.flush().map_err(|e| KvsError::IOError(e.into()))?;
+        self.unflushed.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn with_deadline(&self, deadline: Instant) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..self.clone()
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Expired keys are dropped by the next read or write that touches them
+    /// or a prefix removal covering them. Their
+    /// [`crate::ChangeKind::Expired`] change is sent then.
+    fn watch(&self) -> Receiver<Change> {
+        self.watchers.lock().unwrap().watch()
+    }
+}
+
+/// A value of [`MemoryEngine`] with when it expires, if ever.
+type MemoryEntry = (Vec<u8>, Option<u64>);
+
+/// An engine keeping its pairs in a hash map only, without logs or files.
+///
+/// Everything is gone once the last clone is dropped. Reads share the lock,
+/// writes take it alone, so each operation is atomic like on the other engines.
+#[derive(Clone, Default)]
+pub struct MemoryEngine {
+    inner: Arc<RwLock<HashMap<String, MemoryEntry>>>,
+    limits: SizeLimits,
+    /// Told of each change while the map is still locked, so in order.
+    watchers: Arc<Mutex<Watchers>>,
+    /// See [`KvsEngine::with_deadline`].
+    deadline: Option<Instant>,
+}
+
+impl MemoryEngine {
+    /// Create an empty in-memory engine.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an empty in-memory engine, accepting keys and values up to `limits`.
+    pub fn with_limits(limits: SizeLimits) -> Self {
+        Self {
+            inner: Arc::default(),
+            limits,
+            watchers: Arc::default(),
+            deadline: None,
+        }
+    }
+
+    /// The value of `entry` unless it expired.
+    fn live(entry: Option<&MemoryEntry>) -> Option<&Vec<u8>> {
+        entry
+            .filter(|(_, expires_at)| expires_at.is_none_or(|at| at > now_millis()))
+            .map(|(value, _)| value)
+    }
+
+    /// The value of `entry` as a string unless it expired.
+    fn live_string(entry: Option<&MemoryEntry>) -> Result<Option<String>> {
+        Self::live(entry).cloned().map(utf8).transpose()
+    }
+
+    fn insert(&self, key: String, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        self.limits.check_bytes(&key, &value)?;
+        let mut map = self.inner.write().unwrap();
+        self.notify_set(&key, &value);
+        map.insert(key, (value, expires_at));
         Ok(())
     }
+
+    /// Tell the watchers `key` was set to `value`.
+    fn notify_set(&self, key: &str, value: &[u8]) {
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.notify(key.to_owned(), ChangeKind::Set, Some(value));
+    }
+
+    /// Drop `key` from `map` if it expired, telling the watchers.
+    fn drop_if_expired(&self, map: &mut HashMap<String, MemoryEntry>, key: &str) {
+        if map
+            .get(key)
+            .is_some_and(|entry| Self::live(Some(entry)).is_none())
+        {
+            map.remove(key);
+            let mut watchers = self.watchers.lock().unwrap();
+            watchers.notify(key.to_owned(), ChangeKind::Expired, None);
+        }
+    }
+
+    /// Tell the watchers `key` was removed.
+    fn notify_remove(&self, key: &str) {
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.notify(key.to_owned(), ChangeKind::Remove, None);
+    }
+}
+
+impl KvsEngine for MemoryEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.insert(key, value.into_bytes(), None)
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.insert(key, value.into_bytes(), Some(expires_at))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Self::live_string(self.inner.read().unwrap().get(&key))
+    }
+
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.insert(key, value, None)
+    }
+
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        Ok(Self::live(self.inner.read().unwrap().get(&key)).cloned())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        match self.take(key.clone())? {
+            Some(_) => Ok(()),
+            None => Err(KvsError::NonExistentKey(key)),
+        }
+    }
+
+    fn take(&self, key: String) -> Result<Option<String>> {
+        let mut map = self.inner.write().unwrap();
+        self.drop_if_expired(&mut map, &key);
+        // Read as a string first, so a value that isn't one stays.
+        let value = Self::live_string(map.get(&key))?;
+        if map.remove(&key).is_some() {
+            self.notify_remove(&key);
+        }
+        Ok(value)
+    }
+
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        self.limits.check(&key, &value)?;
+        let mut map = self.inner.write().unwrap();
+        self.drop_if_expired(&mut map, &key);
+        let old = Self::live_string(map.get(&key))?;
+        self.notify_set(&key, value.as_bytes());
+        map.insert(key, (value.into_bytes(), None));
+        Ok(old)
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(Self::live(self.inner.read().unwrap().get(&key)).is_some())
+    }
+
+    fn len(&self) -> Result<usize> {
+        let map = self.inner.read().unwrap();
+        Ok(map
+            .values()
+            .filter(|entry| Self::live(Some(entry)).is_some())
+            .count())
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let map = self.inner.read().unwrap();
+        let mut pairs = Vec::new();
+        for (key, entry) in map.iter().filter(|(key, _)| key.starts_with(&prefix)) {
+            check_deadline(self.deadline)?;
+            if let Some(value) = Self::live_string(Some(entry))? {
+                pairs.push((key.clone(), value));
+            }
+        }
+        pairs.sort();
+        Ok(pairs)
+    }
+
+    fn remove_prefix(&self, prefix: String) -> Result<u64> {
+        let mut map = self.inner.write().unwrap();
+        let mut changes = Vec::new();
+        map.retain(|key, entry| {
+            let matches = key.starts_with(&prefix);
+            if matches {
+                let kind = match Self::live(Some(entry)) {
+                    Some(_) => ChangeKind::Remove,
+                    None => ChangeKind::Expired,
+                };
+                changes.push((key.clone(), kind));
+            }
+            !matches
+        });
+        changes.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut watchers = self.watchers.lock().unwrap();
+        let mut removed = 0;
+        for (key, kind) in changes {
+            if kind == ChangeKind::Remove {
+                removed += 1;
+            }
+            watchers.notify(key, kind, None);
+        }
+        Ok(removed)
+    }
+
+    fn export(&self, mut writer: impl Write) -> Result<()> {
+        let map = self.inner.read().unwrap();
+        let now = now_millis();
+        let mut entries: Vec<_> = map
+            .iter()
+            .filter(|(_, (_, expires_at))| expires_at.is_none_or(|at| at > now))
+            .collect();
+        entries.sort_by_key(|(key, _)| *key);
+        for (key, (value, expires_at)) in entries {
+            check_deadline(self.deadline)?;
+            ExportEntry::write(&mut writer, key.clone(), utf8(value.clone())?, *expires_at)?;
+        }
+        Ok(())
+    }
+
+    fn increment_float(&self, key: String, delta: f64) -> Result<f64> {
+        let mut map = self.inner.write().unwrap();
+        self.drop_if_expired(&mut map, &key);
+        let entry = map.get(&key);
+        let value = add_float(Self::live_string(entry)?, delta)?;
+        self.limits.check(&key, &value.to_string())?;
+        let expires_at = entry.and_then(|(_, expires_at)| *expires_at);
+        self.notify_set(&key, value.to_string().as_bytes());
+        map.insert(key, (value.to_string().into_bytes(), expires_at));
+        Ok(value)
+    }
+
+    fn modify(&self, key: String, op: ModifyOp) -> Result<Option<String>> {
+        let mut map = self.inner.write().unwrap();
+        self.drop_if_expired(&mut map, &key);
+        let entry = map.get(&key);
+        let value = op.apply(Self::live_string(entry)?)?;
+        if let Some(value) = &value {
+            self.limits.check(&key, value)?;
+            let expires_at = entry.and_then(|(_, expires_at)| *expires_at);
+            self.notify_set(&key, value.as_bytes());
+            map.insert(key, (value.clone().into_bytes(), expires_at));
+        }
+        Ok(value)
+    }
+
+    fn commit(&self, watched: Vec<(String, Option<String>)>, batch: Vec<BatchOp>) -> Result<()> {
+        let mut map = self.inner.write().unwrap();
+        // Expired keys are dropped first, as on the other engines.
+        for (key, _) in &watched {
+            self.drop_if_expired(&mut map, key);
+        }
+        for op in &batch {
+            if let BatchOp::Remove(key) = op {
+                self.drop_if_expired(&mut map, key);
+            }
+        }
+        for (key, seen) in watched {
+            if Self::live_string(map.get(&key))? != seen {
+                return Err(KvsError::Conflict);
+            }
+        }
+        // Checked up front, so nothing is applied if an op fails.
+        let mut live = HashMap::new();
+        for op in &batch {
+            match op {
+                BatchOp::Set(key, value) => {
+                    self.limits.check(key, value)?;
+                    live.insert(key.as_str(), true);
+                }
+                BatchOp::Remove(key) => {
+                    let exists = live
+                        .get(key.as_str())
+                        .copied()
+                        .unwrap_or_else(|| Self::live(map.get(key)).is_some());
+                    if !exists {
+                        return Err(KvsError::NonExistentKey(key.clone()));
+                    }
+                    live.insert(key.as_str(), false);
+                }
+            }
+        }
+        for op in batch {
+            match op {
+                BatchOp::Set(key, value) => {
+                    self.notify_set(&key, value.as_bytes());
+                    map.insert(key, (value.into_bytes(), None));
+                }
+                BatchOp::Remove(key) => {
+                    self.notify_remove(&key);
+                    map.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads only hide expired keys. They are dropped by the next write that
+    /// touches them or a prefix removal covering them, and their
+    /// [`crate::ChangeKind::Expired`] change is sent then.
+    fn watch(&self) -> Receiver<Change> {
+        self.watchers.lock().unwrap().watch()
+    }
+
+    fn with_deadline(&self, deadline: Instant) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..self.clone()
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+}
+
+/// Add `delta` to the `current` stored float, which defaults to `0`.
+fn add_float(current: Option<String>, delta: f64) -> Result<f64> {
+    let current = match current {
+        Some(value) => match value.parse::<f64>() {
+            Ok(value) if value.is_finite() => value,
+            _ => return Err(KvsError::NotAFloat),
+        },
+        None => 0.0,
+    };
+    let value = current + delta;
+    if !delta.is_finite() || !value.is_finite() {
+        return Err(KvsError::NonFiniteFloat);
+    }
+    Ok(value)
+}
+
+/// Fail with [`KvsError::DeadlineExceeded`] once `deadline` has passed.
+pub(crate) fn check_deadline(deadline: Option<Instant>) -> Result<()> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(KvsError::DeadlineExceeded),
+        _ => Ok(()),
+    }
+}
+
+/// `bytes` as a string, or an `InvalidData` error for a value stored as
+/// bytes that aren't UTF-8.
+pub(crate) fn utf8(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|e| {
+        KvsError::IOError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid UTF-8: {}", e),
+        ))
+    })
 }
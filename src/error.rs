@@ -4,8 +4,11 @@
 //! with [`thiserror`]
 //!
 use std::io;
+use std::path::PathBuf;
 use thiserror::Error;
 
+use crate::protocol::ErrorCode;
+
 /// Result use the [`KvsError`] as error.
 pub type Result<T> = std::result::Result<T, KvsError>;
 
@@ -27,7 +30,117 @@ pub enum KvsError {
     #[error("error when deserialize from files")]
     DeserializeError,
 
-    /// Response error
-    #[error("response error: {0}")]
-    ResponseError(String),
+    /// A log record failed its checksum
+    #[error("checksum mismatch in {file:?} at offset {offset}")]
+    ChecksumMismatch {
+        /// The log file holding the record
+        file: PathBuf,
+        /// The offset of the record in the file
+        offset: u64,
+    },
+
+    /// The stored value is not a finite float
+    #[error("value is not a float")]
+    NotAFloat,
+
+    /// A float operation would store infinity or NaN
+    #[error("float value must be finite")]
+    NonFiniteFloat,
+
+    /// The stored value is not an integer
+    #[error("value is not an integer")]
+    NotAnInteger,
+
+    /// An integer operation would overflow
+    #[error("integer overflow")]
+    IntegerOverflow,
+
+    /// A key is longer than the configured limit
+    #[error("key of {actual} bytes exceeds the limit of {limit} bytes")]
+    KeyTooLarge {
+        /// The largest key allowed, in bytes
+        limit: usize,
+        /// The size of the rejected key
+        actual: usize,
+    },
+
+    /// A value is longer than the configured limit
+    #[error("value of {actual} bytes exceeds the limit of {limit} bytes")]
+    ValueTooLarge {
+        /// The largest value allowed, in bytes
+        limit: usize,
+        /// The size of the rejected value
+        actual: usize,
+    },
+
+    /// A record the log format of the store can't hold, like a value with a
+    /// space in a text log
+    #[error("the log format of the store can't hold {0}")]
+    UnsupportedByFormat(&'static str),
+
+    /// A thread pool has no room left in its job queue
+    #[error("thread pool queue is full")]
+    QueueFull,
+
+    /// A request could not be answered before its deadline
+    #[error("deadline exceeded")]
+    DeadlineExceeded,
+
+    /// A mutation was sent to a server in read-only mode
+    #[error("server is in read-only mode")]
+    ReadOnly,
+
+    /// A watched key changed before the transaction watching it committed
+    #[error("a watched key changed, the transaction was not applied")]
+    Conflict,
+
+    /// A request was sent without the server's auth token, or with a wrong one
+    #[error("not authenticated")]
+    NotAuthenticated,
+
+    /// A data directory belongs to another engine than the one opening it
+    #[error("Wrong engine! Previous: {previous}, current: {current}")]
+    WrongEngine {
+        /// The engine the directory belongs to
+        previous: String,
+        /// The engine that tried to open it
+        current: String,
+    },
+
+    /// Another store is open for writing on the directory, in this process or another
+    #[error("{0:?} is already open for writing")]
+    AlreadyOpen(PathBuf),
+
+    /// A store was to be opened in a directory that holds none
+    #[error("{0:?} holds no kvs store")]
+    NoStore(PathBuf),
+
+    /// A store was to be started in a directory that holds one already
+    #[error("{0:?} already holds a kvs store")]
+    StoreExists(PathBuf),
+
+    /// A data directory without a marker holds files of both engines
+    #[error("Both kvs and sled data detected")]
+    AmbiguousEngine,
+
+    /// The client and server speak different protocol versions
+    #[error("incompatible protocol: {0}")]
+    IncompatibleProtocol(String),
+
+    /// TLS could not be set up, like on an unreadable certificate
+    #[error("tls error: {0}")]
+    Tls(String),
+
+    /// The server took too long to accept a connection or answer a request
+    #[error("timed out waiting for the server")]
+    Timeout,
+
+    /// The server failed the request
+    #[error("response error: {message}")]
+    ResponseError {
+        /// What kind of failure the server reported
+        code: ErrorCode,
+        /// The server's description of the failure
+        message: String,
+    },
 }
@@ -0,0 +1,173 @@
+//! gRPC access to a [`KvsEngine`], alongside the JSON protocol of `kvs-server`.
+//!
+//! The service is defined in `proto/kvs.proto`; [`pb`] holds the messages and
+//! the generated client and server.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tonic::{Request, Response, Status};
+
+use crate::KvsError;
+use crate::async_kvs::AsyncKvStore;
+use crate::engine::KvsEngine;
+use crate::protocol::{ErrorCode, constant_time_eq};
+
+/// Code generated from `proto/kvs.proto`.
+#[allow(missing_docs)]
+pub mod pb {
+    tonic::include_proto!("kvs");
+}
+
+use pb::kvs_server::{Kvs, KvsServer};
+use pb::{
+    GetReply, GetRequest, Pair, RemoveReply, RemoveRequest, ScanReply, ScanRequest, SetReply,
+    SetRequest,
+};
+
+/// Serves the gRPC `Kvs` service from an engine.
+///
+/// Engine calls block, so each one runs on tokio's blocking pool through an
+/// [`AsyncKvStore`].
+pub struct KvsService<E: KvsEngine> {
+    store: Mutex<AsyncKvStore<E>>,
+    /// See [`KvsService::auth_token`].
+    auth_token: Option<String>,
+    /// See [`KvsService::read_only`].
+    read_only: Arc<AtomicBool>,
+}
+
+impl<E: KvsEngine> KvsService<E> {
+    /// Create a service backed by `engine`.
+    pub fn new(engine: E) -> Self {
+        Self {
+            store: Mutex::new(AsyncKvStore::new(engine)),
+            auth_token: None,
+            read_only: Arc::default(),
+        }
+    }
+
+    /// Serve only calls presenting `token` as `authorization: Bearer <token>`
+    /// metadata, failing others with `UNAUTHENTICATED`.
+    pub fn auth_token(mut self, token: Option<String>) -> Self {
+        self.auth_token = token;
+        self
+    }
+
+    /// Fail sets and removes with `FAILED_PRECONDITION` while `read_only` is set.
+    pub fn read_only(mut self, read_only: Arc<AtomicBool>) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Fail if the service is in read-only mode.
+    fn check_writable(&self) -> Result<(), Status> {
+        if self.read_only.load(Ordering::Relaxed) {
+            return Err(status(&KvsError::ReadOnly));
+        }
+        Ok(())
+    }
+
+    /// The engine, to call off the async runtime.
+    fn store(&self) -> AsyncKvStore<E> {
+        self.store.lock().unwrap().clone()
+    }
+}
+
+/// The gRPC status of an engine error.
+fn status(e: &KvsError) -> Status {
+    let message = e.to_string();
+    match ErrorCode::from(e) {
+        ErrorCode::NotFound => Status::not_found(message),
+        ErrorCode::BadRequest | ErrorCode::TooLarge => Status::invalid_argument(message),
+        ErrorCode::Busy => Status::resource_exhausted(message),
+        ErrorCode::DeadlineExceeded => Status::deadline_exceeded(message),
+        ErrorCode::Internal => Status::internal(message),
+        ErrorCode::ReadOnly => Status::failed_precondition(message),
+        ErrorCode::NotAuthenticated => Status::unauthenticated(message),
+        ErrorCode::Conflict => Status::aborted(message),
+    }
+}
+
+#[tonic::async_trait]
+impl<E: KvsEngine> Kvs for KvsService<E> {
+    async fn set(&self, request: Request<SetRequest>) -> Result<Response<SetReply>, Status> {
+        self.check_writable()?;
+        let SetRequest { key, value } = request.into_inner();
+        self.store()
+            .run(move |engine| engine.set(key, value))
+            .await
+            .map_err(|e| status(&e))?;
+        Ok(Response::new(SetReply {}))
+    }
+
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetReply>, Status> {
+        let GetRequest { key } = request.into_inner();
+        let value = self
+            .store()
+            .run(move |engine| engine.get(key))
+            .await
+            .map_err(|e| status(&e))?;
+        Ok(Response::new(GetReply { value }))
+    }
+
+    async fn remove(
+        &self,
+        request: Request<RemoveRequest>,
+    ) -> Result<Response<RemoveReply>, Status> {
+        self.check_writable()?;
+        let RemoveRequest { key } = request.into_inner();
+        self.store()
+            .run(move |engine| engine.remove(key))
+            .await
+            .map_err(|e| status(&e))?;
+        Ok(Response::new(RemoveReply {}))
+    }
+
+    async fn scan(&self, request: Request<ScanRequest>) -> Result<Response<ScanReply>, Status> {
+        let ScanRequest { prefix } = request.into_inner();
+        let pairs = self
+            .store()
+            .run(move |engine| engine.scan(prefix))
+            .await
+            .map_err(|e| status(&e))?;
+        let pairs = pairs
+            .into_iter()
+            .map(|(key, value)| Pair { key, value })
+            .collect();
+        Ok(Response::new(ScanReply { pairs }))
+    }
+}
+
+/// Serve `engine` over gRPC on `addr` until the server fails.
+pub async fn serve<E: KvsEngine>(engine: E, addr: SocketAddr) -> crate::Result<()> {
+    serve_service(KvsService::new(engine), addr).await
+}
+
+/// Serve `service` over gRPC on `addr` until the server fails, checking the
+/// token of every call first if it has one.
+pub async fn serve_service<E: KvsEngine>(
+    service: KvsService<E>,
+    addr: SocketAddr,
+) -> crate::Result<()> {
+    let token = service.auth_token.clone();
+    let authenticate = move |request: Request<()>| {
+        let Some(expected) = &token else {
+            return Ok(request);
+        };
+        let given = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "));
+        match given {
+            Some(given) if constant_time_eq(given, expected.as_bytes()) => Ok(request),
+            _ => Err(status(&KvsError::NotAuthenticated)),
+        }
+    };
+    tonic::transport::Server::builder()
+        .add_service(KvsServer::with_interceptor(service, authenticate))
+        .serve(addr)
+        .await
+        .map_err(|e| std::io::Error::other(e).into())
+}
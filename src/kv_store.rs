@@ -6,24 +6,397 @@
 //! ## Example Usage
 //!
 //! ```rust
-//! use kvs::KvStore;
+//! use kvs::{KvStore, KvsEngine};
 //!
-//! let mut kvs = KvStore::open("./").unwrap();
+//! let dir = tempfile::TempDir::new().unwrap();
+//! let kvs = KvStore::open(dir.path()).unwrap();
 //!
 //! kvs.get("key1".into()).unwrap();
 //! kvs.set("key1".into(), "value1".into()).unwrap();
 //! kvs.remove("key1".into()).unwrap();
 //! ```
-use std::fs::{self, File, OpenOptions};
+use std::cell::{RefCell, RefMut};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{collections::HashMap, path::PathBuf};
 
 pub use crate::error::{KvsError, Result};
-use walkdir::WalkDir;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
 
-use crate::log_helper::{FileIndex, LogHelper, Record};
+use crate::engine::{BatchOp, ExportEntry, check_deadline, utf8};
+use crate::log_helper::{FileIndex, Format, HEADER_LEN, Hint, LogFile, LogHelper, Record};
+use crate::storage::{FileLock, LockMode, LogReader, LogWriter, Storage};
 
 const MAX_LOG_SIZE: u64 = 1 << 20;
-const MAX_UNCOMPACTED_SIZE: u64 = 1 << 10;
+const MAX_UNCOMPACTED_SIZE: u64 = 1 << 20;
+const MAX_KEY_SIZE: usize = 256;
+const MAX_VALUE_SIZE: usize = 4 << 20;
+/// How long automatic compaction backs off after failing, when tolerated.
+const COMPACTION_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Appended to the name of the log and the hint a compaction writes, until
+/// they are complete.
+const COMPACTING_SUFFIX: &str = ".compacting";
+/// Held exclusively by the store writing to a directory, for as long as it is open.
+pub(crate) const WRITER_LOCK_FILE: &str = "kvs.writer.lock";
+/// Held shared by read-only stores while they replay the logs, and
+/// exclusively by the writer while a compaction deletes logs, so a replay
+/// never finds a log gone half way.
+pub(crate) const LOCK_FILE: &str = "kvs.lock";
+
+/// Decides when the store rewrites its logs to drop stale records.
+///
+/// A record is stale once a later `set` or `remove` of its key supersedes it,
+/// and a `remove` record is stale as soon as it is written. Every strategy
+/// except [`CompactionStrategy::Off`] only fires once there is at least one
+/// stale byte, and is checked after each `set`/`remove`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CompactionStrategy {
+    /// Never compact automatically, only on an explicit `compact` call.
+    Off,
+    /// Compact once stale records take up at least this many bytes and at
+    /// least half of the log's bytes.
+    Auto(u64),
+    /// Compact once the log files add up to at least this many bytes.
+    Size(u64),
+    /// Compact once stale records make up at least this fraction of the log bytes.
+    Ratio(f64),
+    /// Compact on the first write after this much time passed since the last compaction.
+    Interval(Duration),
+}
+
+impl Default for CompactionStrategy {
+    fn default() -> Self {
+        CompactionStrategy::Auto(MAX_UNCOMPACTED_SIZE)
+    }
+}
+
+/// The largest keys and values an engine accepts, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SizeLimits {
+    /// The longest key allowed.
+    pub max_key_size: usize,
+    /// The longest value allowed.
+    pub max_value_size: usize,
+}
+
+impl SizeLimits {
+    /// Check that `key` and `value` are within the limits.
+    pub fn check(&self, key: &str, value: &str) -> Result<()> {
+        self.check_bytes(key, value.as_bytes())
+    }
+
+    /// Check that `key` and the bytes of `value` are within the limits.
+    pub fn check_bytes(&self, key: &str, value: &[u8]) -> Result<()> {
+        if key.len() > self.max_key_size {
+            return Err(KvsError::KeyTooLarge {
+                limit: self.max_key_size,
+                actual: key.len(),
+            });
+        }
+        if value.len() > self.max_value_size {
+            return Err(KvsError::ValueTooLarge {
+                limit: self.max_value_size,
+                actual: value.len(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        SizeLimits {
+            max_key_size: MAX_KEY_SIZE,
+            max_value_size: MAX_VALUE_SIZE,
+        }
+    }
+}
+
+/// Which log files have their record checksums verified on open.
+///
+/// Records skipped on open are still verified whenever they are read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum VerifyLevel {
+    /// Verify nothing, a torn record is only noticed if it is cut short.
+    None,
+    /// Verify only the newest file, the one a crash most likely tore.
+    CurrentFileOnly,
+    /// Verify every file. This replays every record on open, so the index a
+    /// compaction saved in its hint file goes unused.
+    #[default]
+    All,
+}
+
+/// Which data directories [`crate::KvStore::open_with_config`] opens.
+///
+/// Only the logs of a directory decide whether it holds a store. Other files,
+/// like a config file of the application sharing the directory, are left
+/// alone either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum OpenMode {
+    /// Open the store in the directory, or start one if it holds no logs.
+    #[default]
+    Any,
+    /// Only open a store the directory holds already, failing with
+    /// [`KvsError::NoStore`] instead of starting one.
+    Existing,
+    /// Only start a store in a directory without logs, failing with
+    /// [`KvsError::StoreExists`] instead of opening the one it holds.
+    New,
+}
+
+/// How [`crate::KvStore`] writes the records of its logs.
+///
+/// A directory whose logs hold nothing yet is written in the configured
+/// format. Once they hold records, opens keep writing the format of the
+/// newest log whatever is configured, so a directory of an older version
+/// stays readable by it and needs no migration. Logs of either format are
+/// read either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum LogFormat {
+    /// `set <key> <value>` and `rm <key>` lines, as the first versions wrote.
+    ///
+    /// Keys and values can't hold spaces or newlines, values must be UTF-8,
+    /// and keys can't expire. Writes that need any of these fail with
+    /// [`KvsError::UnsupportedByFormat`]. Values are never compressed.
+    Text,
+    /// Checksummed binary frames, which hold any key and value.
+    #[default]
+    Binary,
+}
+
+/// What [`crate::KvStore::check`] found in the logs of a data directory.
+#[derive(Debug, Default)]
+pub struct LogCheck {
+    /// The number of log files.
+    pub files: u64,
+    /// The number of whole records read from them.
+    pub records: u64,
+    /// Records a compaction would drop: removals and the sets that were
+    /// overwritten, removed or expired.
+    pub stale: u64,
+    /// Logs ending in a torn record, which opening the store cuts off, with
+    /// the number of bytes torn.
+    pub torn: Vec<(PathBuf, u64)>,
+    /// Logs with a corrupt record before their end, and what was wrong. Their
+    /// records aren't counted, the store won't open until they are repaired.
+    pub corrupt: Vec<(PathBuf, KvsError)>,
+}
+
+/// What happened to a key, as reported to [`crate::KvsEngine::watch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// The key was given a value.
+    Set,
+    /// The key was removed by a client.
+    Remove,
+    /// The key expired and was dropped from the store.
+    Expired,
+}
+
+/// A change to one key of a [`crate::KvsEngine`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// The key that changed.
+    pub key: String,
+    /// How it changed.
+    pub kind: ChangeKind,
+    /// The value the key was given, `None` unless `kind` is [`ChangeKind::Set`].
+    pub value: Option<Vec<u8>>,
+}
+
+/// Where the changes of an engine are sent, see [`crate::KvsEngine::watch`].
+#[derive(Default)]
+pub(crate) struct Watchers(Vec<Sender<Change>>);
+
+impl Watchers {
+    /// Send every change from now on to the returned receiver.
+    pub(crate) fn watch(&mut self) -> Receiver<Change> {
+        let (sender, receiver) = mpsc::channel();
+        self.0.push(sender);
+        receiver
+    }
+
+    /// Tell the watchers that `key` changed, set to `value` for a
+    /// [`ChangeKind::Set`], and forget the ones that went away.
+    pub(crate) fn notify(&mut self, key: String, kind: ChangeKind, value: Option<&[u8]>) {
+        if self.0.is_empty() {
+            return;
+        }
+        let change = Change {
+            key,
+            kind,
+            value: value.map(<[u8]>::to_vec),
+        };
+        self.0
+            .retain(|watcher| watcher.send(change.clone()).is_ok());
+    }
+}
+
+/// The algorithm compressing large values, see [`Compression`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Codec {
+    /// Zstandard at the given level, 1 (fastest) to 22 (smallest).
+    Zstd(i32),
+}
+
+/// Compress each value longer than `threshold` bytes on its own, so reading
+/// a small value costs nothing extra.
+///
+/// Values that don't shrink are stored as they are. Files written with
+/// compression stay readable with it turned off.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Compression {
+    /// How values are compressed.
+    pub codec: Codec,
+    /// Values of at most this many bytes are never compressed.
+    pub threshold: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            codec: Codec::Zstd(3),
+            threshold: 1024,
+        }
+    }
+}
+
+/// Tuning options for [`crate::KvStore`].
+///
+/// ```rust
+/// use kvs::{CompactionStrategy, KvStoreConfig};
+///
+/// let config = KvStoreConfig::default()
+///     .max_log_size(64 << 20)
+///     .compaction(CompactionStrategy::Size(1 << 30));
+/// assert_eq!(config.max_log_size, 64 << 20);
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KvStoreConfig {
+    /// When to compact the logs automatically.
+    pub compaction: CompactionStrategy,
+    /// Start a new log file once the current one grows past this many bytes.
+    pub max_log_size: u64,
+    /// The largest keys and values `set` accepts.
+    pub limits: SizeLimits,
+    /// Which log files to verify on open.
+    pub verify_on_open: VerifyLevel,
+    /// Fail a write when the log file it should roll over to can't be
+    /// created, instead of growing the current file past `max_log_size`.
+    pub strict_rotation: bool,
+    /// Log an automatic compaction that fails and retry it later, instead of
+    /// failing the write that triggered it.
+    pub tolerate_compaction_failures: bool,
+    /// How large values are compressed, `None` to store every value as is.
+    pub compression: Option<Compression>,
+    /// Compact when the store is closed if this many bytes of the logs are
+    /// stale, `None` to never delay closing with a compaction.
+    pub compact_on_close: Option<u64>,
+    /// Whether the directory must hold a store already, or must not.
+    #[serde(default)]
+    pub open_mode: OpenMode,
+    /// The format of the logs of a new directory, see [`LogFormat`].
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// How often a background thread writes a tombstone for every expired
+    /// key, `None` to leave them until they're read or compacted away.
+    #[serde(default)]
+    pub expiry_sweep_interval: Option<Duration>,
+}
+
+impl KvStoreConfig {
+    /// Set when to compact the logs automatically.
+    pub fn compaction(mut self, compaction: CompactionStrategy) -> Self {
+        self.compaction = compaction;
+        self
+    }
+
+    /// Set the largest keys and values `set` accepts.
+    pub fn limits(mut self, limits: SizeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Set which log files to verify on open.
+    pub fn verify_on_open(mut self, verify_on_open: VerifyLevel) -> Self {
+        self.verify_on_open = verify_on_open;
+        self
+    }
+
+    /// Set the size in bytes past which a new log file is started.
+    pub fn max_log_size(mut self, max_log_size: u64) -> Self {
+        self.max_log_size = max_log_size;
+        self
+    }
+
+    /// Set whether a write fails when its new log file can't be created.
+    pub fn strict_rotation(mut self, strict_rotation: bool) -> Self {
+        self.strict_rotation = strict_rotation;
+        self
+    }
+
+    /// Set whether a failed automatic compaction is logged and retried
+    /// instead of failing the write that triggered it.
+    pub fn tolerate_compaction_failures(mut self, tolerate: bool) -> Self {
+        self.tolerate_compaction_failures = tolerate;
+        self
+    }
+
+    /// Set how large values are compressed, `None` to store them as is.
+    pub fn compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Set the stale bytes past which closing the store compacts it, `None`
+    /// to never compact on close.
+    pub fn compact_on_close(mut self, threshold: Option<u64>) -> Self {
+        self.compact_on_close = threshold;
+        self
+    }
+
+    /// Set whether the directory must hold a store already, or must not.
+    pub fn open_mode(mut self, open_mode: OpenMode) -> Self {
+        self.open_mode = open_mode;
+        self
+    }
+
+    /// Set the format of the logs of a new directory.
+    pub fn log_format(mut self, log_format: LogFormat) -> Self {
+        self.log_format = log_format;
+        self
+    }
+
+    /// Set how often expired keys are swept, `None` to never sweep them.
+    pub fn expiry_sweep_interval(mut self, interval: Option<Duration>) -> Self {
+        self.expiry_sweep_interval = interval;
+        self
+    }
+}
+
+impl Default for KvStoreConfig {
+    fn default() -> Self {
+        KvStoreConfig {
+            compaction: CompactionStrategy::default(),
+            max_log_size: MAX_LOG_SIZE,
+            limits: SizeLimits::default(),
+            verify_on_open: VerifyLevel::default(),
+            strict_rotation: false,
+            tolerate_compaction_failures: false,
+            compression: None,
+            compact_on_close: None,
+            open_mode: OpenMode::default(),
+            log_format: LogFormat::default(),
+            expiry_sweep_interval: None,
+        }
+    }
+}
 
 /// The KvStore structures.
 ///
@@ -31,181 +404,1272 @@ const MAX_UNCOMPACTED_SIZE: u64 = 1 << 10;
 ///
 ///  ## Example Usage
 /// ```rust
-/// use kvs::KvStore;
+/// use kvs::{KvStore, KvsEngine};
 ///
-/// let mut kvs = KvStore::open("./").unwrap();
+/// let dir = tempfile::TempDir::new().unwrap();
+/// let kvs = KvStore::open(dir.path()).unwrap();
 ///
 /// kvs.get("key1".into());
 /// ```
 ///
 pub(crate) struct KvStore {
+    storage: Arc<dyn Storage>,
     log_dir: PathBuf,
-    file_count: i32,
-    cur_file: File,
-    cur_path: PathBuf,
+    /// The number of the newest log, the one written to.
+    file_count: u64,
+    /// The format new records are written in.
+    format: Format,
+    cur_file: Box<dyn LogWriter>,
+    cur_path: Arc<Path>,
+    /// The length of `cur_file`, tracked here so appending needs no `fstat`.
+    write_pos: u64,
+    /// A write to `cur_file` failed part way, so it may end in a torn record.
+    torn: bool,
+    readers: LogReaders,
 
+    idx: Arc<RwLock<HashMap<String, FileIndex>>>,
+    generation: Arc<AtomicU64>,
+    /// Expired keys readers came across, for the writer to drop from the index.
+    expired: Arc<Mutex<Vec<String>>>,
+    stats: LogStats,
+    log_size: u64,
+    /// How many log files had their checksums verified on open.
+    verified_files: u64,
+    /// How many compactions ran since the store was opened.
+    compactions: u64,
+    last_compaction: Instant,
+    /// When the last tolerated automatic compaction failed, to back off from.
+    compaction_failed_at: Option<Instant>,
+    /// Where changes are sent, dropped once their receiver is.
+    watchers: Watchers,
+    config: KvStoreConfig,
+    /// When the operation holding the writer must give up, see
+    /// [`crate::KvsEngine::with_deadline`]. Set each time the writer is locked.
+    pub(crate) deadline: Option<Instant>,
+    /// Keeps other stores from writing to the directory while this one is open.
+    _writer_lock: FileLock,
+}
+
+/// An index replayed from the logs, see [`KvStore::load`].
+struct Loaded {
     idx: HashMap<String, FileIndex>,
-    uncompacted: u64,
+    stats: LogStats,
+    log_size: u64,
+    /// The number of the newest log, 0 if there is none.
+    file_count: u64,
+    /// The format of the newest log holding anything, `None` if none does.
+    format: Option<Format>,
+    /// Whether the newest log holds nothing, not even a header.
+    last_empty: bool,
+    verified_files: u64,
 }
 
 impl KvStore {
     /// Open the [`KvStore`] at a given dir path, and return it.
     /// Here we assume that there are only logs files like **1.log, 2.log** in the path dir.
-    pub(crate) fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+    ///
+    /// Only one store may write to a directory at a time: opening one that
+    /// is already open, by this process or another, fails with
+    /// [`KvsError::AlreadyOpen`].
+    ///
+    /// A directory the configured [`OpenMode`] refuses fails before anything
+    /// is written to it.
+    pub(crate) fn open(
+        storage: Arc<dyn Storage>,
+        path: impl Into<PathBuf>,
+        config: KvStoreConfig,
+    ) -> Result<KvStore> {
         let path = path.into();
-        // Find the maximum log file number
-        let mut file_count = 0;
-        for entry in WalkDir::new(&path).into_iter().filter_map(|e| e.ok()) {
-            if entry.file_type().is_file() {
-                if let Some(name) = entry.file_name().to_str() {
-                    if let Some(num_str) = name.strip_suffix(".log") {
-                        if let Ok(num) = num_str.parse::<i32>() {
-                            if num > file_count {
-                                file_count = num;
-                            }
-                        }
-                    }
-                }
+        if config.open_mode != OpenMode::Any {
+            let has_logs = storage
+                .list(&path)?
+                .iter()
+                .any(|file| log_number(file).is_some());
+            match config.open_mode {
+                OpenMode::Existing if !has_logs => return Err(KvsError::NoStore(path)),
+                OpenMode::New if has_logs => return Err(KvsError::StoreExists(path)),
+                _ => {}
             }
         }
+        let writer_lock = storage
+            .lock(&path.join(WRITER_LOCK_FILE), LockMode::TryExclusive)
+            .map_err(|e| match e {
+                KvsError::IOError(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    KvsError::AlreadyOpen(path.clone())
+                }
+                e => e,
+            })?;
+        let Loaded {
+            idx,
+            stats,
+            log_size,
+            mut file_count,
+            format: loaded_format,
+            last_empty,
+            verified_files,
+        } = KvStore::load(&*storage, &path, config.verify_on_open, true)?;
 
-        let (cur_file, cur_path) = {
-            if file_count < 1 {
-                file_count = 1;
-            }
-            KvStore::open_file(&path, file_count)?
+        // JSON logs can't be appended to anymore, so their directories move on to binary.
+        let format = match loaded_format {
+            Some(Format::Json) => Format::Binary,
+            Some(format) => format,
+            None => config.log_format.into(),
         };
+        // Never append records to a log of another format.
+        if !last_empty && loaded_format != Some(format) {
+            file_count += 1;
+        }
+        let (cur_file, cur_path, write_pos) =
+            KvStore::open_file(&*storage, &path, file_count.max(1), format)?;
+        Ok(Self {
+            storage,
+            log_dir: path,
+            file_count: file_count.max(1),
+            format,
+            cur_file,
+            cur_path: cur_path.into(),
+            write_pos,
+            torn: false,
+            readers: LogReaders::default(),
+            idx: Arc::new(RwLock::new(idx)),
+            generation: Arc::new(AtomicU64::new(0)),
+            expired: Arc::default(),
+            stats,
+            log_size,
+            verified_files,
+            compactions: 0,
+            last_compaction: Instant::now(),
+            compaction_failed_at: None,
+            deadline: None,
+            watchers: Watchers::default(),
+            config,
+            _writer_lock: writer_lock,
+        })
+    }
+
+    /// Replay the logs at `path` into an index.
+    ///
+    /// The logs are found by listing `path` once and replayed in the order
+    /// of their numbers, skipping the gaps compactions leave without a look.
+    ///
+    /// If the oldest log was written by a compaction that left a hint file,
+    /// the index of that log is read from the hint and only the records
+    /// appended since are replayed, unless `verify_on_open` asks for every
+    /// record. A hint that can't be read is ignored for a full replay.
+    ///
+    /// A `writable` load also tidies up after a crash: it cuts torn records
+    /// off the end of logs and removes the log of an unfinished compaction.
+    /// Otherwise the torn records are only skipped and nothing is written.
+    fn load(
+        storage: &dyn Storage,
+        path: &Path,
+        verify_on_open: VerifyLevel,
+        writable: bool,
+    ) -> Result<Loaded> {
+        // Every log, oldest first, and the newest hint.
+        let mut logs = Vec::new();
+        let mut newest_hint = None;
+        for file in storage.list(path)? {
+            if let Some(num) = log_number(&file) {
+                logs.push(num);
+            } else if let Some(num) = hint_number(&file) {
+                newest_hint = newest_hint.max(Some(num));
+            } else if writable
+                && file
+                    .to_str()
+                    .is_some_and(|f| f.ends_with(COMPACTING_SUFFIX))
+            {
+                // A compaction that never finished, the logs before it are whole.
+                warn!(
+                    "removing {} left by an unfinished compaction",
+                    file.display()
+                );
+                storage.remove(&file)?;
+            } else if file.extension().is_some_and(|ext| ext == "log") {
+                warn!("ignoring {}, not named like a log", file.display());
+            }
+        }
+        logs.sort_unstable();
+        let file_count = logs.last().copied().unwrap_or(0);
+
         let mut idx = HashMap::new();
-        let mut uncompacted = 0;
-        for num in 1..=file_count {
+        let mut stats = LogStats::default();
+        let mut log_size = 0;
+        let mut format = None;
+        let mut last_empty = true;
+        let now = now_millis();
+        let mut verified_files = 0;
+        // Where in the oldest log to start replaying.
+        let mut from = 0;
+        if let Some(num) = newest_hint
+            && logs.first() == Some(&num)
+            && verify_on_open != VerifyLevel::All
+            && let Some(Hint { log_len, entries }) = KvStore::read_hint(storage, path, num)
+        {
+            for (key, file_index) in entries {
+                stats.add(&file_index);
+                if file_index.is_expired(now) {
+                    stats.mark_stale(&file_index);
+                } else {
+                    idx.insert(key, file_index);
+                }
+            }
+            from = log_len;
+        }
+        for num in logs {
             let file_path = path.join(format!("{num}.log"));
-            if file_path.exists() {
-                for record in LogHelper::read_all(file_path)? {
-                    let (record, file_index) = record;
-                    match record {
-                        Record::Set(key, _) => {
-                            if let Some(_) = idx.insert(key, file_index) {
-                                uncompacted += 1;
-                            }
+            let verify = match verify_on_open {
+                VerifyLevel::None => false,
+                VerifyLevel::CurrentFileOnly => num == file_count,
+                VerifyLevel::All => true,
+            };
+            verified_files += u64::from(verify);
+            let LogFile {
+                records,
+                valid_len,
+                format: log_format,
+            } = LogHelper::read_all(storage, file_path.clone(), from, verify)?;
+            from = 0;
+            last_empty = valid_len == 0;
+            if !last_empty {
+                format = Some(log_format);
+            }
+            let len = storage.len(&file_path)?;
+            if valid_len < len && writable {
+                // Drop the torn tail so new records follow the last valid one.
+                warn!(
+                    "dropping {} bytes of a torn record at the end of {}",
+                    len - valid_len,
+                    file_path.display()
+                );
+                storage.truncate(&file_path, valid_len)?;
+            }
+            // A hinted log counts whole, its records before `from` included.
+            log_size += valid_len;
+            for record in records {
+                let (record, file_index) = record;
+                stats.add(&file_index);
+                match record {
+                    // An expired set hides the key like a remove does.
+                    Record::Set(key, _, _) if file_index.is_expired(now) => {
+                        stats.mark_stale(&file_index);
+                        if let Some(old) = idx.remove(&key) {
+                            stats.mark_stale(&old);
                         }
-                        Record::Remove(key) => {
-                            uncompacted += 1;
-                            idx.remove(&key);
+                    }
+                    Record::Set(key, _, _) => {
+                        if let Some(old) = idx.insert(key, file_index) {
+                            stats.mark_stale(&old);
+                        }
+                    }
+                    Record::Remove(key) => {
+                        stats.mark_stale(&file_index);
+                        if let Some(old) = idx.remove(&key) {
+                            stats.mark_stale(&old);
                         }
                     }
                 }
             }
         }
-        Ok(Self {
-            log_dir: path,
-            file_count,
-            cur_file,
-            cur_path,
+        Ok(Loaded {
             idx,
-            uncompacted,
+            stats,
+            log_size,
+            file_count,
+            format,
+            last_empty,
+            verified_files,
         })
     }
 
-    /// Set a pair of **key-value**
-    pub(crate) fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.check_if_new_file()?;
-        let idx = LogHelper::write(
-            &mut self.cur_file,
-            self.cur_path.clone(),
-            &Record::Set(key.clone(), value),
-        )?;
-        if let Some(_) = self.idx.insert(key, idx) {
-            self.uncompacted += 1;
-            self.record_uncompact()?;
+    /// Read the hint of log number `num`, or `None` if it is unreadable or
+    /// covers more of the log than there is.
+    fn read_hint(storage: &dyn Storage, path: &Path, num: u64) -> Option<Hint> {
+        let hint_path = path.join(format!("{num}.hint"));
+        let log_path = path.join(format!("{num}.log"));
+        let hint = match LogHelper::read_hint(storage, &hint_path, log_path.as_path().into()) {
+            Ok(hint) => hint,
+            Err(e) => {
+                warn!("ignoring {}: {e}", hint_path.display());
+                return None;
+            }
+        };
+        match storage.len(&log_path) {
+            Ok(len) if len >= hint.log_len => Some(hint),
+            _ => {
+                warn!(
+                    "ignoring {}, {} is shorter than it covers",
+                    hint_path.display(),
+                    log_path.display()
+                );
+                None
+            }
         }
+    }
 
-        Ok(())
+    /// Set a pair of **key-value**
+    pub(crate) fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_with_expiry(key, value.into_bytes(), None)
     }
 
-    /// Get the `value` for `key`
-    pub(crate) fn get(&self, key: String) -> Result<Option<String>> {
-        let idx = self.idx.get(&key);
-        match idx {
-            Some(idx) => {
-                let record = LogHelper::read(idx)?;
-                if let Record::Set(_, value) = record {
-                    Ok(Some(value))
-                } else {
-                    Ok(None)
+    /// Read every log at `path`, verifying each record, and report what was
+    /// found. Unlike [`KvStore::load`] it goes on past a corrupt log and
+    /// never writes.
+    pub(crate) fn check(storage: &dyn Storage, path: &Path) -> Result<LogCheck> {
+        let mut logs: Vec<u64> = storage
+            .list(path)?
+            .iter()
+            .filter_map(|file| log_number(file))
+            .collect();
+        logs.sort_unstable();
+
+        let mut check = LogCheck::default();
+        let mut live = HashMap::new();
+        let now = now_millis();
+        for num in logs {
+            let file_path = path.join(format!("{num}.log"));
+            check.files += 1;
+            let LogFile {
+                records, valid_len, ..
+            } = match LogHelper::read_all(storage, file_path.clone(), 0, true) {
+                Ok(log) => log,
+                Err(e) => {
+                    check.corrupt.push((file_path, e));
+                    continue;
                 }
+            };
+            let len = storage.len(&file_path)?;
+            if valid_len < len {
+                check.torn.push((file_path, len - valid_len));
             }
+            check.records += records.len() as u64;
+            for (record, file_index) in records {
+                match record {
+                    Record::Set(key, _, _) if !file_index.is_expired(now) => {
+                        live.insert(key, ());
+                    }
+                    Record::Set(key, _, _) | Record::Remove(key) => {
+                        live.remove(&key);
+                    }
+                }
+            }
+        }
+        check.stale = check.records - live.len() as u64;
+        Ok(check)
+    }
+
+    /// Set a pair of **key-value** that stops being visible at `expires_at`,
+    /// in milliseconds since the Unix epoch, or never if `None`.
+    pub(crate) fn set_with_expiry(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        self.config.limits.check_bytes(&key, &value)?;
+        let record = Record::Set(key.clone(), value, expires_at);
+        let idx = self.append(&record)?;
+        if let Some(old) = self.idx.write().unwrap().insert(key.clone(), idx) {
+            self.stats.mark_stale(&old);
+        }
+        if let Record::Set(_, value, _) = &record {
+            self.watchers.notify(key, ChangeKind::Set, Some(value));
+        }
+        self.maybe_compact()
+    }
+
+    /// Replace the value of `key`, keeping when it expires.
+    pub(crate) fn update(&mut self, key: String, value: String) -> Result<()> {
+        let expires_at = self
+            .idx
+            .read()
+            .unwrap()
+            .get(&key)
+            .and_then(FileIndex::expires_at);
+        self.set_with_expiry(key, value.into_bytes(), expires_at)
+    }
+
+    /// Get the `value` for `key` through the writer's own file handles.
+    pub(crate) fn get(&mut self, key: &str) -> Result<Option<String>> {
+        self.get_bytes(key)?.map(utf8).transpose()
+    }
+
+    /// Get the `value` for `key` as bytes through the writer's own file handles.
+    pub(crate) fn get_bytes(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.drop_if_expired(key);
+        let idx = self.idx.read().unwrap();
+        match idx.get(key) {
+            Some(idx) => match self.readers.read(&*self.storage, idx)? {
+                Record::Set(_, value, _) => Ok(Some(value)),
+                Record::Remove(_) => Ok(None),
+            },
             None => Ok(None),
         }
     }
 
     /// Remove the `key`.
     pub(crate) fn remove(&mut self, key: String) -> Result<()> {
-        let value = self.idx.get(&key);
-        if value.is_none() {
+        self.drop_if_expired(&key);
+        if !self.idx.read().unwrap().contains_key(&key) {
             Err(KvsError::NonExistentKey(key))
         } else {
-            self.idx.remove(&key);
-            self.check_if_new_file()?;
-            LogHelper::write(
-                &mut self.cur_file,
-                self.cur_path.clone(),
-                &Record::Remove(key),
+            let tombstone = self.append(&Record::Remove(key.clone()))?;
+            if let Some(old) = self.idx.write().unwrap().remove(&key) {
+                self.stats.mark_stale(&old);
+            }
+            self.stats.mark_stale(&tombstone);
+            self.watchers.notify(key, ChangeKind::Remove, None);
+            self.maybe_compact()
+        }
+    }
+
+    /// Write every live key to `writer`, ordered by key, through the
+    /// writer's own file handles.
+    pub(crate) fn export(&mut self, writer: &mut impl Write) -> Result<()> {
+        let idx = self.idx.read().unwrap();
+        let now = now_millis();
+        let mut keys: Vec<_> = idx.iter().filter(|(_, idx)| !idx.is_expired(now)).collect();
+        keys.sort_by_key(|(key, _)| *key);
+        for (key, file_index) in keys {
+            check_deadline(self.deadline)?;
+            if let Record::Set(_, value, expires_at) =
+                self.readers.read(&*self.storage, file_index)?
+            {
+                ExportEntry::write(writer, key.clone(), utf8(value)?, expires_at)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Append every op of `batch` to the current file and sync it once,
+    /// only then updating the index.
+    ///
+    /// The batch is checked up front so nothing is written if an op fails.
+    pub(crate) fn write_batch(&mut self, batch: Vec<BatchOp>) -> Result<()> {
+        self.drop_expired();
+        let mut live = HashMap::new();
+        {
+            let idx = self.idx.read().unwrap();
+            let now = now_millis();
+            for op in &batch {
+                match op {
+                    BatchOp::Set(key, value) => {
+                        self.config.limits.check(key, value)?;
+                        live.insert(key.as_str(), true);
+                    }
+                    BatchOp::Remove(key) => {
+                        let exists = live.get(key.as_str()).copied().unwrap_or_else(|| {
+                            idx.get(key).is_some_and(|idx| !idx.is_expired(now))
+                        });
+                        if !exists {
+                            return Err(KvsError::NonExistentKey(key.clone()));
+                        }
+                        live.insert(key.as_str(), false);
+                    }
+                }
+            }
+        }
+
+        let records = batch
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set(key, value) => Record::Set(key, value.into_bytes(), None),
+                BatchOp::Remove(key) => Record::Remove(key),
+            })
+            .collect();
+        self.append_batch(records, ChangeKind::Remove)?;
+        self.maybe_compact()
+    }
+
+    /// Remove every live key starting with `prefix` as one batch, returning
+    /// how many there were.
+    pub(crate) fn remove_prefix(&mut self, prefix: &str) -> Result<u64> {
+        self.drop_expired();
+        let now = now_millis();
+        let mut keys: Vec<_> = self
+            .idx
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(key, idx)| key.starts_with(prefix) && !idx.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        let removed = keys.len() as u64;
+        if removed > 0 {
+            self.append_batch(
+                keys.into_iter().map(Record::Remove).collect(),
+                ChangeKind::Remove,
+            )?;
+            self.maybe_compact()?;
+        }
+        Ok(removed)
+    }
+
+    /// Write a tombstone for every expired key and drop them from the index,
+    /// returning how many there were.
+    pub(crate) fn sweep_expired(&mut self) -> Result<u64> {
+        let now = now_millis();
+        let mut keys: Vec<_> = self
+            .idx
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, idx)| idx.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        let swept = keys.len() as u64;
+        if swept > 0 {
+            self.append_batch(
+                keys.into_iter().map(Record::Remove).collect(),
+                ChangeKind::Expired,
+            )?;
+            self.maybe_compact()?;
+        }
+        Ok(swept)
+    }
+
+    /// How often a background thread should call [`KvStore::sweep_expired`].
+    pub(crate) fn expiry_sweep_interval(&self) -> Option<Duration> {
+        self.config.expiry_sweep_interval
+    }
+
+    /// Append `records` to the current file and sync it once, then index
+    /// them all under one lock, so readers see either none or all of them.
+    ///
+    /// The batch never rolls over to a new file part way, so one sync covers it.
+    /// Watchers see the keys it removes as `removed`.
+    fn append_batch(&mut self, records: Vec<Record>, removed: ChangeKind) -> Result<()> {
+        for record in &records {
+            LogHelper::check(self.format, record)?;
+        }
+        self.check_if_new_file()?;
+        let mut written = Vec::with_capacity(records.len());
+        let mut result = Ok(());
+        for record in records {
+            match self.write(&record) {
+                Ok(idx) => {
+                    self.log_size += idx.len();
+                    self.stats.add(&idx);
+                    written.push((record, idx));
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        if result.is_ok() {
+            result = self.cur_file.sync();
+            self.torn |= result.is_err();
+        }
+        if let Err(e) = result {
+            // Whatever made it to the log is never indexed.
+            for (_, idx) in &written {
+                self.stats.mark_stale(idx);
+            }
+            return Err(e);
+        }
+
+        let idx = self.idx.clone();
+        let mut idx = idx.write().unwrap();
+        let mut changes = Vec::with_capacity(written.len());
+        for (record, new) in written {
+            match record {
+                Record::Set(key, value, _) => {
+                    if let Some(old) = idx.insert(key.clone(), new) {
+                        self.stats.mark_stale(&old);
+                    }
+                    changes.push((key, ChangeKind::Set, Some(value)));
+                }
+                Record::Remove(key) => {
+                    if let Some(old) = idx.remove(&key) {
+                        self.stats.mark_stale(&old);
+                    }
+                    self.stats.mark_stale(&new);
+                    changes.push((key, removed, None));
+                }
+            }
+        }
+        drop(idx);
+        for (key, kind, value) in changes {
+            self.watchers.notify(key, kind, value.as_deref());
+        }
+        Ok(())
+    }
+
+    /// Drop `key` from the index if it expired. Its records become stale
+    /// without a tombstone, as `open` skips expired records anyway.
+    fn drop_if_expired(&mut self, key: &str) {
+        let mut idx = self.idx.write().unwrap();
+        if idx.get(key).is_some_and(|idx| idx.is_expired(now_millis()))
+            && let Some(old) = idx.remove(key)
+        {
+            drop(idx);
+            self.stats.mark_stale(&old);
+            self.watchers
+                .notify(key.to_owned(), ChangeKind::Expired, None);
+        }
+    }
+
+    /// Send every change from now on to the returned receiver.
+    pub(crate) fn watch(&mut self) -> Receiver<Change> {
+        self.watchers.watch()
+    }
+
+    /// Drop the expired keys readers came across from the index.
+    fn drop_expired(&mut self) {
+        let keys = std::mem::take(&mut *self.expired.lock().unwrap());
+        for key in keys {
+            self.drop_if_expired(&key);
+        }
+    }
+
+    /// Bytes of the logs taken up by stale records.
+    pub(crate) fn stale_bytes(&self) -> u64 {
+        self.stats.stale
+    }
+
+    /// Each log file, oldest first, with the fraction of its record bytes that are stale.
+    pub(crate) fn file_liveness(&self) -> Vec<(PathBuf, f64)> {
+        let mut files: Vec<_> = self
+            .stats
+            .files
+            .iter()
+            .map(|(path, file)| {
+                (
+                    path.to_path_buf(),
+                    file.stale as f64 / file.total.max(1) as f64,
+                )
+            })
+            .collect();
+        files.sort_by_key(|(path, _)| log_number(path));
+        files
+    }
+
+    /// How many compactions ran since the store was opened.
+    pub(crate) fn compactions(&self) -> u64 {
+        self.compactions
+    }
+
+    /// How many log files had their checksums verified on open.
+    pub(crate) fn verified_files(&self) -> u64 {
+        self.verified_files
+    }
+
+    /// Create a reader sharing the index of this store, with its own file handles.
+    pub(crate) fn reader(&self) -> KvStoreReader {
+        KvStoreReader {
+            storage: self.storage.clone(),
+            idx: self.idx.clone(),
+            generation: self.generation.clone(),
+            expired: self.expired.clone(),
+            cache: RefCell::new(ReaderCache::default()),
+        }
+    }
+
+    /// Rewrite the live records into a fresh log and delete the old ones.
+    ///
+    /// The fresh log is written under a temporary name and renamed into
+    /// place once synced, see [`KvStore::write_side_log`], so a crash at any
+    /// point leaves logs that open to the same data. Expired records are not
+    /// moved, so their keys are dropped for good.
+    ///
+    /// Compaction takes `&mut self` like every write, so writes and
+    /// compactions are serialized on the writer lock: a write either lands
+    /// before, and its record is moved, or after, and it replaces the moved
+    /// record. None can slip in between reading the index and swapping the
+    /// moved records into it, which would bring back the value it overwrote.
+    pub(crate) fn compact(&mut self) -> Result<()> {
+        let old_file_count = self.file_count;
+        let num = self.file_count + 1;
+        let path = self.log_dir.join(format!("{num}.log"));
+        let side = self.log_dir.join(format!("{num}.log{COMPACTING_SUFFIX}"));
+
+        // Readers keep using the old files until the moved records are swapped in.
+        let (file, write_pos, copied) = match self.write_side_log(&side, &path) {
+            Ok(written) => written,
+            Err(e) => {
+                // The old logs are untouched, only the side file needs cleaning up.
+                if self.storage.exists(&side) {
+                    let _ = self.storage.remove(&side);
+                }
+                return Err(e);
+            }
+        };
+        // From here on the new log holds every live record for good.
+        self.file_count = num;
+        (self.cur_file, self.cur_path, self.write_pos) = (file, path.into(), write_pos);
+        self.torn = false;
+
+        let Copied {
+            moved,
+            expired,
+            stats,
+            log_size,
+        } = copied;
+        // Without a hint the next open replays the new log, which is only
+        // slower. Text logs can't be replayed from part way, so get none.
+        if self.format == Format::Binary
+            && let Err(e) = self.write_hint(num, &moved)
+        {
+            warn!(
+                "failed to write the hint of {}: {e}",
+                self.cur_path.display()
+            );
+        }
+        let idx = self.idx.clone();
+        let mut idx = idx.write().unwrap();
+        for (key, v) in moved {
+            idx.insert(key, v);
+        }
+        let expired: Vec<_> = expired
+            .into_iter()
+            .filter(|key| idx.remove(key).is_some())
+            .collect();
+        drop(idx);
+        for key in expired {
+            self.watchers.notify(key, ChangeKind::Expired, None);
+        }
+        // No lookup leads to the old files anymore, let readers drop their
+        // handles. One that looked a key up before reopens the old file by
+        // its path, finds it deleted and looks the key up again.
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.stats = stats;
+        self.log_size = log_size;
+        self.compactions += 1;
+        self.last_compaction = Instant::now();
+
+        // Oldest first, so a crash part way leaves a run of newer logs whose
+        // records replay correctly before the new one.
+        let _lock = self
+            .storage
+            .lock(&self.log_dir.join(LOCK_FILE), LockMode::Exclusive)?;
+        let mut old: Vec<_> = self
+            .storage
+            .list(&self.log_dir)?
+            .into_iter()
+            .filter_map(|file| Some((log_number(&file).or_else(|| hint_number(&file))?, file)))
+            .filter(|(num, _)| *num <= old_file_count)
+            .collect();
+        old.sort_unstable();
+        for (_, path) in old {
+            self.readers.remove(&path);
+            self.storage.remove(&path)?;
+        }
+        self.storage.sync_dir(&self.log_dir)
+    }
+
+    /// Save the index of the records a compaction just moved into log number
+    /// `num` as its hint, for `open` to load instead of replaying them.
+    ///
+    /// Like the log, the hint is written under a side name and renamed once
+    /// synced, so a hint file is always whole. A side file left by a failure
+    /// is removed by the next `open`.
+    fn write_hint(&mut self, num: u64, moved: &[(String, FileIndex)]) -> Result<()> {
+        let path = self.log_dir.join(format!("{num}.hint"));
+        let side = self.log_dir.join(format!("{num}.hint{COMPACTING_SUFFIX}"));
+        if self.storage.exists(&side) {
+            self.storage.remove(&side)?;
+        }
+        let mut file = self.storage.open_append(&side)?;
+        LogHelper::write_hint(&mut *file, self.write_pos, moved)?;
+        file.sync()?;
+        self.storage.rename(&side, &path)
+    }
+
+    /// Copy every live record into a new log at `side`, indexed as if at
+    /// `path`, then sync it and rename it to `path`.
+    ///
+    /// Until the rename, `open` never sees the new log, so a crash leaves
+    /// the old logs as they were. After it, the new log replays last and
+    /// holds the latest value of every key.
+    fn write_side_log(
+        &mut self,
+        side: &Path,
+        path: &Path,
+    ) -> Result<(Box<dyn LogWriter>, u64, Copied)> {
+        if self.storage.exists(side) {
+            self.storage.remove(side)?;
+        }
+        let (mut file, mut write_pos) = KvStore::open_log(&*self.storage, side, self.format)?;
+        let mut copied = Copied::default();
+        let now = now_millis();
+        let idx = self.idx.clone();
+        // Every copied record is indexed with the one shared path.
+        let shared_path: Arc<Path> = path.into();
+        for (key, v) in idx.read().unwrap().iter() {
+            if v.is_expired(now) {
+                copied.expired.push(key.clone());
+                continue;
+            }
+            check_deadline(self.deadline)?;
+            let record = self.readers.read(&*self.storage, v)?;
+            let new_v = LogHelper::write(
+                &mut *file,
+                &shared_path,
+                &mut write_pos,
+                &record,
+                self.format,
+                self.config.compression.as_ref(),
             )?;
-            self.record_uncompact()?;
-            Ok(())
+            copied.log_size += new_v.len();
+            copied.stats.add(&new_v);
+            copied.moved.push((key.clone(), new_v));
         }
+        file.sync()?;
+        self.storage.rename(side, path)?;
+        Ok((file, write_pos, copied))
     }
 }
 
+/// The live records a compaction copied into its new log.
+#[derive(Default)]
+struct Copied {
+    /// Each key with where its record now is.
+    moved: Vec<(String, FileIndex)>,
+    /// Keys found expired, which were not copied.
+    expired: Vec<String>,
+    stats: LogStats,
+    log_size: u64,
+}
+
 impl KvStore {
-    pub(crate) fn open_file(log_dir: &PathBuf, file_count: i32) -> Result<(File, PathBuf)> {
+    /// Open log file number `file_count` in `format` for appending,
+    /// returning its length.
+    pub(crate) fn open_file(
+        storage: &dyn Storage,
+        log_dir: &Path,
+        file_count: u64,
+        format: Format,
+    ) -> Result<(Box<dyn LogWriter>, PathBuf, u64)> {
         let file_path = log_dir.join(format!("{}.log", file_count));
-        Ok((
-            OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(file_path.clone())?,
-            file_path,
-        ))
+        let (file, len) = KvStore::open_log(storage, &file_path, format)?;
+        Ok((file, file_path, len))
+    }
+
+    /// Open the log at `path` in `format` for appending, writing its header
+    /// if it needs one and has none yet, and return its length.
+    fn open_log(
+        storage: &dyn Storage,
+        path: &Path,
+        format: Format,
+    ) -> Result<(Box<dyn LogWriter>, u64)> {
+        let mut file = storage.open_append(path)?;
+        let mut len = file.len()?;
+        if format == Format::Binary && len < HEADER_LEN {
+            // Left behind by a rollover that failed while writing the header.
+            if len > 0 {
+                storage.truncate(path, 0)?;
+            }
+            LogHelper::write_header(&mut *file, format)?;
+            len = file.len()?;
+        }
+        Ok((file, len))
     }
 
+    /// Switch to a new log file once it exists for good, keeping the current
+    /// one if anything on the way fails.
     fn new_file(&mut self) -> Result<()> {
+        // Nothing syncs the current file once it is rolled over.
+        self.cur_file.sync()?;
+        let (file, path, len) = KvStore::open_file(
+            &*self.storage,
+            &self.log_dir,
+            self.file_count + 1,
+            self.format,
+        )?;
+        self.storage.sync_dir(&self.log_dir)?;
         self.file_count += 1;
-        (self.cur_file, self.cur_path) = KvStore::open_file(&self.log_dir, self.file_count)?;
+        (self.cur_file, self.cur_path, self.write_pos) = (file, path.into(), len);
+        self.torn = false;
         Ok(())
     }
+
     fn check_if_new_file(&mut self) -> Result<()> {
-        if self.cur_file.metadata()?.len() > MAX_LOG_SIZE {
-            self.new_file()?;
+        // Nothing may follow a torn record, `open` only drops one at the end of a file.
+        if self.torn {
+            return self.new_file();
+        }
+        if self.write_pos > self.config.max_log_size
+            && let Err(e) = self.new_file()
+        {
+            if self.config.strict_rotation {
+                return Err(e);
+            }
+            warn!(
+                "can't start a new log file, still appending to {}: {e}",
+                self.cur_path.display()
+            );
         }
         Ok(())
     }
 
-    fn compact(&mut self) -> Result<()> {
-        self.uncompacted = 0;
-        let old_file_count = self.file_count;
-        self.new_file()?;
+    /// Write `record` to the current file, remembering if it may have been torn.
+    fn write(&mut self, record: &Record) -> Result<FileIndex> {
+        LogHelper::check(self.format, record)?;
+        let result = LogHelper::write(
+            &mut *self.cur_file,
+            &self.cur_path,
+            &mut self.write_pos,
+            record,
+            self.format,
+            self.config.compression.as_ref(),
+        );
+        self.torn |= result.is_err();
+        result
+    }
+
+    fn append(&mut self, record: &Record) -> Result<FileIndex> {
+        self.drop_expired();
+        self.check_if_new_file()?;
+        let idx = self.write(record)?;
+        self.log_size += idx.len();
+        self.stats.add(&idx);
+        Ok(idx)
+    }
+
+    /// Sync the current file. Every other file was synced when it was rolled
+    /// over, so everything written so far is on disk afterwards.
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        self.cur_file.sync()
+    }
 
-        for (_, v) in self.idx.iter_mut() {
-            let record = LogHelper::read(v)?;
-            let new_v = LogHelper::write(&mut self.cur_file, self.cur_path.clone(), &record)?;
-            *v = new_v;
+    /// Sync the current file, then compact if `compact_on_close` says so, to
+    /// leave the logs clean for the next open.
+    ///
+    /// The store stays usable, so closing twice is harmless.
+    pub(crate) fn close(&mut self) -> Result<()> {
+        self.cur_file.sync()?;
+        if let Some(threshold) = self.config.compact_on_close
+            && self.stats.stale > 0
+            && self.stats.stale >= threshold
+        {
+            self.compact()?;
+            self.cur_file.sync()?;
         }
+        Ok(())
+    }
 
-        for num in 1..=old_file_count {
-            let path = self.log_dir.join(format!("{num}.log"));
-            if path.exists() {
-                fs::remove_file(path)?;
+    fn maybe_compact(&mut self) -> Result<()> {
+        let stale = self.stats.stale;
+        if stale == 0 {
+            return Ok(());
+        }
+        let due = match self.config.compaction {
+            CompactionStrategy::Off => false,
+            CompactionStrategy::Auto(threshold) => stale >= threshold && stale * 2 >= self.log_size,
+            CompactionStrategy::Size(max_size) => self.log_size >= max_size,
+            CompactionStrategy::Ratio(ratio) => stale as f64 >= ratio * self.log_size as f64,
+            CompactionStrategy::Interval(interval) => self.last_compaction.elapsed() >= interval,
+        };
+        let backing_off = self
+            .compaction_failed_at
+            .is_some_and(|at| at.elapsed() < COMPACTION_RETRY_DELAY);
+        if !due || backing_off {
+            return Ok(());
+        }
+        match self.compact() {
+            Ok(()) => self.compaction_failed_at = None,
+            // Nothing is wrong, the next write tries again.
+            Err(KvsError::DeadlineExceeded) => {
+                debug!("compaction gave up at the deadline of the write that set it off");
+            }
+            // The write that got here already landed, and the old logs are
+            // still whole, so the store carries on without the compaction.
+            Err(e) if self.config.tolerate_compaction_failures => {
+                warn!("compaction failed, retrying in {COMPACTION_RETRY_DELAY:?}: {e}");
+                self.compaction_failed_at = Some(Instant::now());
             }
+            Err(e) => return Err(e),
         }
         Ok(())
     }
+}
 
-    fn record_uncompact(&mut self) -> Result<()> {
-        self.uncompacted += 1;
-        if self.uncompacted >= MAX_UNCOMPACTED_SIZE {
-            self.compact()?;
+impl Drop for KvStore {
+    /// Close the store, see [`KvStore::close`]. A failure is only logged, as
+    /// the records written so far are in the logs either way.
+    fn drop(&mut self) {
+        // Whatever deadline the last operation had is long gone.
+        self.deadline = None;
+        if let Err(e) = self.close() {
+            warn!("failed to close the store cleanly: {e}");
+        }
+    }
+}
+
+/// Wall-clock time in milliseconds since the Unix epoch, which expiries are measured in.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/// The number `n` of a log file named `n.log`.
+fn log_number(path: &Path) -> Option<u64> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(".log"))
+        .and_then(file_number)
+}
+
+/// The number `n` of a hint file named `n.hint`.
+fn hint_number(path: &Path) -> Option<u64> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(".hint"))
+        .and_then(file_number)
+}
+
+/// The number a log or hint file is named with, written the way the store
+/// writes it: decimal digits without a sign or leading zeros, from 1.
+///
+/// Anything else, like `05`, would name a different file than the number it
+/// parses to.
+pub(crate) fn file_number(num: &str) -> Option<u64> {
+    if num.starts_with('0') || !num.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    num.parse().ok()
+}
+
+/// Byte accounting of the records in the log files, overall and per file.
+#[derive(Default)]
+struct LogStats {
+    /// Bytes taken up by stale records.
+    stale: u64,
+    files: HashMap<Arc<Path>, FileStats>,
+}
+
+#[derive(Default)]
+struct FileStats {
+    total: u64,
+    stale: u64,
+}
+
+impl LogStats {
+    /// Account for a record written at `idx`.
+    fn add(&mut self, idx: &FileIndex) {
+        self.files
+            .entry(idx.shared_path().clone())
+            .or_default()
+            .total += idx.len();
+    }
+
+    /// Account for the record at `idx` being superseded.
+    fn mark_stale(&mut self, idx: &FileIndex) {
+        self.stale += idx.len();
+        self.files
+            .entry(idx.shared_path().clone())
+            .or_default()
+            .stale += idx.len();
+    }
+}
+
+/// Reads from a [`KvStore`] concurrently with its writer and other readers.
+///
+/// Readers share the index behind a read lock and each keeps its own handles
+/// of the log files, so reads never wait on one another. A single `get` only
+/// holds the lock for its lookup, while a `scan` holds it throughout to see
+/// one snapshot of the index.
+pub(crate) struct KvStoreReader {
+    storage: Arc<dyn Storage>,
+    idx: Arc<RwLock<HashMap<String, FileIndex>>>,
+    generation: Arc<AtomicU64>,
+    expired: Arc<Mutex<Vec<String>>>,
+    cache: RefCell<ReaderCache>,
+}
+
+#[derive(Default)]
+struct ReaderCache {
+    generation: u64,
+    readers: LogReaders,
+}
+
+impl Clone for KvStoreReader {
+    /// The clone shares the index but opens its own file handles.
+    fn clone(&self) -> Self {
+        Self {
+            storage: self.storage.clone(),
+            idx: self.idx.clone(),
+            generation: self.generation.clone(),
+            expired: self.expired.clone(),
+            cache: RefCell::new(ReaderCache::default()),
         }
+    }
+}
+
+impl KvStoreReader {
+    /// A reader of the logs at `path` that never writes to them, with an
+    /// index of its own, see [`crate::KvStore::open_readonly`].
+    pub(crate) fn open_readonly(storage: Arc<dyn Storage>, path: &Path) -> Result<KvStoreReader> {
+        let idx = KvStoreReader::load_readonly(&*storage, path)?;
+        Ok(KvStoreReader {
+            storage,
+            idx: Arc::new(RwLock::new(idx)),
+            generation: Arc::default(),
+            expired: Arc::default(),
+            cache: RefCell::new(ReaderCache::default()),
+        })
+    }
+
+    /// Replay the logs at `path` again, replacing the index of a reader
+    /// opened with [`KvStoreReader::open_readonly`].
+    pub(crate) fn reload(&self, path: &Path) -> Result<()> {
+        let idx = KvStoreReader::load_readonly(&*self.storage, path)?;
+        *self.idx.write().unwrap() = idx;
+        // No writer drops them, and the new index is fresh anyway.
+        self.expired.lock().unwrap().clear();
+        // The files may have been replaced, let every clone reopen them.
+        self.generation.fetch_add(1, Ordering::SeqCst);
         Ok(())
     }
+
+    fn load_readonly(storage: &dyn Storage, path: &Path) -> Result<HashMap<String, FileIndex>> {
+        let _lock = storage.lock(&path.join(LOCK_FILE), LockMode::Shared)?;
+        // Records are still checked as they are read.
+        Ok(KvStore::load(storage, path, VerifyLevel::None, false)?.idx)
+    }
+
+    /// Get the `value` for `key`
+    pub(crate) fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key)?.map(utf8).transpose()
+    }
+
+    /// Get the `value` for `key` as bytes.
+    ///
+    /// The index is only locked to look `key` up, not while reading the
+    /// record, so a slow read holds up no writer waiting to update the index.
+    pub(crate) fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        loop {
+            let idx = match self.idx.read().unwrap().get(&key) {
+                Some(idx) if idx.is_expired(now_millis()) => {
+                    // The writer drops it from the index on its next write.
+                    self.expired.lock().unwrap().push(key);
+                    return Ok(None);
+                }
+                Some(idx) => idx.clone(),
+                None => return Ok(None),
+            };
+            match self.readers().read(&*self.storage, &idx) {
+                Ok(Record::Set(_, value, _)) => return Ok(Some(value)),
+                Ok(_) => return Ok(None),
+                // A compaction moved the record to a new file and deleted
+                // this one since the lookup, so look it up again. A log that
+                // is gone for any other reason is still an error.
+                Err(KvsError::IOError(e))
+                    if e.kind() == io::ErrorKind::NotFound && self.moved(&key, &idx) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Whether the record of `key` no longer lives in the file of `old`.
+    fn moved(&self, key: &str, old: &FileIndex) -> bool {
+        self.idx
+            .read()
+            .unwrap()
+            .get(key)
+            .is_none_or(|idx| idx.path() != old.path())
+    }
+
+    /// Check whether `key` is in the index, without touching the logs.
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        self.idx
+            .read()
+            .unwrap()
+            .get(key)
+            .is_some_and(|idx| !idx.is_expired(now_millis()))
+    }
+
+    /// The number of keys in the index that haven't expired.
+    pub(crate) fn len(&self) -> usize {
+        let now = now_millis();
+        self.idx
+            .read()
+            .unwrap()
+            .values()
+            .filter(|idx| !idx.is_expired(now))
+            .count()
+    }
+
+    /// Get the pairs whose key starts with `prefix`, ordered by key.
+    pub(crate) fn scan(
+        &self,
+        prefix: String,
+        deadline: Option<Instant>,
+    ) -> Result<Vec<(String, String)>> {
+        // One read lock over the whole scan, so it sees a single snapshot.
+        let idx = self.idx.read().unwrap();
+        let now = now_millis();
+        let mut entries: Vec<(&String, &FileIndex)> = idx
+            .iter()
+            .filter(|(key, idx)| key.starts_with(&prefix) && !idx.is_expired(now))
+            .collect();
+        entries.sort_by_key(|(key, _)| *key);
+        let mut readers = self.readers();
+        let mut pairs = Vec::with_capacity(entries.len());
+        for (key, idx) in entries {
+            check_deadline(deadline)?;
+            if let Record::Set(_, value, _) = readers.read(&*self.storage, idx)? {
+                pairs.push((key.clone(), utf8(value)?));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Every key with where its latest record lives, ordered by key.
+    pub(crate) fn index(&self) -> Vec<(String, FileIndex)> {
+        let mut entries: Vec<_> = self
+            .idx
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, idx)| (key.clone(), idx.clone()))
+            .collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries
+    }
+
+    /// The file handles of this reader, dropped whenever a compaction retired files.
+    fn readers(&self) -> RefMut<'_, LogReaders> {
+        let generation = self.generation.load(Ordering::SeqCst);
+        let mut cache = self.cache.borrow_mut();
+        if cache.generation != generation {
+            *cache = ReaderCache {
+                generation,
+                readers: LogReaders::default(),
+            };
+        }
+        RefMut::map(cache, |cache| &mut cache.readers)
+    }
+}
+
+/// Open handles of log files, one per file.
+///
+/// They are opened read-only, apart from the writer's append handle, even for
+/// the file being appended to, and every read seeks to its record first. So a
+/// read never shares a file offset with an append running at the same time.
+#[derive(Default)]
+struct LogReaders {
+    handles: HashMap<PathBuf, Box<dyn LogReader>>,
+}
+
+impl LogReaders {
+    /// Read the record at `idx`, opening its file on first use.
+    fn read(&mut self, storage: &dyn Storage, idx: &FileIndex) -> Result<Record> {
+        if !self.handles.contains_key(idx.path()) {
+            let handle = storage.open_read(idx.path())?;
+            self.handles.insert(idx.path().to_path_buf(), handle);
+        }
+        let handle = self.handles.get_mut(idx.path()).unwrap();
+        LogHelper::read(&mut **handle, idx)
+    }
+
+    /// Drop the handle of the file at `path`.
+    fn remove(&mut self, path: &Path) {
+        self.handles.remove(path);
+    }
 }
@@ -1,5 +1,7 @@
 pub mod protocol;
 
+pub mod client;
+
 pub mod thread_pool;
 
 pub mod engine;
@@ -8,7 +10,29 @@ pub mod kv_store;
 
 pub mod error;
 
+pub mod data_dir;
+
+pub mod tls;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+#[cfg(feature = "async")]
+pub mod async_kvs;
+
 mod log_helper;
 
-pub use crate::engine::{KvStore, KvsEngine, SledEngine};
+mod storage;
+
+pub use crate::engine::{
+    BatchOp, ExportEntry, FlushPolicy, KvStore, KvsEngine, LockContention, MemoryEngine, ModifyOp,
+    ReadOnlyKvStore, SledEngine,
+};
 pub use crate::error::{KvsError, Result};
+pub use crate::kv_store::{
+    Change, ChangeKind, Codec, CompactionStrategy, Compression, KvStoreConfig, LogCheck, LogFormat,
+    OpenMode, SizeLimits, VerifyLevel,
+};
+pub use crate::log_helper::FileIndex;
+#[cfg(feature = "fault-injection")]
+pub use crate::storage::FaultyStorage;
@@ -1,87 +1,785 @@
 use crate::error::KvsError;
 use crate::error::Result;
-use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use crate::kv_store::{Codec, Compression, LogFormat};
+use crate::storage::{LogReader, LogWriter, Storage};
+use crc32fast::Hasher;
+use serde::Deserialize;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Tag byte starting a `Set` record.
+const SET_TAG: u8 = 1;
+/// Tag byte starting a `Remove` record.
+const REMOVE_TAG: u8 = 2;
+/// Tag byte starting a `Set` record with an expiry.
+const SET_EXPIRING_TAG: u8 = 3;
+/// Set on the tag byte of a `Set` record whose value is compressed.
+const COMPRESSED_FLAG: u8 = 0x80;
+/// Codec byte of a value compressed with [`Codec::Zstd`].
+const ZSTD_CODEC: u8 = 1;
+/// Magic bytes starting the header of a log file, followed by its format tag.
+const MAGIC: &[u8; 3] = b"KVS";
+/// Length of the magic bytes and the format tag.
+pub(crate) const HEADER_LEN: u64 = 4;
+/// Format tag of a [`Format::Json`] file.
+const JSON_FORMAT: u8 = 1;
+/// Format tag of a [`Format::Binary`] file.
+const BINARY_FORMAT: u8 = 2;
+/// Magic bytes starting a hint file, followed by its version.
+const HINT_MAGIC: &[u8; 3] = b"KVH";
+/// Version byte of the hint files written now.
+const HINT_VERSION: u8 = 1;
+
+/// How the records of a log file are encoded.
+///
+/// Files are written as [`Format::Binary`] or [`Format::Text`], see
+/// [`LogFormat`]. [`Format::Json`] files are still read so files from older
+/// versions keep working without a migration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Format {
+    /// `set <key> <value>` and `rm <key>` lines, in files without a header.
+    Text,
+    /// One JSON encoded [`Record`] per line.
+    Json,
+    /// Checksummed binary frames, see [`LogHelper`].
+    Binary,
+}
+
+impl From<LogFormat> for Format {
+    fn from(format: LogFormat) -> Format {
+        match format {
+            LogFormat::Text => Format::Text,
+            LogFormat::Binary => Format::Binary,
+        }
+    }
+}
+
+impl Format {
+    fn from_tag(tag: u8) -> Option<Format> {
+        match tag {
+            JSON_FORMAT => Some(Format::Json),
+            BINARY_FORMAT => Some(Format::Binary),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub(crate) enum Record {
+    /// A key, its value and when it expires, in milliseconds since the Unix epoch.
+    ///
+    /// Values are bytes, only the string API requires them to be UTF-8.
+    Set(String, Vec<u8>, Option<u64>),
+    Remove(String),
+}
+
+impl Record {
+    /// When the record stops being visible, `None` if never.
+    fn expires_at(&self) -> Option<u64> {
+        match self {
+            Record::Set(_, _, expires_at) => *expires_at,
+            Record::Remove(_) => None,
+        }
+    }
+}
+
+/// A record of a [`Format::Json`] file, from before records could expire.
+#[derive(Deserialize)]
+enum JsonRecord {
     Set(String, String),
     Remove(String),
 }
 
-#[derive(Debug)]
-pub(crate) struct FileIndex {
-    path: PathBuf,
+/// Where the latest record of a key lives in the log files.
+#[derive(Debug, Clone)]
+pub struct FileIndex {
+    /// Shared by the indexes of every record in the file.
+    path: Arc<Path>,
     offset: u64,
+    len: u64,
+    format: Format,
+    expires_at: Option<u64>,
+}
+
+impl FileIndex {
+    /// The log file the record lives in.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The log file the record lives in, to share without copying it.
+    pub(crate) fn shared_path(&self) -> &Arc<Path> {
+        &self.path
+    }
+
+    /// The position of the record in its file, in bytes.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The size of the record in bytes.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// When the record expires, in milliseconds since the Unix epoch.
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    /// Whether the record expired by `now`, in milliseconds since the Unix epoch.
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// The records of a log file, as read on open.
+pub(crate) struct LogFile {
+    pub(crate) records: Vec<(Record, FileIndex)>,
+    /// Length of the file up to the end of its last valid record.
+    pub(crate) valid_len: u64,
+    pub(crate) format: Format,
 }
+
+/// The index of a compacted log as its compaction left it, see
+/// [`LogHelper::write_hint`].
+pub(crate) struct Hint {
+    /// Length of the log when the hint was written, later records are replayed.
+    pub(crate) log_len: u64,
+    pub(crate) entries: Vec<(String, FileIndex)>,
+}
+
+/// Reads and writes log records.
+///
+/// A log file starts with a header of the magic bytes `KVS` and a byte telling
+/// its [`Format`]; files without one are in the original text format.
+///
+/// Each binary record is framed as a tag byte, the varint encoded key length and the
+/// key bytes, followed for `Set` records by the varint encoded value length and
+/// the value bytes, and for a `Set` that expires by the little-endian expiry.
+/// No delimiter is needed, so keys and values may hold any byte.
+/// A `Set` whose tag has the high bit set holds a compressed value, preceded
+/// by a byte telling its [`Codec`].
+/// The frame ends with the little-endian CRC32 of everything before it.
 pub struct LogHelper {}
 
 impl LogHelper {
-    pub(crate) fn read(idx: &FileIndex) -> Result<Record> {
-        let mut file = File::open(idx.path.clone())?;
+    /// Read the record at `idx` from `file`, an open handle of the file it points into.
+    ///
+    /// Exactly the `len` bytes of the record are read, whatever its format, and
+    /// a record that doesn't fill them is corrupt.
+    pub(crate) fn read(file: &mut dyn LogReader, idx: &FileIndex) -> Result<Record> {
         file.seek(SeekFrom::Start(idx.offset))?;
-        let mut reader = BufReader::new(file);
-        let mut buf = String::new();
-        reader.read_line(&mut buf)?;
-        LogHelper::deserialize(&buf)
+        let mut buf = Vec::with_capacity(idx.len as usize);
+        file.take(idx.len).read_to_end(&mut buf)?;
+        if idx.format != Format::Binary {
+            return LogHelper::parse_line(idx.format, &buf);
+        }
+        let mut reader = RecordReader::new(&buf[..], idx.offset, true);
+        match LogHelper::deserialize(&mut reader, idx)? {
+            Some(record) if reader.pos == idx.offset + idx.len => Ok(record),
+            _ => Err(KvsError::DeserializeError),
+        }
     }
 
-    pub(crate) fn read_all(path: PathBuf) -> Result<Vec<(Record, FileIndex)>> {
-        let file = File::open(path.clone())?;
+    /// Read every record of the log at `path` from offset `from`, or from
+    /// the first one if `from` is before it, checking their checksums if `verify`.
+    ///
+    /// Only a binary log can be read from part way. A last record whose
+    /// declared length runs past the end of the log, with no whole record
+    /// after its start, is a torn write and ends the log; corruption anywhere
+    /// else is an error. Unverified records are still checked when
+    /// [`LogHelper::read`] reads them.
+    pub(crate) fn read_all(
+        storage: &dyn Storage,
+        path: PathBuf,
+        from: u64,
+        verify: bool,
+    ) -> Result<LogFile> {
+        let len = storage.len(&path)?;
+        let mut file = storage.open_read(&path)?;
+        let path: Arc<Path> = path.into();
+        let (format, mut start) = LogHelper::read_header(&mut *file)?;
+        if format != Format::Binary {
+            if from > start {
+                return Err(KvsError::DeserializeError);
+            }
+            return LogHelper::read_lines(file, path, format);
+        }
+        if from > start {
+            start = file.seek(SeekFrom::Start(from))?;
+        }
         let mut records = Vec::new();
-        let mut reader = BufReader::new(file);
-        let mut offset = 0;
+        let mut reader = RecordReader::new(BufReader::new(file), start, verify);
 
         loop {
-            let mut buf = Vec::new();
-            let n = reader.read_until(b'\n', &mut buf)?;
-            if n == 0 {
-                break;
+            let mut idx = FileIndex {
+                path: path.clone(),
+                offset: reader.pos,
+                len: 0,
+                format,
+                expires_at: None,
+            };
+            match LogHelper::deserialize(&mut reader, &idx) {
+                Ok(Some(record)) => {
+                    idx.len = reader.pos - idx.offset;
+                    idx.expires_at = record.expires_at();
+                    records.push((record, idx));
+                }
+                Ok(None) => break,
+                Err(KvsError::DeserializeError | KvsError::ChecksumMismatch { .. })
+                    if reader.pos >= len =>
+                {
+                    let mut tail = Vec::new();
+                    let mut file = storage.open_read(&path)?;
+                    file.seek(SeekFrom::Start(idx.offset))?;
+                    file.read_to_end(&mut tail)?;
+                    if !is_torn(&tail) {
+                        return Err(KvsError::ChecksumMismatch {
+                            file: path.to_path_buf(),
+                            offset: idx.offset,
+                        });
+                    }
+                    return Ok(LogFile {
+                        records,
+                        valid_len: idx.offset,
+                        format,
+                    });
+                }
+                Err(e) => return Err(e),
             }
+        }
 
-            let line_str = String::from_utf8_lossy(&buf);
+        Ok(LogFile {
+            records,
+            valid_len: reader.pos,
+            format,
+        })
+    }
 
-            records.push((
-                LogHelper::deserialize(&line_str)?,
+    /// Write the header of a new log file in `format`, if it has one.
+    pub(crate) fn write_header(file: &mut dyn LogWriter, format: Format) -> Result<()> {
+        if format == Format::Binary {
+            let mut header = MAGIC.to_vec();
+            header.push(BINARY_FORMAT);
+            file.write_all(&header)?;
+        }
+        Ok(())
+    }
+
+    /// Write the hint of a log `log_len` bytes long whose records are indexed
+    /// by `entries`.
+    ///
+    /// A hint starts with the magic bytes `KVH`, its version byte and the
+    /// little-endian log length. Each entry follows as the varint
+    /// length-prefixed key, the little-endian offset and length of its record,
+    /// and a byte telling whether the little-endian expiry comes next. The
+    /// file ends with the little-endian CRC32 of everything before it.
+    pub(crate) fn write_hint(
+        file: &mut dyn LogWriter,
+        log_len: u64,
+        entries: &[(String, FileIndex)],
+    ) -> Result<()> {
+        let mut buf = HINT_MAGIC.to_vec();
+        buf.push(HINT_VERSION);
+        buf.extend_from_slice(&log_len.to_le_bytes());
+        for (key, idx) in entries {
+            write_bytes(&mut buf, key.as_bytes());
+            buf.extend_from_slice(&idx.offset.to_le_bytes());
+            buf.extend_from_slice(&idx.len.to_le_bytes());
+            match idx.expires_at {
+                Some(expires_at) => {
+                    buf.push(1);
+                    buf.extend_from_slice(&expires_at.to_le_bytes());
+                }
+                None => buf.push(0),
+            }
+        }
+        let checksum = crc32fast::hash(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+        file.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Read the hint at `path`, indexing its entries into the log at `log_path`.
+    ///
+    /// A hint that is cut short, fails its checksum or has another version
+    /// is a deserialize error.
+    pub(crate) fn read_hint(
+        storage: &dyn Storage,
+        path: &Path,
+        log_path: Arc<Path>,
+    ) -> Result<Hint> {
+        let mut buf = Vec::new();
+        storage.open_read(path)?.read_to_end(&mut buf)?;
+        let (body, stored) = buf
+            .split_last_chunk::<4>()
+            .ok_or(KvsError::DeserializeError)?;
+        if crc32fast::hash(body) != u32::from_le_bytes(*stored) {
+            return Err(KvsError::DeserializeError);
+        }
+        let mut body = match body.split_at_checked(HINT_MAGIC.len() + 1) {
+            Some((header, body)) if header[..HINT_MAGIC.len()] == HINT_MAGIC[..] => {
+                if header[HINT_MAGIC.len()] != HINT_VERSION {
+                    return Err(KvsError::DeserializeError);
+                }
+                body
+            }
+            _ => return Err(KvsError::DeserializeError),
+        };
+        let log_len = read_u64(&mut body)?;
+        let mut entries = Vec::new();
+        while !body.is_empty() {
+            let key = into_string(read_bytes(&mut body)?)?;
+            let offset = read_u64(&mut body)?;
+            let len = read_u64(&mut body)?;
+            let mut expiring = [0u8];
+            read_exact(&mut body, &mut expiring)?;
+            let expires_at = match expiring[0] {
+                0 => None,
+                1 => Some(read_u64(&mut body)?),
+                _ => return Err(KvsError::DeserializeError),
+            };
+            entries.push((
+                key,
                 FileIndex {
-                    path: path.clone(),
+                    path: log_path.clone(),
                     offset,
+                    len,
+                    format: Format::Binary,
+                    expires_at,
                 },
             ));
+        }
+        Ok(Hint { log_len, entries })
+    }
 
-            offset += n as u64; // 精准，因为 n 包含 '\n'
+    /// Find the format of the log `file` and seek past its header.
+    ///
+    /// A file too short to hold a header, but starting like one, is a header
+    /// torn while being written and read as an empty binary log.
+    fn read_header(file: &mut dyn LogReader) -> Result<(Format, u64)> {
+        let mut header = Vec::new();
+        (&mut *file).take(HEADER_LEN).read_to_end(&mut header)?;
+        let (format, start) = match header.split_at_checked(MAGIC.len()) {
+            Some((magic, tag)) if magic == MAGIC => match tag.first() {
+                Some(&tag) => (
+                    Format::from_tag(tag).ok_or(KvsError::DeserializeError)?,
+                    HEADER_LEN,
+                ),
+                None => (Format::Binary, 0),
+            },
+            _ if MAGIC.starts_with(&header) => (Format::Binary, 0),
+            _ => (Format::Text, 0),
+        };
+        file.seek(SeekFrom::Start(start))?;
+        Ok((format, start))
+    }
+
+    /// Read every record of a line based log, positioned after its header.
+    ///
+    /// A last line without its newline is a torn write and ends the log.
+    fn read_lines(file: Box<dyn LogReader>, path: Arc<Path>, format: Format) -> Result<LogFile> {
+        let mut reader = BufReader::new(file);
+        let mut records = Vec::new();
+        let mut offset = reader.stream_position()?;
+        loop {
+            let mut line = Vec::new();
+            let n = reader.read_until(b'\n', &mut line)? as u64;
+            if n == 0 || line.last() != Some(&b'\n') {
+                return Ok(LogFile {
+                    records,
+                    valid_len: offset,
+                    format,
+                });
+            }
+            let idx = FileIndex {
+                path: path.clone(),
+                offset,
+                len: n,
+                format,
+                expires_at: None,
+            };
+            records.push((LogHelper::parse_line(format, &line)?, idx));
+            offset += n;
         }
+    }
 
-        Ok(records)
+    /// Decode a record of a line based format, newline included.
+    fn parse_line(format: Format, line: &[u8]) -> Result<Record> {
+        let line = std::str::from_utf8(line)
+            .map_err(|_| KvsError::DeserializeError)?
+            .trim_end_matches('\n');
+        match format {
+            Format::Json => match serde_json::from_str(line) {
+                Ok(JsonRecord::Set(key, value)) => Ok(Record::Set(key, value.into_bytes(), None)),
+                Ok(JsonRecord::Remove(key)) => Ok(Record::Remove(key)),
+                Err(_) => Err(KvsError::DeserializeError),
+            },
+            _ => match line.split(' ').collect::<Vec<_>>()[..] {
+                ["set", key, value] => Ok(Record::Set(key.to_owned(), value.into(), None)),
+                ["rm", key] => Ok(Record::Remove(key.to_owned())),
+                _ => Err(KvsError::DeserializeError),
+            },
+        }
     }
-    pub(crate) fn write(file: &mut File, path: PathBuf, record: &Record) -> Result<FileIndex> {
-        let serialized_record = LogHelper::serialize(record)?;
-        let offset = file.metadata()?.len();
-        file.write(serialized_record.as_bytes())?;
-        Ok(FileIndex { path, offset })
+
+    /// Append `record` to `file`, a log in `format` whose length is `pos`,
+    /// returning its index.
+    ///
+    /// `pos` is advanced past the record. If the write fails, `pos` no longer
+    /// matches the file and the file must not be appended to again. A record
+    /// the format can't hold fails before anything is written, see
+    /// [`LogHelper::check`].
+    pub(crate) fn write(
+        file: &mut dyn LogWriter,
+        path: &Arc<Path>,
+        pos: &mut u64,
+        record: &Record,
+        format: Format,
+        compression: Option<&Compression>,
+    ) -> Result<FileIndex> {
+        let serialized_record = match format {
+            Format::Binary => LogHelper::serialize(record, compression)?,
+            _ => LogHelper::serialize_line(record)?,
+        };
+        let offset = *pos;
+        file.write_all(&serialized_record)?;
+        *pos += serialized_record.len() as u64;
+        Ok(FileIndex {
+            path: path.clone(),
+            offset,
+            len: serialized_record.len() as u64,
+            format,
+            expires_at: record.expires_at(),
+        })
     }
 
-    fn serialize(record: &Record) -> Result<String> {
-        match record {
-            Record::Set(key, value) => Ok(format!("set {key} {value}\n")),
-            Record::Remove(key) => Ok(format!("rm {key}\n")),
+    /// Check that a log in `format` can hold `record`.
+    ///
+    /// A text log holds `set <key> <value>` and `rm <key>` lines only, so
+    /// neither may contain a space or a newline, values must be UTF-8 and
+    /// nothing can expire. Binary logs hold any record.
+    pub(crate) fn check(format: Format, record: &Record) -> Result<()> {
+        if format == Format::Binary {
+            return Ok(());
         }
+        let plain = |s: &str| !s.contains([' ', '\n']);
+        let (key, value) = match record {
+            Record::Set(_, _, Some(_)) => {
+                return Err(KvsError::UnsupportedByFormat("an expiring key"));
+            }
+            Record::Set(key, value, None) => (key, Some(value)),
+            Record::Remove(key) => (key, None),
+        };
+        if !plain(key) {
+            return Err(KvsError::UnsupportedByFormat(
+                "a key with a space or newline",
+            ));
+        }
+        match value.map(|value| std::str::from_utf8(value)) {
+            Some(Err(_)) => Err(KvsError::UnsupportedByFormat("a value that isn't UTF-8")),
+            Some(Ok(value)) if !plain(value) => Err(KvsError::UnsupportedByFormat(
+                "a value with a space or newline",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Encode `record` as a line of a text log.
+    fn serialize_line(record: &Record) -> Result<Vec<u8>> {
+        LogHelper::check(Format::Text, record)?;
+        Ok(match record {
+            Record::Set(key, value, _) => [b"set ", key.as_bytes(), b" ", value, b"\n"].concat(),
+            Record::Remove(key) => [b"rm ", key.as_bytes(), b"\n"].concat(),
+        })
     }
 
-    fn deserialize(buf: &str) -> Result<Record> {
-        let tokens: Vec<&str> = buf.trim().split(' ').collect();
-        if tokens.is_empty() {
-            Err(KvsError::DeserializeError)
-        } else {
-            match tokens[0] {
-                "set" if tokens.len() == 3 => {
-                    Ok(Record::Set(tokens[1].to_string(), tokens[2].to_string()))
+    fn serialize(record: &Record, compression: Option<&Compression>) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        match record {
+            Record::Set(key, value, expires_at) => {
+                let tag = match expires_at {
+                    Some(_) => SET_EXPIRING_TAG,
+                    None => SET_TAG,
+                };
+                let compressed = match compression {
+                    Some(compression) if value.len() > compression.threshold => {
+                        Some(compress(compression.codec, value)?)
+                            .filter(|(_, compressed)| compressed.len() < value.len())
+                    }
+                    _ => None,
+                };
+                match compressed {
+                    Some((codec, compressed)) => {
+                        buf.push(tag | COMPRESSED_FLAG);
+                        write_bytes(&mut buf, key.as_bytes());
+                        buf.push(codec);
+                        write_bytes(&mut buf, &compressed);
+                    }
+                    None => {
+                        buf.push(tag);
+                        write_bytes(&mut buf, key.as_bytes());
+                        write_bytes(&mut buf, value);
+                    }
+                }
+                if let Some(expires_at) = expires_at {
+                    buf.extend_from_slice(&expires_at.to_le_bytes());
                 }
-                "rm" if tokens.len() == 2 => Ok(Record::Remove(tokens[1].to_string())),
-                _ => Err(KvsError::DeserializeError),
+            }
+            Record::Remove(key) => {
+                buf.push(REMOVE_TAG);
+                write_bytes(&mut buf, key.as_bytes());
             }
         }
+        let checksum = crc32fast::hash(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+        Ok(buf)
     }
+
+    /// Decode the record at `idx`, or `None` at the end of the log.
+    fn deserialize(
+        reader: &mut RecordReader<impl Read>,
+        idx: &FileIndex,
+    ) -> Result<Option<Record>> {
+        let corrupt = || KvsError::ChecksumMismatch {
+            file: idx.path.to_path_buf(),
+            offset: idx.offset,
+        };
+        reader.hasher = Hasher::new();
+        let mut tag = [0u8];
+        if reader.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        let key = read_bytes(reader)?;
+        let compressed = tag[0] & COMPRESSED_FLAG != 0;
+        let codec = match (compressed, tag[0] & !COMPRESSED_FLAG) {
+            (true, SET_TAG | SET_EXPIRING_TAG) => {
+                let mut codec = [0u8];
+                read_exact(reader, &mut codec)?;
+                Some(codec[0])
+            }
+            _ => None,
+        };
+        let (value, expires_at) = match tag[0] & !COMPRESSED_FLAG {
+            SET_TAG => (Some(read_bytes(reader)?), None),
+            SET_EXPIRING_TAG => {
+                let value = read_bytes(reader)?;
+                let mut expires_at = [0u8; 8];
+                read_exact(reader, &mut expires_at)?;
+                (Some(value), Some(u64::from_le_bytes(expires_at)))
+            }
+            REMOVE_TAG if !compressed => (None, None),
+            _ => return Err(corrupt()),
+        };
+        let checksum = std::mem::take(&mut reader.hasher).finalize();
+        let mut stored = [0u8; 4];
+        read_exact(reader, &mut stored)?;
+        if reader.verify && u32::from_le_bytes(stored) != checksum {
+            return Err(corrupt());
+        }
+
+        let key = into_string(key)?;
+        let value = match (value, codec) {
+            (Some(value), Some(codec)) => Some(decompress(codec, &value)?),
+            (value, _) => value,
+        };
+        Ok(Some(match value {
+            Some(value) => Record::Set(key, value, expires_at),
+            None => Record::Remove(key),
+        }))
+    }
+}
+
+/// Tracks the position in the log and the checksum of the record being read.
+struct RecordReader<R> {
+    inner: R,
+    pos: u64,
+    hasher: Hasher,
+    /// Whether checksums are computed and checked at all.
+    verify: bool,
+}
+
+impl<R: Read> RecordReader<R> {
+    fn new(inner: R, pos: u64, verify: bool) -> Self {
+        Self {
+            inner,
+            pos,
+            hasher: Hasher::new(),
+            verify,
+        }
+    }
+}
+
+impl<R: Read> Read for RecordReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if self.verify {
+            self.hasher.update(&buf[..n]);
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Append `bytes` prefixed with its varint encoded length.
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    let mut n = bytes.len() as u64;
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+    buf.extend_from_slice(bytes);
+}
+
+/// Read a varint length-prefixed byte string.
+fn read_bytes(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let mut n = 0u64;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0u8];
+        read_exact(reader, &mut byte)?;
+        if shift >= 64 {
+            return Err(KvsError::DeserializeError);
+        }
+        n |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    let mut bytes = Vec::new();
+    if reader.take(n).read_to_end(&mut bytes)? as u64 != n {
+        return Err(KvsError::DeserializeError);
+    }
+    Ok(bytes)
+}
+
+/// Whether `tail`, the end of a log from a record that can't be read, is a
+/// record torn while being appended: one that declares more bytes than are
+/// left, with no whole record whose checksum holds starting after it.
+///
+/// The records after one with a corrupted length would run back to back to
+/// the end of the log. One pass from the end finds where such runs start, and
+/// only the first of them is checked, so this takes time linear in the tail.
+fn is_torn(tail: &[u8]) -> bool {
+    if frame_len(tail).is_none_or(|n| n <= tail.len() as u64) {
+        return false;
+    }
+    // Whether the records starting at each offset end exactly at the end.
+    let mut runs_to_end = vec![false; tail.len() + 1];
+    runs_to_end[tail.len()] = true;
+    for start in (1..tail.len()).rev() {
+        runs_to_end[start] = frame_len(&tail[start..])
+            .is_some_and(|n| n <= (tail.len() - start) as u64 && runs_to_end[start + n as usize]);
+    }
+    let Some(mut start) = (1..tail.len()).find(|&start| runs_to_end[start]) else {
+        return true;
+    };
+    while start < tail.len() {
+        if is_whole_frame(&tail[start..]) {
+            return false;
+        }
+        start += frame_len(&tail[start..]).expect("checked to run to the end") as usize;
+    }
+    true
+}
+
+/// Whether a record whose checksum holds starts `buf`.
+fn is_whole_frame(buf: &[u8]) -> bool {
+    match frame_len(buf) {
+        Some(n) if n <= buf.len() as u64 => {
+            let (body, stored) = buf[..n as usize].split_at(n as usize - 4);
+            crc32fast::hash(body).to_le_bytes() == stored
+        }
+        _ => false,
+    }
+}
+
+/// The length the record starting `buf` declares, checksum included, which
+/// may run past its end, or `None` if it doesn't start with a record's tag.
+///
+/// A record cut short before its lengths are known runs past the end.
+fn frame_len(buf: &[u8]) -> Option<u64> {
+    let past_end = buf.len() as u64 + 1;
+    let Some(&tag) = buf.first() else {
+        return Some(past_end);
+    };
+    let compressed = tag & COMPRESSED_FLAG != 0;
+    let (set, expiry) = match tag & !COMPRESSED_FLAG {
+        SET_TAG => (true, 0),
+        SET_EXPIRING_TAG => (true, 8),
+        REMOVE_TAG if !compressed => (false, 0),
+        _ => return None,
+    };
+    let Some(mut pos) = skip_bytes(buf, 1) else {
+        return Some(past_end);
+    };
+    if set {
+        pos = match skip_bytes(buf, pos.saturating_add(u64::from(compressed))) {
+            Some(end) => end.saturating_add(expiry),
+            None => return Some(past_end),
+        };
+    }
+    Some(pos.saturating_add(4))
+}
+
+/// The end of the varint length-prefixed byte string at `pos` in `buf`, or
+/// `None` if `buf` ends within its length. A length longer than a `u64`
+/// saturates, running past any end.
+fn skip_bytes(buf: &[u8], pos: u64) -> Option<u64> {
+    let mut n = 0u64;
+    let mut shift = 0;
+    let mut pos = pos;
+    loop {
+        if shift >= 64 {
+            return Some(u64::MAX);
+        }
+        let byte = *buf.get(usize::try_from(pos).ok()?)?;
+        pos += 1;
+        n |= u64::from(byte & 0x7f)
+            .checked_shl(shift)
+            .unwrap_or(u64::MAX);
+        if byte & 0x80 == 0 {
+            return Some(pos.saturating_add(n));
+        }
+        shift += 7;
+    }
+}
+
+/// Compress `value` with `codec`, returning the codec byte to store with it.
+fn compress(codec: Codec, value: &[u8]) -> Result<(u8, Vec<u8>)> {
+    match codec {
+        Codec::Zstd(level) => Ok((ZSTD_CODEC, zstd::bulk::compress(value, level)?)),
+    }
+}
+
+/// Decompress a value stored with the `codec` byte.
+fn decompress(codec: u8, value: &[u8]) -> Result<Vec<u8>> {
+    match codec {
+        ZSTD_CODEC => zstd::decode_all(value).map_err(|_| KvsError::DeserializeError),
+        _ => Err(KvsError::DeserializeError),
+    }
+}
+
+fn into_string(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|_| KvsError::DeserializeError)
+}
+
+/// Read a little-endian `u64`.
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    read_exact(reader, &mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Like [`Read::read_exact`], but a record cut short is a deserialize error.
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => KvsError::DeserializeError,
+        _ => KvsError::IOError(e),
+    })
 }
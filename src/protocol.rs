@@ -3,30 +3,254 @@
 //! This module defines the message types used for communication between
 //! the key-value store client and server over TCP connections.
 
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use base64::prelude::{BASE64_STANDARD, Engine};
 use serde::{Deserialize, Serialize};
 
+use crate::engine::{FlushPolicy, LockContention, ModifyOp};
+use crate::error::{KvsError, Result};
+use crate::kv_store::KvStoreConfig;
+
+/// The version of the protocol spoken by this build, sent in
+/// [`Request::Hello`]. Bump it whenever [`Request`] or [`Response`] change
+/// in a way an older peer can't read.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The crate version with the [`PROTOCOL_VERSION`], as `--version` prints it.
+/// The literal must match [`PROTOCOL_VERSION`].
+pub const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), " (protocol 1)");
+
+/// The length from which values are compressed for connections that asked.
+pub const COMPRESS_VALUES_ABOVE: usize = 1024;
+
 /// Client request message.
 ///
 /// Represents operations that clients can request from the server.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
+    /// Tell the server which protocol version the client speaks, before any
+    /// other request. A server speaking another version answers with a
+    /// `BadRequest` error and closes the connection.
+    ///
+    /// Clients that skip it are served as if they speak the server's version.
+    Hello {
+        /// The client's [`PROTOCOL_VERSION`].
+        protocol_version: u32,
+    },
+    /// Present the shared secret of a server started with an auth token.
+    /// Until it is accepted, every request but this one and `Hello` is
+    /// answered with [`ErrorCode::NotAuthenticated`].
+    Auth {
+        /// The server's token.
+        token: String,
+    },
+    /// Ask for the values of later responses on this connection to be
+    /// compressed where that pays off, see [`Response::CompressedValue`].
+    CompressValues {
+        /// Whether to compress from now on.
+        enabled: bool,
+    },
+    /// Ask for removes on this connection to be answered with
+    /// [`Response::Removed`], telling whether the key was there, instead of
+    /// an `Ok` or a [`ErrorCode::NotFound`] error.
+    ReportRemoved {
+        /// Whether to report removes so from now on.
+        enabled: bool,
+    },
     /// Set a key-value pair in the store.
     Set {
         /// The key to set.
         key: String,
         /// The value to associate with the key.
         value: String,
+        /// Picked by the client to make retrying the set safe: the server
+        /// remembers the ids of recent sets, see
+        /// [`ServerConfig::request_id_cache`], and answers a set with a
+        /// remembered id as the first time without setting the key again.
+        /// A failed set is forgotten, as it set nothing.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<u64>,
+    },
+    /// Set a key-value pair that expires after a number of seconds, by
+    /// the server's wall clock.
+    SetEx {
+        /// The key to set.
+        key: String,
+        /// The value to associate with the key.
+        value: String,
+        /// Seconds until the key expires.
+        ttl_secs: u64,
     },
     /// Get the value associated with a key.
     Get {
         /// The key to retrieve.
         key: String,
     },
+    /// Set a key to a value of any bytes, not just UTF-8.
+    SetBytes {
+        /// The key to set.
+        key: String,
+        /// The value, base64 encoded in JSON.
+        #[serde(with = "base64_bytes")]
+        value: Vec<u8>,
+    },
+    /// Get the value of a key as bytes, answered with a [`Response::Bytes`].
+    GetBytes {
+        /// The key to retrieve.
+        key: String,
+    },
     /// Remove a key-value pair from the store.
     Remove {
         /// The key to remove.
         key: String,
     },
+    /// Remove a key and return the value it held, in one atomic step.
+    Take {
+        /// The key to take.
+        key: String,
+    },
+    /// Set a key and return the value it held before, in one atomic step.
+    GetSet {
+        /// The key to set.
+        key: String,
+        /// The value to associate with the key.
+        value: String,
+    },
+    /// Check whether a key is set, without fetching its value.
+    Exists {
+        /// The key to look up.
+        key: String,
+    },
+    /// Count the keys set, answered with a [`Response::Count`].
+    Len,
+    /// Get all key-value pairs whose key starts with a prefix.
+    Scan {
+        /// The prefix of the keys to return.
+        prefix: String,
+    },
+    /// Remove every key starting with a prefix, answered with how many
+    /// were set.
+    RemovePrefix {
+        /// The prefix of the keys to remove.
+        prefix: String,
+    },
+    /// Atomically add to the float stored at a key.
+    IncrByFloat {
+        /// The key holding the float.
+        key: String,
+        /// The amount to add.
+        delta: f64,
+    },
+    /// Atomically add to the integer stored at a key.
+    Incr {
+        /// The key holding the integer.
+        key: String,
+        /// The amount to add.
+        delta: i64,
+    },
+    /// Atomically apply an operation to the value at a key.
+    Modify {
+        /// The key to modify.
+        key: String,
+        /// The operation to apply.
+        op: ModifyOp,
+    },
+    /// Start a transaction, remembering the values `watch` hold now.
+    ///
+    /// Until the [`Request::Commit`], sets and removes are queued and
+    /// answered with `Ok` instead of applied, and other requests that would
+    /// change data are refused with [`ErrorCode::BadRequest`]. Reads are
+    /// served as usual, without seeing the queued writes.
+    Begin {
+        /// The keys that must not change before the commit.
+        watch: Vec<String>,
+    },
+    /// Apply the writes queued since [`Request::Begin`] at once and end the
+    /// transaction, see [`crate::KvsEngine::commit`]. If a watched key
+    /// changed meanwhile, nothing is applied and the answer is an
+    /// [`ErrorCode::Conflict`].
+    Commit,
+    /// Dump every live key, see [`crate::KvsEngine::export`].
+    Export,
+    /// Load a dump written by an export.
+    Import {
+        /// The dump, one JSON [`crate::ExportEntry`] per line.
+        data: String,
+    },
+    /// Force every write made so far to disk, see [`crate::KvsEngine::flush`],
+    /// as before copying the data directory for a backup.
+    Flush,
+    /// Fetch the configuration the server is running with.
+    Config,
+    /// Fetch the server's request and connection counters.
+    Stats,
+    /// Stop accepting new connections, which wait unanswered until a
+    /// `Resume`. Open connections, this one included, are still served.
+    ///
+    /// The server's accept loop stops watching the listeners shortly after
+    /// the `Ok` is sent, but keeps them bound.
+    Drain,
+    /// Accept new connections again after a `Drain`.
+    Resume,
+    /// Turn read-only mode on or off. While it is on, requests that would
+    /// change data are answered with [`ErrorCode::ReadOnly`]; reads, like
+    /// scans and exports, are still served.
+    SetReadOnly {
+        /// Whether to reject mutations from now on.
+        enabled: bool,
+    },
+    /// Stream a [`Response::Event`] for every later change to a key starting
+    /// with `prefix`, after an `Ok`. The connection serves no other request
+    /// afterwards, and keeps a server worker busy until it is closed or the
+    /// server shuts down.
+    ///
+    /// Every change the engine makes is streamed, in the order it made them,
+    /// see [`crate::KvsEngine::watch`]: a prefix removal or an import as one
+    /// event per key, an expired key once the engine drops it.
+    Subscribe {
+        /// The prefix of the keys to watch, empty for every key.
+        prefix: String,
+    },
+    /// Serve `request` only if it can be answered within `deadline_ms`
+    /// milliseconds of the server reading it.
+    ///
+    /// A request still waiting when the deadline passes is not run. Scans,
+    /// exports and imports check it as they go and stop part way, answered
+    /// with [`ErrorCode::DeadlineExceeded`], an import keeping the keys it set
+    /// so far. A compaction set off by a write gives up until a later write.
+    /// Other requests, once started, run to completion.
+    WithDeadline {
+        /// Milliseconds the server has to answer.
+        deadline_ms: u64,
+        /// The request to serve.
+        request: Box<Request>,
+    },
+}
+
+impl Request {
+    /// Whether serving the request may change stored data.
+    pub fn is_mutation(&self) -> bool {
+        match self {
+            Request::Set { .. }
+            | Request::SetEx { .. }
+            | Request::SetBytes { .. }
+            | Request::Remove { .. }
+            | Request::RemovePrefix { .. }
+            | Request::Take { .. }
+            | Request::GetSet { .. }
+            | Request::IncrByFloat { .. }
+            | Request::Incr { .. }
+            | Request::Modify { .. }
+            | Request::Commit
+            | Request::Import { .. } => true,
+            Request::WithDeadline { request, .. } => request.is_mutation(),
+            _ => false,
+        }
+    }
 }
 
 /// Server response message.
@@ -36,8 +260,299 @@ pub enum Request {
 pub enum Response {
     /// Operation completed successfully.
     Ok,
+    /// The server accepted a [`Request::Hello`].
+    Hello {
+        /// The server's [`PROTOCOL_VERSION`].
+        protocol_version: u32,
+    },
     /// Retrieved value, `None` if key doesn't exist.
+    ///
+    /// Answers a modify with the stored value, `None` if the op didn't apply,
+    /// and a take or get-set with the value it replaced.
     Value(Option<String>),
-    /// Operation failed with error message.
-    Err(String),
+    /// A value retrieved as bytes, base64 encoded in JSON, `None` if the key
+    /// doesn't exist.
+    Bytes(#[serde(with = "base64_bytes::option")] Option<Vec<u8>>),
+    /// A [`Response::Value`] of at least [`COMPRESS_VALUES_ABOVE`] bytes,
+    /// compressed with zstd and base64 encoded. Only sent on connections that
+    /// asked with a [`Request::CompressValues`].
+    CompressedValue(String),
+    /// Whether the key exists.
+    Bool(bool),
+    /// Whether a remove found the key, on connections that asked with a
+    /// [`Request::ReportRemoved`].
+    Removed(bool),
+    /// The float stored after an increment.
+    Float(f64),
+    /// The integer stored after an increment.
+    Int(i64),
+    /// Key-value pairs ordered by key.
+    Pairs(Vec<(String, String)>),
+    /// How many keys a prefix removal removed, or how many are set.
+    Count(u64),
+    /// The dump an export wrote, one JSON [`crate::ExportEntry`] per line.
+    Export(String),
+    /// Operation failed.
+    Err {
+        /// What kind of failure it was.
+        code: ErrorCode,
+        /// A description of the failure for humans.
+        message: String,
+    },
+    /// A key changed, streamed to connections that sent a
+    /// [`Request::Subscribe`] for a prefix of it.
+    Event {
+        /// The key that changed.
+        key: String,
+        /// The value it was set to, `None` if it was removed or expired. A
+        /// value that isn't UTF-8 has its invalid bytes replaced.
+        value: Option<String>,
+    },
+    /// The server's effective configuration.
+    Config(ServerConfig),
+    /// A snapshot of the server's counters.
+    Stats(ServerStats),
+}
+
+/// Counters of a running server, since it started.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerStats {
+    /// Requests received, of any kind.
+    pub requests: u64,
+    /// Requests received per operation, such as `get` or `set`.
+    pub ops: BTreeMap<String, u64>,
+    /// Connections accepted.
+    pub connections: u64,
+    /// Connections currently open.
+    pub active_connections: u64,
+    /// Compactions run by the engine.
+    pub compactions: u64,
+    /// How long requests waited for the engine's lock.
+    pub lock_contention: LockContention,
+    /// How long requests took to answer, per operation, over the requests
+    /// answered since the previous stats request: reading the stats resets
+    /// them. Empty unless the server records latencies.
+    #[serde(default)]
+    pub latencies: BTreeMap<String, Latency>,
+}
+
+/// Percentiles of the time requests took to answer, from receiving them to
+/// flushing their response, each within 1/8 of the exact value.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Latency {
+    /// Requests answered.
+    pub count: u64,
+    /// The median latency, in microseconds.
+    pub p50_micros: u64,
+    /// The 95th percentile latency, in microseconds.
+    pub p95_micros: u64,
+    /// The 99th percentile latency, in microseconds.
+    pub p99_micros: u64,
+}
+
+impl Response {
+    /// The response reporting that a request failed with `e`.
+    pub fn error(e: &KvsError) -> Response {
+        Response::Err {
+            code: e.into(),
+            message: e.to_string(),
+        }
+    }
+
+    /// The response carrying `value`, compressed if it is large and shrinks.
+    pub fn compressed_value(value: Option<String>) -> Response {
+        match &value {
+            Some(value) if value.len() >= COMPRESS_VALUES_ABOVE => {
+                match zstd::bulk::compress(value.as_bytes(), 3) {
+                    // Base64 grows it by a third.
+                    Ok(compressed) if compressed.len() * 4 / 3 < value.len() => {
+                        return Response::CompressedValue(BASE64_STANDARD.encode(compressed));
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        Response::Value(value)
+    }
+
+    /// The response with a [`Response::CompressedValue`] turned back into
+    /// the [`Response::Value`] it was made from.
+    pub fn decompress(self) -> Result<Response> {
+        let Response::CompressedValue(encoded) = self else {
+            return Ok(self);
+        };
+        let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "corrupt compressed value");
+        let compressed = BASE64_STANDARD.decode(encoded).map_err(|_| corrupt())?;
+        let value = zstd::decode_all(&compressed[..]).map_err(|_| corrupt())?;
+        let value = String::from_utf8(value).map_err(|_| corrupt())?;
+        Ok(Response::Value(Some(value)))
+    }
+}
+
+/// The kind of failure a [`Response::Err`] reports, so clients can tell
+/// them apart without matching on the message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The key does not exist.
+    NotFound,
+    /// The request can't apply to the stored value, like incrementing a
+    /// value that is not a number.
+    BadRequest,
+    /// A key or value is larger than the server accepts.
+    TooLarge,
+    /// The server is too busy to serve the request now.
+    Busy,
+    /// The request could not be answered before its deadline.
+    DeadlineExceeded,
+    /// The server failed to serve the request, like on an io error.
+    Internal,
+    /// The request would change data on a server in read-only mode.
+    ReadOnly,
+    /// The connection has not presented the server's auth token, or
+    /// presented a wrong one.
+    NotAuthenticated,
+    /// A key watched by a transaction changed before it committed.
+    Conflict,
+}
+
+impl From<&KvsError> for ErrorCode {
+    fn from(e: &KvsError) -> ErrorCode {
+        match e {
+            KvsError::NonExistentKey(_) => ErrorCode::NotFound,
+            KvsError::NotAFloat
+            | KvsError::NonFiniteFloat
+            | KvsError::NotAnInteger
+            | KvsError::IntegerOverflow
+            | KvsError::UnsupportedByFormat(_)
+            // Only a malformed import hands the engine JSON to parse.
+            | KvsError::SerdeError(_) => ErrorCode::BadRequest,
+            KvsError::KeyTooLarge { .. } | KvsError::ValueTooLarge { .. } => ErrorCode::TooLarge,
+            KvsError::QueueFull => ErrorCode::Busy,
+            KvsError::DeadlineExceeded => ErrorCode::DeadlineExceeded,
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            KvsError::NotAuthenticated => ErrorCode::NotAuthenticated,
+            KvsError::Conflict => ErrorCode::Conflict,
+            KvsError::ResponseError { code, .. } => *code,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+/// The resolved configuration of a running server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    /// The address the server listens on, the first given if several were.
+    pub addr: String,
+    /// More addresses the server listens on, `host:port` or `unix:<path>`.
+    pub listen: Vec<String>,
+    /// The storage engine name, `kvs`, `sled` or `memory`.
+    pub engine: String,
+    /// The directory holding the engine's data.
+    pub data_dir: PathBuf,
+    /// The number of worker threads serving connections.
+    pub threads: u32,
+    /// The number of accepted connections that may wait for a worker,
+    /// beyond which the server stops accepting until one frees up.
+    pub queue_bound: usize,
+    /// The number of scans allowed to run at once, others are rejected as busy.
+    pub max_scans: usize,
+    /// The number of connections served or waiting for a worker at once,
+    /// others are rejected as busy. `None` for no limit.
+    pub max_connections: Option<usize>,
+    /// How long a connection may go without sending a request or reading a
+    /// response before it is closed, `None` to wait forever.
+    pub idle_timeout: Option<Duration>,
+    /// Whether to record how long requests take, reported in
+    /// [`ServerStats::latencies`].
+    pub latency_stats: bool,
+    /// When responses are sent.
+    #[serde(default)]
+    pub response_flush: ResponseFlush,
+    /// How many request ids of recent sets the server remembers, see
+    /// [`Request::Set::request_id`], `0` to ignore them.
+    #[serde(default)]
+    pub request_id_cache: usize,
+    /// Whether the server starts in read-only mode, rejecting mutations until
+    /// a [`Request::SetReadOnly`] turns it off.
+    pub read_only: bool,
+    /// Options of the `kvs` engine, ignored by other engines.
+    pub kvs: KvStoreConfig,
+    /// When the `sled` engine flushes writes to disk, ignored by other
+    /// engines. The `kvs` engine syncs its log when it starts a new file,
+    /// on a [`Request::Flush`] and on shutdown.
+    #[serde(default)]
+    pub sled_flush: FlushPolicy,
+    /// The token connections must present in a [`Request::Auth`], `None`
+    /// to serve anyone. Never sent to clients.
+    #[serde(skip)]
+    pub auth_token: Option<String>,
+}
+
+/// When a server sends the responses of a connection.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResponseFlush {
+    /// Send each response as soon as it is written, a write per response.
+    #[default]
+    Every,
+    /// Hold responses back while more requests of the connection are
+    /// already read, and send them together before waiting for the next.
+    /// Saves writes when clients pipeline, and a latency recorded by
+    /// [`ServerConfig::latency_stats`] then ends before its response is sent.
+    Batched,
+}
+
+/// Compare the `given` token with the `expected` one in a time that depends
+/// on the length of `given` only, so a wrong token gives away neither how
+/// much of it is right nor how long the expected one is.
+pub fn constant_time_eq(given: &[u8], expected: &[u8]) -> bool {
+    // Walk `expected` cyclically for as long as `given` is, and fold the
+    // length difference in rather than returning early on it.
+    let len = expected.len().max(1);
+    let diff = given
+        .iter()
+        .enumerate()
+        .fold(given.len() ^ expected.len(), |diff, (i, x)| {
+            diff | usize::from(x ^ expected.get(i % len).copied().unwrap_or(0))
+        });
+    diff == 0
+}
+
+/// Serializes bytes as a base64 string, which JSON holds far more compactly
+/// than an array of numbers.
+mod base64_bytes {
+    use base64::prelude::{BASE64_STANDARD, Engine};
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64_STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        BASE64_STANDARD.decode(encoded).map_err(de::Error::custom)
+    }
+
+    /// The same for bytes that may be missing.
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            bytes: &Option<Vec<u8>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match bytes {
+                Some(bytes) => serializer.serialize_some(&BASE64_STANDARD.encode(bytes)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Vec<u8>>, D::Error> {
+            Option::<String>::deserialize(deserializer)?
+                .map(|encoded| BASE64_STANDARD.decode(encoded).map_err(de::Error::custom))
+                .transpose()
+        }
+    }
 }
@@ -0,0 +1,526 @@
+//! The file system the log files live in.
+//!
+//! [`crate::kv_store::KvStore`] only touches its files through [`Storage`],
+//! so the same log and compaction code runs on disk or fully in memory.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "fault-injection")]
+use std::sync::Condvar;
+#[cfg(feature = "fault-injection")]
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::error::Result;
+
+/// A readable, seekable log file.
+pub(crate) trait LogReader: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> LogReader for T {}
+
+/// A log file opened for appending.
+pub(crate) trait LogWriter: Write + Send {
+    /// The current length of the file in bytes.
+    fn len(&self) -> Result<u64>;
+    /// Make everything written so far durable, so it survives a power loss.
+    fn sync(&mut self) -> Result<()>;
+}
+
+/// Where log files are stored.
+pub(crate) trait Storage: Send + Sync {
+    /// List the files directly in `dir`, not in its subdirectories.
+    fn list(&self, dir: &Path) -> Result<Vec<PathBuf>>;
+    /// Whether the file at `path` exists.
+    fn exists(&self, path: &Path) -> bool;
+    /// The length of the file at `path` in bytes.
+    fn len(&self, path: &Path) -> Result<u64>;
+    /// Open the file at `path` for reading, with a handle of its own that
+    /// shares no offset with any handle appending to it.
+    fn open_read(&self, path: &Path) -> Result<Box<dyn LogReader>>;
+    /// Open the file at `path` for appending, creating it if needed.
+    fn open_append(&self, path: &Path) -> Result<Box<dyn LogWriter>>;
+    /// Delete the file at `path`.
+    fn remove(&self, path: &Path) -> Result<()>;
+    /// Atomically move the file at `from` to `to`, replacing any file there.
+    /// Handles open on it keep working.
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+    /// Cut the file at `path` down to `len` bytes.
+    fn truncate(&self, path: &Path, len: u64) -> Result<()>;
+    /// Make the files created in `dir` durable, so they survive a power loss.
+    fn sync_dir(&self, dir: &Path) -> Result<()>;
+    /// Take an advisory lock on the file at `path`, held until the returned
+    /// guard is dropped.
+    ///
+    /// Only other processes, or other stores on the same files, can contend
+    /// for it, so storage no one else can see has nothing to lock.
+    fn lock(&self, _path: &Path, _mode: LockMode) -> Result<FileLock> {
+        Ok(FileLock { _file: None })
+    }
+}
+
+/// How [`Storage::lock`] locks a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum LockMode {
+    /// Along with other shared holders, waiting for an exclusive one. A
+    /// missing file was never locked exclusively, so it is not created.
+    Shared,
+    /// Alone, waiting for every other holder.
+    Exclusive,
+    /// Alone, failing with [`io::ErrorKind::WouldBlock`] if held already.
+    TryExclusive,
+}
+
+/// An advisory lock, released when dropped.
+pub(crate) struct FileLock {
+    _file: Option<File>,
+}
+
+/// Log files on the local disk.
+pub(crate) struct DiskStorage;
+
+impl LogWriter for File {
+    fn len(&self) -> Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(self.sync_data()?)
+    }
+}
+
+impl Storage for DiskStorage {
+    fn list(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                files.push(entry.path());
+            }
+        }
+        Ok(files)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn len(&self, path: &Path) -> Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn open_read(&self, path: &Path) -> Result<Box<dyn LogReader>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_append(&self, path: &Path) -> Result<Box<dyn LogWriter>> {
+        Ok(Box::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        ))
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        Ok(fs::remove_file(path)?)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        Ok(fs::rename(from, to)?)
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> Result<()> {
+        Ok(OpenOptions::new().write(true).open(path)?.set_len(len)?)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        // An empty path is the current directory, as for the files in it.
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        Ok(File::open(dir)?.sync_all()?)
+    }
+
+    fn lock(&self, path: &Path, mode: LockMode) -> Result<FileLock> {
+        let file = match mode {
+            LockMode::Shared => match File::open(path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Ok(FileLock { _file: None });
+                }
+                file => file?,
+            },
+            LockMode::Exclusive | LockMode::TryExclusive => OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path)?,
+        };
+        match mode {
+            LockMode::Shared => file.lock_shared()?,
+            LockMode::Exclusive => file.lock()?,
+            LockMode::TryExclusive => file.try_lock().map_err(|e| match e {
+                TryLockError::WouldBlock => io::Error::from(io::ErrorKind::WouldBlock),
+                TryLockError::Error(e) => e,
+            })?,
+        }
+        Ok(FileLock { _file: Some(file) })
+    }
+}
+
+type MemoryFile = Arc<Mutex<Vec<u8>>>;
+
+/// Log files kept in memory, gone once the store is dropped.
+#[derive(Default)]
+pub(crate) struct MemoryStorage {
+    files: Mutex<HashMap<PathBuf, MemoryFile>>,
+}
+
+impl MemoryStorage {
+    fn file(&self, path: &Path) -> Result<MemoryFile> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound).into())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn list(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        Ok(self
+            .files
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+
+    fn len(&self, path: &Path) -> Result<u64> {
+        Ok(self.file(path)?.lock().unwrap().len() as u64)
+    }
+
+    fn open_read(&self, path: &Path) -> Result<Box<dyn LogReader>> {
+        Ok(Box::new(MemoryReader {
+            file: self.file(path)?,
+            pos: 0,
+        }))
+    }
+
+    fn open_append(&self, path: &Path) -> Result<Box<dyn LogWriter>> {
+        let file = self
+            .files
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_default()
+            .clone();
+        Ok(Box::new(MemoryWriter { file }))
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        match self.files.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let file = files
+            .remove(from)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> Result<()> {
+        self.file(path)?.lock().unwrap().truncate(len as usize);
+        Ok(())
+    }
+
+    fn sync_dir(&self, _dir: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct MemoryReader {
+    file: MemoryFile,
+    pos: u64,
+}
+
+impl Read for MemoryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let file = self.file.lock().unwrap();
+        let start = (self.pos as usize).min(file.len());
+        let n = buf.len().min(file.len() - start);
+        buf[..n].copy_from_slice(&file[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for MemoryReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.file.lock().unwrap().len() as i64;
+        let pos = match pos {
+            SeekFrom::Start(n) => n as i64,
+            SeekFrom::End(n) => len + n,
+            SeekFrom::Current(n) => self.pos as i64 + n,
+        };
+        if pos < 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+struct MemoryWriter {
+    file: MemoryFile,
+}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl LogWriter for MemoryWriter {
+    fn len(&self) -> Result<u64> {
+        Ok(self.file.lock().unwrap().len() as u64)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// In-memory log files that start failing after a set number of writes,
+/// to test how the store recovers from a crash.
+///
+/// Every write, file creation, removal, rename and truncation spends one
+/// unit of the budget given to [`FaultyStorage::crash_after`]. The write that
+/// runs it out only lands half its bytes, and from then on every change fails
+/// as if the process died, until [`FaultyStorage::heal`]. Reads keep working,
+/// so a store reopened on the same `FaultyStorage` sees exactly what made it
+/// to "disk".
+#[cfg(feature = "fault-injection")]
+#[derive(Clone, Default)]
+pub struct FaultyStorage {
+    inner: Arc<MemoryStorage>,
+    budget: Arc<Mutex<Budget>>,
+    refuse_new_files: Arc<AtomicBool>,
+    syncs: Arc<AtomicU64>,
+    stall: Arc<Stall>,
+}
+
+/// Changes left before the crash, `None` while healthy.
+#[cfg(feature = "fault-injection")]
+#[derive(Default)]
+struct Budget {
+    left: Option<u64>,
+    crashed: bool,
+}
+
+/// A file open held up as on a slow disk, see [`FaultyStorage::stall_next_open`].
+#[cfg(feature = "fault-injection")]
+#[derive(Default)]
+struct Stall {
+    state: Mutex<StallState>,
+    resumed: Condvar,
+}
+
+#[cfg(feature = "fault-injection")]
+#[derive(Default)]
+struct StallState {
+    /// Whether the next open for reading stalls.
+    armed: bool,
+    /// Whether a stalled open is still held up.
+    held: bool,
+}
+
+#[cfg(feature = "fault-injection")]
+impl FaultyStorage {
+    /// Create an empty storage that never fails until told to.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail every change after the next `ops` ones.
+    pub fn crash_after(&self, ops: u64) {
+        self.budget.lock().unwrap().left = Some(ops);
+    }
+
+    /// Stop failing, as if the process restarted.
+    pub fn heal(&self) {
+        *self.budget.lock().unwrap() = Budget::default();
+    }
+
+    /// Whether the budget ran out since the last [`FaultyStorage::heal`].
+    pub fn crashed(&self) -> bool {
+        self.budget.lock().unwrap().crashed
+    }
+
+    /// Fail to create any file while `refuse` is set, as on a full disk,
+    /// while existing files can still be appended to.
+    pub fn refuse_new_files(&self, refuse: bool) {
+        self.refuse_new_files.store(refuse, Ordering::SeqCst);
+    }
+
+    /// Hold up the next file opened for reading until
+    /// [`FaultyStorage::resume_opens`], as a slow disk would.
+    pub fn stall_next_open(&self) {
+        let mut state = self.stall.state.lock().unwrap();
+        state.armed = true;
+        state.held = true;
+    }
+
+    /// Let the stalled open go on.
+    pub fn resume_opens(&self) {
+        self.stall.state.lock().unwrap().held = false;
+        self.stall.resumed.notify_all();
+    }
+
+    /// How many times a log file was synced, across every file.
+    pub fn syncs(&self) -> u64 {
+        self.syncs.load(Ordering::SeqCst)
+    }
+
+    /// The files directly in `dir`.
+    pub fn files(&self, dir: &Path) -> Vec<PathBuf> {
+        let mut files = self.inner.list(dir).unwrap();
+        files.sort();
+        files
+    }
+
+    /// Spend one unit of the budget. Once none is left the change fails,
+    /// telling whether it is the one the crash happened in.
+    fn spend(budget: &Mutex<Budget>) -> std::result::Result<(), bool> {
+        let mut budget = budget.lock().unwrap();
+        match budget.left {
+            Some(0) => {
+                let first = !budget.crashed;
+                budget.crashed = true;
+                Err(first)
+            }
+            Some(ref mut n) => {
+                *n -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "fault-injection")]
+fn injected_crash() -> io::Error {
+    io::Error::other("injected crash")
+}
+
+#[cfg(feature = "fault-injection")]
+impl Storage for FaultyStorage {
+    fn list(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        self.inner.list(dir)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn len(&self, path: &Path) -> Result<u64> {
+        self.inner.len(path)
+    }
+
+    fn open_read(&self, path: &Path) -> Result<Box<dyn LogReader>> {
+        let mut state = self.stall.state.lock().unwrap();
+        if state.armed {
+            state.armed = false;
+            while state.held {
+                state = self.stall.resumed.wait(state).unwrap();
+            }
+        }
+        drop(state);
+        self.inner.open_read(path)
+    }
+
+    fn open_append(&self, path: &Path) -> Result<Box<dyn LogWriter>> {
+        if self.refuse_new_files.load(Ordering::SeqCst) && !self.inner.exists(path) {
+            return Err(io::Error::from(io::ErrorKind::StorageFull).into());
+        }
+        Self::spend(&self.budget).map_err(|_| injected_crash())?;
+        Ok(Box::new(FaultyWriter {
+            inner: self.inner.open_append(path)?,
+            budget: self.budget.clone(),
+            syncs: self.syncs.clone(),
+        }))
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        Self::spend(&self.budget).map_err(|_| injected_crash())?;
+        self.inner.remove(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        Self::spend(&self.budget).map_err(|_| injected_crash())?;
+        self.inner.rename(from, to)
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> Result<()> {
+        Self::spend(&self.budget).map_err(|_| injected_crash())?;
+        self.inner.truncate(path, len)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        self.inner.sync_dir(dir)
+    }
+}
+
+#[cfg(feature = "fault-injection")]
+struct FaultyWriter {
+    inner: Box<dyn LogWriter>,
+    budget: Arc<Mutex<Budget>>,
+    syncs: Arc<AtomicU64>,
+}
+
+#[cfg(feature = "fault-injection")]
+impl Write for FaultyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Err(first) = FaultyStorage::spend(&self.budget) {
+            // The write the crash happens in leaves a torn record behind.
+            if first {
+                self.inner.write_all(&buf[..buf.len() / 2])?;
+            }
+            return Err(injected_crash());
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(feature = "fault-injection")]
+impl LogWriter for FaultyWriter {
+    fn len(&self) -> Result<u64> {
+        self.inner.len()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        self.inner.sync()
+    }
+}
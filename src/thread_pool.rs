@@ -1,11 +1,23 @@
 //! A module for thread pool.
 use std::{
+    fs,
     panic::{AssertUnwindSafe, catch_unwind},
-    sync::{Arc, Mutex, mpsc},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{self, AtomicBool, AtomicUsize, Ordering},
+        mpsc,
+    },
     thread::{self},
 };
 
-use crate::error::Result;
+use crossbeam_deque::{Injector, Steal};
+use crossbeam_utils::Backoff;
+use log::error;
+
+use crate::error::{KvsError, Result};
+
+/// How many jobs may wait for a worker by default before `spawn` blocks.
+pub const DEFAULT_QUEUE_BOUND: usize = 1024;
 
 /// A trait for thread pools.
 ///
@@ -14,7 +26,7 @@ use crate::error::Result;
 pub trait ThreadPool: Sized {
     /// Create a new thread pool.
     fn new(threads: u32) -> Result<Self>;
-    /// Spawn a new job on the thread pool.
+    /// Spawn a new job on the thread pool, blocking while its queue is full.
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
@@ -32,15 +44,24 @@ pub enum Message {
 }
 
 /// A naive thread pool.
+///
+/// Every worker takes its jobs from one `mpsc` receiver behind a mutex, so
+/// handing out jobs is serialized, which shows with many short jobs.
+/// [`SharedQueueThreadPool`] behaves the same without that lock.
+///
+/// Jobs wait in a bounded queue until a worker is free. Once it is full,
+/// [`ThreadPool::spawn`] blocks, which slows down whoever floods the pool
+/// instead of letting the queue eat up memory. Dropping the pool waits for
+/// every queued job to run.
 pub struct NaiveThreadPool {
     workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    sender: mpsc::SyncSender<Message>,
 }
 
-impl ThreadPool for NaiveThreadPool {
-    /// Create a new naive thread pool.
-    fn new(threads: u32) -> Result<Self> {
-        let (sender, receiver) = mpsc::channel();
+impl NaiveThreadPool {
+    /// Create a pool of `threads` workers queueing at most `bound` jobs.
+    pub fn with_queue_bound(threads: u32, bound: usize) -> Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel(bound);
         let mut workers = Vec::new();
         let receiver = Arc::new(Mutex::new(receiver));
         for id in 0..threads {
@@ -48,6 +69,27 @@ impl ThreadPool for NaiveThreadPool {
         }
         Ok(Self { workers, sender })
     }
+
+    /// Spawn a job unless the queue is full, failing with
+    /// [`KvsError::QueueFull`] instead of blocking.
+    pub fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let job = Box::new(job);
+        match self.sender.try_send(Message::NewJob(job)) {
+            Ok(()) => Ok(()),
+            Err(mpsc::TrySendError::Full(_)) => Err(KvsError::QueueFull),
+            Err(e @ mpsc::TrySendError::Disconnected(_)) => panic!("{e}"),
+        }
+    }
+}
+
+impl ThreadPool for NaiveThreadPool {
+    /// Create a new naive thread pool queueing up to [`DEFAULT_QUEUE_BOUND`] jobs.
+    fn new(threads: u32) -> Result<Self> {
+        Self::with_queue_bound(threads, DEFAULT_QUEUE_BOUND)
+    }
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
@@ -58,16 +100,22 @@ impl ThreadPool for NaiveThreadPool {
 }
 
 impl Drop for NaiveThreadPool {
+    /// Run every job already spawned, then stop the workers.
+    ///
+    /// The terminate messages queue up behind the jobs, and a worker takes
+    /// one only after every job ahead of it was taken, each run to the end by
+    /// the worker that took it. Dropping needs the pool by `&mut`, so no job
+    /// can be spawned once the terminate messages are on their way.
     fn drop(&mut self) {
         for _ in &self.workers {
             self.sender.send(Message::Terminate).unwrap();
         }
 
         for worker in &mut self.workers {
-            if let Some(thread) = worker.thread.take() {
-                if let Err(e) = thread.join() {
-                    eprintln!("Worker {} join failed: {:?}", worker.id, e);
-                }
+            if let Some(thread) = worker.thread.take()
+                && let Err(e) = thread.join()
+            {
+                error!("Worker {} join failed: {:?}", worker.id, e);
             }
         }
     }
@@ -89,12 +137,7 @@ impl Worker {
                     receiver.recv()
                 };
                 match msg {
-                    Ok(Message::NewJob(job)) => {
-                        let result = catch_unwind(AssertUnwindSafe(job));
-                        if let Err(e) = result {
-                            eprintln!("Worker {} job execution panicked: {:?}", id, e);
-                        }
-                    }
+                    Ok(Message::NewJob(job)) => run_job(id, job),
                     Ok(Message::Terminate) | Err(_) => break,
                 }
             }
@@ -106,4 +149,259 @@ impl Worker {
     }
 }
 
-pub type SharedQueueThreadPool = NaiveThreadPool;
+/// Run a job, logging rather than spreading a panic so the worker survives.
+fn run_job(id: u32, job: Job) {
+    if let Err(e) = catch_unwind(AssertUnwindSafe(job)) {
+        error!("Worker {} job execution panicked: {:?}", id, e);
+    }
+}
+
+/// A thread pool whose workers share a lock-free queue.
+///
+/// Spawning pushes onto a [`crossbeam_deque::Injector`] and workers steal
+/// from it concurrently, so no lock is taken to hand out a job. A lock is only
+/// taken to put an idle worker or a spawner waiting for room to sleep, and to
+/// wake them. Otherwise it behaves like [`NaiveThreadPool`]: the queue is
+/// bounded, [`ThreadPool::spawn`] blocks while it is full, and dropping the
+/// pool waits for every queued job to run.
+pub struct SharedQueueThreadPool {
+    shared: Arc<Shared>,
+    workers: Vec<Worker>,
+}
+
+struct Shared {
+    jobs: Injector<Job>,
+    /// Jobs spawned and not yet taken by a worker, at most `bound`.
+    queued: AtomicUsize,
+    bound: usize,
+    shutdown: AtomicBool,
+    /// Workers sleeping until a job is spawned.
+    idle: Sleepers,
+    /// Spawners sleeping until a job is taken off a full queue.
+    blocked: Sleepers,
+}
+
+impl Shared {
+    /// Take a slot in the queue, unless it is full.
+    fn reserve(&self) -> bool {
+        self.queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                (queued < self.bound).then_some(queued + 1)
+            })
+            .is_ok()
+    }
+
+    fn push(&self, job: Job) {
+        self.jobs.push(job);
+        self.idle.notify_one();
+    }
+
+    fn take(&self) -> Option<Job> {
+        loop {
+            match self.jobs.steal() {
+                Steal::Success(job) => {
+                    if !self.jobs.is_empty() {
+                        self.idle.notify_one();
+                    }
+                    self.queued.fetch_sub(1, Ordering::SeqCst);
+                    self.blocked.notify_one();
+                    return Some(job);
+                }
+                Steal::Empty => return None,
+                Steal::Retry => continue,
+            }
+        }
+    }
+}
+
+/// Threads sleeping until some condition holds, which whoever makes it hold
+/// wakes with [`Sleepers::notify_one`].
+///
+/// Only one wakeup is on its way at a time: a thread woken up takes a while
+/// to run, and waking another for each job spawned meanwhile would cost a
+/// syscall each. So whoever a wakeup was for and finds more left to do, such
+/// as more jobs queued, wakes the next sleeper.
+#[derive(Default)]
+struct Sleepers {
+    count: AtomicUsize,
+    /// Whether a wakeup was sent and its sleeper has not woken up yet.
+    notified: AtomicBool,
+    lock: Mutex<()>,
+    condvar: Condvar,
+}
+
+impl Sleepers {
+    fn wait_until(&self, ready: impl Fn() -> bool) {
+        let mut guard = self.lock.lock().unwrap();
+        self.count.fetch_add(1, Ordering::SeqCst);
+        // A wakeup still marked as on its way went to a sleeper that has
+        // since left, or it would hold the lock.
+        self.notified.store(false, Ordering::SeqCst);
+        // Pairs with the fence in `notify_one`: either `ready` sees the
+        // change or the notifier sees this thread counted and takes the lock,
+        // which it only gets once this thread waits.
+        atomic::fence(Ordering::SeqCst);
+        while !ready() {
+            guard = self.condvar.wait(guard).unwrap();
+            self.notified.store(false, Ordering::SeqCst);
+        }
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn notify_one(&self) {
+        atomic::fence(Ordering::SeqCst);
+        if self.count.load(Ordering::SeqCst) > 0 && !self.notified.swap(true, Ordering::SeqCst) {
+            let _guard = self.lock.lock().unwrap();
+            self.condvar.notify_one();
+        }
+    }
+
+    fn notify_all(&self) {
+        let _guard = self.lock.lock().unwrap();
+        self.condvar.notify_all();
+    }
+}
+
+impl SharedQueueThreadPool {
+    /// Create a pool of `threads` workers queueing at most `bound` jobs.
+    ///
+    /// Unlike [`NaiveThreadPool`], a `bound` of zero still lets one job wait.
+    pub fn with_queue_bound(threads: u32, bound: usize) -> Result<Self> {
+        let shared = Arc::new(Shared {
+            jobs: Injector::new(),
+            queued: AtomicUsize::new(0),
+            bound: bound.max(1),
+            shutdown: AtomicBool::new(false),
+            idle: Sleepers::default(),
+            blocked: Sleepers::default(),
+        });
+        let workers = (0..threads)
+            .map(|id| {
+                let shared = shared.clone();
+                let thread = thread::spawn(move || {
+                    // An idle worker spins, then yields, for a while before it
+                    // sleeps, as jobs spawned back to back would otherwise each
+                    // pay for waking it up.
+                    let backoff = Backoff::new();
+                    loop {
+                        // Read before looking for a job: every job was pushed
+                        // before the flag was set, so an empty queue then
+                        // means every job was taken.
+                        let shutdown = shared.shutdown.load(Ordering::SeqCst);
+                        match shared.take() {
+                            Some(job) => {
+                                run_job(id, job);
+                                backoff.reset();
+                            }
+                            None if shutdown => break,
+                            None if !backoff.is_completed() => backoff.snooze(),
+                            None => {
+                                shared.idle.wait_until(|| {
+                                    !shared.jobs.is_empty()
+                                        || shared.shutdown.load(Ordering::SeqCst)
+                                });
+                                backoff.reset();
+                            }
+                        }
+                    }
+                });
+                Worker {
+                    id,
+                    thread: Some(thread),
+                }
+            })
+            .collect();
+        Ok(Self { shared, workers })
+    }
+
+    /// Spawn a job unless the queue is full, failing with
+    /// [`KvsError::QueueFull`] instead of blocking.
+    pub fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if !self.shared.reserve() {
+            return Err(KvsError::QueueFull);
+        }
+        self.shared.push(Box::new(job));
+        Ok(())
+    }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    /// Create a new shared queue thread pool queueing up to [`DEFAULT_QUEUE_BOUND`] jobs.
+    fn new(threads: u32) -> Result<Self> {
+        Self::with_queue_bound(threads, DEFAULT_QUEUE_BOUND)
+    }
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if !self.shared.reserve() {
+            let shared = &self.shared;
+            shared.blocked.wait_until(|| shared.reserve());
+            if shared.queued.load(Ordering::SeqCst) < shared.bound {
+                shared.blocked.notify_one();
+            }
+        }
+        self.shared.push(Box::new(job));
+    }
+}
+
+impl Drop for SharedQueueThreadPool {
+    /// Run every job already spawned, then stop the workers.
+    ///
+    /// Workers only stop once they find the queue empty after seeing the
+    /// shutdown flag, and no job can be spawned once it is set, as dropping
+    /// needs the pool by `&mut`.
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        self.shared.idle.notify_all();
+
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take()
+                && let Err(e) = thread.join()
+            {
+                error!("Worker {} join failed: {:?}", worker.id, e);
+            }
+        }
+    }
+}
+
+/// The number of worker threads to run by default: the CPUs of the machine,
+/// capped by the CPU quota of the process's cgroup, as containers get.
+pub fn default_threads() -> u32 {
+    thread_count(num_cpus::get(), cgroup_cpu_quota())
+}
+
+/// `cpus` capped by a `quota` of CPUs, rounded up as a fractional quota still
+/// runs that many threads at once. Always at least one.
+pub fn thread_count(cpus: usize, quota: Option<f64>) -> u32 {
+    let cpus = cpus as u32;
+    let count = match quota {
+        Some(quota) if quota > 0.0 => cpus.min(quota.ceil() as u32),
+        _ => cpus,
+    };
+    count.max(1)
+}
+
+/// Parse the CPU quota from the contents of a cgroup v2 `cpu.max` file,
+/// `<quota> <period>` in microseconds or `max <period>` for no quota.
+pub fn parse_cpu_max(contents: &str) -> Option<f64> {
+    let mut fields = contents.split_whitespace();
+    let quota: f64 = fields.next()?.parse().ok()?;
+    let period: f64 = fields.next()?.parse().ok()?;
+    (period > 0.0).then(|| quota / period)
+}
+
+/// The CPU quota of this process's cgroup, in CPUs, if it has one.
+fn cgroup_cpu_quota() -> Option<f64> {
+    if let Ok(contents) = fs::read_to_string("/sys/fs/cgroup/cpu.max") {
+        return parse_cpu_max(&contents);
+    }
+    // cgroup v1 splits the same pair over two files, with -1 for no quota.
+    let read = |file| fs::read_to_string(format!("/sys/fs/cgroup/cpu/{file}")).ok();
+    let quota: i64 = read("cpu.cfs_quota_us")?.trim().parse().ok()?;
+    let period: i64 = read("cpu.cfs_period_us")?.trim().parse().ok()?;
+    (quota > 0 && period > 0).then(|| quota as f64 / period as f64)
+}
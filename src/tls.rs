@@ -0,0 +1,147 @@
+//! TLS
+//!
+//! Encryption in transit for the JSON protocol, with [`rustls`]. Only the
+//! stream changes: requests and responses are framed just like over plain TCP.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConnection, RootCertStore, ServerConnection, StreamOwned};
+
+use crate::error::{KvsError, Result};
+
+/// The configuration of a server presenting the certificate chain in the PEM
+/// file `cert`, signed with the private key in the PEM file `key`.
+pub fn server_config(cert: &Path, key: &Path) -> Result<Arc<rustls::ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| KvsError::Tls(format!("can't read {}: {e}", cert.display())))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| KvsError::Tls(format!("can't read {}: {e}", key.display())))?;
+    let config = rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| KvsError::Tls(e.to_string()))?;
+    Ok(Arc::new(config))
+}
+
+/// The configuration of a client trusting the certificates in the PEM file `ca`.
+pub fn client_config(ca: &Path) -> Result<Arc<rustls::ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca)
+        .map_err(|e| KvsError::Tls(format!("can't read {}: {e}", ca.display())))?
+    {
+        let cert = cert.map_err(|e| KvsError::Tls(format!("can't read {}: {e}", ca.display())))?;
+        roots.add(cert).map_err(|e| KvsError::Tls(e.to_string()))?;
+    }
+    let config = rustls::ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| KvsError::Tls(e.to_string()))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// One end of a TLS connection over TCP.
+///
+/// Clones share the session, so one can read while another writes, but not
+/// at the same time: a clone blocked reading holds up the others.
+#[derive(Clone)]
+pub struct TlsStream(Arc<Mutex<Session>>);
+
+enum Session {
+    Client(StreamOwned<ClientConnection, TcpStream>),
+    Server(StreamOwned<ServerConnection, TcpStream>),
+}
+
+impl TlsStream {
+    /// Start a session with the server `name` over `sock`. The handshake
+    /// runs on the first read or write.
+    pub fn connect(
+        config: Arc<rustls::ClientConfig>,
+        name: &str,
+        sock: TcpStream,
+    ) -> Result<TlsStream> {
+        let name = ServerName::try_from(name.to_owned())
+            .map_err(|e| KvsError::Tls(format!("invalid server name {name:?}: {e}")))?;
+        let conn = ClientConnection::new(config, name).map_err(|e| KvsError::Tls(e.to_string()))?;
+        Ok(TlsStream::new(Session::Client(StreamOwned::new(
+            conn, sock,
+        ))))
+    }
+
+    /// Start a session with the client that connected over `sock`. The
+    /// handshake runs on the first read or write.
+    pub fn accept(config: Arc<rustls::ServerConfig>, sock: TcpStream) -> Result<TlsStream> {
+        let conn = ServerConnection::new(config).map_err(|e| KvsError::Tls(e.to_string()))?;
+        Ok(TlsStream::new(Session::Server(StreamOwned::new(
+            conn, sock,
+        ))))
+    }
+
+    fn new(session: Session) -> TlsStream {
+        TlsStream(Arc::new(Mutex::new(session)))
+    }
+
+    /// Set the read and write timeouts of the underlying socket.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let session = self.0.lock().unwrap();
+        let sock = match &*session {
+            Session::Client(stream) => &stream.sock,
+            Session::Server(stream) => &stream.sock,
+        };
+        sock.set_read_timeout(timeout)?;
+        sock.set_write_timeout(timeout)
+    }
+
+    /// Tell the peer the session is over, then shut the socket down.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let mut session = self.0.lock().unwrap();
+        match &mut *session {
+            Session::Client(stream) => {
+                stream.conn.send_close_notify();
+                stream.flush()?;
+                stream.sock.shutdown(how)
+            }
+            Session::Server(stream) => {
+                stream.conn.send_close_notify();
+                stream.flush()?;
+                stream.sock.shutdown(how)
+            }
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut *self.0.lock().unwrap() {
+            Session::Client(stream) => stream.read(buf),
+            Session::Server(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut *self.0.lock().unwrap() {
+            Session::Client(stream) => stream.write(buf),
+            Session::Server(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut *self.0.lock().unwrap() {
+            Session::Client(stream) => stream.flush(),
+            Session::Server(stream) => stream.flush(),
+        }
+    }
+}
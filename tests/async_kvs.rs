@@ -0,0 +1,153 @@
+use kvs::async_kvs::{self, AsyncClient, AsyncKvStore};
+use kvs::client::Client;
+use kvs::protocol::{ErrorCode, Request, Response};
+use kvs::{KvStore, KvsError};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// Engine calls through futures, several of them in flight at once.
+#[tokio::test]
+async fn async_store_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = AsyncKvStore::new(KvStore::open(temp_dir.path()).unwrap());
+
+    let sets: Vec<_> = (0..10)
+        .map(|i| tokio::spawn(store.set(format!("key{i}"), format!("value{i}"))))
+        .collect();
+    for set in sets {
+        set.await.unwrap().unwrap();
+    }
+    assert_eq!(
+        store.get("key3".to_owned()).await.unwrap(),
+        Some("value3".to_owned())
+    );
+    store.remove("key3".to_owned()).await.unwrap();
+    assert_eq!(store.get("key3".to_owned()).await.unwrap(), None);
+    assert!(matches!(
+        store.remove("key3".to_owned()).await,
+        Err(KvsError::NonExistentKey(_))
+    ));
+}
+
+// The async server speaks the JSON protocol to async and blocking clients alike.
+#[tokio::test]
+async fn async_server_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = AsyncKvStore::new(KvStore::open(temp_dir.path()).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async_kvs::serve(listener, store));
+
+    let mut client = AsyncClient::connect(addr).await.unwrap();
+    let set = Request::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+        request_id: None,
+    };
+    assert!(matches!(client.request(&set).await.unwrap(), Response::Ok));
+    let get = Request::Get {
+        key: "key1".to_owned(),
+    };
+    assert!(matches!(
+        client.request(&get).await.unwrap(),
+        Response::Value(Some(value)) if value == "value1"
+    ));
+    assert!(matches!(
+        client.request(&Request::Stats).await.unwrap(),
+        Response::Err {
+            code: ErrorCode::BadRequest,
+            ..
+        }
+    ));
+
+    let response = tokio::task::spawn_blocking(move || {
+        let mut client = Client::connect(addr).unwrap();
+        client.request(&get).unwrap()
+    })
+    .await
+    .unwrap();
+    assert!(matches!(response, Response::Value(Some(value)) if value == "value1"));
+
+    let remove = Request::Remove {
+        key: "key1".to_owned(),
+    };
+    assert!(matches!(
+        client.request(&remove).await.unwrap(),
+        Response::Ok
+    ));
+    assert!(matches!(
+        client.request(&remove).await.unwrap(),
+        Response::Err {
+            code: ErrorCode::NotFound,
+            ..
+        }
+    ));
+}
+
+// Requests are split from the stream wherever the reads end, with brackets,
+// quotes and backslashes inside strings, several in one read or one across
+// many, and a message that isn't an object or a string is a bad request.
+#[tokio::test]
+async fn async_server_framing() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = AsyncKvStore::new(KvStore::open(temp_dir.path()).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async_kvs::serve(listener, store));
+
+    let value = r#"{"not": ["a request"]} \" \\"#;
+    let set = serde_json::to_string(&Request::Set {
+        key: "key1".to_owned(),
+        value: value.to_owned(),
+        request_id: None,
+    })
+    .unwrap();
+    let get = serde_json::to_string(&Request::Get {
+        key: "key1".to_owned(),
+    })
+    .unwrap();
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    for piece in set.as_bytes().chunks(7) {
+        stream.write_all(piece).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    stream
+        .write_all(format!("\n{get} {get}\"Len\"").as_bytes())
+        .await
+        .unwrap();
+    stream.shutdown().await.unwrap();
+    let mut output = Vec::new();
+    stream.read_to_end(&mut output).await.unwrap();
+    let responses: Vec<Response> = serde_json::Deserializer::from_slice(&output)
+        .into_iter()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(responses.len(), 4);
+    assert!(matches!(responses[0], Response::Ok));
+    for response in &responses[1..3] {
+        assert!(matches!(response, Response::Value(Some(v)) if v == value));
+    }
+    // Served with a BadRequest like any other unsupported request.
+    assert!(matches!(
+        responses[3],
+        Response::Err {
+            code: ErrorCode::BadRequest,
+            ..
+        }
+    ));
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(b"  42").await.unwrap();
+    let mut output = Vec::new();
+    stream.read_to_end(&mut output).await.unwrap();
+    let response: Response = serde_json::from_slice(&output).unwrap();
+    assert!(matches!(
+        response,
+        Response::Err {
+            code: ErrorCode::BadRequest,
+            ..
+        }
+    ));
+}
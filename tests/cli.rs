@@ -1,7 +1,15 @@
+use assert_cmd::cargo_bin;
 use assert_cmd::prelude::*;
+use kvs::client::{Client, ClientConfig};
+use kvs::protocol::{ErrorCode, PROTOCOL_VERSION, Request, Response, ServerConfig};
+use kvs::{CompactionStrategy, FlushPolicy, KvStore, KvsEngine, KvsError, ModifyOp, SledEngine};
 use predicates::str::{contains, is_empty};
+use serde_json::{Deserializer, json};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -11,37 +19,33 @@ use tempfile::TempDir;
 #[test]
 fn client_cli_no_args() {
     let temp_dir = TempDir::new().unwrap();
-    let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+    let mut cmd = Command::new(cargo_bin!("kvs-client"));
     cmd.current_dir(&temp_dir).assert().failure();
 }
 
 #[test]
 fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["get"])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["get", "extra", "field"])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -50,37 +54,32 @@ fn client_cli_invalid_get() {
 #[test]
 fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["set"])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["set", "missing_field"])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -89,30 +88,26 @@ fn client_cli_invalid_set() {
 #[test]
 fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["rm"])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["rm", "extra", "field"])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -121,9 +116,8 @@ fn client_cli_invalid_rm() {
 #[test]
 fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["unknown"])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -133,8 +127,8 @@ fn client_cli_invalid_subcommand() {
 #[test]
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
-    let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    let mut cmd = Command::new(cargo_bin!("kvs-client"));
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -144,8 +138,8 @@ fn client_cli_version() {
 #[test]
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
-    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    let mut cmd = Command::new(cargo_bin!("kvs-server"));
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -155,15 +149,16 @@ fn server_cli_version() {
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
     let stderr_path = temp_dir.path().join("stderr");
-    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut cmd = Command::new(cargo_bin!("kvs-server"));
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -176,17 +171,18 @@ fn cli_wrong_engine() {
     // sled first, kvs second
     {
         let temp_dir = TempDir::new().unwrap();
-        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        let mut cmd = Command::new(cargo_bin!("kvs-server"));
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
-        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        let mut cmd = Command::new(cargo_bin!("kvs-server"));
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -195,17 +191,18 @@ fn cli_wrong_engine() {
     // kvs first, sled second
     {
         let temp_dir = TempDir::new().unwrap();
-        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        let mut cmd = Command::new(cargo_bin!("kvs-server"));
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
-        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        let mut cmd = Command::new(cargo_bin!("kvs-server"));
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -215,77 +212,90 @@ fn cli_wrong_engine() {
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
-    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut server = Command::new(cargo_bin!("kvs-server"));
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout("value1\n");
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout("value2\n");
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("Key not found"));
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+    // A default stands in for a missing key, and only for a missing key.
+    for (key, expected) in [("key1", "value2\n"), ("key2", "fallback\n")] {
+        Command::new(cargo_bin!("kvs-client"))
+            .args(["get", key, "--default", "fallback", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(expected);
+    }
+
+    for (key, exists) in [("key1", "true\n"), ("key2", "false\n")] {
+        Command::new(cargo_bin!("kvs-client"))
+            .args(["exists", key, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(exists);
+    }
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
-        .stderr(contains("Key not found"));
+        .code(1)
+        .stdout(is_empty())
+        .stderr("Key not found\n");
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -296,28 +306,27 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     // Reopen and check value
     let (sender, receiver) = mpsc::sync_channel(0);
-    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut server = Command::new(cargo_bin!("kvs-server"));
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
-    Command::cargo_bin("kvs-client")
-        .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -335,3 +344,1890 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// Send `requests` over a single connection and collect one response per request.
+fn send_requests(addr: &str, requests: &[Request]) -> Vec<Response> {
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut writer = BufWriter::new(stream.try_clone().unwrap());
+    let mut responses = Deserializer::from_reader(BufReader::new(stream)).into_iter::<Response>();
+    requests
+        .iter()
+        .map(|request| {
+            serde_json::to_writer(&mut writer, request).unwrap();
+            writer.flush().unwrap();
+            responses.next().unwrap().unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn cli_compaction_off() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--compaction", "off"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // Far more overwrites than the default strategy tolerates before compacting.
+    let requests: Vec<Request> = (0..5000)
+        .map(|i| Request::Set {
+            key: "key".to_owned(),
+            value: format!("value{}", i),
+            request_id: None,
+        })
+        .collect();
+    for response in send_requests(addr, &requests) {
+        assert!(matches!(response, Response::Ok));
+    }
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let logs: Vec<_> = fs::read_dir(&temp_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name.to_string_lossy().ends_with(".log"))
+        .collect();
+    assert_eq!(logs, vec!["1.log"]);
+    // Every overwrite is still on disk after the 4 byte header: tag, "key" and
+    // "value{i}" with their length bytes, and a checksum.
+    let expected: u64 = 4
+        + (0..5000)
+            .map(|i| 10 + format!("value{}", i).len() as u64)
+            .sum::<u64>();
+    let len = fs::metadata(temp_dir.path().join("1.log")).unwrap().len();
+    assert_eq!(len, expected);
+}
+
+#[test]
+fn cli_server_config() {
+    let addr = "127.0.0.1:4007";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .args(["--compaction", "ratio", "--compaction-ratio", "0.25"])
+        .args(["--max-log-size", "4096", "--threads", "3"])
+        .args(["--sled-flush", "every-ops", "--sled-flush-ops", "10"])
+        .args(["--auth-token", "config-secret"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let output = Command::new(cargo_bin!("kvs-client"))
+        .args(["config", "--addr", addr, "--auth-token", "config-secret"])
+        .output()
+        .unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(!stdout.contains("config-secret"), "token leaked: {stdout}");
+    let config: ServerConfig = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(config.addr, addr);
+    assert_eq!(config.engine, "kvs");
+    assert_eq!(
+        config.data_dir.canonicalize().unwrap(),
+        temp_dir.path().canonicalize().unwrap()
+    );
+    assert_eq!(config.threads, 3);
+    assert_eq!(config.kvs.compaction, CompactionStrategy::Ratio(0.25));
+    assert_eq!(config.kvs.max_log_size, 4096);
+    assert_eq!(config.sled_flush, FlushPolicy::EveryOps(10));
+
+    Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--threads", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--threads"));
+}
+
+#[test]
+fn cli_max_scans() {
+    let addr = "127.0.0.1:4008";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--max-scans", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let requests: Vec<Request> = (0..2000)
+        .map(|i| Request::Set {
+            key: format!("scan{:04}", i),
+            value: "v".repeat(100),
+            request_id: None,
+        })
+        .collect();
+    send_requests(addr, &requests);
+
+    let scanners: Vec<_> = (0..8)
+        .map(|_| {
+            thread::spawn(move || {
+                let prefix = "scan".to_owned();
+                send_requests(addr, &[Request::Scan { prefix }])
+                    .pop()
+                    .unwrap()
+            })
+        })
+        .collect();
+    // Point operations keep being served while the scans run.
+    for i in 0..20 {
+        let key = format!("scan{:04}", i);
+        let response = send_requests(addr, &[Request::Get { key }]).pop().unwrap();
+        assert!(matches!(response, Response::Value(Some(value)) if value.len() == 100));
+    }
+
+    let mut completed = 0;
+    for scanner in scanners {
+        match scanner.join().unwrap() {
+            Response::Pairs(pairs) => {
+                assert_eq!(pairs.len(), 2000);
+                completed += 1;
+            }
+            Response::Err { code, message } => {
+                assert_eq!(code, ErrorCode::Busy);
+                assert!(message.contains("busy"));
+            }
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+    assert!(completed >= 1);
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_size_limits() {
+    for (engine, addr) in [("kvs", "127.0.0.1:4010"), ("sled", "127.0.0.1:4011")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut child = Command::new(cargo_bin!("kvs-server"))
+            .args(["--engine", engine, "--addr", addr])
+            .args(["--max-key-size", "4", "--max-value-size", "8"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        let set = |key: &str, value: &str| Request::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+            request_id: None,
+        };
+        let responses = send_requests(
+            addr,
+            &[
+                set("key", "value"),
+                set("key1x", "v"),
+                set("key", "value1234"),
+            ],
+        );
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+
+        assert!(matches!(responses[0], Response::Ok));
+        assert!(
+            matches!(&responses[1], Response::Err { code: ErrorCode::TooLarge, message }
+            if message.contains("key of 5 bytes"))
+        );
+        assert!(
+            matches!(&responses[2], Response::Err { code: ErrorCode::TooLarge, message }
+            if message.contains("value of 9 bytes"))
+        );
+    }
+}
+
+// Per-request logs are debug level, silenced by `RUST_LOG=info`.
+#[test]
+fn cli_log_level() {
+    let addr = "127.0.0.1:4012";
+    for (level, logged) in [("info", false), ("debug", true)] {
+        let temp_dir = TempDir::new().unwrap();
+        let stderr_path = temp_dir.path().join("stderr");
+        let mut child = Command::new(cargo_bin!("kvs-server"))
+            .args(["--engine", "kvs", "--addr", addr])
+            .env("RUST_LOG", level)
+            .current_dir(&temp_dir)
+            .stderr(File::create(&stderr_path).unwrap())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        let key = "key1".to_owned();
+        send_requests(addr, &[Request::Get { key }]);
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+
+        let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+        assert!(content.contains("INFO"));
+        assert!(content.contains(addr));
+        assert_eq!(content.contains("Received request"), logged);
+    }
+}
+
+#[test]
+fn cli_modify() {
+    let addr = "127.0.0.1:4013";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let modify = |op| Request::Modify {
+        key: "key".to_owned(),
+        op,
+    };
+    let responses = send_requests(
+        addr,
+        &[
+            modify(ModifyOp::IncrBy(2)),
+            modify(ModifyOp::Append("0".to_owned())),
+            modify(ModifyOp::SetIfEqual {
+                expected: "2".to_owned(),
+                new: "3".to_owned(),
+            }),
+            modify(ModifyOp::Prepend("x".to_owned())),
+            modify(ModifyOp::IncrBy(1)),
+        ],
+    );
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    assert!(matches!(&responses[0], Response::Value(Some(v)) if v == "2"));
+    assert!(matches!(&responses[1], Response::Value(Some(v)) if v == "20"));
+    assert!(matches!(&responses[2], Response::Value(None)));
+    assert!(matches!(&responses[3], Response::Value(Some(v)) if v == "x20"));
+    assert!(
+        matches!(&responses[4], Response::Err { code: ErrorCode::BadRequest, message }
+        if message.contains("not an integer"))
+    );
+}
+
+#[test]
+fn cli_stats() {
+    let addr = "127.0.0.1:4014";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .args(["--compaction", "ratio", "--compaction-ratio", "0.5"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let set = || Request::Set {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+        request_id: None,
+    };
+    let get = || Request::Get {
+        key: "key".to_owned(),
+    };
+    let response = send_requests(addr, &[set(), set(), set(), get(), get(), Request::Stats])
+        .pop()
+        .unwrap();
+    let Response::Stats(stats) = response else {
+        panic!("unexpected response {:?}", response);
+    };
+    assert_eq!(stats.requests, 6);
+    assert_eq!(stats.ops["set"], 3);
+    assert_eq!(stats.ops["get"], 2);
+    assert_eq!(stats.ops["stats"], 1);
+    assert_eq!(stats.ops["remove"], 0);
+    assert_eq!(stats.connections, 1);
+    assert_eq!(stats.active_connections, 1);
+    assert!(stats.compactions >= 1);
+    assert!(stats.lock_contention.acquisitions >= 3);
+    assert!(stats.latencies.is_empty());
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"requests\": 7"));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_repl() {
+    let addr = "127.0.0.1:4015";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // Errors are reported without ending the session, and everything shares one connection.
+    assert_cmd::Command::new(cargo_bin!("kvs-client"))
+        .args(["repl", "--addr", addr])
+        .current_dir(&temp_dir)
+        .write_stdin("set key1 value1\nget key1\n\nset key2 \"two words\"\nget key2\nrm key1\nrm key1\nget key1\nbogus\nexists key2\nstats\n")
+        .assert()
+        .success()
+        .stdout(contains("value1\ntwo words\nKey not found\ntrue\n"))
+        .stdout(contains("\"connections\": 1"))
+        .stderr(contains("Key not found"))
+        .stderr(contains("bogus"));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_request_timeout() {
+    // Accepts the connection, but never answers.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key", "--addr", &addr, "--request-timeout", "200"])
+        .assert()
+        .failure()
+        .stderr(contains("timed out"));
+}
+
+#[test]
+fn cli_dump_index() {
+    let temp_dir = TempDir::new().unwrap();
+    for (key, value) in [("key1", "value1"), ("key2", "value2"), ("key1", "value3")] {
+        Command::new(cargo_bin!("kvs"))
+            .args(["set", key, value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    let output = Command::new(cargo_bin!("kvs"))
+        .args(["dump-index", "--data-dir"])
+        .arg(temp_dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<Vec<&str>> = stdout.lines().map(|l| l.split('\t').collect()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0][0], "key1");
+    assert_eq!(lines[1][0], "key2");
+    // key1 was overwritten, so its latest record comes after key2's.
+    let offset = |line: &[&str]| line[2].parse::<u64>().unwrap();
+    assert!(offset(&lines[0]) > offset(&lines[1]));
+    for line in &lines {
+        let len = fs::metadata(line[1]).unwrap().len();
+        assert!(offset(line) + line[3].parse::<u64>().unwrap() <= len);
+    }
+}
+
+#[test]
+fn cli_check() {
+    let temp_dir = TempDir::new().unwrap();
+    for i in 1..=3 {
+        Command::new(cargo_bin!("kvs"))
+            .args(["set", &format!("key{i}"), &format!("value{i}")])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::new(cargo_bin!("kvs"))
+        .args(["rm", "key3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::new(cargo_bin!("kvs"))
+        .args(["check"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1 files, 4 records, 2 stale, 0 corrupt\n");
+
+    // A torn record at the end is reported, but left for the store to cut off.
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log).unwrap();
+    content.push(1);
+    fs::write(&log, &content).unwrap();
+    Command::new(cargo_bin!("kvs"))
+        .args(["check"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1 files, 4 records, 2 stale, 0 corrupt\n")
+        .stderr(contains("1 bytes of a torn record"));
+
+    // After the 4 byte header each set is 17 bytes long, flip a byte in the
+    // value of the second one.
+    content[4 + 17 + 8] ^= 0xff;
+    fs::write(&log, &content).unwrap();
+    Command::new(cargo_bin!("kvs"))
+        .args(["check"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout("1 files, 0 records, 0 stale, 1 corrupt\n")
+        .stderr(contains("checksum mismatch"));
+    assert_eq!(fs::read(&log).unwrap(), content);
+}
+
+#[test]
+fn cli_migrate_delete_source() {
+    let temp_dir = TempDir::new().unwrap();
+    for (key, value) in [("key1", "value1"), ("key2", "value2"), ("key1", "value3")] {
+        Command::new(cargo_bin!("kvs"))
+            .args(["set", key, value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::new(cargo_bin!("kvs"))
+        .args(["migrate", "--to", "sled", "--delete-source"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Migrated 2 keys from kvs to sled\n");
+
+    let names: Vec<String> = fs::read_dir(&temp_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert!(
+        names.iter().all(|name| !name.ends_with(".log")),
+        "{names:?}"
+    );
+    assert!(names.contains(&"db".to_owned()));
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join(".kvs-engine")).unwrap(),
+        "sled\n"
+    );
+
+    // The kvs tool no longer opens the directory.
+    Command::new(cargo_bin!("kvs"))
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("WrongEngine"));
+
+    let store = SledEngine::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value3".to_owned())
+    );
+    assert_eq!(
+        store.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+}
+
+#[test]
+fn cli_error_codes() {
+    let addr = "127.0.0.1:4018";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--max-key-size", "4"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let responses = send_requests(
+        addr,
+        &[
+            Request::Remove {
+                key: "key".to_owned(),
+            },
+            Request::Set {
+                key: "key".to_owned(),
+                value: "value".to_owned(),
+                request_id: None,
+            },
+            Request::IncrByFloat {
+                key: "key".to_owned(),
+                delta: 1.0,
+            },
+        ],
+    );
+    let codes: Vec<_> = responses
+        .iter()
+        .map(|response| match response {
+            Response::Err { code, .. } => Some(*code),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        codes,
+        [Some(ErrorCode::NotFound), None, Some(ErrorCode::BadRequest)]
+    );
+
+    // The client tells the failures apart by its exit status.
+    for (args, status) in [
+        (&["rm", "missing"][..], 1),
+        (&["incrbyfloat", "key", "1"][..], 3),
+        (&["set", "key12", "value"][..], 4),
+    ] {
+        Command::new(cargo_bin!("kvs-client"))
+            .args(args)
+            .args(["--addr", addr])
+            .assert()
+            .code(status);
+    }
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_set_with_ttl() {
+    let addr = "127.0.0.1:4019";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key1", "value1", "--ttl", "1", "--addr", addr])
+        .assert()
+        .success();
+    let get = || Request::Get {
+        key: "key1".to_owned(),
+    };
+    let response = send_requests(addr, &[get()]).pop().unwrap();
+    assert!(matches!(response, Response::Value(Some(v)) if v == "value1"));
+
+    thread::sleep(Duration::from_millis(1200));
+    let response = send_requests(addr, &[get()]).pop().unwrap();
+    assert!(matches!(response, Response::Value(None)));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// The server sweeps expired keys nothing reads, appending their tombstones.
+#[test]
+fn cli_expiry_sweep() {
+    let addr = "127.0.0.1:4052";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .args(["--compaction", "off", "--expiry-sweep-interval", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key1", "value1", "--ttl", "1", "--addr", addr])
+        .assert()
+        .success();
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log).unwrap().len();
+    thread::sleep(Duration::from_secs(3));
+    assert!(fs::metadata(&log).unwrap().len() > len);
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_incr() {
+    let addr = "127.0.0.1:4020";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for (args, expected) in [
+        (&["incr", "hits"][..], "1"),
+        (&["incr", "hits", "5"][..], "6"),
+        (&["incr", "hits", "-10"][..], "-4"),
+    ] {
+        Command::new(cargo_bin!("kvs-client"))
+            .args(args)
+            .args(["--addr", addr])
+            .assert()
+            .success()
+            .stdout(format!("{expected}\n"));
+    }
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "name", "kvs", "--addr", addr])
+        .assert()
+        .success();
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["incr", "name", "--addr", addr])
+        .assert()
+        .code(3)
+        .stderr("value is not an integer\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_clone() {
+    let addr = "127.0.0.1:4021";
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..1500 {
+        store.set(format!("key{i}"), format!("value{i}")).unwrap();
+    }
+    store.remove("key0".to_owned()).unwrap();
+    store
+        .set_with_ttl(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+    drop(store);
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--auth-token", "secret"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let replica = TempDir::new().unwrap();
+    let into = replica.path().join("data");
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["clone", "--addr", addr, "--into"])
+        .arg(&into)
+        .assert()
+        .code(9)
+        .stderr(contains("not authenticated"));
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["clone", "--addr", addr, "--auth-token", "secret", "--into"])
+        .arg(&into)
+        .assert()
+        .success()
+        .stdout("Copied 1499 keys\n")
+        .stderr("copied 1000 of 1499 keys\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let store = KvStore::open(&into).unwrap();
+    let pairs = store.scan(String::new()).unwrap();
+    assert_eq!(pairs.len(), 1499);
+    assert_eq!(store.get("key0".to_owned()).unwrap(), None);
+    for i in 1..1500 {
+        assert_eq!(
+            store.get(format!("key{i}")).unwrap(),
+            Some(format!("value{i}"))
+        );
+    }
+    // The expiry came along with the key.
+    let mut dump = Vec::new();
+    store.export(&mut dump).unwrap();
+    let expiring: Vec<_> = dump
+        .lines()
+        .map(|line| line.unwrap())
+        .filter(|line| line.contains("expires_at"))
+        .collect();
+    assert_eq!(expiring.len(), 1);
+    assert!(expiring[0].contains("\"key1\""), "{}", expiring[0]);
+}
+
+#[test]
+fn cli_deadline() {
+    let addr = "127.0.0.1:4022";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // An import far too big for a 5ms budget stops part way through.
+    let dump: String = (0..20000)
+        .map(|i| format!("{{\"key\":\"imported{i}\",\"value\":\"v\"}}\n"))
+        .collect();
+    assert_cmd::Command::new(cargo_bin!("kvs-client"))
+        .args(["import", "--deadline", "5", "--addr", addr])
+        .write_stdin(dump)
+        .assert()
+        .code(7)
+        .stderr("deadline exceeded\n");
+    let imported = match send_requests(addr, &[Request::Len])[..] {
+        [Response::Count(count)] => count,
+        ref responses => panic!("{responses:?}"),
+    };
+    assert!(imported < 20000, "imported {imported}");
+    send_requests(
+        addr,
+        &[Request::RemovePrefix {
+            prefix: "imported".to_owned(),
+        }],
+    );
+
+    // A scan slow enough to blow a 1ms budget.
+    let sets: Vec<_> = (0..5000)
+        .map(|i| Request::Set {
+            key: format!("key{i}"),
+            value: "v".repeat(1000),
+            request_id: None,
+        })
+        .collect();
+    send_requests(addr, &sets);
+    let scan = |deadline_ms| Request::WithDeadline {
+        deadline_ms,
+        request: Box::new(Request::Scan {
+            prefix: String::new(),
+        }),
+    };
+    let mut responses = send_requests(addr, &[scan(1), scan(60_000)]);
+    assert!(matches!(
+        responses.pop(),
+        Some(Response::Pairs(pairs)) if pairs.len() == 5000
+    ));
+    assert!(matches!(
+        responses.pop(),
+        Some(Response::Err {
+            code: ErrorCode::DeadlineExceeded,
+            ..
+        })
+    ));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_pipelined_and_malformed_requests() {
+    let addr = "127.0.0.1:4023";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // Three requests sent back to back are answered in order.
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut writer = BufWriter::new(stream.try_clone().unwrap());
+    for request in [
+        Request::Set {
+            key: "key".to_owned(),
+            value: "value".to_owned(),
+            request_id: None,
+        },
+        Request::Get {
+            key: "key".to_owned(),
+        },
+        Request::Remove {
+            key: "key".to_owned(),
+        },
+    ] {
+        serde_json::to_writer(&mut writer, &request).unwrap();
+    }
+    writer.flush().unwrap();
+    let responses: Vec<_> = Deserializer::from_reader(BufReader::new(stream))
+        .into_iter::<Response>()
+        .take(3)
+        .map(Result::unwrap)
+        .collect();
+    assert!(matches!(
+        responses[..],
+        [
+            Response::Ok,
+            Response::Value(Some(ref v)),
+            Response::Ok
+        ] if v == "value"
+    ));
+    // Free the worker serving this connection for the next one.
+    drop(writer);
+
+    // A malformed request gets one error, then the connection closes.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(br#"{"Get": {"key": 1}} {"Stats": null}"#)
+        .unwrap();
+    let mut responses = Deserializer::from_reader(BufReader::new(stream)).into_iter::<Response>();
+    assert!(matches!(
+        responses.next(),
+        Some(Ok(Response::Err {
+            code: ErrorCode::BadRequest,
+            ..
+        }))
+    ));
+    assert!(responses.next().is_none());
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_multiple_listeners() {
+    let addr = "127.0.0.1:4024";
+    let temp_dir = TempDir::new().unwrap();
+    let socket = temp_dir.path().join("kvs.sock");
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--listen"])
+        .arg(format!("unix:{}", socket.display()))
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let set = Request::Set {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+        request_id: None,
+    };
+    assert!(matches!(send_requests(addr, &[set])[..], [Response::Ok]));
+
+    // The Unix socket serves the same engine.
+    let mut stream = UnixStream::connect(&socket).unwrap();
+    let get = Request::Get {
+        key: "key".to_owned(),
+    };
+    serde_json::to_writer(&mut stream, &get).unwrap();
+    let response = Deserializer::from_reader(stream)
+        .into_iter::<Response>()
+        .next()
+        .unwrap()
+        .unwrap();
+    assert!(matches!(response, Response::Value(Some(v)) if v == "value"));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_multiple_addrs() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args([
+            "--engine",
+            "kvs",
+            "--addr",
+            "127.0.0.1:0",
+            "--addr",
+            "[::1]:0",
+        ])
+        .env("RUST_LOG", "info")
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // The ports chosen for port 0 are logged as the listeners are bound.
+    let addrs: Vec<String> = BufReader::new(child.stderr.take().unwrap())
+        .lines()
+        .filter_map(|line| Some(line.unwrap().split_once("Listening on ")?.1.to_owned()))
+        .take(2)
+        .collect();
+    assert!(addrs[0].starts_with("127.0.0.1:"), "{addrs:?}");
+    assert!(addrs[1].starts_with("[::1]:"), "{addrs:?}");
+
+    // Both serve the same engine.
+    let set = Request::Set {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+        request_id: None,
+    };
+    assert!(matches!(
+        send_requests(&addrs[0], &[set])[..],
+        [Response::Ok]
+    ));
+    let get = Request::Get {
+        key: "key".to_owned(),
+    };
+    assert!(matches!(
+        &send_requests(&addrs[1], &[get])[..],
+        [Response::Value(Some(v))] if v == "value"
+    ));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_memory_engine() {
+    let addr = "127.0.0.1:4025";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["rm", "key2", "--addr", addr])
+        .assert()
+        .code(1)
+        .stderr("Key not found\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // Nothing was written to the data directory.
+    assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+}
+
+// The first server records its engine in `.kvs-engine`, which later servers
+// trust over the files they find.
+#[test]
+fn cli_engine_marker() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4026"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join(".kvs-engine")).unwrap(),
+        "sled\n"
+    );
+
+    // A stray log file no longer passes for kvs data.
+    fs::write(temp_dir.path().join("other-tool.log"), "").unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4026"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    assert!(child.try_wait().unwrap().is_none());
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4026"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Wrong engine! Previous: sled, current: kvs"));
+}
+
+// A server refuses a data directory marked for another engine.
+#[test]
+fn cli_engine_marker_mismatch() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join(".kvs-engine"), "kvs\n").unwrap();
+    Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Wrong engine! Previous: kvs, current: sled"));
+}
+
+// A dump exported from one server loads into another through stdin.
+#[test]
+fn cli_export_import() {
+    let addr = "127.0.0.1:4028";
+    let run_server = |dir: &TempDir| {
+        let child = Command::new(cargo_bin!("kvs-server"))
+            .args(["--engine", "kvs", "--addr", addr])
+            .current_dir(dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child
+    };
+
+    let source = TempDir::new().unwrap();
+    let mut child = run_server(&source);
+    for (key, value) in [("key2", "value2"), ("key1", "value 1")] {
+        Command::new(cargo_bin!("kvs-client"))
+            .args(["set", key, value, "--addr", addr])
+            .assert()
+            .success();
+    }
+    let output = Command::new(cargo_bin!("kvs-client"))
+        .args(["export", "--addr", addr])
+        .output()
+        .unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert!(output.status.success());
+    let dump = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        dump,
+        "{\"key\":\"key1\",\"value\":\"value 1\"}\n{\"key\":\"key2\",\"value\":\"value2\"}\n"
+    );
+
+    let dest = TempDir::new().unwrap();
+    let mut child = run_server(&dest);
+    assert_cmd::Command::new(cargo_bin!("kvs-client"))
+        .args(["import", "--addr", addr])
+        .write_stdin(dump)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value 1\n");
+    assert_cmd::Command::new(cargo_bin!("kvs-client"))
+        .args(["import", "--addr", addr])
+        .write_stdin("not json\n")
+        .assert()
+        .code(3);
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// A draining server leaves new connections unaccepted but keeps serving open
+// ones, until it is resumed on the very same port.
+#[test]
+fn cli_drain_resume() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:0", "--threads", "2"])
+        .env("RUST_LOG", "info")
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let addr = BufReader::new(child.stderr.take().unwrap())
+        .lines()
+        .find_map(|line| Some(line.unwrap().split_once("Listening on ")?.1.to_owned()))
+        .unwrap();
+    let addr = addr.as_str();
+
+    let mut client = Client::connect(addr).unwrap();
+    assert!(matches!(
+        client.request(&Request::Drain).unwrap(),
+        Response::Ok
+    ));
+    thread::sleep(Duration::from_millis(200));
+
+    // A connection made now waits in the backlog, unanswered.
+    let get = Request::Get {
+        key: "key1".to_owned(),
+    };
+    let mut waiting = TcpStream::connect(addr).unwrap();
+    waiting
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    serde_json::to_writer(&mut waiting, &get).unwrap();
+    let mut byte = [0; 1];
+    let err = waiting.read(&mut byte).unwrap_err();
+    assert!(
+        matches!(
+            err.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ),
+        "{err:?}"
+    );
+
+    let set = Request::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+        request_id: None,
+    };
+    assert!(matches!(client.request(&set).unwrap(), Response::Ok));
+    assert!(matches!(
+        client.request(&Request::Resume).unwrap(),
+        Response::Ok
+    ));
+
+    // Once resumed, the waiting connection is served on the port it was
+    // made to, and so are new ones.
+    waiting.set_read_timeout(None).unwrap();
+    let mut responses = Deserializer::from_reader(BufReader::new(waiting)).into_iter::<Response>();
+    assert!(matches!(
+        responses.next().unwrap().unwrap(),
+        Response::Value(Some(value)) if value == "value1"
+    ));
+    drop(responses);
+    client.shutdown().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["drain", "--addr", addr])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(200));
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr, "--request-timeout", "300"])
+        .assert()
+        .failure();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// Clients announce their protocol version, and a server speaking another one
+// turns them away before serving anything.
+#[test]
+fn cli_protocol_handshake() {
+    let addr = "127.0.0.1:4030";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let responses = send_requests(
+        addr,
+        &[Request::Hello {
+            protocol_version: PROTOCOL_VERSION,
+        }],
+    );
+    assert!(matches!(
+        responses[..],
+        [Response::Hello { protocol_version }] if protocol_version == PROTOCOL_VERSION
+    ));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let hello = Request::Hello {
+        protocol_version: PROTOCOL_VERSION + 1,
+    };
+    serde_json::to_writer(&mut stream, &hello).unwrap();
+    serde_json::to_writer(&mut stream, &Request::Stats).unwrap();
+    let mut responses = Deserializer::from_reader(BufReader::new(stream)).into_iter::<Response>();
+    assert!(matches!(
+        responses.next().unwrap().unwrap(),
+        Response::Err { code: ErrorCode::BadRequest, message } if message.contains("protocol version")
+    ));
+    assert!(responses.next().is_none());
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["stats", "--addr", addr])
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    for bin in [cargo_bin!("kvs-client"), cargo_bin!("kvs-server")] {
+        Command::new(bin)
+            .arg("--version")
+            .assert()
+            .success()
+            .stdout(contains(format!("(protocol {PROTOCOL_VERSION})")));
+    }
+}
+
+// In read-only mode gets are still served but writes are rejected, until it
+// is turned off again.
+#[test]
+fn cli_read_only() {
+    let addr = "127.0.0.1:4031";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["read-only", "on", "--addr", addr])
+        .assert()
+        .success();
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key1", "value2", "--addr", addr])
+        .assert()
+        .code(8)
+        .stderr(contains("read-only"));
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["rm", "key1", "--addr", addr])
+        .assert()
+        .code(8);
+
+    let mut client = Client::connect(addr).unwrap();
+    let set = Request::Set {
+        key: "key1".to_owned(),
+        value: "value2".to_owned(),
+        request_id: None,
+    };
+    assert!(matches!(
+        client.request(&set).unwrap(),
+        Response::Err {
+            code: ErrorCode::ReadOnly,
+            ..
+        }
+    ));
+    assert!(matches!(
+        client
+            .request(&Request::SetReadOnly { enabled: false })
+            .unwrap(),
+        Response::Ok
+    ));
+    assert!(matches!(client.request(&set).unwrap(), Response::Ok));
+    client.shutdown().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value2\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// With a certificate and key the server talks TLS, to clients trusting it.
+#[test]
+fn cli_tls() {
+    let addr = "127.0.0.1:4032";
+    let temp_dir = TempDir::new().unwrap();
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()]).unwrap();
+    let cert = temp_dir.path().join("cert.pem");
+    let key = temp_dir.path().join("key.pem");
+    fs::write(&cert, certified.cert.pem()).unwrap();
+    fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--tls-cert"])
+        .arg(&cert)
+        .arg("--tls-key")
+        .arg(&key)
+        .current_dir(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key1", "value1", "--addr", addr, "--tls", "--ca"])
+        .arg(&cert)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr, "--tls", "--ca"])
+        .arg(&cert)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    // Plain TCP gets nowhere.
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr, "--request-timeout", "1000"])
+        .assert()
+        .failure();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// A server started with an auth token serves only connections presenting it.
+#[test]
+fn cli_auth_token() {
+    let addr = "127.0.0.1:4033";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--auth-token", "secret"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args([
+            "set",
+            "key1",
+            "value1",
+            "--addr",
+            addr,
+            "--auth-token",
+            "secret",
+        ])
+        .assert()
+        .success();
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr, "--auth-token", "secret"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr, "--auth-token", "secreT"])
+        .assert()
+        .code(9)
+        .stderr(contains("not authenticated"));
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .code(9);
+
+    // Rejected until the right token is presented on the connection.
+    let mut client = Client::connect(addr).unwrap();
+    let get = Request::Get {
+        key: "key1".to_owned(),
+    };
+    assert!(matches!(
+        client.request(&get).unwrap(),
+        Response::Err {
+            code: ErrorCode::NotAuthenticated,
+            ..
+        }
+    ));
+    let auth = |token: &str| Request::Auth {
+        token: token.to_owned(),
+    };
+    // Neither a prefix, an extension nor a repeat of the token passes.
+    for wrong in ["wrong", "", "secre", "secrets", "secretsecret"] {
+        assert!(matches!(
+            client.request(&auth(wrong)).unwrap(),
+            Response::Err {
+                code: ErrorCode::NotAuthenticated,
+                ..
+            }
+        ));
+    }
+    assert!(matches!(
+        client.request(&auth("secret")).unwrap(),
+        Response::Ok
+    ));
+    assert!(matches!(
+        client.request(&get).unwrap(),
+        Response::Value(Some(value)) if value == "value1"
+    ));
+    client.shutdown().unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// Connections past --max-connections are turned away as busy, until one of
+// the open ones closes.
+#[test]
+fn cli_max_connections() {
+    let addr = "127.0.0.1:4034";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--max-connections", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // One is served, the other waits for the worker, both count.
+    let first = TcpStream::connect(addr).unwrap();
+    let second = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = Client::connect(addr).unwrap();
+    let get = Request::Get {
+        key: "key1".to_owned(),
+    };
+    assert!(matches!(
+        client.request(&get),
+        Err(KvsError::ResponseError {
+            code: ErrorCode::Busy,
+            ..
+        })
+    ));
+    drop(client);
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .code(5)
+        .stderr(contains("too many connections"));
+
+    drop(first);
+    drop(second);
+    thread::sleep(Duration::from_millis(200));
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `rm-prefix` removes the keys under a prefix and prints how many there were.
+#[test]
+fn cli_remove_prefix() {
+    let addr = "127.0.0.1:4035";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for key in ["tenant:1:a", "tenant:1:b", "tenant:2:a"] {
+        Command::new(cargo_bin!("kvs-client"))
+            .args(["set", key, "value", "--addr", addr])
+            .assert()
+            .success();
+    }
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["rm-prefix", "tenant:1:", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("2\n");
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "tenant:1:a", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "tenant:2:a", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_latency_stats() {
+    let addr = "127.0.0.1:4036";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--latency-stats"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let set = || Request::Set {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+        request_id: None,
+    };
+    let get = || Request::Get {
+        key: "key".to_owned(),
+    };
+    let mut responses = send_requests(
+        addr,
+        &[set(), set(), set(), get(), Request::Stats, Request::Stats],
+    );
+    let Some(Response::Stats(second)) = responses.pop() else {
+        panic!("unexpected responses {:?}", responses);
+    };
+    let Some(Response::Stats(first)) = responses.pop() else {
+        panic!("unexpected responses {:?}", responses);
+    };
+    let set = first.latencies["set"];
+    assert_eq!(set.count, 3);
+    assert!(set.p50_micros <= set.p95_micros && set.p95_micros <= set.p99_micros);
+    assert_eq!(first.latencies["get"].count, 1);
+    assert!(!first.latencies.contains_key("remove"));
+    // Reading the stats resets the latencies, leaving only the first stats request.
+    assert_eq!(second.latencies.keys().collect::<Vec<_>>(), ["stats"]);
+    assert_eq!(second.latencies["stats"].count, 1);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_read_only_flag() {
+    let addr = "127.0.0.1:4037";
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--read-only"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key1", "value2", "--addr", addr])
+        .assert()
+        .code(8)
+        .stderr(contains("read-only"));
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["read-only", "off", "--addr", addr])
+        .assert()
+        .success();
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key1", "value2", "--addr", addr])
+        .assert()
+        .success();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_idle_timeout() {
+    let addr = "127.0.0.1:4038";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--threads", "1"])
+        .args(["--idle-timeout", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // A client that never sends anything is hung up on.
+    let mut idle = TcpStream::connect(addr).unwrap();
+    idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0u8; 1];
+    assert_eq!(idle.read(&mut buf).unwrap(), 0);
+
+    // Which freed the only worker for the next client.
+    assert_cmd::Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key1", "value1", "--addr", addr])
+        .timeout(Duration::from_secs(5))
+        .assert()
+        .success();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_compress_values() {
+    let addr = "127.0.0.1:4039";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let value = r#"{"name": "kvs", "tags": ["a", "b"]} "#.repeat(500);
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "doc", &value, "--addr", addr])
+        .assert()
+        .success();
+
+    // Only connections that ask get compressed values.
+    let get = || Request::Get {
+        key: "doc".to_owned(),
+    };
+    let responses = send_requests(
+        addr,
+        &[get(), Request::CompressValues { enabled: true }, get()],
+    );
+    assert!(matches!(&responses[0], Response::Value(Some(v)) if *v == value));
+    let Response::CompressedValue(compressed) = &responses[2] else {
+        panic!("unexpected response {:?}", responses[2]);
+    };
+    assert!(compressed.len() < value.len() / 5);
+
+    let config = ClientConfig::default().compress_values();
+    let mut client = Client::connect_with_config(addr, &config).unwrap();
+    assert!(matches!(
+        client.request(&get()).unwrap(),
+        Response::Value(Some(v)) if v == value
+    ));
+    drop(client);
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "doc", "--compress", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(format!("{value}\n"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_getset() {
+    let addr = "127.0.0.1:4040";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["getset", "lease", "holder1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["getset", "lease", "holder2", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("holder1\n");
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "lease", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("holder2\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_len() {
+    let addr = "127.0.0.1:4041";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["len", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("0\n");
+    for key in ["key1", "key2", "key1"] {
+        Command::new(cargo_bin!("kvs-client"))
+            .args(["set", key, "value", "--addr", addr])
+            .assert()
+            .success();
+    }
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["dbsize", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("2\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_bytes() {
+    let addr = "127.0.0.1:4042";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let binary = [0xff, 0xfe, 0, 1, b'\n'];
+    assert_cmd::Command::new(cargo_bin!("kvs-client"))
+        .args(["set-bytes", "blob", "--addr", addr])
+        .write_stdin(binary)
+        .assert()
+        .success()
+        .stdout("");
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get-bytes", "blob", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(binary.to_vec());
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get-bytes", "missing", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    // The string command reports the value as unreadable instead of mangling it.
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "blob", "--addr", addr])
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `--output json` prints every response and error as one JSON object on
+// stdout, keeping the exit statuses.
+#[test]
+fn cli_json_output() {
+    let addr = "127.0.0.1:4049";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let json = |args: &[&str], status: i32| -> serde_json::Value {
+        let output = Command::new(cargo_bin!("kvs-client"))
+            .args(args)
+            .args(["--addr", addr, "--output", "json"])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(status), "{output:?}");
+        assert!(output.stderr.is_empty(), "{output:?}");
+        serde_json::from_slice(&output.stdout).unwrap()
+    };
+    assert_eq!(json(&["set", "key1", "value1"], 0), json!({ "ok": true }));
+    assert_eq!(json(&["get", "key1"], 0), json!({ "value": "value1" }));
+    assert_eq!(json(&["get", "key2"], 0), json!({ "value": null }));
+    assert_eq!(
+        json(&["get", "key2", "--default", "none"], 0),
+        json!({ "value": "none" })
+    );
+    assert_eq!(json(&["exists", "key1"], 0), json!({ "value": true }));
+    assert_eq!(json(&["incr", "n", "2"], 0), json!({ "value": 2 }));
+    assert_eq!(json(&["len"], 0), json!({ "value": 2 }));
+    assert_eq!(
+        json(&["rm", "key2"], 1),
+        json!({ "error": "Key not found", "code": "NotFound" })
+    );
+    assert_eq!(json(&["incrbyfloat", "key1", "1"], 3)["code"], "BadRequest");
+
+    // `--format` is the same option, and plain output stays the default.
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr, "--format", "json"])
+        .assert()
+        .success()
+        .stdout("{\"value\":\"value1\"}\n");
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // Failures of the client itself are objects too.
+    let output = Command::new(cargo_bin!("kvs-client"))
+        .args(["get", "key1", "--addr", addr, "--output", "json"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let error: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(error["error"].is_string(), "{error}");
+}
+
+// `flush` forces the server's writes to disk, answering with nothing, and
+// is served in read-only mode too as it changes no data.
+#[test]
+fn cli_flush() {
+    let addr = "127.0.0.1:4050";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["flush", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["read-only", "on", "--addr", addr])
+        .assert()
+        .success();
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["flush", "--addr", addr])
+        .assert()
+        .success();
+
+    let mut client = Client::connect(addr).unwrap();
+    let Response::Stats(stats) = client.request(&Request::Stats).unwrap() else {
+        panic!("expected stats");
+    };
+    assert_eq!(stats.ops["flush"], 2);
+    client.shutdown().unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// A set retried under the same request id applies once, on any connection,
+// until the id is pushed out of the server's bounded cache.
+#[test]
+fn cli_request_id_dedupes_sets() {
+    let addr = "127.0.0.1:4051";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--request-id-cache", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let set = |value: &str, request_id| {
+        let mut client = Client::connect(addr).unwrap();
+        let response = client
+            .request(&Request::Set {
+                key: "key".to_owned(),
+                value: value.to_owned(),
+                request_id,
+            })
+            .unwrap();
+        client.shutdown().unwrap();
+        response
+    };
+    let get = || {
+        let output = Command::new(cargo_bin!("kvs-client"))
+            .args(["get", "key", "--addr", addr])
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    assert!(matches!(set("first", Some(7)), Response::Ok));
+    assert!(matches!(set("second", None), Response::Ok));
+    // The retry is answered like the first try, which it doesn't repeat.
+    assert!(matches!(set("first", Some(7)), Response::Ok));
+    assert_eq!(get(), "second\n");
+    assert!(matches!(
+        set("other", Some(7)),
+        Response::Err {
+            code: ErrorCode::BadRequest,
+            ..
+        }
+    ));
+    assert_eq!(get(), "second\n");
+
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key", "third", "--request-id", "8", "--addr", addr])
+        .assert()
+        .success();
+    Command::new(cargo_bin!("kvs-client"))
+        .args(["set", "key", "fourth", "--request-id", "8", "--addr", addr])
+        .assert()
+        .failure();
+    assert_eq!(get(), "third\n");
+
+    // A third id pushes out 7, the least recently seen, which applies again.
+    assert!(matches!(set("ninth", Some(9)), Response::Ok));
+    assert!(matches!(set("first", Some(7)), Response::Ok));
+    assert_eq!(get(), "first\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
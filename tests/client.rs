@@ -0,0 +1,572 @@
+use assert_cmd::cargo_bin;
+use kvs::client::{BufferedClient, Client, ClientConfig, KvsClient};
+use kvs::protocol::{ErrorCode, PROTOCOL_VERSION, Request, Response};
+use kvs::{ExportEntry, KvsError};
+use serde_json::Deserializer;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read};
+use std::net::{TcpListener, TcpStream};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Queued requests are all answered, in order, over a single connection.
+#[test]
+fn buffered_client() {
+    let addr = "127.0.0.1:4016";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = BufferedClient::connect(addr, 64).unwrap();
+    let sets: Vec<_> = (0..10000)
+        .map(|i| {
+            client.send(Request::Set {
+                key: format!("key{}", i % 100),
+                value: format!("value{}", i),
+                request_id: None,
+            })
+        })
+        .collect();
+    for pending in sets {
+        assert!(matches!(pending.wait().unwrap(), Response::Ok));
+    }
+
+    let gets: Vec<_> = (0..100)
+        .map(|i| {
+            client.send(Request::Get {
+                key: format!("key{}", i),
+            })
+        })
+        .collect();
+    for (i, pending) in gets.into_iter().enumerate() {
+        let expected = format!("value{}", 9900 + i);
+        assert!(matches!(pending.wait().unwrap(), Response::Value(Some(v)) if v == expected));
+    }
+
+    let Response::Stats(stats) = client.send(Request::Stats).wait().unwrap() else {
+        panic!("expected stats");
+    };
+    assert_eq!(stats.connections, 1);
+    assert_eq!(stats.requests, 10101);
+    drop(client);
+    thread::sleep(Duration::from_millis(100));
+
+    // The connection is closed once the client is dropped.
+    let mut client = Client::connect(addr).unwrap();
+    let Response::Stats(stats) = client.request(&Request::Stats).unwrap() else {
+        panic!("expected stats");
+    };
+    assert_eq!(stats.connections, 2);
+    assert_eq!(stats.active_connections, 1);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// A server that accepts the connection but never answers times the request out.
+#[test]
+fn request_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let config = ClientConfig::default().request_timeout(Duration::from_millis(200));
+    let mut client = Client::connect_with_config(listener.local_addr().unwrap(), &config).unwrap();
+
+    let start = Instant::now();
+    let result = client.request(&Request::Stats);
+    assert!(matches!(result, Err(KvsError::Timeout)), "{result:?}");
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+// A refused connection is retried until the server comes up.
+#[test]
+fn connect_retries() {
+    let addr = "127.0.0.1:4017";
+    let refused = Client::connect(addr);
+    assert!(
+        matches!(&refused, Err(KvsError::IOError(e)) if e.kind() == ErrorKind::ConnectionRefused)
+    );
+
+    let server = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        let listener = TcpListener::bind(addr).unwrap();
+        listener.accept().unwrap();
+    });
+    // Backing off 100, 200 and 400 ms outlasts the server starting up.
+    let config = ClientConfig::default().retries(5);
+    Client::connect_with_config(addr, &config).unwrap();
+    server.join().unwrap();
+}
+
+// A server that can't read the handshake fails the first request with a
+// clear error instead of a serde one.
+#[test]
+fn handshake_with_older_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let response = Response::Err {
+            code: ErrorCode::BadRequest,
+            message: "malformed request: unknown variant `Hello`".to_owned(),
+        };
+        serde_json::to_writer(&mut stream, &response).unwrap();
+        // Like the server, hang up only after the client is done.
+        let _ = stream.read_to_end(&mut Vec::new());
+    });
+
+    let mut client = Client::connect(addr).unwrap();
+    let result = client.request(&Request::Stats);
+    assert!(
+        matches!(&result, Err(KvsError::IncompatibleProtocol(message)) if message.contains("predates")),
+        "{result:?}"
+    );
+    client.shutdown().unwrap();
+    server.join().unwrap();
+}
+
+// A server hanging up before answering, like when it crashed serving the
+// request, fails the request instead of panicking the client.
+#[test]
+fn server_hangs_up_mid_request() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut requests = Deserializer::from_reader(&stream).into_iter::<Request>();
+        assert!(matches!(
+            requests.next().unwrap().unwrap(),
+            Request::Hello { .. }
+        ));
+        let response = Response::Hello {
+            protocol_version: PROTOCOL_VERSION,
+        };
+        serde_json::to_writer(&stream, &response).unwrap();
+        // Read the request, then crash without answering it.
+        assert!(matches!(
+            requests.next().unwrap().unwrap(),
+            Request::Get { .. }
+        ));
+    });
+
+    let mut client = Client::connect(addr).unwrap();
+    let result = client.request(&Request::Get {
+        key: "key1".to_owned(),
+    });
+    server.join().unwrap();
+    assert!(
+        matches!(&result, Err(KvsError::IOError(e))
+            if e.kind() == ErrorKind::UnexpectedEof && e.to_string() == "no response from server"),
+        "{result:?}"
+    );
+}
+
+// The typed client gets, sets and removes over the protocol, telling a
+// missing key apart from other failures.
+#[test]
+fn kvs_client() {
+    let addr = "127.0.0.1:4043";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    client.remove("key1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    let result = client.remove("key1".to_owned());
+    assert!(
+        matches!(&result, Err(KvsError::NonExistentKey(key)) if key == "key1"),
+        "{result:?}"
+    );
+
+    // Requests without a method go through the untyped client.
+    let mut client = client.into_inner();
+    assert!(matches!(
+        client.request(&Request::Len).unwrap(),
+        Response::Count(0)
+    ));
+    client.shutdown().unwrap();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// A subscriber hears about the changes other connections make to keys under
+// its prefix, and only those.
+#[test]
+fn subscribe_to_changes() {
+    let addr = "127.0.0.1:4044";
+    let temp_dir = TempDir::new().unwrap();
+    // The subscriber keeps a worker to itself.
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--threads", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut subscriber = Client::connect(addr).unwrap();
+    let response = subscriber
+        .request(&Request::Subscribe {
+            prefix: "config/".to_owned(),
+        })
+        .unwrap();
+    assert!(matches!(response, Response::Ok), "{response:?}");
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("other".to_owned(), "value".to_owned()).unwrap();
+    client
+        .set("config/timeout".to_owned(), "30".to_owned())
+        .unwrap();
+    client.remove("config/timeout".to_owned()).unwrap();
+
+    let response = subscriber.recv().unwrap();
+    assert!(
+        matches!(&response, Response::Event { key, value: Some(value) }
+            if key == "config/timeout" && value == "30"),
+        "{response:?}"
+    );
+    let response = subscriber.recv().unwrap();
+    assert!(
+        matches!(&response, Response::Event { key, value: None } if key == "config/timeout"),
+        "{response:?}"
+    );
+
+    // Every other way of changing a key is heard about too.
+    let mut expect = |want_key: &str, want: Option<&str>| {
+        let response = subscriber.recv().unwrap();
+        assert!(
+            matches!(&response, Response::Event { key, value }
+                if key == want_key && value.as_deref() == want),
+            "{response:?}"
+        );
+    };
+    client.begin(Vec::new()).unwrap();
+    client.set("config/tx".to_owned(), "1".to_owned()).unwrap();
+    client.commit().unwrap();
+    expect("config/tx", Some("1"));
+
+    let mut client = client.into_inner();
+    let requests = [
+        Request::SetBytes {
+            key: "config/raw".to_owned(),
+            value: vec![0xff, b'a'],
+        },
+        Request::Import {
+            data: [
+                ExportEntry {
+                    key: "config/a".to_owned(),
+                    value: "2".to_owned(),
+                    expires_at: None,
+                },
+                ExportEntry {
+                    key: "config/b".to_owned(),
+                    value: "3".to_owned(),
+                    expires_at: None,
+                },
+            ]
+            .iter()
+            .map(|entry| serde_json::to_string(entry).unwrap() + "\n")
+            .collect(),
+        },
+    ];
+    for request in &requests {
+        let response = client.request(request).unwrap();
+        assert!(matches!(response, Response::Ok), "{response:?}");
+    }
+    expect("config/raw", Some("\u{fffd}a"));
+    expect("config/a", Some("2"));
+    expect("config/b", Some("3"));
+
+    let response = client
+        .request(&Request::RemovePrefix {
+            prefix: "config/".to_owned(),
+        })
+        .unwrap();
+    assert!(matches!(response, Response::Count(4)), "{response:?}");
+    for key in ["config/a", "config/b", "config/raw", "config/tx"] {
+        expect(key, None);
+    }
+
+    // An expired key is heard about once a write after reading it drops it.
+    let set_ex = Request::SetEx {
+        key: "config/ttl".to_owned(),
+        value: "4".to_owned(),
+        ttl_secs: 1,
+    };
+    assert!(matches!(client.request(&set_ex).unwrap(), Response::Ok));
+    expect("config/ttl", Some("4"));
+    thread::sleep(Duration::from_millis(1100));
+    let get = Request::Get {
+        key: "config/ttl".to_owned(),
+    };
+    assert!(matches!(
+        client.request(&get).unwrap(),
+        Response::Value(None)
+    ));
+    let set = Request::Set {
+        key: "other".to_owned(),
+        value: "value".to_owned(),
+        request_id: None,
+    };
+    assert!(matches!(client.request(&set).unwrap(), Response::Ok));
+    expect("config/ttl", None);
+
+    // The server notices the subscriber left when an event can't be sent,
+    // and stops following the engine. A new subscriber starts it again.
+    subscriber.shutdown().unwrap();
+    for i in 0..3 {
+        let set = Request::Set {
+            key: "config/gone".to_owned(),
+            value: i.to_string(),
+            request_id: None,
+        };
+        assert!(matches!(client.request(&set).unwrap(), Response::Ok));
+        thread::sleep(Duration::from_millis(100));
+    }
+    let mut subscriber = Client::connect(addr).unwrap();
+    let response = subscriber
+        .request(&Request::Subscribe {
+            prefix: "config/".to_owned(),
+        })
+        .unwrap();
+    assert!(matches!(response, Response::Ok), "{response:?}");
+    let set = Request::Set {
+        key: "config/back".to_owned(),
+        value: "5".to_owned(),
+        request_id: None,
+    };
+    assert!(matches!(client.request(&set).unwrap(), Response::Ok));
+    let response = subscriber.recv().unwrap();
+    assert!(
+        matches!(&response, Response::Event { key, value: Some(value) }
+            if key == "config/back" && value == "5"),
+        "{response:?}"
+    );
+
+    client.shutdown().unwrap();
+    subscriber.shutdown().unwrap();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// A connection that asked for it learns whether a remove found the key,
+// instead of getting a `NotFound` error for a missing one.
+#[test]
+fn report_removed() {
+    let addr = "127.0.0.1:4045";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = Client::connect(addr).unwrap();
+    let remove = Request::Remove {
+        key: "key1".to_owned(),
+    };
+    let response = client.request(&remove).unwrap();
+    assert!(
+        matches!(
+            response,
+            Response::Err {
+                code: ErrorCode::NotFound,
+                ..
+            }
+        ),
+        "{response:?}"
+    );
+
+    let response = client
+        .request(&Request::ReportRemoved { enabled: true })
+        .unwrap();
+    assert!(matches!(response, Response::Ok), "{response:?}");
+    client
+        .request(&Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+            request_id: None,
+        })
+        .unwrap();
+    let response = client.request(&remove).unwrap();
+    assert!(matches!(response, Response::Removed(true)), "{response:?}");
+    let response = client.request(&remove).unwrap();
+    assert!(matches!(response, Response::Removed(false)), "{response:?}");
+    client.shutdown().unwrap();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// A client closing its connection before reading the responses to its
+// requests only ends that connection: the server goes on serving others and
+// nothing panics, whether it sends responses one by one or batched.
+#[test]
+fn client_hangs_up_before_response() {
+    for (flush, addr) in [("every", "127.0.0.1:4046"), ("batched", "127.0.0.1:4047")] {
+        let temp_dir = TempDir::new().unwrap();
+        let stderr_path = temp_dir.path().join("stderr");
+        let mut child = Command::new(cargo_bin!("kvs-server"))
+            .args(["--engine", "kvs", "--addr", addr, "--response-flush", flush])
+            .current_dir(&temp_dir)
+            .stderr(File::create(&stderr_path).unwrap())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        let mut client = KvsClient::connect(addr).unwrap();
+        client.set("big".to_owned(), "x".repeat(1 << 20)).unwrap();
+        client.shutdown().unwrap();
+
+        // Megabytes of responses the server can't send all of before it
+        // finds the connection closed.
+        let stream = TcpStream::connect(addr).unwrap();
+        for _ in 0..20 {
+            let get = Request::Get {
+                key: "big".to_owned(),
+            };
+            serde_json::to_writer(&stream, &get).unwrap();
+        }
+        drop(stream);
+        thread::sleep(Duration::from_millis(500));
+
+        // Pipelined requests are all answered, in order.
+        let mut client = Client::connect(addr).unwrap();
+        for i in 0..100 {
+            client
+                .send(&Request::Set {
+                    key: format!("key{i}"),
+                    value: i.to_string(),
+                    request_id: None,
+                })
+                .unwrap();
+            client
+                .send(&Request::Get {
+                    key: format!("key{i}"),
+                })
+                .unwrap();
+        }
+        client.flush().unwrap();
+        for i in 0..100 {
+            assert!(matches!(client.recv().unwrap(), Response::Ok));
+            let response = client.recv().unwrap();
+            assert!(
+                matches!(&response, Response::Value(Some(value)) if *value == i.to_string()),
+                "{response:?}"
+            );
+        }
+        client.shutdown().unwrap();
+
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+        let stderr = fs::read_to_string(&stderr_path).unwrap();
+        assert!(!stderr.contains("panicked"), "{stderr}");
+        assert!(!stderr.contains("Error handling stream"), "{stderr}");
+    }
+}
+
+// A transaction whose watched key another connection changed applies none of
+// its writes, and one left alone applies them all.
+#[test]
+fn transaction_conflict() {
+    let addr = "127.0.0.1:4048";
+    let temp_dir = TempDir::new().unwrap();
+    // Both connections stay open, each keeping a worker.
+    let mut child = Command::new(cargo_bin!("kvs-server"))
+        .args(["--engine", "kvs", "--addr", addr, "--threads", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let mut other = KvsClient::connect(addr).unwrap();
+    client.set("counter".to_owned(), "1".to_owned()).unwrap();
+
+    client.begin(vec!["counter".to_owned()]).unwrap();
+    client.set("counter".to_owned(), "2".to_owned()).unwrap();
+    client.set("history".to_owned(), "1,2".to_owned()).unwrap();
+    // Reads aren't queued, and don't see the queued writes.
+    assert_eq!(
+        client.get("counter".to_owned()).unwrap(),
+        Some("1".to_owned())
+    );
+    other.set("counter".to_owned(), "5".to_owned()).unwrap();
+    let result = client.commit();
+    assert!(matches!(result, Err(KvsError::Conflict)), "{result:?}");
+    assert_eq!(
+        client.get("counter".to_owned()).unwrap(),
+        Some("5".to_owned())
+    );
+    assert_eq!(client.get("history".to_owned()).unwrap(), None);
+
+    client.begin(vec!["counter".to_owned()]).unwrap();
+    client.set("counter".to_owned(), "6".to_owned()).unwrap();
+    client.set("history".to_owned(), "5,6".to_owned()).unwrap();
+    client.commit().unwrap();
+    assert_eq!(
+        other.get("counter".to_owned()).unwrap(),
+        Some("6".to_owned())
+    );
+    assert_eq!(
+        other.get("history".to_owned()).unwrap(),
+        Some("5,6".to_owned())
+    );
+
+    // Only sets and removes can be queued, and only a begun transaction
+    // committed.
+    let mut client = client.into_inner();
+    let response = client.request(&Request::Begin { watch: vec![] }).unwrap();
+    assert!(matches!(response, Response::Ok), "{response:?}");
+    let response = client
+        .request(&Request::Incr {
+            key: "counter".to_owned(),
+            delta: 1,
+        })
+        .unwrap();
+    assert!(
+        matches!(
+            response,
+            Response::Err {
+                code: ErrorCode::BadRequest,
+                ..
+            }
+        ),
+        "{response:?}"
+    );
+    let response = client.request(&Request::Commit).unwrap();
+    assert!(matches!(response, Response::Ok), "{response:?}");
+    let response = client.request(&Request::Commit).unwrap();
+    assert!(
+        matches!(
+            response,
+            Response::Err {
+                code: ErrorCode::BadRequest,
+                ..
+            }
+        ),
+        "{response:?}"
+    );
+    client.shutdown().unwrap();
+    other.shutdown().unwrap();
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
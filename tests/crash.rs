@@ -0,0 +1,374 @@
+//! Torture test for compaction under injected crashes.
+//!
+//! Run with `cargo test --features fault-injection --test crash`. Set
+//! `KVS_CRASH_ITERATIONS` to run more than the default number of rounds.
+use std::collections::BTreeMap;
+use std::env;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::{
+    BatchOp, CompactionStrategy, FaultyStorage, KvStore, KvStoreConfig, KvsEngine, KvsError, Result,
+};
+
+/// A small xorshift generator, so every failing seed can be replayed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+fn config() -> KvStoreConfig {
+    // Small files so a compaction has several of them to retire.
+    KvStoreConfig::default()
+        .compaction(CompactionStrategy::Off)
+        .max_log_size(256)
+}
+
+/// Apply random sets and removes to both `store` and `model`.
+fn random_ops(rng: &mut Rng, store: &KvStore, model: &mut BTreeMap<String, String>) -> Result<()> {
+    for _ in 0..rng.below(64) {
+        let key = format!("key{}", rng.below(16));
+        if rng.below(4) == 0 && model.contains_key(&key) {
+            store.remove(key.clone())?;
+            model.remove(&key);
+        } else {
+            let value = "v".repeat(rng.below(32) as usize);
+            store.set(key.clone(), value.clone())?;
+            model.insert(key, value);
+        }
+    }
+    Ok(())
+}
+
+fn assert_matches(store: &KvStore, model: &BTreeMap<String, String>, seed: u64) -> Result<()> {
+    let live: BTreeMap<String, String> = store.scan(String::new())?.into_iter().collect();
+    assert_eq!(&live, model, "seed {seed}");
+    Ok(())
+}
+
+/// Crash at a random point of each compaction. Every write acknowledged
+/// before it must survive, and no removed key may come back, whether the
+/// store is reopened or keeps going after the fault clears.
+#[test]
+fn crash_during_compaction() -> Result<()> {
+    let iterations = env::var("KVS_CRASH_ITERATIONS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(2000);
+
+    for seed in 0..iterations {
+        let mut rng = Rng::new(seed);
+        let storage = FaultyStorage::new();
+        let mut model = BTreeMap::new();
+        let mut store = KvStore::open_faulty(&storage, config())?;
+
+        for _ in 0..4 {
+            random_ops(&mut rng, &store, &mut model)?;
+            storage.crash_after(rng.below(48));
+            let compacted = store.compact();
+            let crashed = storage.crashed();
+            storage.heal();
+            assert_eq!(compacted.is_err(), crashed, "seed {seed}");
+
+            if crashed && rng.below(2) == 0 {
+                // The process died, start over from what reached the storage.
+                drop(store);
+                store = KvStore::open_faulty(&storage, config())?;
+            }
+            assert_matches(&store, &model, seed)?;
+        }
+
+        drop(store);
+        let store = KvStore::open_faulty(&storage, config())?;
+        assert_matches(&store, &model, seed)?;
+    }
+    Ok(())
+}
+
+/// A compaction cut short at any point, then the process dying, leaves
+/// either all the old logs or the complete new one, never a partial log that
+/// `open` would replay.
+#[test]
+fn compaction_swaps_atomically() -> Result<()> {
+    let is_side_log = |path: &Path| path.to_string_lossy().ends_with(".compacting");
+    for crash_at in 0.. {
+        let storage = FaultyStorage::new();
+        let store = KvStore::open_faulty(&storage, config())?;
+        let mut model = BTreeMap::new();
+        for i in 0..48 {
+            let key = format!("key{}", i % 12);
+            if i % 5 == 4 && model.contains_key(&key) {
+                store.remove(key.clone())?;
+                model.remove(&key);
+            } else {
+                store.set(key.clone(), format!("value{i}"))?;
+                model.insert(key, format!("value{i}"));
+            }
+        }
+        let old_logs = storage.files(Path::new(""));
+
+        storage.crash_after(crash_at);
+        let compacted = store.compact();
+        drop(store);
+        let crashed = storage.crashed();
+        storage.heal();
+        if compacted.is_ok() {
+            assert!(!crashed);
+            assert!(crash_at > 0);
+            return Ok(());
+        }
+
+        let logs = storage.files(Path::new(""));
+        let new_logs = logs
+            .iter()
+            .filter(|path| !old_logs.contains(path) && !is_side_log(path))
+            .count();
+        if new_logs == 0 {
+            assert!(
+                old_logs.iter().all(|path| logs.contains(path)),
+                "crash {crash_at}"
+            );
+        }
+        let store = KvStore::open_faulty(&storage, config())?;
+        assert_matches(&store, &model, crash_at)?;
+        assert!(
+            !storage.files(Path::new("")).iter().any(|p| is_side_log(p)),
+            "crash {crash_at}"
+        );
+    }
+    unreachable!()
+}
+
+/// A log file that can't be rolled over keeps taking writes, unless the
+/// config asks for them to fail, and the store stays consistent either way.
+#[test]
+fn failed_rotation() -> Result<()> {
+    let storage = FaultyStorage::new();
+    let store = KvStore::open_faulty(&storage, config())?;
+    let mut model = BTreeMap::new();
+    for i in 0..8 {
+        store.set(format!("key{i}"), "v".repeat(32))?;
+        model.insert(format!("key{i}"), "v".repeat(32));
+    }
+    let files = storage.files(Path::new(""));
+
+    storage.refuse_new_files(true);
+    for i in 8..16 {
+        store.set(format!("key{i}"), "v".repeat(32))?;
+        model.insert(format!("key{i}"), "v".repeat(32));
+    }
+    assert_eq!(storage.files(Path::new("")), files);
+    assert_matches(&store, &model, 0)?;
+
+    let strict = KvStore::open_faulty(&storage, config().strict_rotation(true))?;
+    assert!(strict.set("key0".to_owned(), "lost".to_owned()).is_err());
+    assert_eq!(storage.files(Path::new("")), files);
+    drop(strict);
+
+    storage.refuse_new_files(false);
+    store.set("key16".to_owned(), "v".to_owned())?;
+    model.insert("key16".to_owned(), "v".to_owned());
+    assert!(storage.files(Path::new("")).len() > files.len());
+    assert_matches(&store, &model, 0)?;
+
+    drop(store);
+    let store = KvStore::open_faulty(&storage, config())?;
+    assert_matches(&store, &model, 0)?;
+    Ok(())
+}
+
+/// With failures tolerated, a compaction that fails doesn't fail the write
+/// that triggered it, and the store keeps serving until it compacts again.
+#[test]
+fn tolerated_compaction_failure() -> Result<()> {
+    let storage = FaultyStorage::new();
+    let tolerant = config()
+        .compaction(CompactionStrategy::Ratio(0.0))
+        .tolerate_compaction_failures(true);
+    let store = KvStore::open_faulty(&storage, tolerant.clone())?;
+    let mut model = BTreeMap::new();
+    for i in 0..8 {
+        store.set(format!("key{i}"), "v".repeat(32))?;
+        model.insert(format!("key{i}"), "v".repeat(32));
+    }
+    let compactions = store.compactions();
+
+    // The overwrite lands, then its compaction crashes on its first change.
+    storage.crash_after(1);
+    store.set("key0".to_owned(), "new".to_owned())?;
+    model.insert("key0".to_owned(), "new".to_owned());
+    assert!(storage.crashed());
+    assert_eq!(store.compactions(), compactions);
+    assert_matches(&store, &model, 0)?;
+
+    storage.heal();
+    store.set("key1".to_owned(), "new".to_owned())?;
+    model.insert("key1".to_owned(), "new".to_owned());
+    assert_matches(&store, &model, 0)?;
+    store.compact()?;
+    assert_matches(&store, &model, 0)?;
+
+    drop(store);
+    let store = KvStore::open_faulty(&storage, config())?;
+    assert_matches(&store, &model, 0)?;
+
+    // Without the option the same failure fails the write, though it landed.
+    let storage = FaultyStorage::new();
+    let store = KvStore::open_faulty(&storage, tolerant.tolerate_compaction_failures(false))?;
+    store.set("key".to_owned(), "old".to_owned())?;
+    storage.crash_after(1);
+    assert!(store.set("key".to_owned(), "new".to_owned()).is_err());
+    storage.heal();
+    assert_eq!(store.get("key".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+/// A read stuck on a slow disk holds up no writer, not even a compaction
+/// deleting the file it was about to read, and then finds the record where
+/// the compaction moved it.
+#[test]
+fn slow_read_blocks_no_writer() -> Result<()> {
+    const WRITERS: usize = 4;
+
+    let storage = FaultyStorage::new();
+    let store = KvStore::open_faulty(&storage, KvStoreConfig::default())?;
+    store.set("slow".to_owned(), "value".to_owned())?;
+    let files = storage.files(Path::new(""));
+
+    storage.stall_next_open();
+    let reader = {
+        let store = store.clone();
+        thread::spawn(move || store.get("slow".to_owned()))
+    };
+    // Let it look the key up and get stuck opening the log.
+    thread::sleep(Duration::from_millis(100));
+
+    let writers: Vec<_> = (0..WRITERS)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    store.set(format!("key{t}-{i}"), "v".to_owned()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    store.compact()?;
+    assert!(!reader.is_finished());
+    let left = storage.files(Path::new(""));
+    assert!(files.iter().all(|file| !left.contains(file)));
+
+    storage.resume_opens();
+    assert_eq!(reader.join().unwrap()?, Some("value".to_owned()));
+    Ok(())
+}
+
+/// A file is synced as it is rolled over, so a flush, syncing the current
+/// one, leaves every write made so far on disk.
+#[test]
+fn flush_syncs_current_file() -> Result<()> {
+    let storage = FaultyStorage::new();
+    let store = KvStore::open_faulty(&storage, config())?;
+    for i in 0..32 {
+        store.set(format!("key{i}"), "v".repeat(32))?;
+    }
+    let logs = storage.files(Path::new("")).len();
+    assert!(logs > 1);
+    assert_eq!(storage.syncs(), logs as u64 - 1);
+
+    store.flush()?;
+    assert_eq!(storage.syncs(), logs as u64);
+    Ok(())
+}
+
+/// A batch is appended to one file and synced once, however large, and
+/// survives a reopen in full.
+#[test]
+fn batch_syncs_once() -> Result<()> {
+    let storage = FaultyStorage::new();
+    let store = KvStore::open_faulty(&storage, config())?;
+    let mut model = BTreeMap::new();
+    let mut batch = Vec::new();
+    for i in 0..1000 {
+        batch.push(BatchOp::Set(format!("key{i}"), "v".repeat(32)));
+        model.insert(format!("key{i}"), "v".repeat(32));
+    }
+    for i in (0..1000).step_by(3) {
+        batch.push(BatchOp::Remove(format!("key{i}")));
+        model.remove(&format!("key{i}"));
+    }
+    let files = storage.files(Path::new(""));
+
+    store.write_batch(batch)?;
+    assert_eq!(storage.syncs(), 1);
+    assert_eq!(storage.files(Path::new("")).len(), files.len());
+    assert_matches(&store, &model, 0)?;
+
+    // A batch failing its checks writes nothing.
+    let invalid = vec![
+        BatchOp::Set("new".to_owned(), "v".to_owned()),
+        BatchOp::Remove("key0".to_owned()),
+    ];
+    assert!(matches!(
+        store.write_batch(invalid),
+        Err(KvsError::NonExistentKey(key)) if key == "key0"
+    ));
+    assert_eq!(storage.syncs(), 1);
+    assert_matches(&store, &model, 0)?;
+
+    drop(store);
+    let store = KvStore::open_faulty(&storage, config())?;
+    assert_matches(&store, &model, 0)?;
+    Ok(())
+}
+
+/// A deadline stops a scan or a compaction held up by a slow disk part way
+/// through, leaving the store as it was.
+#[test]
+fn deadline_stops_slow_operations() -> Result<()> {
+    let storage = FaultyStorage::new();
+    let store = KvStore::open_faulty(&storage, config())?;
+    let mut model = BTreeMap::new();
+    for i in 0..32 {
+        store.set(format!("key{i}"), "v".repeat(32))?;
+        model.insert(format!("key{i}"), "v".repeat(32));
+    }
+
+    // Each gets stuck opening the first log it reads, past its deadline.
+    let stalled = |op: fn(&KvStore) -> Result<()>| {
+        storage.stall_next_open();
+        let store = store.with_deadline(Instant::now() + Duration::from_millis(50));
+        let op = thread::spawn(move || op(&store));
+        thread::sleep(Duration::from_millis(200));
+        storage.resume_opens();
+        op.join().unwrap()
+    };
+    let scan = stalled(|store| store.scan(String::new()).map(drop));
+    assert!(matches!(scan, Err(KvsError::DeadlineExceeded)), "{scan:?}");
+    let compact = stalled(KvStore::compact);
+    assert!(
+        matches!(compact, Err(KvsError::DeadlineExceeded)),
+        "{compact:?}"
+    );
+    assert_eq!(store.compactions(), 0);
+    assert_matches(&store, &model, 0)?;
+
+    store.compact()?;
+    assert_eq!(store.compactions(), 1);
+    assert_matches(&store, &model, 0)?;
+    Ok(())
+}
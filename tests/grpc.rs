@@ -0,0 +1,142 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use kvs::grpc::KvsService;
+use kvs::grpc::pb::kvs_client::KvsClient;
+use kvs::grpc::pb::{GetRequest, Pair, RemoveRequest, ScanRequest, SetRequest};
+use kvs::{KvStore, MemoryEngine};
+use tempfile::TempDir;
+use tonic::transport::Channel;
+use tonic::{Code, Request};
+
+// Set, get, scan and remove through a generated client.
+#[tokio::test]
+async fn grpc_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let addr = "127.0.0.1:4009".parse().unwrap();
+    tokio::spawn(kvs::grpc::serve(engine, addr));
+
+    let mut client = loop {
+        match KvsClient::connect("http://127.0.0.1:4009").await {
+            Ok(client) => break client,
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+
+    for (key, value) in [("key1", "value1"), ("key2", "value2"), ("other", "x")] {
+        client
+            .set(SetRequest {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            })
+            .await
+            .unwrap();
+    }
+    let reply = client
+        .get(GetRequest {
+            key: "key1".to_owned(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(reply.into_inner().value, Some("value1".to_owned()));
+
+    let reply = client
+        .scan(ScanRequest {
+            prefix: "key".to_owned(),
+        })
+        .await
+        .unwrap();
+    let pair = |key: &str, value: &str| Pair {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    };
+    assert_eq!(
+        reply.into_inner().pairs,
+        vec![pair("key1", "value1"), pair("key2", "value2")]
+    );
+
+    client
+        .remove(RemoveRequest {
+            key: "key1".to_owned(),
+        })
+        .await
+        .unwrap();
+    let reply = client
+        .get(GetRequest {
+            key: "key1".to_owned(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(reply.into_inner().value, None);
+    let status = client
+        .remove(RemoveRequest {
+            key: "key1".to_owned(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+}
+
+// A service with a token refuses calls without it, and one in read-only mode
+// refuses writes.
+#[tokio::test]
+async fn grpc_auth_and_read_only() {
+    let read_only = Arc::new(AtomicBool::new(false));
+    let service = KvsService::new(MemoryEngine::new())
+        .auth_token(Some("secret".to_owned()))
+        .read_only(read_only.clone());
+    let addr = "127.0.0.1:4054".parse().unwrap();
+    tokio::spawn(kvs::grpc::serve_service(service, addr));
+
+    let channel = loop {
+        match Channel::from_static("http://127.0.0.1:4054")
+            .connect()
+            .await
+        {
+            Ok(channel) => break channel,
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    let set = || SetRequest {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    let with_token = |token: &'static str| {
+        move |mut request: Request<()>| {
+            let value = format!("Bearer {token}").parse().unwrap();
+            request.metadata_mut().insert("authorization", value);
+            Ok(request)
+        }
+    };
+
+    let mut anonymous = KvsClient::new(channel.clone());
+    let status = anonymous.set(set()).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+    let mut wrong = KvsClient::with_interceptor(channel.clone(), with_token("secre"));
+    let status = wrong.set(set()).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+
+    let mut client = KvsClient::with_interceptor(channel, with_token("secret"));
+    let get = || GetRequest {
+        key: "key1".to_owned(),
+    };
+    assert_eq!(client.get(get()).await.unwrap().into_inner().value, None);
+    client.set(set()).await.unwrap();
+
+    read_only.store(true, Ordering::Relaxed);
+    let status = client.set(set()).await.unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    let status = client
+        .remove(RemoveRequest {
+            key: "key1".to_owned(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    assert_eq!(
+        client.get(get()).await.unwrap().into_inner().value,
+        Some("value1".to_owned())
+    );
+}
@@ -1,9 +1,65 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    BatchOp, Change, ChangeKind, Codec, CompactionStrategy, Compression, ExportEntry, FlushPolicy,
+    KvStore, KvStoreConfig, KvsEngine, KvsError, LockContention, LogFormat, MemoryEngine, ModifyOp,
+    OpenMode, Result, SizeLimits, SledEngine, VerifyLevel,
+};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+/// Run each generic test below on a fresh engine of every kind, as the tests
+/// `<name>::kvs`, `<name>::sled` and `<name>::memory`. Tests that open their
+/// engines another way, like with size limits, have wrappers of their own.
+macro_rules! engine_tests {
+    ($($name:ident),* $(,)?) => {$(
+        mod $name {
+            use super::*;
+
+            #[test]
+            fn kvs() -> Result<()> {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                super::$name(KvStore::open(temp_dir.path())?)
+            }
+
+            #[test]
+            fn sled() -> Result<()> {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                super::$name(SledEngine::open(temp_dir.path())?)
+            }
+
+            #[test]
+            fn memory() -> Result<()> {
+                super::$name(MemoryEngine::new())
+            }
+        }
+    )*};
+}
+
+engine_tests! {
+    remove_non_existent,
+    increment_float,
+    commit,
+    contains_key,
+    modify,
+    increment,
+    take,
+    get_set,
+    remove_prefix,
+    len,
+    binary_values,
+    expiry,
+    watch_changes,
+    watch_expired,
+}
+
 // Should get previously stored value
 #[test]
 fn get_stored_value() -> Result<()> {
@@ -71,6 +127,18 @@ fn remove_non_existent_key() -> Result<()> {
     Ok(())
 }
 
+// Both engines report a missing key the same way.
+fn remove_non_existent<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    for key in ["key1", "key2"] {
+        assert!(
+            matches!(store.remove(key.to_owned()), Err(KvsError::NonExistentKey(k)) if k == key)
+        );
+    }
+    Ok(())
+}
+
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -81,131 +149,2161 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// Keys and values may contain any character, including record-like separators.
 #[test]
-fn compaction() -> Result<()> {
+fn binary_safe_records() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
+    let pairs = [
+        ("key\n1".to_owned(), "value\n\nwith newlines\n".to_owned()),
+        ("key\0\x32".to_owned(), "value\0with\0nulls".to_owned()),
+        ("key 3".to_owned(), "set key 3 value\nrm key 3".to_owned()),
+        ("".to_owned(), "".to_owned()),
+    ];
+    for (key, value) in pairs.iter() {
+        store.set(key.clone(), value.clone())?;
+    }
+    store.remove("key 3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(pairs[0].0.clone())?, Some(pairs[0].1.clone()));
+    assert_eq!(store.get(pairs[1].0.clone())?, Some(pairs[1].1.clone()));
+    assert_eq!(store.get(pairs[2].0.clone())?, None);
+    assert_eq!(store.get(pairs[3].0.clone())?, Some(pairs[3].1.clone()));
+    Ok(())
+}
+
+// Records are read by the length stored in their index, not by scanning for
+// the end of a line.
+#[test]
+fn records_read_by_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "line one\nline two\n".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut index = store.index();
+    index.sort_by_key(|(_, idx)| idx.offset());
+    let log = fs::read(index[0].1.path())?;
+    assert_eq!(index[0].1.offset() + index[0].1.len(), index[1].1.offset());
+    assert_eq!(index[1].1.offset() + index[1].1.len(), log.len() as u64);
+    let start = index[0].1.offset() as usize;
+    let record = &log[start..start + index[0].1.len() as usize];
+    assert!(record.windows(9).any(|w| w == b"line one\n"));
+
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("line one\nline two\n".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Indexes of records in the same log point at one shared path, whether they
+// were written, replayed on open or copied by a compaction.
+#[test]
+fn index_paths_shared() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let shared = |store: &KvStore| {
+        let index = store.index();
+        let first = index[0].1.path();
+        index.len() > 1 && index.iter().all(|(_, idx)| std::ptr::eq(idx.path(), first))
     };
 
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
-        }
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(shared(&store));
+    store.compact()?;
+    assert!(shared(&store));
+    drop(store);
 
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
-        }
-        // Compaction triggered
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(shared(&store));
+    Ok(())
+}
 
-        drop(store);
-        // reopen and check content
-        let store = KvStore::open(temp_dir.path())?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
-        }
-        return Ok(());
+// A second store writing to an open directory is refused instead of
+// corrupting the first one's logs, until every clone of the first is dropped.
+#[test]
+fn open_twice() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let result = KvStore::open(temp_dir.path());
+    assert!(
+        matches!(&result, Err(KvsError::AlreadyOpen(path)) if path == temp_dir.path()),
+        "{:?}",
+        result.err()
+    );
+
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::AlreadyOpen(_))
+    ));
+    clone.set("key2".to_owned(), "value2".to_owned())?;
+    drop(clone);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A read-only store reads alongside the writer, catching up with its writes
+// and compactions by reloading, while a second writer is turned away.
+#[test]
+fn open_readonly_alongside_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let writer = KvStore::open(temp_dir.path())?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+    let reader = KvStore::open_readonly(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::AlreadyOpen(_))
+    ));
+
+    writer.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    reader.reload()?;
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // The compaction deletes the log the reader's index points into, so a
+    // clone without a handle open on it reloads when it finds it gone.
+    writer.set("key1".to_owned(), "value3".to_owned())?;
+    writer.compact()?;
+    let clone = reader.clone();
+    assert_eq!(clone.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(
+        reader.scan("key".to_owned())?,
+        vec![
+            ("key1".to_owned(), "value3".to_owned()),
+            ("key2".to_owned(), "value2".to_owned())
+        ]
+    );
+
+    drop(writer);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+// Values over the compression threshold are stored compressed and read back
+// unchanged, smaller ones are stored as they are.
+#[test]
+fn compressed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::default().compression(Some(Compression {
+        codec: Codec::Zstd(3),
+        threshold: 1024,
+    }));
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+
+    let large = "compressible ".repeat(10_000);
+    let small = "small value ".repeat(50);
+    store.set("large".to_owned(), large.clone())?;
+    store.set_with_ttl("small".to_owned(), small.clone(), Duration::from_secs(3600))?;
+
+    let log: Vec<u8> = fs::read_dir(temp_dir.path())?
+        .map(|entry| fs::read(entry?.path()))
+        .collect::<std::io::Result<Vec<_>>>()?
+        .concat();
+    assert!(log.len() < large.len() / 10);
+    assert!(
+        log.windows(small.len())
+            .any(|window| window == small.as_bytes())
+    );
+
+    drop(store);
+    for config in [config, KvStoreConfig::default()] {
+        let store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+        assert_eq!(store.get("small".to_owned())?, Some(small.clone()));
+    }
+    Ok(())
+}
+
+// Should get the live pairs under a prefix, ordered by key
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["b:2", "a:1", "b:1", "b:3", "c:1"] {
+        store.set(key.to_owned(), format!("{}-value", key))?;
     }
+    store.set("b:2".to_owned(), "updated".to_owned())?;
+    store.remove("b:3".to_owned())?;
 
-    panic!("No compaction detected");
+    let pairs = store.scan("b:".to_owned())?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("b:1".to_owned(), "b:1-value".to_owned()),
+            ("b:2".to_owned(), "updated".to_owned()),
+        ]
+    );
+    assert_eq!(store.scan("".to_owned())?.len(), 4);
+    assert!(store.scan("d".to_owned())?.is_empty());
+    Ok(())
 }
 
+// A flipped byte inside the log is reported with the file and offset of the bad record.
 #[test]
-fn concurrent_set() -> Result<()> {
+fn corrupted_record() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    let barrier = Arc::new(Barrier::new(1001));
-    for i in 0..1000 {
-        let store = store.clone();
-        let barrier = barrier.clone();
-        thread::spawn(move || {
-            store
-                .set(format!("key{}", i), format!("value{}", i))
-                .unwrap();
-            barrier.wait();
-        });
+    for i in 1..=3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
     }
-    barrier.wait();
+    drop(store);
 
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    // After the 4 byte header each record is 17 bytes long, flip a byte in the
+    // value of the second one.
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log)?;
+    content[4 + 17 + 8] ^= 0xff;
+    fs::write(&log, content)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::ChecksumMismatch { file, offset }) => {
+            assert_eq!(file, log);
+            assert_eq!(offset, 4 + 17);
+        }
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("corruption not detected"),
     }
+    Ok(())
+}
 
-    // Open from disk again and check persistent data
+// Files written in older formats are read alongside new binary ones.
+#[test]
+fn mixed_format_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // The original text format, without a header.
+    fs::write(
+        temp_dir.path().join("1.log"),
+        "set key1 value1\nset key2 value2\nrm key1\nset key3 value3\n",
+    )?;
+    // JSON lines, tagged with format 1 in the header.
+    fs::write(
+        temp_dir.path().join("2.log"),
+        "KVS\x01{\"Set\":[\"key3\",\"json3\"]}\n{\"Remove\":\"key2\"}\n{\"Set\":[\"key4\",\"json4\"]}\n",
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("json3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("json4".to_owned()));
+
+    // New records go to a new binary file instead of the JSON one.
+    store.set("key5".to_owned(), "value5".to_owned())?;
     drop(store);
+    assert!(fs::read(temp_dir.path().join("3.log"))?.starts_with(b"KVS\x02"));
+
     let store = KvStore::open(temp_dir.path())?;
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
-    }
+    assert_eq!(
+        store.scan("key".to_owned())?,
+        vec![
+            ("key3".to_owned(), "json3".to_owned()),
+            ("key4".to_owned(), "json4".to_owned()),
+            ("key5".to_owned(), "value5".to_owned()),
+        ]
+    );
+    store.compact()?;
+    assert_eq!(store.get("key3".to_owned())?, Some("json3".to_owned()));
+    Ok(())
+}
+
+// A directory of text logs keeps being written as text, compactions included,
+// whatever format new directories are configured to get.
+#[test]
+fn text_format_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    fs::write(&log, "set key1 value1\nset key2 value2\nrm key1\n")?;
+
+    let config = KvStoreConfig::default().log_format(LogFormat::Binary);
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(
+        fs::read_to_string(&log)?,
+        "set key1 value1\nset key2 value2\nrm key1\nset key3 value3\nrm key2\n"
+    );
+
+    // Records the text format can't hold are refused, leaving the log alone.
+    let result = store.set("key4".to_owned(), "two words".to_owned());
+    assert!(matches!(result, Err(KvsError::UnsupportedByFormat(_))));
+    let result = store.set_with_ttl("key4".to_owned(), "v".to_owned(), Duration::from_secs(60));
+    assert!(matches!(result, Err(KvsError::UnsupportedByFormat(_))));
+    let result = store.write_batch(vec![
+        BatchOp::Set("key4".to_owned(), "value4".to_owned()),
+        BatchOp::Set("key 5".to_owned(), "value5".to_owned()),
+    ]);
+    assert!(matches!(result, Err(KvsError::UnsupportedByFormat(_))));
+    assert_eq!(store.get("key4".to_owned())?, None);
+    assert!(fs::read_to_string(&log)?.ends_with("rm key2\n"));
 
+    store.compact()?;
+    drop(store);
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("2.log"))?,
+        "set key3 value3\n"
+    );
+    assert!(!temp_dir.path().join("2.hint").exists());
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
     Ok(())
 }
 
+// New directories get the configured format, which a directory holding
+// records of the other one ignores.
 #[test]
-fn concurrent_get() -> Result<()> {
+fn configured_log_format() -> Result<()> {
+    let text = KvStoreConfig::default().log_format(LogFormat::Text);
+
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_config(temp_dir.path(), text.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let log = temp_dir.path().join("1.log");
+    assert_eq!(fs::read_to_string(&log)?, "set key1 value1\n");
     let store = KvStore::open(temp_dir.path())?;
-    for i in 0..100 {
-        store
-            .set(format!("key{}", i), format!("value{}", i))
-            .unwrap();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    assert_eq!(
+        fs::read_to_string(&log)?,
+        "set key1 value1\nset key2 value2\n"
+    );
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), text)?;
+    store.set("key2".to_owned(), "two words".to_owned())?;
+    drop(store);
+    assert!(fs::read(temp_dir.path().join("1.log"))?.starts_with(b"KVS\x02"));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("two words".to_owned()));
+    Ok(())
+}
+
+// A torn last record is dropped on open instead of making the store unopenable.
+#[test]
+fn corrupted_trailing_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let valid_len = fs::metadata(&log)?.len();
+    // Records cut short in their checksum, their value and their key, and a
+    // large one whose value bytes look like records themselves.
+    let mut large = b"\x01\x04key3\x80\x80\x04".to_vec();
+    large.resize(large.len() + 60_000, 1);
+    for garbage in [
+        &b"\x01\x04key3\x06value3\0\0"[..],
+        &b"\x01\x04key3\x06val"[..],
+        &b"\x01\x04ke"[..],
+        &large,
+    ] {
+        OpenOptions::new()
+            .append(true)
+            .open(&log)?
+            .write_all(garbage)?;
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(fs::metadata(&log)?.len(), valid_len);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
     }
 
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
-            }
-        });
-        handles.push(handle);
+    // A complete record with a bad checksum wasn't torn while being appended.
+    OpenOptions::new()
+        .append(true)
+        .open(&log)?
+        .write_all(b"\x01\x04key3\x06value3\0\0\0\0")?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::ChecksumMismatch { offset, .. }) if offset == valid_len
+    ));
+    OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(valid_len)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A length corrupted mid-log so its record runs to the end of the log isn't
+// mistaken for a torn tail, which would silently drop the records after it.
+#[test]
+fn corrupted_length_mid_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 1..=3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
     }
-    for handle in handles {
-        handle.join().unwrap();
+    drop(store);
+
+    // After the 4 byte header each set is 17 bytes long, make the value
+    // length of the first one run past the end of the log.
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log)?;
+    assert_eq!(content[4 + 6], 6);
+    content[4 + 6] = 0x7f;
+    fs::write(&log, &content)?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::ChecksumMismatch { offset: 4, .. })
+    ));
+    assert_eq!(fs::read(&log)?, content);
+    Ok(())
+}
+
+// A line based log cut off mid-line, as by a crash, loses only that line.
+#[test]
+fn truncated_text_line() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    fs::write(&log, "set key1 value1\nset key2 value2\nset key")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::read(&log)?, b"set key1 value1\nset key2 value2\n");
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, None);
+    Ok(())
+}
+
+// `CurrentFileOnly` still drops a torn tail, but leaves sealed files to be
+// verified when their records are read.
+#[test]
+fn verify_on_open_levels() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::default().max_log_size(64);
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
     }
+    drop(store);
+    let logs = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "log"))
+        .count();
+    assert!(logs >= 3);
 
-    // Open from disk again and check persistent data
+    // Flip a byte in the value of key0, in the sealed 1.log.
+    let sealed = temp_dir.path().join("1.log");
+    let mut content = fs::read(&sealed)?;
+    content[4 + 10] ^= 0x01;
+    fs::write(&sealed, content)?;
+    // And tear the tail of the current file.
+    let current = temp_dir.path().join(format!("{}.log", logs));
+    let valid_len = fs::metadata(&current)?.len();
+    OpenOptions::new()
+        .append(true)
+        .open(&current)?
+        .write_all(b"\x01\x04key3\x06val")?;
+
+    let open =
+        |level| KvStore::open_with_config(temp_dir.path(), config.clone().verify_on_open(level));
+    let store = open(VerifyLevel::CurrentFileOnly)?;
+    assert_eq!(store.verified_files(), 1);
+    assert_eq!(fs::metadata(&current)?.len(), valid_len);
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    assert!(matches!(
+        store.get("key0".to_owned()),
+        Err(KvsError::ChecksumMismatch { .. })
+    ));
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
-            }
-        });
-        handles.push(handle);
+
+    assert_eq!(open(VerifyLevel::None)?.verified_files(), 0);
+    assert!(matches!(
+        open(VerifyLevel::All),
+        Err(KvsError::ChecksumMismatch { offset: 4, .. })
+    ));
+    Ok(())
+}
+
+// After a compaction the index loaded from its hint file matches the one
+// replayed from the logs, records written since and expiries included, and a
+// hint that can't be read only costs a full replay.
+#[test]
+fn hint_matches_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::default()
+        .compaction(CompactionStrategy::Off)
+        .verify_on_open(VerifyLevel::CurrentFileOnly);
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
     }
-    for handle in handles {
-        handle.join().unwrap();
+    for i in 0..10 {
+        store.remove(format!("key{}", i))?;
     }
+    store.set_with_ttl(
+        "lease".to_owned(),
+        "holder".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.compact()?;
+    // Written after the hint, so replayed on top of it.
+    store.set("key10".to_owned(), "again".to_owned())?;
+    store.remove("key11".to_owned())?;
+    store.set("new".to_owned(), "value".to_owned())?;
+    drop(store);
+    let hints: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "hint"))
+        .collect();
+    assert_eq!(hints.len(), 1);
+    thread::sleep(Duration::from_millis(300));
+
+    let snapshot = || -> Result<_> {
+        let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        let mut index: Vec<_> = store
+            .index()
+            .into_iter()
+            .map(|(key, idx)| {
+                let path = idx.path().to_owned();
+                (key, path, idx.offset(), idx.len(), idx.expires_at())
+            })
+            .collect();
+        index.sort();
+        assert_eq!(store.get("key10".to_owned())?, Some("again".to_owned()));
+        assert_eq!(store.get("key11".to_owned())?, None);
+        assert_eq!(store.get("lease".to_owned())?, None);
+        Ok((index, store.stale_bytes()))
+    };
+    let hinted = snapshot()?;
+    assert_eq!(hinted.0.len(), 90);
+
+    fs::write(&hints[0], b"garbage")?;
+    assert_eq!(snapshot()?, hinted);
+    fs::remove_file(&hints[0])?;
+    assert_eq!(snapshot()?, hinted);
+    Ok(())
+}
+
+// Only `<n>.log` files directly in the data directory belong to the store.
+#[test]
+fn ignore_nested_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let nested = temp_dir.path().join("backup");
+    fs::create_dir(&nested)?;
+    fs::write(nested.join("99.log"), "set key stray\n")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, None);
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    assert!(temp_dir.path().join("1.log").exists());
+    assert!(!temp_dir.path().join("99.log").exists());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+/// The bytes of a log holding `pairs`, set in order.
+fn log_of(pairs: &[(&str, &str)]) -> Result<Vec<u8>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for (key, value) in pairs {
+        store.set(key.to_string(), value.to_string())?;
+    }
+    drop(store);
+    Ok(fs::read(temp_dir.path().join("1.log"))?)
+}
+
+// Logs replay in the order of their numbers however far apart they are,
+// writes and compactions carry on from the newest, and `.log` files not named
+// like a log are left alone.
+#[test]
+fn gapped_and_large_log_numbers() -> Result<()> {
+    let older = log_of(&[("first", "1"), ("shared", "old")])?;
+    let newer = log_of(&[("shared", "new"), ("second", "2")])?;
+    for (old, new) in [(3u64, 7u64), (1 << 32, (1 << 53) + 1)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let dir = temp_dir.path();
+        fs::write(dir.join(format!("{old}.log")), &older)?;
+        fs::write(dir.join(format!("{new}.log")), &newer)?;
+        fs::write(dir.join(format!("0{old}.log")), &newer)?;
+        fs::write(dir.join("notes.log"), "not a log")?;
+
+        let store = KvStore::open(dir)?;
+        assert_eq!(store.get("first".to_owned())?, Some("1".to_owned()));
+        assert_eq!(store.get("shared".to_owned())?, Some("new".to_owned()));
+        store.set("third".to_owned(), "3".to_owned())?;
+        let files: Vec<_> = store
+            .file_liveness()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                dir.join(format!("{old}.log")),
+                dir.join(format!("{new}.log"))
+            ]
+        );
+
+        store.compact()?;
+        drop(store);
+        assert!(!dir.join(format!("{old}.log")).exists());
+        assert!(!dir.join(format!("{new}.log")).exists());
+        assert!(dir.join(format!("{}.log", new + 1)).exists());
+        assert!(dir.join(format!("0{old}.log")).exists());
+        assert!(dir.join("notes.log").exists());
+
+        let store = KvStore::open(dir)?;
+        assert_eq!(store.get("shared".to_owned())?, Some("new".to_owned()));
+        assert_eq!(store.get("third".to_owned())?, Some("3".to_owned()));
+        assert_eq!(store.len()?, 4);
+    }
+    Ok(())
+}
+
+// Only the logs present are replayed, oldest first: a remove in the newer log
+// hides a key the older one set, and the newer value of a key wins.
+#[test]
+fn replay_sparse_logs_in_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let newer_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(newer_dir.path())?;
+    store.set("gone".to_owned(), "again".to_owned())?;
+    store.remove("gone".to_owned())?;
+    store.set("shared".to_owned(), "9".to_owned())?;
+    drop(store);
+    fs::copy(newer_dir.path().join("1.log"), dir.join("9.log"))?;
+    fs::write(
+        dir.join("6.log"),
+        log_of(&[("gone", "6"), ("shared", "6"), ("kept", "6")])?,
+    )?;
+
+    let store = KvStore::open(dir)?;
+    assert_eq!(store.get("gone".to_owned())?, None);
+    assert_eq!(store.get("shared".to_owned())?, Some("9".to_owned()));
+    assert_eq!(store.get("kept".to_owned())?, Some("6".to_owned()));
+    store.set("new".to_owned(), "9".to_owned())?;
+    drop(store);
+    let mut logs: Vec<_> = fs::read_dir(dir)?
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name.to_str().is_some_and(|name| name.ends_with(".log")))
+        .collect();
+    logs.sort();
+    assert_eq!(logs, ["6.log", "9.log"]);
+    Ok(())
+}
+
+// A data directory shared with other files opens like one of its own, and
+// compaction leaves those files alone.
+#[test]
+fn open_shared_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let config_toml = "[server]\nport = 4000\n";
+    fs::write(dir.join("config.toml"), config_toml)?;
+
+    let store = KvStore::open(dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    assert_eq!(fs::read_to_string(dir.join("config.toml"))?, config_toml);
+    Ok(())
+}
+
+// A store can be required to exist already, or not to, and a directory that
+// fails the requirement is left as it was.
+#[test]
+fn open_modes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    fs::write(dir.join("config.toml"), "")?;
+    let files = || -> Result<Vec<_>> {
+        let mut files: Vec<_> = fs::read_dir(dir)?
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        Ok(files)
+    };
+
+    let existing = KvStoreConfig::default().open_mode(OpenMode::Existing);
+    let result = KvStore::open_with_config(dir, existing.clone());
+    assert!(matches!(result, Err(KvsError::NoStore(_))));
+    assert_eq!(files()?, ["config.toml"]);
+
+    let new = KvStoreConfig::default().open_mode(OpenMode::New);
+    let store = KvStore::open_with_config(dir, new.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let before = files()?;
+    let result = KvStore::open_with_config(dir, new);
+    assert!(matches!(result, Err(KvsError::StoreExists(_))));
+    assert_eq!(files()?, before);
+    let store = KvStore::open_with_config(dir, existing)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+
+        drop(store);
+        // reopen and check content
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// Dropping the last clone of a store compacts it only when configured to,
+// and only once enough of its logs are stale.
+#[test]
+fn compact_on_close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::default().compaction(CompactionStrategy::Off);
+    let overwrite = |store: &KvStore| -> Result<()> {
+        for i in 0..100 {
+            store.set("key".to_owned(), format!("value{i}"))?;
+        }
+        Ok(())
+    };
+
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    overwrite(&store)?;
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let stale = store.stale_bytes();
+    assert!(stale > 0);
+    drop(store);
+
+    // Not stale enough yet.
+    let closing = config.clone().compact_on_close(Some(stale * 2));
+    let store = KvStore::open_with_config(temp_dir.path(), closing.clone())?;
+    store.close()?;
+    assert_eq!(store.stale_bytes(), stale);
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), closing)?;
+    overwrite(&store)?;
+    let clone = store.clone();
+    drop(store);
+    assert!(clone.stale_bytes() > 0);
+    drop(clone);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.stale_bytes(), 0);
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// An in-memory store runs the same log and compaction code as one on disk.
+// Large values under a byte-based threshold should roll over log files
+// without compacting on every overwrite.
+#[test]
+fn configured_thresholds() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::default()
+        .max_log_size(16 * 1024)
+        .compaction(CompactionStrategy::Size(1 << 20));
+    let value = "v".repeat(4096);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for i in 0..32 {
+        store.set(format!("key{}", i % 4), value.clone())?;
+    }
+    drop(store);
+
+    let log_count = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().extension().is_some_and(|ext| ext == "log"))
+            .count()
+    };
+    // 32 values of 4 KiB, no more than 4 of them to a file, none compacted away.
+    assert!(log_count() >= 8);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..4 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+    Ok(())
+}
+
+// Compaction is triggered by stale bytes, not by how many records are stale.
+#[test]
+fn byte_based_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::default().compaction(CompactionStrategy::Auto(8 * 1024));
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let dir_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.metadata().ok())
+            .filter(|m| m.is_file())
+            .map(|m| m.len())
+            .sum::<u64>()
+    };
+
+    // Hundreds of stale records that only add up to a few KiB.
+    for i in 0..500 {
+        store.set("s".to_owned(), format!("{}", i % 10))?;
+    }
+    let size = dir_size();
+    assert!(
+        size >= 500 * 9,
+        "compacted too early, logs are {size} bytes"
+    );
+
+    // A single overwrite of a large value is enough to go over 8 KiB.
+    let value = "v".repeat(6 * 1024);
+    store.set("large".to_owned(), value.clone())?;
+    assert!(dir_size() > size);
+    store.set("large".to_owned(), value.clone())?;
+    assert!(dir_size() < 7 * 1024, "expected a compaction");
+
+    assert_eq!(store.get("large".to_owned())?, Some(value));
+    assert_eq!(store.get("s".to_owned())?, Some("9".to_owned()));
+    Ok(())
+}
+
+// Each superseded record counts toward compaction exactly once.
+#[test]
+fn stale_accounting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::default().compaction(CompactionStrategy::Off);
+    // A `Set("key", "value")` record takes 15 bytes, a `Remove("key")` 9.
+    let (set_len, remove_len) = (15, 9);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.stale_bytes(), 0);
+    for _ in 0..100 {
+        store.set("key".to_owned(), "value".to_owned())?;
+    }
+    assert_eq!(store.stale_bytes(), 100 * set_len);
+
+    // Removing makes both the last set and the tombstone itself stale.
+    store.remove("key".to_owned())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.stale_bytes(), 101 * set_len + remove_len);
+    drop(store);
+
+    // Replaying the logs gives the same count.
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.stale_bytes(), 101 * set_len + remove_len);
+    store.compact()?;
+    assert_eq!(store.stale_bytes(), 0);
+    Ok(())
+}
+
+// Stale bytes are tracked per file, so the file holding overwritten keys stands out.
+#[test]
+fn file_liveness() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::default()
+        .compaction(CompactionStrategy::Off)
+        .max_log_size(200);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+
+    // Each record takes 17 bytes, so the first file holds the first 11 keys or so.
+    for i in 0..30 {
+        store.set(format!("key{i:02}"), "value".to_owned())?;
+    }
+    for i in 0..10 {
+        store.set(format!("key{i:02}"), "value".to_owned())?;
+    }
+
+    let liveness = store.file_liveness();
+    assert!(liveness.len() > 2);
+    assert!(liveness[0].0.ends_with("1.log"));
+    assert!(liveness[0].1 > 0.8, "{liveness:?}");
+    for (path, ratio) in &liveness[1..] {
+        assert_eq!(*ratio, 0.0, "{path:?}");
+    }
+
+    store.compact()?;
+    assert!(store.file_liveness().iter().all(|(_, ratio)| *ratio == 0.0));
+    Ok(())
+}
+
+// Every index entry points inside an existing log file, at a record of its key.
+#[test]
+fn index_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::default()
+        .compaction(CompactionStrategy::Off)
+        .max_log_size(200);
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for i in 0..40 {
+        store.set(format!("key{}", i % 20), format!("value{i}"))?;
+    }
+    store.remove("key3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let index = store.index();
+    assert_eq!(index.len(), 19);
+    assert!(index.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    for (key, idx) in &index {
+        let bytes = fs::read(idx.path())?;
+        let end = (idx.offset() + idx.len()) as usize;
+        assert!(end <= bytes.len(), "{key} past the end of {:?}", idx.path());
+        let record = &bytes[idx.offset() as usize..end];
+        let value = format!("value{}", key[3..].parse::<u32>().unwrap() + 20);
+        assert!(record.windows(key.len()).any(|w| w == key.as_bytes()));
+        assert!(record.windows(value.len()).any(|w| w == value.as_bytes()));
+    }
+    Ok(())
+}
+
+// The offsets tracked while writing match the ones read back from the logs,
+// across file rotations and compactions.
+#[test]
+fn write_offsets() -> Result<()> {
+    fn entries(store: &KvStore) -> Vec<(String, PathBuf, u64, u64)> {
+        store
+            .index()
+            .into_iter()
+            .map(|(key, idx)| (key, idx.path().to_owned(), idx.offset(), idx.len()))
+            .collect()
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::default()
+        .compaction(CompactionStrategy::Off)
+        .max_log_size(256);
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for round in 0..3 {
+        for i in 0..50 {
+            store.set(format!("key{}", i % 30), format!("value{round}-{i}"))?;
+        }
+        store.compact()?;
+    }
+    for i in 0..20 {
+        store.set(format!("key{i}"), "after".repeat(i))?;
+    }
+    let written = entries(&store);
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(entries(&store), written);
+    Ok(())
+}
+
+#[test]
+fn in_memory_matches_disk() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let disk = KvStore::open(temp_dir.path())?;
+    let memory = KvStore::open_in_memory()?;
+
+    for store in [&disk, &memory] {
+        // Overwrites and removes leave stale records for the compaction below.
+        for iter in 0..30 {
+            for key_id in 0..100 {
+                store.set(format!("key{}", key_id), format!("value{}", iter))?;
+            }
+        }
+        for key_id in (0..100).step_by(3) {
+            store.remove(format!("key{}", key_id))?;
+        }
+        store.compact()?;
+        store.set("key0".to_owned(), "revived".to_owned())?;
+    }
+
+    for key_id in 0..101 {
+        let key = format!("key{}", key_id);
+        assert_eq!(disk.get(key.clone())?, memory.get(key.clone())?);
+        assert_eq!(disk.remove(key.clone()).is_ok(), memory.remove(key).is_ok());
+    }
+    assert_eq!(memory.get("key0".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(1001));
+    let handles: Vec<_> = (0..1000)
+        .map(|i| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                store
+                    .set(format!("key{}", i), format!("value{}", i))
+                    .unwrap();
+                barrier.wait();
+            })
+        })
+        .collect();
+    barrier.wait();
+
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // Open from disk again and check persistent data, once every clone is
+    // dropped so the directory is free to open.
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+/// Threads setting and removing their own keys, and racing on a shared one,
+/// each see their own writes and leave a consistent store behind.
+fn concurrent_set_remove<E: KvsEngine>(store: E) -> Result<E> {
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..200 {
+                    let key = format!("key{thread_id}-{}", i % 10);
+                    store.set(key.clone(), i.to_string())?;
+                    assert_eq!(store.get(key.clone())?, Some(i.to_string()));
+                    if i % 3 == 0 {
+                        store.remove(key.clone())?;
+                        assert_eq!(store.get(key)?, None);
+                    }
+                    store.set("shared".to_owned(), thread_id.to_string())?;
+                    match store.remove("shared".to_owned()) {
+                        Ok(()) | Err(KvsError::NonExistentKey(_)) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    for thread_id in 0..8 {
+        for k in 0..10 {
+            // The last write of key `k` was at `i = 190 + k`, removed if a multiple of 3.
+            let i = 190 + k;
+            let expected = (i % 3 != 0).then(|| i.to_string());
+            assert_eq!(store.get(format!("key{thread_id}-{k}"))?, expected);
+        }
+    }
+    assert_eq!(store.get("shared".to_owned())?, None);
+    Ok(store)
+}
+
+#[test]
+fn concurrent_set_remove_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(concurrent_set_remove(KvStore::open(temp_dir.path())?)?);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0-1".to_owned())?, Some("191".to_owned()));
+    assert_eq!(store.get("key0-2".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn concurrent_set_remove_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(concurrent_set_remove(SledEngine::open(temp_dir.path())?)?);
+    let store = SledEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key0-1".to_owned())?, Some("191".to_owned()));
+    assert_eq!(store.get("key0-2".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn concurrent_set_remove_memory() -> Result<()> {
+    concurrent_set_remove(MemoryEngine::new()).map(drop)
+}
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+// Readers keep seeing a valid value while a writer overwrites and compacts underneath them.
+// Reads of just written keys never see a torn or misplaced record while
+// appends go on, across file rotations too.
+#[test]
+fn concurrent_append_and_read() -> Result<()> {
+    const KEYS: usize = 2000;
+    // Lengths vary, so a read at a wrong offset can't pass for the right value.
+    fn value(i: usize) -> String {
+        format!("{i}-{}", "x".repeat(i % 97))
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::default()
+        .compaction(CompactionStrategy::Off)
+        .max_log_size(4096);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let written = Arc::new(AtomicUsize::new(0));
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let written = written.clone();
+            thread::spawn(move || -> Result<()> {
+                loop {
+                    let n = written.load(Ordering::SeqCst);
+                    if n == 0 {
+                        continue;
+                    }
+                    for i in [n - 1, n / 2] {
+                        assert_eq!(store.get(format!("key{i}"))?, Some(value(i)));
+                    }
+                    if n == KEYS {
+                        return Ok(());
+                    }
+                }
+            })
+        })
+        .collect();
+    for i in 0..KEYS {
+        store.set(format!("key{i}"), value(i))?;
+        written.store(i + 1, Ordering::SeqCst);
+    }
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn concurrent_read_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let readers: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..2000 {
+                    let key = format!("key{}", (i + thread_id) % 100);
+                    let value = store.get(key).unwrap().expect("value lost");
+                    assert!(value.parse::<u32>().unwrap() < 50);
+                }
+            })
+        })
+        .collect();
+    // Enough overwrites to compact several times.
+    for iter in 1..50 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("49".to_owned()));
+    }
+    Ok(())
+}
+
+// A write racing a compaction waits for it, and is never undone by the
+// compaction swapping in the moved, older record of its key.
+#[test]
+fn write_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_config(
+        temp_dir.path(),
+        KvStoreConfig::default().compaction(CompactionStrategy::Off),
+    )?;
+    // Enough keys for a compaction to take a while.
+    for key_id in 0..5000 {
+        store.set(format!("key{key_id}"), "old".to_owned())?;
+    }
+
+    for round in 0..20 {
+        let barrier = Arc::new(Barrier::new(2));
+        let compactor = {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                store.compact()
+            })
+        };
+        barrier.wait();
+        // Land the write at a different point of the compaction every round.
+        thread::sleep(Duration::from_micros(round * 100));
+        let value = format!("new{round}");
+        store.set(format!("key{round}"), value.clone())?;
+        assert_eq!(store.get(format!("key{round}"))?, Some(value));
+        compactor.join().unwrap()?;
+    }
+
+    for round in 0..20 {
+        assert_eq!(
+            store.get(format!("key{round}"))?,
+            Some(format!("new{round}"))
+        );
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for round in 0..20 {
+        assert_eq!(
+            store.get(format!("key{round}"))?,
+            Some(format!("new{round}"))
+        );
+    }
+    assert_eq!(store.get("key20".to_owned())?, Some("old".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn lock_contention() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.lock_contention(), LockContention::default());
+
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..200 {
+                    store.set(format!("key{thread_id}"), i.to_string()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let contention = store.lock_contention();
+    assert!(contention.acquisitions >= 8 * 200);
+    assert!(contention.total_wait_nanos > 0);
+    assert!(contention.max_wait_nanos > 0);
+    assert!(contention.max_wait_nanos <= contention.total_wait_nanos);
+    Ok(())
+}
+
+#[test]
+fn sled_flush_policies() -> Result<()> {
+    for policy in [
+        FlushPolicy::Always,
+        FlushPolicy::EveryOps(3),
+        FlushPolicy::Interval(Duration::from_millis(10)),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store =
+            SledEngine::open_with_flush_policy(temp_dir.path(), SizeLimits::default(), policy)?;
+        for i in 0..10 {
+            store.set(format!("key{i}"), format!("value{i}"))?;
+        }
+        store.remove("key0".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        thread::sleep(Duration::from_millis(50));
+
+        drop(store);
+        let store = SledEngine::open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    }
+    Ok(())
+}
+
+fn increment_float<E: KvsEngine>(store: E) -> Result<()> {
+    assert_eq!(store.increment_float("f".to_owned(), 1.5)?, 1.5);
+    assert_eq!(store.increment_float("f".to_owned(), -0.25)?, 1.25);
+    assert_eq!(store.get("f".to_owned())?, Some("1.25".to_owned()));
+    // Large and tiny values are stored as plain decimals.
+    assert_eq!(store.increment_float("big".to_owned(), 1e21)?, 1e21);
+    assert_eq!(
+        store.get("big".to_owned())?,
+        Some("1000000000000000000000".to_owned())
+    );
+
+    store.set("text".to_owned(), "abc".to_owned())?;
+    store.set("inf".to_owned(), "inf".to_owned())?;
+    for key in ["text", "inf"] {
+        assert!(matches!(
+            store.increment_float(key.to_owned(), 1.0),
+            Err(KvsError::NotAFloat)
+        ));
+    }
+    for delta in [f64::NAN, f64::INFINITY] {
+        assert!(matches!(
+            store.increment_float("f".to_owned(), delta),
+            Err(KvsError::NonFiniteFloat)
+        ));
+    }
+    store.set("max".to_owned(), f64::MAX.to_string())?;
+    assert!(matches!(
+        store.increment_float("max".to_owned(), f64::MAX),
+        Err(KvsError::NonFiniteFloat)
+    ));
+    assert_eq!(store.get("f".to_owned())?, Some("1.25".to_owned()));
+
+    // No increment is lost when many threads add at once.
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    store.increment_float("sum".to_owned(), 0.1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let sum: f64 = store.get("sum".to_owned())?.unwrap().parse().unwrap();
+    assert!((sum - 80.0).abs() < 1e-9);
+    Ok(())
+}
+
+fn commit<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("balance".to_owned(), "10".to_owned())?;
+    store.set("stale".to_owned(), "x".to_owned())?;
+    let watched = vec![
+        ("balance".to_owned(), Some("10".to_owned())),
+        ("lock".to_owned(), None),
+    ];
+    store.commit(
+        watched.clone(),
+        vec![
+            BatchOp::Set("balance".to_owned(), "7".to_owned()),
+            BatchOp::Set("log".to_owned(), "-3".to_owned()),
+            BatchOp::Remove("stale".to_owned()),
+        ],
+    )?;
+    assert_eq!(store.get("balance".to_owned())?, Some("7".to_owned()));
+    assert_eq!(store.get("log".to_owned())?, Some("-3".to_owned()));
+    assert_eq!(store.get("stale".to_owned())?, None);
+
+    // The balance changed since it was watched, so nothing is applied.
+    let result = store.commit(
+        watched,
+        vec![BatchOp::Set("log".to_owned(), "-3,-3".to_owned())],
+    );
+    assert!(matches!(result, Err(KvsError::Conflict)), "{result:?}");
+    assert_eq!(store.get("log".to_owned())?, Some("-3".to_owned()));
+
+    // A watched key that appeared conflicts too.
+    store.set("lock".to_owned(), "held".to_owned())?;
+    let result = store.commit(vec![("lock".to_owned(), None)], vec![]);
+    assert!(matches!(result, Err(KvsError::Conflict)), "{result:?}");
+
+    // A remove of a missing key fails the whole batch.
+    let result = store.commit(
+        vec![],
+        vec![
+            BatchOp::Set("balance".to_owned(), "0".to_owned()),
+            BatchOp::Remove("stale".to_owned()),
+        ],
+    );
+    assert!(
+        matches!(&result, Err(KvsError::NonExistentKey(key)) if key == "stale"),
+        "{result:?}"
+    );
+    assert_eq!(store.get("balance".to_owned())?, Some("7".to_owned()));
+    Ok(())
+}
+
+/// Copy every file under `from` to `to`, as a backup of a data directory would.
+fn copy_dir(from: &std::path::Path, to: &std::path::Path) -> Result<()> {
+    for entry in WalkDir::new(from) {
+        let entry = entry.unwrap();
+        let dest = to.join(entry.path().strip_prefix(from).unwrap());
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(dest)?;
+        } else {
+            fs::copy(entry.path(), dest)?;
+        }
+    }
+    Ok(())
+}
+
+// A copy of the data directory taken after a flush, with the store still
+// open, holds every write made before it.
+#[test]
+fn flush_before_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        KvStore::open_with_config(temp_dir.path(), KvStoreConfig::default().max_log_size(256))?;
+    for i in 0..100 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    store.flush()?;
+    copy_dir(temp_dir.path(), backup.path())?;
+    store.set("later".to_owned(), "value".to_owned())?;
+
+    let copy = KvStore::open(backup.path())?;
+    assert_eq!(copy.len()?, 100);
+    assert_eq!(copy.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(copy.get("later".to_owned())?, None);
+
+    // Sled keeps writes in memory until flushed, unless it flushes each one.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledEngine::open_with_flush_policy(
+        temp_dir.path(),
+        SizeLimits::default(),
+        FlushPolicy::EveryOps(1000),
+    )?;
+    for i in 0..100 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    store.flush()?;
+    copy_dir(temp_dir.path(), backup.path())?;
+
+    let copy = SledEngine::open(backup.path())?;
+    assert_eq!(copy.len()?, 100);
+    assert_eq!(copy.get("key99".to_owned())?, Some("value99".to_owned()));
+
+    // Nothing to do for an engine with nothing on disk.
+    MemoryEngine::new().flush()
+}
+
+fn size_limits<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("k".repeat(16), "v".repeat(32))?;
+    assert!(matches!(
+        store.set("k".repeat(17), "v".to_owned()),
+        Err(KvsError::KeyTooLarge {
+            limit: 16,
+            actual: 17
+        })
+    ));
+    assert!(matches!(
+        store.set("key".to_owned(), "v".repeat(33)),
+        Err(KvsError::ValueTooLarge {
+            limit: 32,
+            actual: 33
+        })
+    ));
+    assert_eq!(store.get("key".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn size_limits_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let limits = SizeLimits {
+        max_key_size: 16,
+        max_value_size: 32,
+    };
+    let config = KvStoreConfig::default().limits(limits);
+    size_limits(KvStore::open_with_config(temp_dir.path(), config)?)
+}
+
+#[test]
+fn size_limits_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let limits = SizeLimits {
+        max_key_size: 16,
+        max_value_size: 32,
+    };
+    size_limits(SledEngine::open_with_limits(temp_dir.path(), limits)?)
+}
+
+#[test]
+fn size_limits_memory() -> Result<()> {
+    size_limits(MemoryEngine::with_limits(SizeLimits {
+        max_key_size: 16,
+        max_value_size: 32,
+    }))
+}
+
+// Only keys under the prefix go, and expired ones aren't counted.
+fn remove_prefix<E: KvsEngine>(store: E) -> Result<()> {
+    for i in 0..10 {
+        store.set(format!("tenant:1:{i}"), "v".to_owned())?;
+        store.set(format!("tenant:2:{i}"), "v".to_owned())?;
+    }
+    store.set_with_ttl("tenant:1:old".to_owned(), "v".to_owned(), Duration::ZERO)?;
+    assert_eq!(store.remove_prefix("tenant:1:".to_owned())?, 10);
+    assert!(store.scan("tenant:1:".to_owned())?.is_empty());
+    assert_eq!(store.scan("tenant:".to_owned())?.len(), 10);
+    assert_eq!(store.remove_prefix("tenant:1:".to_owned())?, 0);
+    assert_eq!(store.remove_prefix(String::new())?, 10);
+    assert_eq!(store.get("tenant:2:0".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn remove_prefix_persists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    remove_prefix(KvStore::open(temp_dir.path())?)?;
+    // The removals are in the log.
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.scan(String::new())?.is_empty());
+    Ok(())
+}
+
+// Readers racing a prefix removal see all of its keys or none.
+#[test]
+fn remove_prefix_atomic() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let done = Arc::new(AtomicUsize::new(0));
+    let reader = {
+        let store = store.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            while done.load(Ordering::SeqCst) == 0 {
+                let len = store.scan("prefix:".to_owned()).unwrap().len();
+                assert!(len == 0 || len == 100, "saw {len} keys");
+            }
+        })
+    };
+    for _ in 0..20 {
+        // A batch lands at once too.
+        let batch = (0..100)
+            .map(|i| BatchOp::Set(format!("prefix:{i}"), "v".to_owned()))
+            .collect();
+        store.write_batch(batch)?;
+        assert_eq!(store.remove_prefix("prefix:".to_owned())?, 100);
+    }
+    done.store(1, Ordering::SeqCst);
+    reader.join().unwrap();
+    Ok(())
+}
+
+fn contains_key<E: KvsEngine>(store: E) -> Result<()> {
+    assert!(!store.contains_key("key".to_owned())?);
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert!(store.contains_key("key".to_owned())?);
+    store.remove("key".to_owned())?;
+    assert!(!store.contains_key("key".to_owned())?);
+    Ok(())
+}
+
+fn modify<E: KvsEngine>(store: E) -> Result<()> {
+    let modify = |key: &str, op| store.modify(key.to_owned(), op);
+    let some = |value: &str| Some(value.to_owned());
+
+    assert_eq!(modify("s", ModifyOp::Append("b".to_owned()))?, some("b"));
+    assert_eq!(modify("s", ModifyOp::Append("c".to_owned()))?, some("bc"));
+    assert_eq!(modify("s", ModifyOp::Prepend("a".to_owned()))?, some("abc"));
+    assert_eq!(modify("p", ModifyOp::Prepend("x".to_owned()))?, some("x"));
+
+    assert_eq!(modify("n", ModifyOp::IncrBy(5))?, some("5"));
+    assert_eq!(modify("n", ModifyOp::IncrBy(-7))?, some("-2"));
+    assert!(matches!(
+        modify("s", ModifyOp::IncrBy(1)),
+        Err(KvsError::NotAnInteger)
+    ));
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        modify("max", ModifyOp::IncrBy(1)),
+        Err(KvsError::IntegerOverflow)
+    ));
+
+    let set_if_equal = |expected: &str, new: &str| ModifyOp::SetIfEqual {
+        expected: expected.to_owned(),
+        new: new.to_owned(),
+    };
+    assert_eq!(modify("s", set_if_equal("abc", "xyz"))?, some("xyz"));
+    assert_eq!(modify("s", set_if_equal("abc", "new"))?, None);
+    assert_eq!(modify("missing", set_if_equal("", "new"))?, None);
+
+    // Failed and skipped ops leave the values alone.
+    assert_eq!(store.get("s".to_owned())?, some("xyz"));
+    assert_eq!(store.get("max".to_owned())?, Some(i64::MAX.to_string()));
+    assert_eq!(store.get("missing".to_owned())?, None);
+    Ok(())
+}
+
+fn increment<E: KvsEngine>(store: E) -> Result<()> {
+    assert_eq!(store.increment("n".to_owned(), 5)?, 5);
+    assert_eq!(store.increment("n".to_owned(), -7)?, -2);
+    assert_eq!(store.get("n".to_owned())?, Some("-2".to_owned()));
+
+    store.set("text".to_owned(), "abc".to_owned())?;
+    assert!(matches!(
+        store.increment("text".to_owned(), 1),
+        Err(KvsError::NotAnInteger)
+    ));
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        store.increment("max".to_owned(), 1),
+        Err(KvsError::IntegerOverflow)
+    ));
+
+    // No increment is lost when many threads add at once.
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    store.increment("hits".to_owned(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("hits".to_owned())?, Some("800".to_owned()));
+    Ok(())
+}
+
+fn take<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.take("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(store.take("key".to_owned())?, None);
+
+    // Of many threads taking the same key, exactly one gets it.
+    for round in 0..10 {
+        store.set("job".to_owned(), round.to_string())?;
+        let barrier = Arc::new(Barrier::new(16));
+        let handles: Vec<_> = (0..16)
+            .map(|_| {
+                let store = store.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    store.take("job".to_owned()).unwrap()
+                })
+            })
+            .collect();
+        let taken: Vec<_> = handles
+            .into_iter()
+            .filter_map(|handle| handle.join().unwrap())
+            .collect();
+        assert_eq!(taken, vec![round.to_string()]);
+    }
+    Ok(())
+}
+
+fn get_set<E: KvsEngine>(store: E) -> Result<()> {
+    assert_eq!(store.get_set("key".to_owned(), "one".to_owned())?, None);
+    assert_eq!(
+        store.get_set("key".to_owned(), "two".to_owned())?,
+        Some("one".to_owned())
+    );
+    assert_eq!(store.get("key".to_owned())?, Some("two".to_owned()));
+
+    // An expired value is not returned.
+    store.set_with_ttl(
+        "lease".to_owned(),
+        "old".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.get_set("lease".to_owned(), "new".to_owned())?, None);
+
+    // Every value set is handed back exactly once, to the next caller.
+    let handles: Vec<_> = (0..16)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || store.get_set("lease".to_owned(), i.to_string()).unwrap())
+        })
+        .collect();
+    let mut seen: Vec<_> = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap().unwrap())
+        .collect();
+    seen.push(store.get("lease".to_owned())?.unwrap());
+    seen.sort();
+    let mut expected: Vec<_> = (0..16).map(|i: i32| i.to_string()).collect();
+    expected.push("new".to_owned());
+    expected.sort();
+    assert_eq!(seen, expected);
+    Ok(())
+}
+
+fn len<E: KvsEngine>(store: E) -> Result<()> {
+    assert!(store.is_empty()?);
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    // Overwrites and removals change the count of live keys, not records.
+    for _ in 0..5 {
+        store.set("key0".to_owned(), "again".to_owned())?;
+    }
+    store.remove("key9".to_owned())?;
+    assert_eq!(store.len()?, 9);
+
+    store.set_with_ttl(
+        "lease".to_owned(),
+        "holder".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    assert_eq!(store.len()?, 10);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.len()?, 9);
+    Ok(())
+}
+
+#[test]
+fn len_after_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    len(KvStore::open(temp_dir.path())?)?;
+
+    // Reopened, the count comes from the replayed index.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 9);
+    Ok(())
+}
+
+fn binary_values<E: KvsEngine>(store: E) -> Result<()> {
+    let binary = vec![0xff, 0xfe, 0, 1];
+    store.set_bytes("blob".to_owned(), binary.clone())?;
+    store.set("text".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get_bytes("blob".to_owned())?, Some(binary.clone()));
+    assert_eq!(store.get_bytes("text".to_owned())?, Some(b"value".to_vec()));
+    assert_eq!(store.get_bytes("missing".to_owned())?, None);
+
+    // The string methods refuse a value that isn't UTF-8 and leave it alone.
+    for result in [
+        store.get("blob".to_owned()),
+        store.take("blob".to_owned()),
+        store.get_set("blob".to_owned(), "other".to_owned()),
+    ] {
+        match result {
+            Err(KvsError::IOError(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+            other => panic!("expected an InvalidData error, got {:?}", other),
+        }
+    }
+    assert_eq!(store.get_bytes("blob".to_owned())?, Some(binary));
+    Ok(())
+}
+
+#[test]
+fn binary_values_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    binary_values(store.clone())?;
+
+    // The bytes survive compaction and a reopen.
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_bytes("blob".to_owned())?,
+        Some(vec![0xff, 0xfe, 0, 1])
+    );
+    Ok(())
+}
+
+fn expiry<E: KvsEngine>(store: E) -> Result<()> {
+    let ttl = Duration::from_millis(500);
+    store.set_with_ttl("short".to_owned(), "1".to_owned(), ttl)?;
+    store.set_with_ttl("long".to_owned(), "2".to_owned(), Duration::from_secs(3600))?;
+    store.set_with_ttl("plain".to_owned(), "3".to_owned(), ttl)?;
+    store.set("plain".to_owned(), "4".to_owned())?;
+    store.set_with_ttl("counter".to_owned(), "1".to_owned(), ttl)?;
+    assert_eq!(store.increment_float("counter".to_owned(), 1.0)?, 2.0);
+    assert_eq!(store.get("short".to_owned())?, Some("1".to_owned()));
+    assert!(store.contains_key("short".to_owned())?);
+
+    thread::sleep(ttl + Duration::from_millis(100));
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert!(!store.contains_key("short".to_owned())?);
+    assert!(matches!(
+        store.remove("short".to_owned()),
+        Err(KvsError::NonExistentKey(_))
+    ));
+    // A plain set clears the expiry, an increment keeps it.
+    assert_eq!(store.get("plain".to_owned())?, Some("4".to_owned()));
+    assert_eq!(store.get("counter".to_owned())?, None);
+    let keys: Vec<_> = store
+        .scan(String::new())?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, ["long", "plain"]);
+
+    // An expired key counts as missing for read-modify-writes too.
+    assert_eq!(store.increment_float("short".to_owned(), 5.0)?, 5.0);
+    assert_eq!(store.get("short".to_owned())?, Some("5".to_owned()));
+    Ok(())
+}
+
+// An export dumps the live keys in order, with their expiry, and an import
+// of it into an empty engine restores them.
+fn export_import<E: KvsEngine>(source: E, dest: E) -> Result<()> {
+    source.set("b".to_owned(), "2".to_owned())?;
+    source.set("a".to_owned(), "1\nwith newline".to_owned())?;
+    source.set("gone".to_owned(), "x".to_owned())?;
+    source.remove("gone".to_owned())?;
+    source.set_with_ttl("c".to_owned(), "3".to_owned(), Duration::from_secs(3600))?;
+    source.set_with_ttl(
+        "lapsed".to_owned(),
+        "4".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+
+    let mut dump = Vec::new();
+    source.export(&mut dump)?;
+    let entries: Vec<ExportEntry> = String::from_utf8(dump.clone())
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let keys: Vec<_> = entries.iter().map(|entry| entry.key.as_str()).collect();
+    assert_eq!(keys, ["a", "b", "c"]);
+    assert_eq!(entries[0].value, "1\nwith newline");
+    assert_eq!(entries[0].expires_at, None);
+    assert!(entries[2].expires_at.is_some());
+
+    dest.import(&dump[..])?;
+    assert_eq!(dest.scan(String::new())?, source.scan(String::new())?);
+    let mut again = Vec::new();
+    dest.export(&mut again)?;
+    let expiry = String::from_utf8(again)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            serde_json::from_str::<ExportEntry>(line)
+                .unwrap()
+                .expires_at
+        })
+        .collect::<Vec<_>>();
+    // The expiry is carried over as a TTL, so it may land a moment later.
+    assert_eq!(expiry[..2], [None, None]);
+    let delay = expiry[2].unwrap() - entries[2].expires_at.unwrap();
+    assert!(delay < 1000);
+
+    assert!(dest.import(&b"not json\n"[..]).is_err());
+    Ok(())
+}
+
+#[test]
+fn export_import_kvs() -> Result<()> {
+    let source = TempDir::new().expect("unable to create temporary working directory");
+    let dest = TempDir::new().expect("unable to create temporary working directory");
+    export_import(KvStore::open(source.path())?, KvStore::open(dest.path())?)
+}
+
+#[test]
+fn export_import_sled() -> Result<()> {
+    let source = TempDir::new().expect("unable to create temporary working directory");
+    let dest = TempDir::new().expect("unable to create temporary working directory");
+    export_import(
+        SledEngine::open(source.path())?,
+        SledEngine::open(dest.path())?,
+    )
+}
+
+#[test]
+fn export_import_memory() -> Result<()> {
+    export_import(MemoryEngine::new(), MemoryEngine::new())
+}
+
+// Watchers see sets and removes as they happen, and expired keys once they
+// are dropped, which is distinct from a client's remove.
+#[test]
+fn watch_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let changes = store.watch();
+    let change = |key: &str, kind, value: Option<&str>| Change {
+        key: key.to_owned(),
+        kind,
+        value: value.map(|value| value.as_bytes().to_vec()),
+    };
+
+    let ttl = Duration::from_millis(200);
+    store.set_with_ttl("short".to_owned(), "1".to_owned(), ttl)?;
+    store.set("plain".to_owned(), "2".to_owned())?;
+    store.remove("plain".to_owned())?;
+    assert_eq!(
+        changes.try_recv(),
+        Ok(change("short", ChangeKind::Set, Some("1")))
+    );
+    assert_eq!(
+        changes.try_recv(),
+        Ok(change("plain", ChangeKind::Set, Some("2")))
+    );
+    assert_eq!(
+        changes.try_recv(),
+        Ok(change("plain", ChangeKind::Remove, None))
+    );
+
+    thread::sleep(ttl + Duration::from_millis(100));
+    assert!(changes.try_recv().is_err());
+    // The read comes across the expired key, the next write drops it.
+    assert_eq!(store.get("short".to_owned())?, None);
+    store.set("other".to_owned(), "3".to_owned())?;
+    assert_eq!(
+        changes.try_recv(),
+        Ok(change("short", ChangeKind::Expired, None))
+    );
+    assert_eq!(
+        changes.try_recv(),
+        Ok(change("other", ChangeKind::Set, Some("3")))
+    );
+
+    // Compaction drops expired keys nothing touched.
+    store.set_with_ttl("short".to_owned(), "4".to_owned(), ttl)?;
+    assert_eq!(
+        changes.try_recv(),
+        Ok(change("short", ChangeKind::Set, Some("4")))
+    );
+    thread::sleep(ttl + Duration::from_millis(100));
+    store.compact()?;
+    assert_eq!(
+        changes.try_recv(),
+        Ok(change("short", ChangeKind::Expired, None))
+    );
+    assert!(changes.try_recv().is_err());
+    Ok(())
+}
+
+// Every engine reports the changes made through it, in the order they were
+// made, with the values set.
+fn watch_changes<E: KvsEngine>(store: E) -> Result<()> {
+    let changes = store.watch();
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set_bytes("b".to_owned(), vec![0xff])?;
+    let batch = vec![
+        BatchOp::Remove("a".to_owned()),
+        BatchOp::Set("c".to_owned(), "2".to_owned()),
+    ];
+    store.commit(Vec::new(), batch)?;
+    assert_eq!(store.remove_prefix(String::new())?, 2);
+
+    let change = |key: &str, kind, value: Option<&[u8]>| Change {
+        key: key.to_owned(),
+        kind,
+        value: value.map(<[u8]>::to_vec),
+    };
+    let expected = [
+        change("a", ChangeKind::Set, Some(b"1")),
+        change("b", ChangeKind::Set, Some(&[0xff])),
+        change("a", ChangeKind::Remove, None),
+        change("c", ChangeKind::Set, Some(b"2")),
+        change("b", ChangeKind::Remove, None),
+        change("c", ChangeKind::Remove, None),
+    ];
+    assert_eq!(changes.try_iter().collect::<Vec<_>>(), expected);
+    Ok(())
+}
+
+// Every engine reports a key it drops on expiry as expired, not removed,
+// and only once.
+fn watch_expired<E: KvsEngine>(store: E) -> Result<()> {
+    let changes = store.watch();
+    let ttl = Duration::from_millis(200);
+    store.set_with_ttl("short".to_owned(), "1".to_owned(), ttl)?;
+    thread::sleep(ttl + Duration::from_millis(100));
+    assert!(matches!(
+        store.remove("short".to_owned()),
+        Err(KvsError::NonExistentKey(_))
+    ));
+    assert_eq!(store.get("short".to_owned())?, None);
+    store.set("other".to_owned(), "2".to_owned())?;
+
+    let change = |key: &str, kind, value: Option<&str>| Change {
+        key: key.to_owned(),
+        kind,
+        value: value.map(|value| value.as_bytes().to_vec()),
+    };
+    let expected = [
+        change("short", ChangeKind::Set, Some("1")),
+        change("short", ChangeKind::Expired, None),
+        change("other", ChangeKind::Set, Some("2")),
+    ];
+    assert_eq!(changes.try_iter().collect::<Vec<_>>(), expected);
+    Ok(())
+}
+
+// Expired records stay hidden after a restart and are reclaimed by compaction.
+#[test]
+fn expiry_across_reopen_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig::default().compaction(CompactionStrategy::Off);
+    let ttl = Duration::from_millis(500);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key".to_owned(), "old".to_owned())?;
+    store.set_with_ttl("key".to_owned(), "new".to_owned(), ttl)?;
+    for i in 0..10 {
+        store.set_with_ttl(format!("temp{i}"), "value".to_owned(), ttl)?;
+    }
+    store.set("kept".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    assert_eq!(store.get("key".to_owned())?, Some("new".to_owned()));
+    thread::sleep(ttl + Duration::from_millis(100));
+    // Reading an expired key drops it from the index on the next write.
+    assert_eq!(store.get("temp0".to_owned())?, None);
+    store.set("other".to_owned(), "value".to_owned())?;
+    assert!(store.index().iter().all(|(key, _)| key != "temp0"));
+    drop(store);
+
+    // The expired set hides the older value rather than bringing it back.
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key".to_owned())?, None);
+    store.compact()?;
+    let keys: Vec<_> = store.index().into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, ["kept", "other"]);
+    assert_eq!(store.stale_bytes(), 0);
+    Ok(())
+}
+
+// The expiry sweep drops expired keys nothing reads from the index, with a
+// tombstone each, and stops with the store.
+#[test]
+fn expiry_sweep() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let interval = Duration::from_millis(100);
+    let config = KvStoreConfig::default()
+        .compaction(CompactionStrategy::Off)
+        .expiry_sweep_interval(Some(interval));
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let changes = store.watch();
+    let ttl = Duration::from_millis(200);
+    for i in 0..500 {
+        store.set_with_ttl(format!("temp{i}"), "value".to_owned(), ttl)?;
+    }
+    store.set("kept".to_owned(), "value".to_owned())?;
+    assert!(store.index().len() > 1);
+    let stale = store.stale_bytes();
+    let log_size = fs::metadata(temp_dir.path().join("1.log"))?.len();
+
+    thread::sleep(ttl + interval * 3);
+    let keys: Vec<_> = store.index().into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, ["kept"]);
+    assert_eq!(store.len()?, 1);
+    assert!(store.stale_bytes() > stale);
+    assert!(fs::metadata(temp_dir.path().join("1.log"))?.len() > log_size);
+    let expired = changes
+        .try_iter()
+        .filter(|change| change.kind == ChangeKind::Expired)
+        .count();
+    assert_eq!(expired, 500);
+    drop(store);
 
+    // The sweeper let go of the store, so it opens again right away.
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.index().len(), 1);
     Ok(())
 }
@@ -1,8 +1,10 @@
-use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::Duration;
 
-use kvs::Result;
 use kvs::thread_pool::*;
+use kvs::{KvsError, Result};
 
 use crossbeam_utils::sync::WaitGroup;
 
@@ -29,6 +31,30 @@ fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
     Ok(())
 }
 
+// The bounded queue both pools offer outside of `ThreadPool`.
+trait BoundedPool: ThreadPool {
+    fn with_queue_bound(threads: u32, bound: usize) -> Result<Self>;
+    fn try_spawn(&self, job: impl FnOnce() + Send + 'static) -> Result<()>;
+}
+
+impl BoundedPool for NaiveThreadPool {
+    fn with_queue_bound(threads: u32, bound: usize) -> Result<Self> {
+        NaiveThreadPool::with_queue_bound(threads, bound)
+    }
+    fn try_spawn(&self, job: impl FnOnce() + Send + 'static) -> Result<()> {
+        NaiveThreadPool::try_spawn(self, job)
+    }
+}
+
+impl BoundedPool for SharedQueueThreadPool {
+    fn with_queue_bound(threads: u32, bound: usize) -> Result<Self> {
+        SharedQueueThreadPool::with_queue_bound(threads, bound)
+    }
+    fn try_spawn(&self, job: impl FnOnce() + Send + 'static) -> Result<()> {
+        SharedQueueThreadPool::try_spawn(self, job)
+    }
+}
+
 fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
     const TASK_NUM: usize = 1000;
 
@@ -64,7 +90,131 @@ fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
 //     spawn_counter(pool)
 // }
 
+#[test]
+fn naive_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<NaiveThreadPool>()
+}
+
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn cgroup_quota_caps_threads() {
+    assert_eq!(thread_count(8, None), 8);
+    assert_eq!(thread_count(8, Some(2.0)), 2);
+    // Half a CPU more still runs one more thread at a time.
+    assert_eq!(thread_count(8, Some(2.5)), 3);
+    assert_eq!(thread_count(2, Some(16.0)), 2);
+    assert_eq!(thread_count(8, Some(0.1)), 1);
+
+    assert_eq!(parse_cpu_max("max 100000\n"), None);
+    assert_eq!(parse_cpu_max("150000 100000\n"), Some(1.5));
+    assert_eq!(parse_cpu_max(""), None);
+    assert!(default_threads() >= 1);
+}
+
+fn full_queue_applies_backpressure<P: BoundedPool + Sync>() -> Result<()> {
+    let pool = P::with_queue_bound(1, 2)?;
+    let (release, blocked) = mpsc::channel::<()>();
+    let (started, on_start) = mpsc::channel();
+    // Keep the only worker busy until released.
+    pool.spawn(move || {
+        started.send(()).unwrap();
+        blocked.recv().unwrap();
+    });
+    on_start.recv().unwrap();
+
+    let ran = Arc::new(AtomicUsize::new(0));
+    for _ in 0..2 {
+        let ran = ran.clone();
+        pool.try_spawn(move || {
+            ran.fetch_add(1, Ordering::SeqCst);
+        })?;
+    }
+    assert!(matches!(pool.try_spawn(|| {}), Err(KvsError::QueueFull)));
+
+    // A blocking spawn waits for room instead of failing.
+    thread::scope(|scope| {
+        let spawner = scope.spawn(|| {
+            let ran = ran.clone();
+            pool.spawn(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(!spawner.is_finished());
+        release.send(()).unwrap();
+        spawner.join().unwrap();
+    });
+
+    drop(pool);
+    assert_eq!(ran.load(Ordering::SeqCst), 3);
+    Ok(())
+}
+
+#[test]
+fn naive_full_queue_applies_backpressure() -> Result<()> {
+    full_queue_applies_backpressure::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_full_queue_applies_backpressure() -> Result<()> {
+    full_queue_applies_backpressure::<SharedQueueThreadPool>()
+}
+
+// Dropping the pool runs every job queued before it, not just the ones
+// already taken by a worker.
+fn drop_drains_queued_jobs<P: BoundedPool>() -> Result<()> {
+    const TASK_NUM: usize = 100;
+
+    let pool = P::with_queue_bound(2, TASK_NUM)?;
+    let ran = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let ran = ran.clone();
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(1));
+            ran.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    drop(pool);
+    assert_eq!(ran.load(Ordering::SeqCst), TASK_NUM);
+    Ok(())
+}
+
+#[test]
+fn naive_drop_drains_queued_jobs() -> Result<()> {
+    drop_drains_queued_jobs::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_drop_drains_queued_jobs() -> Result<()> {
+    drop_drains_queued_jobs::<SharedQueueThreadPool>()
+}
+
+// Many spawners racing for a small queue all get their jobs run, none left
+// asleep once there is room.
+#[test]
+fn shared_queue_concurrent_spawners() -> Result<()> {
+    const SPAWNERS: usize = 8;
+    const TASK_NUM: usize = 1000;
+
+    let pool = SharedQueueThreadPool::with_queue_bound(4, 4)?;
+    let ran = Arc::new(AtomicUsize::new(0));
+    thread::scope(|scope| {
+        for _ in 0..SPAWNERS {
+            scope.spawn(|| {
+                for _ in 0..TASK_NUM {
+                    let ran = ran.clone();
+                    pool.spawn(move || {
+                        ran.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+            });
+        }
+    });
+    drop(pool);
+    assert_eq!(ran.load(Ordering::SeqCst), SPAWNERS * TASK_NUM);
+    Ok(())
+}